@@ -1,34 +1,374 @@
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::convert::{TryFrom, TryInto};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Error {
     // Generic
     Unexpected(String),
+    Io(Cause),
     NotImplemented,
+    NotFound {
+        path: Path,
+    },
+    // Another file is already at the path, e.g. the destination of a rename.
+    AlreadyExists {
+        path: Path,
+    },
+    PermissionDenied,
+    // The storage is a ReadOnlyStorage.
+    ReadOnly,
+    InvalidPath(String),
+    Config(String),
+    WriteVerificationFailed {
+        path: Path,
+        expected_hash: [u8; 32],
+        actual_hash: [u8; 32],
+    },
+    ReplicationTimeout {
+        region: String,
+    },
+    QuotaExceeded {
+        requested: u64,
+        available: u64,
+    },
+    SameBackend,
+    // The backend received a content that doesn't match its checksum, i.e. it was corrupted in
+    // transit.
+    ChecksumMismatch,
+    // The replicas in these regions have different files from the primary one.
+    JournalDivergence {
+        regions: Vec<String>,
+    },
 
     // For s3
-    RusotoFail(String),
-    SerdeFail(String),
+    RusotoFail(Cause),
+    SerdeFail(Cause),
+    CryptoFail(String),
+}
+
+// The underlying error from another library, which Error::source returns. It's shared so that
+// Error stays Clone, and compared and hashed by its message.
+#[derive(Clone)]
+pub struct Cause(Arc<dyn std::error::Error + Send + Sync>);
+
+impl Cause {
+    // A String or a &str makes a cause of just the message.
+    pub fn new(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        Cause(Arc::from(err.into()))
+    }
+
+    // Use downcast_ref on this to get e.g. the std::io::Error.
+    pub fn get(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+        &*self.0
+    }
+}
+
+impl std::fmt::Debug for Cause {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        std::fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+impl std::fmt::Display for Cause {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        std::fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl PartialEq for Cause {
+    fn eq(&self, other: &Cause) -> bool {
+        self.to_string() == other.to_string()
+    }
+}
+
+impl Eq for Cause {}
+
+impl std::hash::Hash for Cause {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.to_string().hash(state);
+    }
+}
+
+impl Error {
+    // Check if the operation may succeed on retry, e.g., a network error. Backend errors are
+    // classified by their messages because RusotoError is generic over the operation.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::RusotoFail(cause) => {
+                let msg = cause.to_string().to_lowercase();
+                [
+                    "connection reset",
+                    "timeout",
+                    "timed out",
+                    "slowdown",
+                    "503",
+                ]
+                .iter()
+                .any(|pat| msg.contains(pat))
+            }
+            Error::ReplicationTimeout { .. } => true,
+            Error::ChecksumMismatch => true,
+            _ => false,
+        }
+    }
+
+    // Check if the error is due to the credentials or the permissions, which retrying won't fix.
+    pub fn is_auth_error(&self) -> bool {
+        match self {
+            Error::PermissionDenied => true,
+            Error::RusotoFail(cause) => {
+                let msg = cause.to_string();
+                [
+                    "AccessDenied",
+                    "InvalidAccessKeyId",
+                    "SignatureDoesNotMatch",
+                    "ExpiredToken",
+                ]
+                .iter()
+                .any(|pat| msg.contains(pat))
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Unexpected(msg) => write!(f, "unexpected error: {}", msg),
+            Error::Io(cause) => write!(f, "I/O error: {}", cause),
+            Error::NotImplemented => write!(f, "not implemented"),
+            Error::NotFound { path } => write!(f, "file not found: {}", path),
+            Error::AlreadyExists { path } => write!(f, "file already exists: {}", path),
+            Error::PermissionDenied => write!(f, "permission denied"),
+            Error::ReadOnly => write!(f, "storage is read-only"),
+            Error::InvalidPath(msg) => write!(f, "invalid path: {}", msg),
+            Error::Config(msg) => write!(f, "invalid configuration: {}", msg),
+            Error::WriteVerificationFailed { path, .. } => write!(
+                f,
+                "written content of {} doesn't match its digest",
+                path.to_string()
+            ),
+            Error::ReplicationTimeout { region } => {
+                write!(f, "replication to {} timed out", region)
+            }
+            Error::QuotaExceeded {
+                requested,
+                available,
+            } => write!(
+                f,
+                "quota exceeded: {} bytes requested, {} bytes available",
+                requested, available
+            ),
+            Error::SameBackend => write!(f, "source and destination are the same storage"),
+            Error::ChecksumMismatch => write!(f, "content doesn't match its checksum"),
+            Error::JournalDivergence { regions } => {
+                write!(
+                    f,
+                    "regions diverged from the primary: {}",
+                    regions.join(", ")
+                )
+            }
+            Error::RusotoFail(cause) => write!(f, "S3 error: {}", cause),
+            Error::SerdeFail(cause) => write!(f, "serialization error: {}", cause),
+            Error::CryptoFail(msg) => write!(f, "crypto error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(cause) | Error::RusotoFail(cause) | Error::SerdeFail(cause) => {
+                Some(cause.get())
+            }
+            _ => None,
+        }
+    }
 }
 
 impl From<std::io::Error> for Error {
     fn from(from: std::io::Error) -> Self {
-        Error::Unexpected(from.to_string())
+        Error::Io(Cause::new(from))
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+// Path is serialized as a slash-joined string (e.g. "a/b") in human-readable formats such as JSON,
+// and as the struct itself in binary formats such as bincode.
+// Paths are ordered lexicographically by their elements, so "a/b" comes before "a-b".
+// The default is the root, which has no elements.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Path {
     elms: Vec<String>,
 }
 
+// The representation of Path in binary formats.
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Path")]
+struct PathRepr {
+    elms: Vec<String>,
+}
+
+impl Serialize for Path {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            PathRepr {
+                elms: self.elms.clone(),
+            }
+            .serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Path {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        let path = if deserializer.is_human_readable() {
+            Path::try_from(String::deserialize(deserializer)?.as_str())
+        } else {
+            Path::new(PathRepr::deserialize(deserializer)?.elms)
+        };
+        path.map_err(D::Error::custom)
+    }
+}
+
 impl Path {
-    pub fn new(elms: Vec<String>) -> Self {
+    /// Make a path of the elements, e.g. `["a", "b"]` for "a/b".
+    ///
+    /// An element must not be empty, `.` or `..`, nor contain '/', so that a path never
+    /// escapes the root it is resolved against.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use asynq::aqfs::Path;
+    ///
+    /// let path = Path::new(vec!["a".to_string(), "b".to_string()]).unwrap();
+    /// assert_eq!(path.to_string(), "a/b");
+    /// assert_eq!(path, "a/b".parse::<Path>().unwrap());
+    /// assert!(Path::new(vec!["..".to_string()]).is_err());
+    /// ```
+    pub fn new(elms: Vec<String>) -> Result<Self, Error> {
+        for elm in &elms {
+            validate_elm(elm)?;
+        }
+        Ok(Path { elms })
+    }
+
+    // Resolve self against base, i.e., concatenate base and self.
+    // This is purely structural; no filesystem is accessed, so symlinks are not followed.
+    // Neither path can contain `..`, so the result is always under base.
+    pub fn resolve(&self, base: &Path) -> Path {
+        let mut elms = base.elms.clone();
+        elms.extend(self.elms.iter().cloned());
         Path { elms }
     }
+
+    // The inverse of resolve: strip base from self if self starts with it.
+    pub fn relative_to(&self, base: &Path) -> Option<Path> {
+        if !self.elms.starts_with(&base.elms) {
+            return None;
+        }
+        Some(Path {
+            elms: self.elms[base.elms.len()..].to_vec(),
+        })
+    }
+
+    // Match self against a glob pattern such as "src/**/*.rs", without any glob library.
+    // pattern is split by '/' into segments, each of which matches one component. In a segment,
+    // '*' matches any characters, but never '/', so "src/*" matches "src/a" but not "src/a/b".
+    // A segment of just "**" matches any number of components, including none, so "**/*.rs"
+    // matches "a.rs" and "a/b/c.rs". There are no other special characters, e.g. '?' or "[a-z]".
+    pub fn simple_match(&self, pattern: &str) -> bool {
+        let segments: Vec<&str> = if pattern.is_empty() {
+            vec![]
+        } else {
+            pattern.split('/').collect()
+        };
+        match_segments(&segments, &self.elms)
+    }
+
+    // Whether any component starts with '.', e.g. ".DS_Store" or ".git/config".
+    pub fn is_hidden(&self) -> bool {
+        self.elms.iter().any(|elm| elm.starts_with('.'))
+    }
+}
+
+fn validate_elm(elm: &str) -> Result<(), Error> {
+    if elm.is_empty() {
+        return Err(Error::InvalidPath("empty component".to_string()));
+    }
+    if elm == "." || elm == ".." {
+        return Err(Error::InvalidPath(format!("{:?} component", elm)));
+    }
+    if elm.contains('/') {
+        return Err(Error::InvalidPath(format!("'/' in component: {:?}", elm)));
+    }
+    Ok(())
+}
+
+fn match_segments(segments: &[&str], elms: &[String]) -> bool {
+    match segments.split_first() {
+        None => elms.is_empty(),
+        Some((&"**", rest)) => (0..=elms.len()).any(|skip| match_segments(rest, &elms[skip..])),
+        Some((segment, rest)) => match elms.split_first() {
+            Some((elm, elms)) => {
+                match_segment(segment.as_bytes(), elm.as_bytes()) && match_segments(rest, elms)
+            }
+            None => false,
+        },
+    }
+}
+
+// Bytes are compared. '*' may stop in the middle of a character, but then nothing after it can
+// match, since a UTF-8 continuation byte never equals the first byte of a character.
+fn match_segment(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| match_segment(rest, &name[skip..])),
+        Some((c, rest)) => name.first() == Some(c) && match_segment(rest, &name[1..]),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PathError {
+    // The path has a root, a prefix, `.`, or `..`.
+    AbsoluteOrRelativePath,
+    NonUtf8Component,
+}
+
+impl From<PathError> for Error {
+    fn from(from: PathError) -> Self {
+        Error::InvalidPath(format!("{:?}", from))
+    }
+}
+
+impl TryFrom<&std::path::Path> for Path {
+    type Error = PathError;
+
+    fn try_from(from: &std::path::Path) -> Result<Self, Self::Error> {
+        let mut elms = Vec::new();
+        for component in from.components() {
+            match component {
+                std::path::Component::Normal(elm) => {
+                    elms.push(elm.to_str().ok_or(PathError::NonUtf8Component)?.to_string())
+                }
+                _ => return Err(PathError::AbsoluteOrRelativePath),
+            }
+        }
+        Ok(Path { elms })
+    }
 }
 
 impl From<&Path> for std::path::PathBuf {
@@ -41,116 +381,3790 @@ impl From<&Path> for std::path::PathBuf {
     }
 }
 
-impl ToString for Path {
-    fn to_string(&self) -> String {
-        self.elms.join("/")
+// Split a slash-joined string such as "a/b". The empty string is the root.
+// A leading, trailing or doubled '/' makes an empty component, which is rejected.
+impl TryFrom<&str> for Path {
+    type Error = Error;
+
+    fn try_from(from: &str) -> Result<Self, Error> {
+        if from.is_empty() {
+            return Ok(Path { elms: vec![] });
+        }
+        Path::new(from.split('/').map(|elm| elm.to_string()).collect())
+    }
+}
+
+impl std::str::FromStr for Path {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Path::try_from(s)
+    }
+}
+
+// The inverse of TryFrom<&str>, e.g. "a/b". The root is the empty string.
+impl std::fmt::Display for Path {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.elms.join("/"))
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+/// The metadata of a file.
+///
+/// Human-readable formats such as JSON get an RFC 3339 mtime.
+/// Binary formats such as bincode keep the derived representation.
+///
+/// # Examples
+///
+/// ```
+/// use asynq::aqfs::{FileMeta, Path};
+/// use chrono::{TimeZone, Utc};
+///
+/// let meta = FileMeta {
+///     path: "a/b.txt".parse().unwrap(),
+///     mtime: Utc.timestamp(0, 0),
+///     locked: false,
+///     content_type: Some("text/plain".to_string()),
+///     presigned_url: None,
+///     custom: Default::default(),
+///     sha256: None,
+///     size: None,
+/// };
+/// // Or only with what matters.
+/// let built = FileMeta::builder()
+///     .path("a/b.txt")
+///     .mtime(Utc.timestamp(0, 0))
+///     .content_type("text/plain")
+///     .build()
+///     .unwrap();
+/// assert_eq!(meta, built);
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct FileMeta {
     pub path: Path,
+    #[serde(with = "mtime_serde")]
     pub mtime: DateTime<Utc>,
+    // Locked files can't be overwritten or removed until they are unlocked by unlock_file.
+    pub locked: bool,
+    // MIME type of the content, e.g. "image/png". None if unknown.
+    #[serde(default)]
+    pub content_type: Option<String>,
+    // URL to download the content without credentials, which is set only by create_file of
+    // storages configured to do so. It's never stored.
+    #[serde(skip)]
+    pub presigned_url: Option<String>,
+    // Backend-specific attributes by namespaced keys, e.g. "xattr.user.foo" for local::Storage.
+    // It's a BTreeMap, not a HashMap, to keep FileMeta hashable.
+    #[serde(default)]
+    pub custom: BTreeMap<String, String>,
+    // SHA-256 digest of the content as written, if the storage records it.
+    #[serde(default)]
+    pub sha256: Option<[u8; 32]>,
+    // Length of the content in bytes. None if unknown, e.g. for files journaled before it was
+    // recorded.
+    #[serde(default)]
+    pub size: Option<u64>,
 }
 
-#[async_trait(?Send)]
-pub trait File {
-    fn meta(&self) -> &FileMeta;
-    async fn read_all(&mut self) -> Result<Vec<u8>, Error>;
+impl FileMeta {
+    pub fn builder() -> FileMetaBuilder {
+        FileMetaBuilder::default()
+    }
+
+    // Check if self and other are the same file content-wise, ignoring timestamps.
+    // The sizes and the digests are compared only if both have them; otherwise this is path_eq.
+    pub fn content_eq(&self, other: &FileMeta) -> bool {
+        if let (Some(s0), Some(s1)) = (self.size, other.size) {
+            if s0 != s1 {
+                return false;
+            }
+        }
+        match (&self.sha256, &other.sha256) {
+            (Some(d0), Some(d1)) => self.path_eq(other) && d0 == d1,
+            _ => self.path_eq(other),
+        }
+    }
+
+    // Check if self and other are at the same path.
+    pub fn path_eq(&self, other: &FileMeta) -> bool {
+        self.path == other.path
+    }
 }
 
-#[async_trait(?Send)]
-pub trait StorageEntity<F: File> {
-    async fn list_files(&mut self) -> Result<Vec<F>, Error>;
-    async fn create_file(&mut self, mut file: impl File + 'async_trait) -> Result<(), Error>;
-    async fn remove_file(&mut self, file: &F) -> Result<(), Error>;
+// Hash only what identifies the file, not when it was written, so that the same file at different
+// times falls into the same bucket. Equality still compares every field, so FileMeta is still a
+// poor map key: a touched file becomes a different key. Key maps by Path, as RamStorage does.
+impl std::hash::Hash for FileMeta {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.path.hash(state);
+        self.sha256.hash(state);
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub struct RamFile {
-    meta: FileMeta,
-    data: Vec<u8>,
+// Order by path and then by mtime. The other fields only break ties, to stay consistent with Eq.
+impl Ord for FileMeta {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (&self.path, &self.mtime)
+            .cmp(&(&other.path, &other.mtime))
+            .then_with(|| self.locked.cmp(&other.locked))
+            .then_with(|| self.content_type.cmp(&other.content_type))
+            .then_with(|| self.presigned_url.cmp(&other.presigned_url))
+            .then_with(|| self.custom.cmp(&other.custom))
+            .then_with(|| self.sha256.cmp(&other.sha256))
+            .then_with(|| self.size.cmp(&other.size))
+    }
 }
 
-impl RamFile {
-    pub fn new(meta: FileMeta, data: Vec<u8>) -> Self {
-        Self { meta, data }
+impl PartialOrd for FileMeta {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
     }
 }
 
-#[async_trait(?Send)]
-impl File for RamFile {
-    fn meta(&self) -> &FileMeta {
-        &self.meta
+// FileMeta hashed by all its fields, for when mtime matters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetaWithMtime<'a>(pub &'a FileMeta);
+
+impl std::hash::Hash for MetaWithMtime<'_> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        let meta = self.0;
+        meta.path.hash(state);
+        meta.mtime.hash(state);
+        meta.locked.hash(state);
+        meta.content_type.hash(state);
+        meta.presigned_url.hash(state);
+        meta.custom.hash(state);
+        meta.sha256.hash(state);
+        meta.size.hash(state);
     }
+}
 
-    async fn read_all(&mut self) -> Result<Vec<u8>, Error> {
-        Ok(self.data.clone())
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileMetaError {
+    MissingPath,
+    InvalidPath(String),
+}
+
+impl From<FileMetaError> for Error {
+    fn from(from: FileMetaError) -> Self {
+        match from {
+            FileMetaError::InvalidPath(msg) => Error::InvalidPath(msg),
+            _ => Error::Unexpected(format!("{:?}", from)),
+        }
     }
 }
 
-pub struct RamStorage {
-    files: HashMap<Path, RamFile>,
+// Build a FileMeta without spelling out every field. Only path is required; mtime defaults to
+// the time of build.
+#[derive(Debug, Clone, Default)]
+pub struct FileMetaBuilder {
+    // An invalid path is kept as its error message until build.
+    path: Option<Result<Path, String>>,
+    mtime: Option<DateTime<Utc>>,
+    locked: bool,
+    content_type: Option<String>,
+    custom: BTreeMap<String, String>,
+    sha256: Option<[u8; 32]>,
+    size: Option<u64>,
 }
 
-impl RamStorage {
-    pub fn new() -> Self {
-        Self {
-            files: HashMap::new(),
+impl FileMetaBuilder {
+    pub fn path<P>(&mut self, path: P) -> &mut Self
+    where
+        P: TryInto<Path>,
+        P::Error: std::fmt::Display,
+    {
+        self.path = Some(path.try_into().map_err(|e| e.to_string()));
+        self
+    }
+
+    pub fn mtime(&mut self, mtime: DateTime<Utc>) -> &mut Self {
+        self.mtime = Some(mtime);
+        self
+    }
+
+    pub fn locked(&mut self, locked: bool) -> &mut Self {
+        self.locked = locked;
+        self
+    }
+
+    pub fn content_type(&mut self, content_type: &str) -> &mut Self {
+        self.content_type = Some(content_type.to_string());
+        self
+    }
+
+    pub fn custom(&mut self, custom: BTreeMap<String, String>) -> &mut Self {
+        self.custom = custom;
+        self
+    }
+
+    pub fn sha256(&mut self, digest: [u8; 32]) -> &mut Self {
+        self.sha256 = Some(digest);
+        self
+    }
+
+    pub fn size(&mut self, size: u64) -> &mut Self {
+        self.size = Some(size);
+        self
+    }
+
+    pub fn build(&self) -> Result<FileMeta, FileMetaError> {
+        Ok(FileMeta {
+            path: self
+                .path
+                .clone()
+                .ok_or(FileMetaError::MissingPath)?
+                .map_err(FileMetaError::InvalidPath)?,
+            mtime: self.mtime.unwrap_or_else(Utc::now),
+            locked: self.locked,
+            content_type: self.content_type.clone(),
+            presigned_url: None,
+            custom: self.custom.clone(),
+            sha256: self.sha256,
+            size: self.size,
+        })
+    }
+}
+
+pub(crate) mod mtime_serde {
+    use chrono::{DateTime, Utc};
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        mtime: &DateTime<Utc>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&mtime.to_rfc3339())
+        } else {
+            mtime.serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<Utc>, D::Error> {
+        if deserializer.is_human_readable() {
+            let s = String::deserialize(deserializer)?;
+            DateTime::parse_from_rfc3339(&s)
+                .map(|t| t.with_timezone(&Utc))
+                .map_err(D::Error::custom)
+        } else {
+            DateTime::<Utc>::deserialize(deserializer)
         }
     }
 }
 
-#[async_trait(?Send)]
-impl StorageEntity<RamFile> for RamStorage {
-    async fn list_files(&mut self) -> Result<Vec<RamFile>, Error> {
+// What a storage is backed by, e.g., a directory or a bucket. Two storages with the same identity
+// read and write the same files.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StorageIdentity(String);
+
+impl StorageIdentity {
+    pub fn new(id: String) -> Self {
+        StorageIdentity(id)
+    }
+}
+
+// Optional features a storage supports, so that callers can choose the cheaper way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities {
+    // list_files_stream yields files before the whole listing is done.
+    pub supports_streaming_list: bool,
+}
+
+#[async_trait]
+pub trait File: Send + Sync {
+    fn meta(&self) -> &FileMeta;
+    /// Read the whole content.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use asynq::aqfs::{File, FileMeta, RamFile};
+    ///
+    /// let meta = FileMeta::builder().path("a.txt").build().unwrap();
+    /// let mut file = RamFile::new(meta, b"hello".to_vec());
+    /// let data = tokio_test::block_on(file.read_all()).unwrap();
+    /// assert_eq!(data, b"hello");
+    /// ```
+    #[must_use = "this async operation must be awaited"]
+    async fn read_all(&mut self) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::new();
+        self.read_stream().await?.read_to_end(&mut data).await?;
+        Ok(data)
+    }
+
+    // Read the content little by little, so that a large file needn't be in memory at once.
+    #[must_use = "this async operation must be awaited"]
+    async fn read_stream<'a>(&'a mut self) -> Result<FileReader<'a>, Error>;
+
+    // The length of the content if it's known without reading it.
+    fn size_hint(&self) -> Option<u64> {
+        None
+    }
+
+    // SHA-256 digest of the content: FileMeta::sha256 if the storage recorded it, or else
+    // computed by reading the content.
+    #[must_use = "this async operation must be awaited"]
+    async fn content_hash(&mut self) -> Result<[u8; 32], Error> {
+        if let Some(digest) = self.meta().sha256 {
+            return Ok(digest);
+        }
+        hash_reader(self.read_stream().await?).await
+    }
+
+    // Read at most len bytes from offset. As HTTP ranges, a range past the end yields only the
+    // bytes before the end, which is nothing if offset is at or after the end.
+    // By default the stream is read up to offset and discarded.
+    #[must_use = "this async operation must be awaited"]
+    async fn read_range(&mut self, offset: u64, len: u64) -> Result<Vec<u8>, Error> {
+        let mut reader = self.read_stream().await?;
+        tokio::io::copy(&mut (&mut reader).take(offset), &mut tokio::io::sink()).await?;
+        let mut data = Vec::new();
+        reader.take(len).read_to_end(&mut data).await?;
+        Ok(data)
+    }
+
+    // Erase the type, e.g. to hold files of different storages together.
+    fn boxed(self) -> Box<dyn File + Send>
+    where
+        Self: Sized + Send + 'static,
+    {
+        Box::new(self)
+    }
+}
+
+// A stream of the content of a file.
+pub type FileReader<'a> = Pin<Box<dyn AsyncRead + Send + 'a>>;
+
+// The file type is an associated type rather than a type parameter since a storage has only one
+// kind of files. This saves users, e.g. StorageSyncer, from carrying it around with PhantomData.
+// Storages, files and their futures are Send, so that they can be driven from tokio::spawn.
+#[async_trait]
+pub trait StorageEntity: Send {
+    type File: File;
+
+    /// List all the files in the storage, in no particular order.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use asynq::aqfs::{File, FileMeta, RamFile, RamStorage, StorageEntity};
+    ///
+    /// tokio_test::block_on(async {
+    ///     let mut st = RamStorage::new();
+    ///     for name in &["a", "b"] {
+    ///         let meta = FileMeta::builder().path(*name).build()?;
+    ///         st.create_file(RamFile::new(meta, vec![])).await?;
+    ///     }
+    ///     let mut files = st.list_files().await?;
+    ///     files.sort_by(|f0, f1| f0.meta().cmp(f1.meta()));
+    ///     assert_eq!(files[0].meta().path.to_string(), "a");
+    ///     assert_eq!(files[1].meta().path.to_string(), "b");
+    ///     Ok::<(), asynq::aqfs::Error>(())
+    /// })
+    /// .unwrap();
+    /// ```
+    #[must_use = "this async operation must be awaited"]
+    async fn list_files(&mut self) -> Result<Vec<Self::File>, Error>;
+
+    // The metadata of the files listed by list_files, for callers that don't read the contents,
+    // e.g. to show them. By default this is list_files; backends should override it if a file
+    // costs more than its metadata.
+    #[must_use = "this async operation must be awaited"]
+    async fn list_filemetas(&mut self) -> Result<Vec<FileMeta>, Error> {
         Ok(self
-            .files
-            .iter()
-            .map(|f| f.1.clone())
-            .collect::<Vec<RamFile>>())
+            .list_files()
+            .await?
+            .into_iter()
+            .map(|f| f.meta().clone())
+            .collect())
     }
 
-    async fn create_file(&mut self, mut file: impl File + 'async_trait) -> Result<(), Error> {
-        self.files.insert(
-            file.meta().path.clone(),
-            RamFile::new(file.meta().clone(), file.read_all().await?),
-        );
+    // The file at path, or Error::NotFound. By default this scans list_files; backends should
+    // override it with a direct lookup.
+    #[must_use = "this async operation must be awaited"]
+    async fn get_file(&mut self, path: &Path) -> Result<Self::File, Error> {
+        self.list_files()
+            .await?
+            .into_iter()
+            .find(|f| &f.meta().path == path)
+            .ok_or_else(|| Error::NotFound { path: path.clone() })
+    }
+
+    // The files and the subdirectories directly under path, sorted by name. A missing directory
+    // is empty. By default this groups list_files by the first component below path.
+    #[must_use = "this async operation must be awaited"]
+    async fn list_dir(&mut self, path: &Path) -> Result<Vec<DirEntry>, Error> {
+        let files = self.list_files().await?;
+        Ok(dir_entries(path, files.iter().map(|f| f.meta())))
+    }
+
+    // Whether a file is at path, without opening it.
+    #[must_use = "this async operation must be awaited"]
+    async fn exists(&mut self, path: &Path) -> Result<bool, Error> {
+        match self.get_file(path).await {
+            Ok(_) => Ok(true),
+            Err(Error::NotFound { .. }) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Write file to the storage, replacing the one at the same path unless it's locked.
+    /// Returns the metadata of the file as it's actually stored, which may differ from the given
+    /// one, e.g., in the resolution of mtime.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use asynq::aqfs::{File, FileMeta, RamFile, RamStorage, StorageEntity};
+    ///
+    /// tokio_test::block_on(async {
+    ///     let mut st = RamStorage::new();
+    ///     let meta = FileMeta::builder().path("a.txt").build()?;
+    ///     let stored = st.create_file(RamFile::new(meta, b"hello".to_vec())).await?;
+    ///     assert_eq!(stored.path.to_string(), "a.txt");
+    ///     assert_eq!(st.list_files().await?[0].read_all().await?, b"hello");
+    ///     Ok::<(), asynq::aqfs::Error>(())
+    /// })
+    /// .unwrap();
+    /// ```
+    #[must_use = "this async operation must be awaited"]
+    async fn create_file(&mut self, mut file: impl File + 'async_trait) -> Result<FileMeta, Error>;
+
+    // Write files as create_file does. The paths must be distinct. By default they are created one
+    // by one, so a failure leaves the files before it created; backends should override it to
+    // write them together.
+    #[must_use = "this async operation must be awaited"]
+    async fn create_files(&mut self, files: Vec<impl File + 'async_trait>) -> Result<(), Error> {
+        for file in files {
+            self.create_file(file).await?;
+        }
         Ok(())
     }
 
-    async fn remove_file(&mut self, file: &RamFile) -> Result<(), Error> {
-        self.files.remove(&file.meta().path);
+    /// Remove file, which is one listed by list_files, unless it's locked.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use asynq::aqfs::{FileMeta, RamFile, RamStorage, StorageEntity};
+    ///
+    /// tokio_test::block_on(async {
+    ///     let mut st = RamStorage::new();
+    ///     let meta = FileMeta::builder().path("a.txt").build()?;
+    ///     st.create_file(RamFile::new(meta, vec![])).await?;
+    ///     let file = st.list_files().await?.remove(0);
+    ///     st.remove_file(&file).await?;
+    ///     assert!(st.list_files().await?.is_empty());
+    ///     Ok::<(), asynq::aqfs::Error>(())
+    /// })
+    /// .unwrap();
+    /// ```
+    #[must_use = "this async operation must be awaited"]
+    async fn remove_file(&mut self, file: &Self::File) -> Result<(), Error>;
+    #[must_use = "this async operation must be awaited"]
+    async fn unlock_file(&mut self, file: &Self::File) -> Result<(), Error>;
+
+    // Move file, which is one listed by list_files, to new_path, which must be vacant. A locked
+    // file can't be moved as it can't be removed. By default the content is copied to new_path
+    // and file is removed; backends should override it to move the file in place.
+    #[must_use = "this async operation must be awaited"]
+    async fn rename_file(&mut self, file: &Self::File, new_path: Path) -> Result<(), Error> {
+        if file.meta().locked {
+            return Err(Error::PermissionDenied);
+        }
+        if self.exists(&new_path).await? {
+            return Err(Error::AlreadyExists { path: new_path });
+        }
+        let data = self.get_file(&file.meta().path).await?.read_all().await?;
+        self.create_file(RamFile::new(
+            FileMeta {
+                path: new_path,
+                ..file.meta().clone()
+            },
+            data,
+        ))
+        .await?;
+        self.remove_file(file).await
+    }
+
+    // Duplicate file, which is one listed by list_files, at dest with mtime set to now. The rest of
+    // the metadata, including locked, is kept. dest must be vacant unless overwrite, with which an
+    // unlocked file at dest is replaced. By default the content is read and written again;
+    // backends should override it to copy in place.
+    #[must_use = "this async operation must be awaited"]
+    async fn copy_file(
+        &mut self,
+        file: &Self::File,
+        dest: Path,
+        overwrite: bool,
+    ) -> Result<(), Error> {
+        if !overwrite && self.exists(&dest).await? {
+            return Err(Error::AlreadyExists { path: dest });
+        }
+        let data = self.get_file(&file.meta().path).await?.read_all().await?;
+        self.create_file(RamFile::new(
+            FileMeta {
+                path: dest,
+                mtime: Utc::now(),
+                ..file.meta().clone()
+            },
+            data,
+        ))
+        .await?;
+        Ok(())
+    }
+
+    // None if the backend can't be identified, in which case it's assumed to be unique.
+    fn identity(&self) -> Option<StorageIdentity> {
+        None
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    // Yield files as they are found so that callers can start processing before the listing
+    // completes. By default this just streams the result of list_files.
+    fn list_files_stream<'a>(&'a mut self) -> BoxStream<'a, Result<Self::File, Error>>
+    where
+        Self::File: 'a,
+    {
+        futures::stream::once(self.list_files())
+            .map_ok(|files| futures::stream::iter(files.into_iter().map(Ok)))
+            .try_flatten()
+            .boxed()
+    }
+
+    // List the files except hidden ones, whose paths are Path::is_hidden. Backends may hide more,
+    // e.g. by file attributes. list_files still lists every file.
+    #[must_use = "this async operation must be awaited"]
+    async fn list_files_visible(&mut self) -> Result<Vec<Self::File>, Error> {
+        Ok(self
+            .list_files()
+            .await?
+            .into_iter()
+            .filter(|f| !f.meta().path.is_hidden())
+            .collect())
+    }
+
+    // List at most limit files after cursor, which is the next_cursor of the previous page or None
+    // for the first page. Files are ordered by their paths, and the cursor is the path of the last
+    // file of the page, so files added or removed between pages don't shift the others. limit must
+    // be positive.
+    // FIXME: This lists and sorts all the files for each page, so listing n files page by page
+    // costs O(n^2 / limit) rather than O(n). Backends should override it.
+    #[must_use = "this async operation must be awaited"]
+    async fn list_files_paged(
+        &mut self,
+        cursor: Option<String>,
+        limit: usize,
+    ) -> Result<PagedResult<Self::File>, Error> {
+        if limit == 0 {
+            return Err(Error::Unexpected("limit must be positive".to_string()));
+        }
+        let mut files: Vec<(String, Self::File)> = self
+            .list_files()
+            .await?
+            .into_iter()
+            .map(|f| (f.meta().path.to_string(), f))
+            .filter(|(path, _)| cursor.as_ref().map_or(true, |cursor| path > cursor))
+            .collect();
+        files.sort_by(|(path0, _), (path1, _)| path0.cmp(path1));
+        let next_cursor = if files.len() > limit {
+            Some(files[limit - 1].0.clone())
+        } else {
+            None
+        };
+        Ok(PagedResult {
+            items: files.into_iter().take(limit).map(|(_, f)| f).collect(),
+            next_cursor,
+        })
+    }
+
+    // Return the file at path, or create it with the metadata and the content made by generator
+    // if it doesn't exist. The path of the generated metadata is ignored.
+    // By default this is not atomic: concurrent callers may both call their generators.
+    #[must_use = "this async operation must be awaited"]
+    async fn get_or_create_file<Gen, Fut>(
+        &mut self,
+        path: &Path,
+        generator: Gen,
+    ) -> Result<Self::File, Error>
+    where
+        Gen: FnOnce() -> Fut + Send,
+        Fut: Future<Output = Result<(FileMeta, Vec<u8>), Error>> + Send,
+    {
+        match self.get_file(path).await {
+            Err(Error::NotFound { .. }) => {}
+            res => return res,
+        }
+        let (meta, data) = generator().await?;
+        self.create_file(RamFile::new(
+            FileMeta {
+                path: path.clone(),
+                ..meta
+            },
+            data,
+        ))
+        .await?;
+        self.get_file(path).await
+    }
+
+    // Check that the content of each file matches FileMeta::sha256.
+    #[must_use = "this async operation must be awaited"]
+    async fn verify_integrity(&mut self) -> Result<IntegrityReport, Error> {
+        let mut report = IntegrityReport::default();
+        for mut f in self.list_files().await? {
+            let expected = match f.meta().sha256 {
+                Some(digest) => digest,
+                None => {
+                    report.unverifiable.push(f.meta().clone());
+                    continue;
+                }
+            };
+            let actual = hash_file(&mut f).await?;
+            if actual != expected {
+                report.corrupted.push((f.meta().clone(), actual));
+            }
+        }
+        Ok(report)
+    }
+
+    // The number of bytes the storage can hold, or None if unlimited or unknown.
+    #[must_use = "this async operation must be awaited"]
+    async fn capacity(&mut self) -> Result<Option<u64>, Error> {
+        Ok(None)
+    }
+
+    // The total size of the files in bytes. By default every file is read to count it.
+    #[must_use = "this async operation must be awaited"]
+    async fn used_space(&mut self) -> Result<u64, Error> {
+        let mut used = 0;
+        for mut f in self.list_files().await? {
+            used += f.read_all().await?.len() as u64;
+        }
+        Ok(used)
+    }
+
+    // Check if size more bytes can be stored before starting to upload them.
+    #[must_use = "this async operation must be awaited"]
+    async fn quota_check(&mut self, size: u64) -> Result<(), Error> {
+        if let Some(capacity) = self.capacity().await? {
+            let used = self.used_space().await?;
+            if used + size > capacity {
+                return Err(Error::QuotaExceeded {
+                    requested: size,
+                    available: capacity.saturating_sub(used),
+                });
+            }
+        }
         Ok(())
     }
+
+    // Pipe the content of the file at path to writer without making any intermediate storage.
+    // Returns the number of bytes copied.
+    #[must_use = "this async operation must be awaited"]
+    async fn copy_to_writer<W: AsyncWrite + Unpin + Send>(
+        &mut self,
+        path: &Path,
+        writer: &mut W,
+    ) -> Result<u64, Error> {
+        let mut file = self.get_file(path).await?;
+        let mut reader = file.read_stream().await?;
+        Ok(tokio::io::copy(&mut reader, writer).await?)
+    }
+
+    // Erase the type, e.g. to choose the backend at runtime. See DynStorage.
+    fn boxed(self) -> BoxedStorage
+    where
+        Self: Sized + 'static,
+        Self::File: 'static,
+    {
+        Box::new(Erased(self))
+    }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use chrono::offset::TimeZone;
+// An entry of StorageEntity::list_dir. Except in local storage, a directory exists only as long
+// as some file is under it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DirEntry {
+    File(FileMeta),
+    // The name of a subdirectory, e.g. "b" for "a/b/c" in the listing of "a".
+    Dir(String),
+}
 
-    #[tokio::test]
-    async fn ram_storage_works() -> Result<(), Error> {
-        let mut storage = RamStorage::new();
-        let files = storage.list_files().await?;
-        assert_eq!(files.len(), 0);
-        storage
-            .create_file(RamFile::new(
-                FileMeta {
-                    path: Path::new(vec!["dummy-path".to_string()]),
-                    mtime: Utc.timestamp(0, 0),
-                },
-                "dummy content".to_string().into_bytes(),
-            ))
-            .await?;
-        let mut files = storage.list_files().await?;
-        assert_eq!(files.len(), 1);
-        let bytes = files[0].read_all().await?;
-        assert_eq!(std::str::from_utf8(&bytes).unwrap(), "dummy content");
-        storage.remove_file(&files[0]).await?;
-        let files = storage.list_files().await?;
-        assert_eq!(files.len(), 0);
+impl DirEntry {
+    pub fn name(&self) -> &str {
+        match self {
+            DirEntry::File(meta) => meta.path.elms.last().map_or("", |elm| elm.as_str()),
+            DirEntry::Dir(name) => name,
+        }
+    }
+}
+
+// Sort entries by name. A directory comes before a file of the same name, which only flat
+// namespaces such as S3 can have.
+pub(crate) fn sort_dir_entries(entries: &mut Vec<DirEntry>) {
+    entries.sort_by(|a, b| {
+        let is_file = |e: &DirEntry| matches!(e, DirEntry::File(_));
+        (a.name(), is_file(a)).cmp(&(b.name(), is_file(b)))
+    });
+}
+
+// Synthesise the listing of dir from a flat set of files.
+pub(crate) fn dir_entries<'a>(
+    dir: &Path,
+    metas: impl IntoIterator<Item = &'a FileMeta>,
+) -> Vec<DirEntry> {
+    let mut dirs = BTreeSet::new();
+    let mut entries = Vec::new();
+    for meta in metas {
+        let rel = match meta.path.relative_to(dir) {
+            Some(rel) => rel,
+            None => continue,
+        };
+        match rel.elms.len() {
+            0 => {}
+            1 => entries.push(DirEntry::File(meta.clone())),
+            _ => {
+                dirs.insert(rel.elms[0].clone());
+            }
+        }
+    }
+    entries.extend(dirs.into_iter().map(DirEntry::Dir));
+    sort_dir_entries(&mut entries);
+    entries
+}
+
+// The result of StorageEntity::verify_integrity.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[must_use]
+pub struct IntegrityReport {
+    // The files whose contents don't match their digests, with the actual digests.
+    pub corrupted: Vec<(FileMeta, [u8; 32])>,
+    // The files without digests.
+    pub unverifiable: Vec<FileMeta>,
+}
+
+// A page of StorageEntity::list_files_paged. next_cursor is None on the last page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PagedResult<F> {
+    pub items: Vec<F>,
+    pub next_cursor: Option<String>,
+}
+
+// Two storages operated together, e.g., the source and the destination of a sync.
+pub struct StoragePair<A: StorageEntity, B: StorageEntity> {
+    first: A,
+    second: B,
+}
+
+impl<A: StorageEntity, B: StorageEntity> StoragePair<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        Self { first, second }
+    }
+
+    pub fn first(&self) -> &A {
+        &self.first
+    }
+
+    pub fn first_mut(&mut self) -> &mut A {
+        &mut self.first
+    }
+
+    pub fn second(&self) -> &B {
+        &self.second
+    }
+
+    pub fn second_mut(&mut self) -> &mut B {
+        &mut self.second
+    }
+
+    // Borrow both at once, e.g., to copy files from one to the other.
+    pub fn both_mut(&mut self) -> (&mut A, &mut B) {
+        (&mut self.first, &mut self.second)
+    }
+
+    pub fn into_parts(self) -> (A, B) {
+        (self.first, self.second)
+    }
+
+    // Check if both are backed by the same thing. Unidentifiable storages are assumed to differ.
+    pub fn is_same_backend(&self) -> bool {
+        match (self.first.identity(), self.second.identity()) {
+            (Some(id0), Some(id1)) => id0 == id1,
+            _ => false,
+        }
+    }
+}
+
+// A file of either of two storages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EitherFile<FA, FB> {
+    A(FA),
+    B(FB),
+}
+
+#[async_trait]
+impl<FA: File, FB: File> File for EitherFile<FA, FB> {
+    fn meta(&self) -> &FileMeta {
+        match self {
+            EitherFile::A(f) => f.meta(),
+            EitherFile::B(f) => f.meta(),
+        }
+    }
+
+    async fn read_all(&mut self) -> Result<Vec<u8>, Error> {
+        match self {
+            EitherFile::A(f) => f.read_all().await,
+            EitherFile::B(f) => f.read_all().await,
+        }
+    }
+
+    async fn read_stream<'a>(&'a mut self) -> Result<FileReader<'a>, Error> {
+        match self {
+            EitherFile::A(f) => f.read_stream().await,
+            EitherFile::B(f) => f.read_stream().await,
+        }
+    }
+
+    fn size_hint(&self) -> Option<u64> {
+        match self {
+            EitherFile::A(f) => f.size_hint(),
+            EitherFile::B(f) => f.size_hint(),
+        }
+    }
+
+    async fn read_range(&mut self, offset: u64, len: u64) -> Result<Vec<u8>, Error> {
+        match self {
+            EitherFile::A(f) => f.read_range(offset, len).await,
+            EitherFile::B(f) => f.read_range(offset, len).await,
+        }
+    }
+
+    async fn content_hash(&mut self) -> Result<[u8; 32], Error> {
+        match self {
+            EitherFile::A(f) => f.content_hash().await,
+            EitherFile::B(f) => f.content_hash().await,
+        }
+    }
+}
+
+// The files of a and those of b, where a's one wins if both have a file at the same path.
+async fn list_union<A: StorageEntity, B: StorageEntity>(
+    a: &mut A,
+    b: &mut B,
+) -> Result<Vec<EitherFile<A::File, B::File>>, Error> {
+    let mut files: Vec<_> = a
+        .list_files()
+        .await?
+        .into_iter()
+        .map(EitherFile::A)
+        .collect();
+    let paths: HashSet<Path> = files.iter().map(|f| f.meta().path.clone()).collect();
+    files.extend(
+        b.list_files()
+            .await?
+            .into_iter()
+            .filter(|f| !paths.contains(&f.meta().path))
+            .map(EitherFile::B),
+    );
+    Ok(files)
+}
+
+// The file at path in a, or in b only if a doesn't have it, as list_union prefers a's.
+async fn get_either<A: StorageEntity, B: StorageEntity>(
+    a: &mut A,
+    b: &mut B,
+    path: &Path,
+) -> Result<EitherFile<A::File, B::File>, Error> {
+    match a.get_file(path).await {
+        Ok(file) => Ok(EitherFile::A(file)),
+        Err(Error::NotFound { .. }) => Ok(EitherFile::B(b.get_file(path).await?)),
+        Err(err) => Err(err),
+    }
+}
+
+// Re-key the file at path in files to new_path, for the storages in memory.
+fn rename_in_map(
+    files: &mut HashMap<Path, RamFile>,
+    path: &Path,
+    new_path: Path,
+) -> Result<(), Error> {
+    match files.get(path) {
+        None => return Err(Error::NotFound { path: path.clone() }),
+        Some(f) if f.meta.locked => return Err(Error::PermissionDenied),
+        Some(_) => {}
+    }
+    if files.contains_key(&new_path) {
+        return Err(Error::AlreadyExists { path: new_path });
+    }
+    let mut f = files.remove(path).unwrap();
+    f.meta.path = new_path.clone();
+    files.insert(new_path, f);
+    Ok(())
+}
+
+// The copy of the file at path in files to be put at dest, for the storages in memory.
+fn copy_in_map(
+    files: &HashMap<Path, RamFile>,
+    path: &Path,
+    dest: &Path,
+    overwrite: bool,
+) -> Result<RamFile, Error> {
+    let mut f = files
+        .get(path)
+        .cloned()
+        .ok_or_else(|| Error::NotFound { path: path.clone() })?;
+    match files.get(dest) {
+        Some(_) if !overwrite => return Err(Error::AlreadyExists { path: dest.clone() }),
+        Some(g) if g.meta.locked => return Err(Error::PermissionDenied),
+        _ => {}
+    }
+    f.meta.path = dest.clone();
+    f.meta.mtime = Utc::now();
+    Ok(f)
+}
+
+pub(crate) async fn find_file<ST: StorageEntity>(
+    st: &mut ST,
+    path: &Path,
+) -> Result<Option<ST::File>, Error> {
+    match st.get_file(path).await {
+        Ok(f) => Ok(Some(f)),
+        Err(Error::NotFound { .. }) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+pub(crate) async fn remove_at<ST: StorageEntity>(st: &mut ST, path: &Path) -> Result<(), Error> {
+    if let Some(f) = find_file(st, path).await? {
+        st.remove_file(&f).await?;
+    }
+    Ok(())
+}
+
+pub(crate) async fn unlock_at<ST: StorageEntity>(st: &mut ST, path: &Path) -> Result<(), Error> {
+    if let Some(f) = find_file(st, path).await? {
+        st.unlock_file(&f).await?;
+    }
+    Ok(())
+}
+
+// Write to both storages, e.g. chain(local, s3) to keep a local copy of everything uploaded.
+// Files are listed from both, preferring a's, and removed from both.
+pub struct ChainStorage<A: StorageEntity, B: StorageEntity> {
+    a: A,
+    b: B,
+}
+
+pub fn chain<A: StorageEntity, B: StorageEntity>(a: A, b: B) -> ChainStorage<A, B> {
+    ChainStorage { a, b }
+}
+
+impl<A: StorageEntity, B: StorageEntity> ChainStorage<A, B> {
+    pub fn into_parts(self) -> (A, B) {
+        (self.a, self.b)
+    }
+}
+
+#[async_trait]
+impl<A: StorageEntity, B: StorageEntity> StorageEntity for ChainStorage<A, B> {
+    type File = EitherFile<A::File, B::File>;
+
+    async fn list_files(&mut self) -> Result<Vec<Self::File>, Error> {
+        list_union(&mut self.a, &mut self.b).await
+    }
+
+    async fn get_file(&mut self, path: &Path) -> Result<Self::File, Error> {
+        get_either(&mut self.a, &mut self.b, path).await
+    }
+
+    async fn exists(&mut self, path: &Path) -> Result<bool, Error> {
+        Ok(self.a.exists(path).await? || self.b.exists(path).await?)
+    }
+
+    // Returns the metadata stored in a. The file is streamed into a and then copied from there
+    // to b, so that it's never held in memory as a whole.
+    async fn create_file(&mut self, file: impl File + 'async_trait) -> Result<FileMeta, Error> {
+        let meta = self.a.create_file(file).await?;
+        let stored = self.a.get_file(&meta.path).await?;
+        self.b.create_file(stored).await?;
+        Ok(meta)
+    }
+
+    async fn remove_file(&mut self, file: &Self::File) -> Result<(), Error> {
+        remove_at(&mut self.a, &file.meta().path).await?;
+        remove_at(&mut self.b, &file.meta().path).await
+    }
+
+    async fn unlock_file(&mut self, file: &Self::File) -> Result<(), Error> {
+        unlock_at(&mut self.a, &file.meta().path).await?;
+        unlock_at(&mut self.b, &file.meta().path).await
+    }
+}
+
+// Read from primary, falling back to backup for files primary doesn't have, e.g.
+// fallback(cache, origin) for a read-through cache. Files are written only to primary but removed
+// from both.
+pub struct FallbackStorage<A: StorageEntity, B: StorageEntity> {
+    primary: A,
+    backup: B,
+}
+
+pub fn fallback<A: StorageEntity, B: StorageEntity>(
+    primary: A,
+    backup: B,
+) -> FallbackStorage<A, B> {
+    FallbackStorage { primary, backup }
+}
+
+impl<A: StorageEntity, B: StorageEntity> FallbackStorage<A, B> {
+    pub fn into_parts(self) -> (A, B) {
+        (self.primary, self.backup)
+    }
+}
+
+#[async_trait]
+impl<A: StorageEntity, B: StorageEntity> StorageEntity for FallbackStorage<A, B> {
+    type File = EitherFile<A::File, B::File>;
+
+    async fn list_files(&mut self) -> Result<Vec<Self::File>, Error> {
+        list_union(&mut self.primary, &mut self.backup).await
+    }
+
+    async fn get_file(&mut self, path: &Path) -> Result<Self::File, Error> {
+        get_either(&mut self.primary, &mut self.backup, path).await
+    }
+
+    async fn exists(&mut self, path: &Path) -> Result<bool, Error> {
+        Ok(self.primary.exists(path).await? || self.backup.exists(path).await?)
+    }
+
+    async fn create_file(&mut self, file: impl File + 'async_trait) -> Result<FileMeta, Error> {
+        self.primary.create_file(file).await
+    }
+
+    async fn remove_file(&mut self, file: &Self::File) -> Result<(), Error> {
+        remove_at(&mut self.primary, &file.meta().path).await?;
+        remove_at(&mut self.backup, &file.meta().path).await
+    }
+
+    async fn unlock_file(&mut self, file: &Self::File) -> Result<(), Error> {
+        unlock_at(&mut self.primary, &file.meta().path).await?;
+        unlock_at(&mut self.backup, &file.meta().path).await
+    }
+}
+
+// Pass reads through to the inner storage but fail every write with Error::ReadOnly without
+// touching it, e.g. to make sure a sync never modifies its source.
+pub struct ReadOnlyStorage<S: StorageEntity> {
+    inner: S,
+}
+
+impl<S: StorageEntity> ReadOnlyStorage<S> {
+    pub fn new(inner: S) -> Self {
+        ReadOnlyStorage { inner }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+#[async_trait]
+impl<S: StorageEntity> StorageEntity for ReadOnlyStorage<S> {
+    type File = S::File;
+
+    async fn list_files(&mut self) -> Result<Vec<S::File>, Error> {
+        self.inner.list_files().await
+    }
+
+    async fn list_filemetas(&mut self) -> Result<Vec<FileMeta>, Error> {
+        self.inner.list_filemetas().await
+    }
+
+    async fn get_file(&mut self, path: &Path) -> Result<S::File, Error> {
+        self.inner.get_file(path).await
+    }
+
+    async fn list_dir(&mut self, path: &Path) -> Result<Vec<DirEntry>, Error> {
+        self.inner.list_dir(path).await
+    }
+
+    async fn exists(&mut self, path: &Path) -> Result<bool, Error> {
+        self.inner.exists(path).await
+    }
+
+    async fn create_file(&mut self, _file: impl File + 'async_trait) -> Result<FileMeta, Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn create_files(&mut self, _files: Vec<impl File + 'async_trait>) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn remove_file(&mut self, _file: &S::File) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn unlock_file(&mut self, _file: &S::File) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn rename_file(&mut self, _file: &S::File, _new_path: Path) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn copy_file(
+        &mut self,
+        _file: &S::File,
+        _dest: Path,
+        _overwrite: bool,
+    ) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    fn identity(&self) -> Option<StorageIdentity> {
+        self.inner.identity()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn list_files_stream<'a>(&'a mut self) -> BoxStream<'a, Result<S::File, Error>>
+    where
+        S::File: 'a,
+    {
+        self.inner.list_files_stream()
+    }
+
+    async fn list_files_visible(&mut self) -> Result<Vec<S::File>, Error> {
+        self.inner.list_files_visible().await
+    }
+
+    // A missing file fails without calling generator.
+    async fn get_or_create_file<Gen, Fut>(
+        &mut self,
+        path: &Path,
+        _generator: Gen,
+    ) -> Result<S::File, Error>
+    where
+        Gen: FnOnce() -> Fut + Send,
+        Fut: Future<Output = Result<(FileMeta, Vec<u8>), Error>> + Send,
+    {
+        match self.inner.get_file(path).await {
+            Err(Error::NotFound { .. }) => Err(Error::ReadOnly),
+            res => res,
+        }
+    }
+
+    async fn capacity(&mut self) -> Result<Option<u64>, Error> {
+        self.inner.capacity().await
+    }
+
+    async fn used_space(&mut self) -> Result<u64, Error> {
+        self.inner.used_space().await
+    }
+
+    async fn quota_check(&mut self, _size: u64) -> Result<(), Error> {
+        Err(Error::ReadOnly)
+    }
+
+    async fn copy_to_writer<W: AsyncWrite + Unpin + Send>(
+        &mut self,
+        path: &Path,
+        writer: &mut W,
+    ) -> Result<u64, Error> {
+        self.inner.copy_to_writer(path, writer).await
+    }
+}
+
+// Show the files under base in the inner storage as if base were the root, e.g. to sync only
+// "photos/2023" of a bucket. The paths given to it are resolved against base, which they can't
+// escape since a Path has no "..".
+pub struct ScopedStorage<S: StorageEntity> {
+    inner: S,
+    base: Path,
+}
+
+impl<S: StorageEntity> ScopedStorage<S> {
+    pub fn new(inner: S, base: Path) -> Self {
+        ScopedStorage { inner, base }
+    }
+
+    pub fn base(&self) -> &Path {
+        &self.base
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    // The path in the scope of path in the inner storage, or None if it's outside the scope.
+    // base itself is outside as a file can't be at the root.
+    fn scoped(&self, path: &Path) -> Option<Path> {
+        path.relative_to(&self.base).filter(|p| !p.elms.is_empty())
+    }
+
+    fn scoped_meta(&self, meta: FileMeta) -> Result<FileMeta, Error> {
+        match self.scoped(&meta.path) {
+            Some(path) => Ok(FileMeta { path, ..meta }),
+            None => Err(Error::Unexpected(format!(
+                "{} is out of the scope {}",
+                meta.path, self.base
+            ))),
+        }
+    }
+}
+
+// A file whose metadata is replaced, e.g. by ScopedStorage to translate its path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScopedFile<F> {
+    inner: F,
+    meta: FileMeta,
+}
+
+impl<F> ScopedFile<F> {
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+}
+
+#[async_trait]
+impl<F: File> File for ScopedFile<F> {
+    fn meta(&self) -> &FileMeta {
+        &self.meta
+    }
+
+    async fn read_all(&mut self) -> Result<Vec<u8>, Error> {
+        self.inner.read_all().await
+    }
+
+    async fn read_stream<'a>(&'a mut self) -> Result<FileReader<'a>, Error> {
+        self.inner.read_stream().await
+    }
+
+    fn size_hint(&self) -> Option<u64> {
+        self.inner.size_hint()
+    }
+
+    async fn read_range(&mut self, offset: u64, len: u64) -> Result<Vec<u8>, Error> {
+        self.inner.read_range(offset, len).await
+    }
+
+    async fn content_hash(&mut self) -> Result<[u8; 32], Error> {
+        self.inner.content_hash().await
+    }
+}
+
+#[async_trait]
+impl<S: StorageEntity> StorageEntity for ScopedStorage<S> {
+    type File = ScopedFile<S::File>;
+
+    async fn list_files(&mut self) -> Result<Vec<Self::File>, Error> {
+        let files = self.inner.list_files().await?;
+        Ok(files
+            .into_iter()
+            .filter_map(|f| {
+                let path = self.scoped(&f.meta().path)?;
+                Some(ScopedFile {
+                    meta: FileMeta {
+                        path,
+                        ..f.meta().clone()
+                    },
+                    inner: f,
+                })
+            })
+            .collect())
+    }
+
+    async fn get_file(&mut self, path: &Path) -> Result<Self::File, Error> {
+        // The empty path would be base itself, which is outside the scope.
+        if path.elms.is_empty() {
+            return Err(Error::NotFound { path: path.clone() });
+        }
+        let file = match self.inner.get_file(&path.resolve(&self.base)).await {
+            Err(Error::NotFound { .. }) => return Err(Error::NotFound { path: path.clone() }),
+            res => res?,
+        };
+        Ok(ScopedFile {
+            meta: FileMeta {
+                path: path.clone(),
+                ..file.meta().clone()
+            },
+            inner: file,
+        })
+    }
+
+    async fn list_dir(&mut self, path: &Path) -> Result<Vec<DirEntry>, Error> {
+        let entries = self.inner.list_dir(&path.resolve(&self.base)).await?;
+        entries
+            .into_iter()
+            .map(|entry| match entry {
+                DirEntry::File(meta) => Ok(DirEntry::File(self.scoped_meta(meta)?)),
+                dir => Ok(dir),
+            })
+            .collect()
+    }
+
+    async fn exists(&mut self, path: &Path) -> Result<bool, Error> {
+        if path.elms.is_empty() {
+            return Ok(false);
+        }
+        self.inner.exists(&path.resolve(&self.base)).await
+    }
+
+    async fn create_file(&mut self, file: impl File + 'async_trait) -> Result<FileMeta, Error> {
+        let meta = FileMeta {
+            path: file.meta().path.resolve(&self.base),
+            ..file.meta().clone()
+        };
+        let stored = self
+            .inner
+            .create_file(ScopedFile { inner: file, meta })
+            .await?;
+        self.scoped_meta(stored)
+    }
+
+    async fn create_files(&mut self, files: Vec<impl File + 'async_trait>) -> Result<(), Error> {
+        let base = &self.base;
+        let files: Vec<_> = files
+            .into_iter()
+            .map(|file| ScopedFile {
+                meta: FileMeta {
+                    path: file.meta().path.resolve(base),
+                    ..file.meta().clone()
+                },
+                inner: file,
+            })
+            .collect();
+        self.inner.create_files(files).await
+    }
+
+    async fn remove_file(&mut self, file: &Self::File) -> Result<(), Error> {
+        self.inner.remove_file(&file.inner).await
+    }
+
+    async fn unlock_file(&mut self, file: &Self::File) -> Result<(), Error> {
+        self.inner.unlock_file(&file.inner).await
+    }
+
+    async fn rename_file(&mut self, file: &Self::File, new_path: Path) -> Result<(), Error> {
+        self.inner
+            .rename_file(&file.inner, new_path.resolve(&self.base))
+            .await
+    }
+
+    async fn copy_file(
+        &mut self,
+        file: &Self::File,
+        dest: Path,
+        overwrite: bool,
+    ) -> Result<(), Error> {
+        self.inner
+            .copy_file(&file.inner, dest.resolve(&self.base), overwrite)
+            .await
+    }
+
+    // Views of different scopes of a storage are different storages, though they may overlap.
+    fn identity(&self) -> Option<StorageIdentity> {
+        let id = self.inner.identity()?;
+        if self.base.elms.is_empty() {
+            return Some(id);
+        }
+        Some(StorageIdentity(format!("{}/{}", id.0, self.base)))
+    }
+}
+
+// A reversible transformation of contents, e.g. compression, which TransformedStorage applies to
+// the contents before writing them to its inner storage and undoes on read.
+pub trait ContentTransform: Clone + Send + Sync {
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>, Error>;
+
+    // Get back the content from what encode returned.
+    fn decode(&self, data: Vec<u8>) -> Result<Vec<u8>, Error>;
+
+    // The metadata of the content of a file whose stored object is described by meta. By default
+    // the size and the digest of the content are left unknown, since they can't be told without
+    // reading it, and File::content_hash reads the content to compute it.
+    fn content_meta(&self, meta: FileMeta) -> FileMeta {
+        FileMeta {
+            size: None,
+            sha256: None,
+            ..meta
+        }
+    }
+}
+
+// Store the contents of the files in the inner storage transformed by T, e.g. compressed or
+// encrypted. Paths and the rest of the metadata are stored as they are.
+pub struct TransformedStorage<S: StorageEntity, T: ContentTransform> {
+    inner: S,
+    transform: T,
+}
+
+impl<S: StorageEntity, T: ContentTransform> TransformedStorage<S, T> {
+    pub fn new(inner: S, transform: T) -> Self {
+        TransformedStorage { inner, transform }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+async fn encode_file<T: ContentTransform>(
+    transform: &T,
+    mut file: impl File,
+) -> Result<RamFile, Error> {
+    let data = transform.encode(&file.read_all().await?)?;
+    // Let RamFile set the size and the digest of the stored object.
+    let meta = FileMeta {
+        size: None,
+        sha256: None,
+        ..file.meta().clone()
+    };
+    Ok(RamFile::new(meta, data))
+}
+
+pub struct TransformedFile<F, T> {
+    inner: F,
+    meta: FileMeta,
+    transform: T,
+}
+
+impl<F: File, T: ContentTransform> TransformedFile<F, T> {
+    fn new(inner: F, transform: T) -> Self {
+        TransformedFile {
+            meta: transform.content_meta(inner.meta().clone()),
+            inner,
+            transform,
+        }
+    }
+
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+}
+
+#[async_trait]
+impl<F: File, T: ContentTransform> File for TransformedFile<F, T> {
+    fn meta(&self) -> &FileMeta {
+        &self.meta
+    }
+
+    async fn read_all(&mut self) -> Result<Vec<u8>, Error> {
+        self.transform.decode(self.inner.read_all().await?)
+    }
+
+    // The whole content is decoded first, which is also what keeps a tampered encrypted content
+    // from being read at all.
+    // FIXME: Decode while streaming for transforms which allow it, e.g. decompression.
+    async fn read_stream<'a>(&'a mut self) -> Result<FileReader<'a>, Error> {
+        let data = self.read_all().await?;
+        Ok(Box::pin(std::io::Cursor::new(data)))
+    }
+
+    fn size_hint(&self) -> Option<u64> {
+        self.meta.size
+    }
+}
+
+#[async_trait]
+impl<S: StorageEntity, T: ContentTransform> StorageEntity for TransformedStorage<S, T> {
+    type File = TransformedFile<S::File, T>;
+
+    async fn list_files(&mut self) -> Result<Vec<Self::File>, Error> {
+        let files = self.inner.list_files().await?;
+        let transform = &self.transform;
+        Ok(files
+            .into_iter()
+            .map(|f| TransformedFile::new(f, transform.clone()))
+            .collect())
+    }
+
+    async fn list_filemetas(&mut self) -> Result<Vec<FileMeta>, Error> {
+        let metas = self.inner.list_filemetas().await?;
+        let transform = &self.transform;
+        Ok(metas
+            .into_iter()
+            .map(|meta| transform.content_meta(meta))
+            .collect())
+    }
+
+    async fn get_file(&mut self, path: &Path) -> Result<Self::File, Error> {
+        let file = self.inner.get_file(path).await?;
+        Ok(TransformedFile::new(file, self.transform.clone()))
+    }
+
+    async fn list_dir(&mut self, path: &Path) -> Result<Vec<DirEntry>, Error> {
+        let entries = self.inner.list_dir(path).await?;
+        let transform = &self.transform;
+        Ok(entries
+            .into_iter()
+            .map(|entry| match entry {
+                DirEntry::File(meta) => DirEntry::File(transform.content_meta(meta)),
+                dir => dir,
+            })
+            .collect())
+    }
+
+    async fn exists(&mut self, path: &Path) -> Result<bool, Error> {
+        self.inner.exists(path).await
+    }
+
+    async fn create_file(&mut self, file: impl File + 'async_trait) -> Result<FileMeta, Error> {
+        let file = encode_file(&self.transform, file).await?;
+        let stored = self.inner.create_file(file).await?;
+        Ok(self.transform.content_meta(stored))
+    }
+
+    async fn create_files(&mut self, files: Vec<impl File + 'async_trait>) -> Result<(), Error> {
+        let mut encoded = Vec::with_capacity(files.len());
+        for file in files {
+            encoded.push(encode_file(&self.transform, file).await?);
+        }
+        self.inner.create_files(encoded).await
+    }
+
+    async fn remove_file(&mut self, file: &Self::File) -> Result<(), Error> {
+        self.inner.remove_file(&file.inner).await
+    }
+
+    async fn unlock_file(&mut self, file: &Self::File) -> Result<(), Error> {
+        self.inner.unlock_file(&file.inner).await
+    }
+
+    async fn rename_file(&mut self, file: &Self::File, new_path: Path) -> Result<(), Error> {
+        self.inner.rename_file(&file.inner, new_path).await
+    }
+
+    async fn copy_file(
+        &mut self,
+        file: &Self::File,
+        dest: Path,
+        overwrite: bool,
+    ) -> Result<(), Error> {
+        self.inner.copy_file(&file.inner, dest, overwrite).await
+    }
+
+    fn identity(&self) -> Option<StorageIdentity> {
+        self.inner.identity()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn list_files_stream<'a>(&'a mut self) -> BoxStream<'a, Result<Self::File, Error>>
+    where
+        Self::File: 'a,
+    {
+        let transform = self.transform.clone();
+        self.inner
+            .list_files_stream()
+            .map_ok(move |f| TransformedFile::new(f, transform.clone()))
+            .boxed()
+    }
+}
+
+// A file written incrementally, chunk by chunk, without buffering the whole content.
+// The file appears in the storage only after close succeeds.
+#[async_trait]
+pub trait SinkFile: Send {
+    #[must_use = "this async operation must be awaited"]
+    async fn write_chunk(&mut self, data: &[u8]) -> Result<(), Error>;
+    // Returns the metadata of the file as it's actually stored, like StorageEntity::create_file.
+    #[must_use = "this async operation must be awaited"]
+    async fn close(self) -> Result<FileMeta, Error>;
+}
+
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+// The part of data File::read_range returns.
+fn slice_range(data: &[u8], offset: u64, len: u64) -> &[u8] {
+    let start = std::cmp::min(offset, data.len() as u64) as usize;
+    let end = std::cmp::min(offset.saturating_add(len), data.len() as u64) as usize;
+    &data[start..end]
+}
+
+pub(crate) fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut digest = [0u8; 32];
+    digest.copy_from_slice(&Sha256::digest(data));
+    digest
+}
+
+// SHA-256 digest of the content of file, computed while streaming it.
+pub async fn hash_file(file: &mut impl File) -> Result<[u8; 32], Error> {
+    hash_reader(file.read_stream().await?).await
+}
+
+async fn hash_reader(mut reader: FileReader<'_>) -> Result<[u8; 32], Error> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; HASH_BUFFER_SIZE];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.input(&buf[..n]);
+    }
+    let mut digest = [0u8; 32];
+    digest.copy_from_slice(&hasher.result());
+    Ok(digest)
+}
+
+// The content is shared by Arc, so cloning a RamFile doesn't copy it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RamFile {
+    meta: FileMeta,
+    data: Arc<Vec<u8>>,
+}
+
+impl RamFile {
+    // The size of meta is set to that of data, and so is the digest unless meta has one.
+    pub fn new(meta: FileMeta, data: Vec<u8>) -> Self {
+        Self {
+            meta: FileMeta {
+                size: Some(data.len() as u64),
+                sha256: meta.sha256.or_else(|| Some(sha256(&data))),
+                ..meta
+            },
+            data: Arc::new(data),
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    // The content is copied only if it's shared with other RamFiles.
+    pub fn into_parts(self) -> (FileMeta, Vec<u8>) {
+        let data = Arc::try_unwrap(self.data).unwrap_or_else(|data| data.as_ref().clone());
+        (self.meta, data)
+    }
+}
+
+impl From<RamFile> for (FileMeta, Vec<u8>) {
+    fn from(from: RamFile) -> Self {
+        from.into_parts()
+    }
+}
+
+impl From<(FileMeta, Vec<u8>)> for RamFile {
+    fn from((meta, data): (FileMeta, Vec<u8>)) -> Self {
+        RamFile::new(meta, data)
+    }
+}
+
+#[async_trait]
+impl File for RamFile {
+    fn meta(&self) -> &FileMeta {
+        &self.meta
+    }
+
+    async fn read_all(&mut self) -> Result<Vec<u8>, Error> {
+        Ok(self.data.as_ref().clone())
+    }
+
+    async fn read_stream<'a>(&'a mut self) -> Result<FileReader<'a>, Error> {
+        Ok(Box::pin(&self.data[..]))
+    }
+
+    fn size_hint(&self) -> Option<u64> {
+        Some(self.data.len() as u64)
+    }
+
+    async fn read_range(&mut self, offset: u64, len: u64) -> Result<Vec<u8>, Error> {
+        Ok(slice_range(&self.data, offset, len).to_vec())
+    }
+}
+
+#[async_trait]
+impl<F: File + ?Sized> File for Box<F> {
+    fn meta(&self) -> &FileMeta {
+        (**self).meta()
+    }
+
+    async fn read_all(&mut self) -> Result<Vec<u8>, Error> {
+        (**self).read_all().await
+    }
+
+    async fn read_stream<'a>(&'a mut self) -> Result<FileReader<'a>, Error> {
+        (**self).read_stream().await
+    }
+
+    fn size_hint(&self) -> Option<u64> {
+        (**self).size_hint()
+    }
+
+    async fn read_range(&mut self, offset: u64, len: u64) -> Result<Vec<u8>, Error> {
+        (**self).read_range(offset, len).await
+    }
+
+    async fn content_hash(&mut self) -> Result<[u8; 32], Error> {
+        (**self).content_hash().await
+    }
+}
+
+// A pair of metadata and content can be passed to create_file as is.
+#[async_trait]
+impl File for (FileMeta, Vec<u8>) {
+    fn meta(&self) -> &FileMeta {
+        &self.0
+    }
+
+    async fn read_all(&mut self) -> Result<Vec<u8>, Error> {
+        Ok(self.1.clone())
+    }
+
+    async fn read_stream<'b>(&'b mut self) -> Result<FileReader<'b>, Error> {
+        Ok(Box::pin(&self.1[..]))
+    }
+
+    fn size_hint(&self) -> Option<u64> {
+        Some(self.1.len() as u64)
+    }
+
+    async fn read_range(&mut self, offset: u64, len: u64) -> Result<Vec<u8>, Error> {
+        Ok(slice_range(&self.1, offset, len).to_vec())
+    }
+}
+
+#[async_trait]
+impl<'a> File for (FileMeta, &'a [u8]) {
+    fn meta(&self) -> &FileMeta {
+        &self.0
+    }
+
+    async fn read_all(&mut self) -> Result<Vec<u8>, Error> {
+        Ok(self.1.to_vec())
+    }
+
+    async fn read_stream<'b>(&'b mut self) -> Result<FileReader<'b>, Error> {
+        Ok(Box::pin(self.1))
+    }
+
+    fn size_hint(&self) -> Option<u64> {
+        Some(self.1.len() as u64)
+    }
+
+    async fn read_range(&mut self, offset: u64, len: u64) -> Result<Vec<u8>, Error> {
+        Ok(slice_range(self.1, offset, len).to_vec())
+    }
+}
+
+// Things that can be turned into a File, e.g. a pair of metadata and a string content.
+pub trait IntoFile {
+    type File: File;
+
+    fn into_file(self) -> Self::File;
+}
+
+impl<F: File> IntoFile for F {
+    type File = F;
+
+    fn into_file(self) -> F {
+        self
+    }
+}
+
+impl IntoFile for (FileMeta, String) {
+    type File = RamFile;
+
+    fn into_file(self) -> RamFile {
+        RamFile::new(self.0, self.1.into_bytes())
+    }
+}
+
+#[derive(Clone)]
+pub struct RamStorage {
+    files: HashMap<Path, RamFile>,
+    // Interning table of contents keyed by their SHA-256 digests, so that files with identical
+    // contents share the same allocation.
+    contents: HashMap<[u8; 32], Arc<Vec<u8>>>,
+    capacity: Option<u64>,
+}
+
+impl RamStorage {
+    /// Make an empty storage in memory, e.g. for tests.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use asynq::aqfs::{RamStorage, StorageEntity};
+    ///
+    /// let mut st = RamStorage::new();
+    /// assert!(tokio_test::block_on(st.list_files()).unwrap().is_empty());
+    /// ```
+    pub fn new() -> Self {
+        Self {
+            files: HashMap::new(),
+            contents: HashMap::new(),
+            capacity: None,
+        }
+    }
+
+    // Limit the total size of the files. Shared contents are counted for each file.
+    pub fn with_capacity(mut self, capacity: u64) -> Self {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    // Capture the current state, e.g. to diff it with the state after an operation.
+    // The contents are shared with self and never copied.
+    pub fn clone_snapshot(&self) -> RamStorage {
+        self.clone()
+    }
+
+    fn used(&self) -> u64 {
+        self.files.values().map(|f| f.data.len() as u64).sum()
+    }
+
+    fn intern(&mut self, data: Vec<u8>, digest: [u8; 32]) -> Arc<Vec<u8>> {
+        Arc::clone(
+            self.contents
+                .entry(digest)
+                .or_insert_with(|| Arc::new(data)),
+        )
+    }
+
+    // Drop the content of a file that has gone from the interning table if no one uses it.
+    fn release(&mut self, file: RamFile) {
+        if self
+            .files
+            .values()
+            .any(|f| Arc::ptr_eq(&f.data, &file.data))
+        {
+            return;
+        }
+        // The content is keyed by its digest, which is usually the one in the metadata. It may
+        // be a given wrong one, e.g. to test corruption, and then the digest is computed.
+        let interned = |contents: &HashMap<[u8; 32], Arc<Vec<u8>>>, digest: &[u8; 32]| {
+            contents
+                .get(digest)
+                .map_or(false, |data| Arc::ptr_eq(data, &file.data))
+        };
+        let digest = file
+            .meta
+            .sha256
+            .filter(|digest| interned(&self.contents, digest))
+            .unwrap_or_else(|| sha256(&file.data));
+        if interned(&self.contents, &digest) {
+            self.contents.remove(&digest);
+        }
+    }
+}
+
+// The paths that differ between two RamStorages, each sorted.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[must_use]
+pub struct StorageDiff {
+    pub added: Vec<Path>,
+    pub removed: Vec<Path>,
+    // The metadata or the content changed.
+    pub modified: Vec<Path>,
+}
+
+impl StorageDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+#[must_use]
+pub fn diff(before: &RamStorage, after: &RamStorage) -> StorageDiff {
+    let mut res = StorageDiff::default();
+    for (path, f) in after.files.iter() {
+        match before.files.get(path) {
+            None => res.added.push(path.clone()),
+            Some(g) if f.meta != g.meta || f.data != g.data => res.modified.push(path.clone()),
+            Some(_) => {}
+        }
+    }
+    for path in before.files.keys() {
+        if !after.files.contains_key(path) {
+            res.removed.push(path.clone());
+        }
+    }
+    for paths in vec![&mut res.added, &mut res.removed, &mut res.modified] {
+        paths.sort();
+    }
+    res
+}
+
+#[async_trait]
+impl StorageEntity for RamStorage {
+    type File = RamFile;
+
+    async fn list_files(&mut self) -> Result<Vec<RamFile>, Error> {
+        Ok(self
+            .files
+            .iter()
+            .map(|f| f.1.clone())
+            .collect::<Vec<RamFile>>())
+    }
+
+    async fn get_file(&mut self, path: &Path) -> Result<RamFile, Error> {
+        self.files
+            .get(path)
+            .cloned()
+            .ok_or_else(|| Error::NotFound { path: path.clone() })
+    }
+
+    async fn list_dir(&mut self, path: &Path) -> Result<Vec<DirEntry>, Error> {
+        Ok(dir_entries(path, self.files.values().map(|f| &f.meta)))
+    }
+
+    async fn exists(&mut self, path: &Path) -> Result<bool, Error> {
+        Ok(self.files.contains_key(path))
+    }
+
+    async fn create_file(&mut self, mut file: impl File + 'async_trait) -> Result<FileMeta, Error> {
+        if let Some(f) = self.files.get(&file.meta().path) {
+            if f.meta.locked {
+                return Err(Error::PermissionDenied);
+            }
+        }
+        let data = file.read_all().await?;
+        if let Some(capacity) = self.capacity {
+            let replaced = self
+                .files
+                .get(&file.meta().path)
+                .map_or(0, |f| f.data.len() as u64);
+            let used = self.used() - replaced;
+            if used + data.len() as u64 > capacity {
+                return Err(Error::QuotaExceeded {
+                    requested: data.len() as u64,
+                    available: capacity.saturating_sub(used),
+                });
+            }
+        }
+        let digest = sha256(&data);
+        let meta = FileMeta {
+            size: Some(data.len() as u64),
+            sha256: file.meta().sha256.or(Some(digest)),
+            ..file.meta().clone()
+        };
+        let data = self.intern(data, digest);
+        let old = self.files.insert(
+            meta.path.clone(),
+            RamFile {
+                meta: meta.clone(),
+                data,
+            },
+        );
+        if let Some(old) = old {
+            self.release(old);
+        }
+        Ok(meta)
+    }
+
+    async fn remove_file(&mut self, file: &RamFile) -> Result<(), Error> {
+        if let Some(f) = self.files.get(&file.meta().path) {
+            if f.meta.locked {
+                return Err(Error::PermissionDenied);
+            }
+        }
+        if let Some(old) = self.files.remove(&file.meta().path) {
+            self.release(old);
+        }
+        Ok(())
+    }
+
+    async fn rename_file(&mut self, file: &RamFile, new_path: Path) -> Result<(), Error> {
+        rename_in_map(&mut self.files, &file.meta().path, new_path)
+    }
+
+    // The copy shares the content with file.
+    async fn copy_file(
+        &mut self,
+        file: &RamFile,
+        dest: Path,
+        overwrite: bool,
+    ) -> Result<(), Error> {
+        let copied = copy_in_map(&self.files, &file.meta().path, &dest, overwrite)?;
+        if let Some(capacity) = self.capacity {
+            let replaced = self.files.get(&dest).map_or(0, |f| f.data.len() as u64);
+            let used = self.used() - replaced;
+            if used + copied.data.len() as u64 > capacity {
+                return Err(Error::QuotaExceeded {
+                    requested: copied.data.len() as u64,
+                    available: capacity.saturating_sub(used),
+                });
+            }
+        }
+        if let Some(old) = self.files.insert(dest, copied) {
+            self.release(old);
+        }
+        Ok(())
+    }
+
+    async fn unlock_file(&mut self, file: &RamFile) -> Result<(), Error> {
+        if let Some(f) = self.files.get_mut(&file.meta().path) {
+            f.meta.locked = false;
+        }
+        Ok(())
+    }
+
+    async fn capacity(&mut self) -> Result<Option<u64>, Error> {
+        Ok(self.capacity)
+    }
+
+    async fn used_space(&mut self) -> Result<u64, Error> {
+        Ok(self.used())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            supports_streaming_list: true,
+        }
+    }
+
+    // A file is cloned only when it's yielded.
+    fn list_files_stream<'a>(&'a mut self) -> BoxStream<'a, Result<RamFile, Error>> {
+        futures::stream::iter(self.files.values().map(|f| Ok(f.clone()))).boxed()
+    }
+}
+
+// A RamStorage-like storage that can be shared among threads. Its clones share the same files.
+// No interning of contents is done unlike RamStorage.
+#[derive(Debug, Clone, Default)]
+pub struct SharedRamStorage {
+    files: Arc<RwLock<HashMap<Path, RamFile>>>,
+    // Serializes get_or_create_file among the clones.
+    create_lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl SharedRamStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl StorageEntity for SharedRamStorage {
+    type File = RamFile;
+
+    async fn list_files(&mut self) -> Result<Vec<RamFile>, Error> {
+        Ok(self.files.read().unwrap().values().cloned().collect())
+    }
+
+    async fn get_file(&mut self, path: &Path) -> Result<RamFile, Error> {
+        self.files
+            .read()
+            .unwrap()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| Error::NotFound { path: path.clone() })
+    }
+
+    async fn list_dir(&mut self, path: &Path) -> Result<Vec<DirEntry>, Error> {
+        let files = self.files.read().unwrap();
+        Ok(dir_entries(path, files.values().map(|f| &f.meta)))
+    }
+
+    async fn exists(&mut self, path: &Path) -> Result<bool, Error> {
+        Ok(self.files.read().unwrap().contains_key(path))
+    }
+
+    async fn create_file(&mut self, mut file: impl File + 'async_trait) -> Result<FileMeta, Error> {
+        // Read the content before taking the lock not to hold it across await.
+        let data = file.read_all().await?;
+        let mut files = self.files.write().unwrap();
+        if let Some(f) = files.get(&file.meta().path) {
+            if f.meta.locked {
+                return Err(Error::PermissionDenied);
+            }
+        }
+        let f = RamFile::new(file.meta().clone(), data);
+        let meta = f.meta.clone();
+        files.insert(meta.path.clone(), f);
+        Ok(meta)
+    }
+
+    async fn remove_file(&mut self, file: &RamFile) -> Result<(), Error> {
+        let mut files = self.files.write().unwrap();
+        if let Some(f) = files.get(&file.meta().path) {
+            if f.meta.locked {
+                return Err(Error::PermissionDenied);
+            }
+        }
+        files.remove(&file.meta().path);
+        Ok(())
+    }
+
+    async fn rename_file(&mut self, file: &RamFile, new_path: Path) -> Result<(), Error> {
+        rename_in_map(
+            &mut self.files.write().unwrap(),
+            &file.meta().path,
+            new_path,
+        )
+    }
+
+    async fn copy_file(
+        &mut self,
+        file: &RamFile,
+        dest: Path,
+        overwrite: bool,
+    ) -> Result<(), Error> {
+        let mut files = self.files.write().unwrap();
+        let copied = copy_in_map(&files, &file.meta().path, &dest, overwrite)?;
+        files.insert(dest, copied);
+        Ok(())
+    }
+
+    async fn unlock_file(&mut self, file: &RamFile) -> Result<(), Error> {
+        if let Some(f) = self.files.write().unwrap().get_mut(&file.meta().path) {
+            f.meta.locked = false;
+        }
+        Ok(())
+    }
+
+    // Atomic among the clones.
+    async fn get_or_create_file<Gen, Fut>(
+        &mut self,
+        path: &Path,
+        generator: Gen,
+    ) -> Result<RamFile, Error>
+    where
+        Gen: FnOnce() -> Fut + Send,
+        Fut: Future<Output = Result<(FileMeta, Vec<u8>), Error>> + Send,
+    {
+        let _guard = self.create_lock.lock().await;
+        if let Some(f) = self.files.read().unwrap().get(path) {
+            return Ok(f.clone());
+        }
+        let (meta, data) = generator().await?;
+        let file = RamFile::new(
+            FileMeta {
+                path: path.clone(),
+                ..meta
+            },
+            data,
+        );
+        self.create_file(file.clone()).await?;
+        Ok(file)
+    }
+
+    // Clones share the files, so they are identified by the address of them.
+    fn identity(&self) -> Option<StorageIdentity> {
+        Some(StorageIdentity::new(format!(
+            "ram:{:p}",
+            Arc::as_ptr(&self.files)
+        )))
+    }
+}
+
+// A storage shared among tasks. Each method holds the lock until it returns, so that e.g.
+// get_or_create_file of S is atomic among the holders of the Arc.
+#[async_trait]
+impl<S: StorageEntity> StorageEntity for Arc<tokio::sync::Mutex<S>> {
+    type File = S::File;
+
+    async fn list_files(&mut self) -> Result<Vec<S::File>, Error> {
+        let mut st = self.lock().await;
+        st.list_files().await
+    }
+
+    async fn get_file(&mut self, path: &Path) -> Result<S::File, Error> {
+        let mut st = self.lock().await;
+        st.get_file(path).await
+    }
+
+    async fn list_dir(&mut self, path: &Path) -> Result<Vec<DirEntry>, Error> {
+        let mut st = self.lock().await;
+        st.list_dir(path).await
+    }
+
+    async fn exists(&mut self, path: &Path) -> Result<bool, Error> {
+        let mut st = self.lock().await;
+        st.exists(path).await
+    }
+
+    async fn create_file(&mut self, file: impl File + 'async_trait) -> Result<FileMeta, Error> {
+        let mut st = self.lock().await;
+        st.create_file(file).await
+    }
+
+    async fn create_files(&mut self, files: Vec<impl File + 'async_trait>) -> Result<(), Error> {
+        let mut st = self.lock().await;
+        st.create_files(files).await
+    }
+
+    async fn list_filemetas(&mut self) -> Result<Vec<FileMeta>, Error> {
+        let mut st = self.lock().await;
+        st.list_filemetas().await
+    }
+
+    async fn remove_file(&mut self, file: &S::File) -> Result<(), Error> {
+        let mut st = self.lock().await;
+        st.remove_file(file).await
+    }
+
+    async fn unlock_file(&mut self, file: &S::File) -> Result<(), Error> {
+        let mut st = self.lock().await;
+        st.unlock_file(file).await
+    }
+
+    async fn rename_file(&mut self, file: &S::File, new_path: Path) -> Result<(), Error> {
+        let mut st = self.lock().await;
+        st.rename_file(file, new_path).await
+    }
+
+    async fn copy_file(
+        &mut self,
+        file: &S::File,
+        dest: Path,
+        overwrite: bool,
+    ) -> Result<(), Error> {
+        let mut st = self.lock().await;
+        st.copy_file(file, dest, overwrite).await
+    }
+
+    // These can't wait for the lock. While it's held elsewhere, the storage is taken as without
+    // any capabilities, and identified by the address of the mutex as when S has no identity, so
+    // that the holders of the same Arc are still taken as the same backend.
+    fn identity(&self) -> Option<StorageIdentity> {
+        self.try_lock()
+            .ok()
+            .and_then(|st| st.identity())
+            .or_else(|| {
+                Some(StorageIdentity::new(format!(
+                    "mutex:{:p}",
+                    Arc::as_ptr(self)
+                )))
+            })
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.try_lock()
+            .map(|st| st.capabilities())
+            .unwrap_or_default()
+    }
+
+    async fn get_or_create_file<Gen, Fut>(
+        &mut self,
+        path: &Path,
+        generator: Gen,
+    ) -> Result<S::File, Error>
+    where
+        Gen: FnOnce() -> Fut + Send,
+        Fut: Future<Output = Result<(FileMeta, Vec<u8>), Error>> + Send,
+    {
+        let mut st = self.lock().await;
+        st.get_or_create_file(path, generator).await
+    }
+
+    async fn capacity(&mut self) -> Result<Option<u64>, Error> {
+        let mut st = self.lock().await;
+        st.capacity().await
+    }
+
+    async fn used_space(&mut self) -> Result<u64, Error> {
+        let mut st = self.lock().await;
+        st.used_space().await
+    }
+}
+
+// File is object safe as it is, so a file of any storage can be boxed by File::boxed.
+pub type BoxedFile = Box<dyn File>;
+
+pub type BoxedStorage = Box<dyn DynStorage>;
+
+// An object-safe version of StorageEntity, whose files are BoxedFile. Files are passed by their
+// paths since the concrete file type is unknown. Make one by StorageEntity::boxed; BoxedStorage is
+// a StorageEntity again, e.g. to be synced by StorageSyncer.
+#[async_trait]
+pub trait DynStorage: Send {
+    async fn list_files(&mut self) -> Result<Vec<BoxedFile>, Error>;
+    async fn list_filemetas(&mut self) -> Result<Vec<FileMeta>, Error>;
+    async fn get_file(&mut self, path: &Path) -> Result<BoxedFile, Error>;
+    async fn list_dir(&mut self, path: &Path) -> Result<Vec<DirEntry>, Error>;
+    async fn exists(&mut self, path: &Path) -> Result<bool, Error>;
+    async fn create_file<'a>(&mut self, file: Box<dyn File + 'a>) -> Result<FileMeta, Error>;
+    async fn create_files<'a>(&mut self, files: Vec<Box<dyn File + 'a>>) -> Result<(), Error>;
+    async fn remove_file(&mut self, path: &Path) -> Result<(), Error>;
+    async fn unlock_file(&mut self, path: &Path) -> Result<(), Error>;
+    async fn rename_file(&mut self, path: &Path, new_path: Path) -> Result<(), Error>;
+    async fn copy_file(&mut self, path: &Path, dest: Path, overwrite: bool) -> Result<(), Error>;
+    fn identity(&self) -> Option<StorageIdentity>;
+    fn capabilities(&self) -> Capabilities;
+}
+
+// The adapter of StorageEntity::boxed.
+struct Erased<S>(S);
+
+#[async_trait]
+impl<S: StorageEntity> DynStorage for Erased<S>
+where
+    S::File: 'static,
+{
+    async fn list_files(&mut self) -> Result<Vec<BoxedFile>, Error> {
+        Ok(self
+            .0
+            .list_files()
+            .await?
+            .into_iter()
+            .map(|f| Box::new(f) as BoxedFile)
+            .collect())
+    }
+
+    async fn list_filemetas(&mut self) -> Result<Vec<FileMeta>, Error> {
+        self.0.list_filemetas().await
+    }
+
+    async fn get_file(&mut self, path: &Path) -> Result<BoxedFile, Error> {
+        Ok(Box::new(self.0.get_file(path).await?))
+    }
+
+    async fn list_dir(&mut self, path: &Path) -> Result<Vec<DirEntry>, Error> {
+        self.0.list_dir(path).await
+    }
+
+    async fn exists(&mut self, path: &Path) -> Result<bool, Error> {
+        self.0.exists(path).await
+    }
+
+    async fn create_file<'a>(&mut self, file: Box<dyn File + 'a>) -> Result<FileMeta, Error> {
+        self.0.create_file(file).await
+    }
+
+    async fn create_files<'a>(&mut self, files: Vec<Box<dyn File + 'a>>) -> Result<(), Error> {
+        self.0.create_files(files).await
+    }
+
+    async fn remove_file(&mut self, path: &Path) -> Result<(), Error> {
+        let file = self.0.get_file(path).await?;
+        self.0.remove_file(&file).await
+    }
+
+    async fn unlock_file(&mut self, path: &Path) -> Result<(), Error> {
+        let file = self.0.get_file(path).await?;
+        self.0.unlock_file(&file).await
+    }
+
+    async fn rename_file(&mut self, path: &Path, new_path: Path) -> Result<(), Error> {
+        let file = self.0.get_file(path).await?;
+        self.0.rename_file(&file, new_path).await
+    }
+
+    async fn copy_file(&mut self, path: &Path, dest: Path, overwrite: bool) -> Result<(), Error> {
+        let file = self.0.get_file(path).await?;
+        self.0.copy_file(&file, dest, overwrite).await
+    }
+
+    fn identity(&self) -> Option<StorageIdentity> {
+        self.0.identity()
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.0.capabilities()
+    }
+}
+
+#[async_trait]
+impl StorageEntity for BoxedStorage {
+    type File = BoxedFile;
+
+    async fn list_files(&mut self) -> Result<Vec<BoxedFile>, Error> {
+        DynStorage::list_files(&mut **self).await
+    }
+
+    async fn list_filemetas(&mut self) -> Result<Vec<FileMeta>, Error> {
+        DynStorage::list_filemetas(&mut **self).await
+    }
+
+    async fn get_file(&mut self, path: &Path) -> Result<BoxedFile, Error> {
+        DynStorage::get_file(&mut **self, path).await
+    }
+
+    async fn list_dir(&mut self, path: &Path) -> Result<Vec<DirEntry>, Error> {
+        DynStorage::list_dir(&mut **self, path).await
+    }
+
+    async fn exists(&mut self, path: &Path) -> Result<bool, Error> {
+        DynStorage::exists(&mut **self, path).await
+    }
+
+    async fn create_file(&mut self, file: impl File + 'async_trait) -> Result<FileMeta, Error> {
+        DynStorage::create_file(&mut **self, Box::new(file)).await
+    }
+
+    async fn create_files(&mut self, files: Vec<impl File + 'async_trait>) -> Result<(), Error> {
+        let files = files
+            .into_iter()
+            .map(|f| Box::new(f) as Box<dyn File + 'async_trait>)
+            .collect();
+        DynStorage::create_files(&mut **self, files).await
+    }
+
+    async fn remove_file(&mut self, file: &BoxedFile) -> Result<(), Error> {
+        DynStorage::remove_file(&mut **self, &file.meta().path).await
+    }
+
+    async fn unlock_file(&mut self, file: &BoxedFile) -> Result<(), Error> {
+        DynStorage::unlock_file(&mut **self, &file.meta().path).await
+    }
+
+    async fn rename_file(&mut self, file: &BoxedFile, new_path: Path) -> Result<(), Error> {
+        DynStorage::rename_file(&mut **self, &file.meta().path, new_path).await
+    }
+
+    async fn copy_file(
+        &mut self,
+        file: &BoxedFile,
+        dest: Path,
+        overwrite: bool,
+    ) -> Result<(), Error> {
+        DynStorage::copy_file(&mut **self, &file.meta().path, dest, overwrite).await
+    }
+
+    fn identity(&self) -> Option<StorageIdentity> {
+        DynStorage::identity(&**self)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        DynStorage::capabilities(&**self)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use chrono::offset::TimeZone;
+
+    // A file whose stream fails after yielding size bytes, to test the cleanup of the backends.
+    pub(crate) struct FailingFile {
+        pub meta: FileMeta,
+        pub size: usize,
+    }
+
+    struct FailingReader {
+        remaining: usize,
+    }
+
+    impl AsyncRead for FailingReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &mut [u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            if self.remaining == 0 {
+                return std::task::Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "broken stream",
+                )));
+            }
+            let n = std::cmp::min(self.remaining, buf.len());
+            for b in &mut buf[..n] {
+                *b = b'x';
+            }
+            self.remaining -= n;
+            std::task::Poll::Ready(Ok(n))
+        }
+    }
+
+    #[async_trait]
+    impl File for FailingFile {
+        fn meta(&self) -> &FileMeta {
+            &self.meta
+        }
+
+        async fn read_stream<'a>(&'a mut self) -> Result<FileReader<'a>, Error> {
+            Ok(Box::pin(FailingReader {
+                remaining: self.size,
+            }))
+        }
+    }
+
+    // A read-only storage of files as they are given, e.g. without the digests that RamStorage
+    // records, to test the default methods of StorageEntity.
+    pub(crate) struct FixedStorage(pub Vec<RamFile>);
+
+    #[async_trait]
+    impl StorageEntity for FixedStorage {
+        type File = RamFile;
+
+        async fn list_files(&mut self) -> Result<Vec<RamFile>, Error> {
+            Ok(self.0.clone())
+        }
+
+        async fn create_file(
+            &mut self,
+            _file: impl File + 'async_trait,
+        ) -> Result<FileMeta, Error> {
+            Err(Error::ReadOnly)
+        }
+
+        async fn remove_file(&mut self, _file: &RamFile) -> Result<(), Error> {
+            Err(Error::ReadOnly)
+        }
+
+        async fn unlock_file(&mut self, _file: &RamFile) -> Result<(), Error> {
+            Err(Error::ReadOnly)
+        }
+    }
+
+    // Check File::read_range of f, whose content must be b"0123456789", so that every backend
+    // behaves the same.
+    pub(crate) async fn check_read_range(f: &mut impl File) -> Result<(), Error> {
+        for (offset, len, expected) in vec![
+            (0, 10, &b"0123456789"[..]),
+            (2, 3, &b"234"[..]),
+            (0, 0, &b""[..]),
+            // Straddling the end.
+            (8, 5, &b"89"[..]),
+            (5, u64::MAX, &b"56789"[..]),
+            // Past the end.
+            (10, 1, &b""[..]),
+            (20, 5, &b""[..]),
+        ] {
+            assert_eq!(
+                f.read_range(offset, len).await?,
+                expected,
+                "{}+{}",
+                offset,
+                len
+            );
+        }
+        Ok(())
+    }
+
+    fn path(elms: &[&str]) -> Path {
+        Path::new(elms.iter().map(|s| s.to_string()).collect()).unwrap()
+    }
+
+    #[test]
+    fn file_meta_hash_ignores_mtime() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+
+        fn hash_of(value: impl Hash) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            value.hash(&mut hasher);
+            hasher.finish()
+        }
+        let meta0 = FileMeta::builder()
+            .path("a")
+            .mtime(Utc.timestamp(0, 0))
+            .build()
+            .unwrap();
+        let meta1 = FileMeta {
+            mtime: Utc.timestamp(1, 0),
+            ..meta0.clone()
+        };
+        assert_eq!(hash_of(&meta0), hash_of(&meta1));
+        assert_ne!(
+            hash_of(MetaWithMtime(&meta0)),
+            hash_of(MetaWithMtime(&meta1))
+        );
+        assert_eq!(
+            hash_of(MetaWithMtime(&meta0)),
+            hash_of(MetaWithMtime(&meta0.clone()))
+        );
+    }
+
+    #[test]
+    fn file_meta_builder_works() {
+        use std::str::FromStr;
+
+        let meta = FileMeta::builder().path("a/b/c").build().unwrap();
+        assert_eq!(meta.path, Path::from_str("a/b/c").unwrap());
+        assert_eq!(meta.path, path(&["a", "b", "c"]));
+        assert!(!meta.locked);
+        assert_eq!(meta.content_type, None);
+
+        let meta = FileMeta::builder()
+            .path(path(&["x"]))
+            .mtime(Utc.timestamp(0, 0))
+            .locked(true)
+            .content_type("text/plain")
+            .build()
+            .unwrap();
+        assert_eq!(meta.mtime, Utc.timestamp(0, 0));
+        assert!(meta.locked);
+        assert_eq!(meta.content_type.as_deref(), Some("text/plain"));
+
+        assert_eq!(
+            FileMeta::builder().mtime(Utc::now()).build(),
+            Err(FileMetaError::MissingPath)
+        );
+    }
+
+    #[test]
+    fn path_resolve_works() {
+        assert_eq!(
+            path(&["c", "d"]).resolve(&path(&["a", "b"])),
+            path(&["a", "b", "c", "d"])
+        );
+        assert_eq!(path(&["c"]).resolve(&path(&[])), path(&["c"]));
+        assert_eq!(path(&[]).resolve(&path(&[])), path(&[]));
+    }
+
+    #[test]
+    fn path_parse_works() {
+        for s in &["", "a", "a/b", "a/b.txt", ".hidden/x", "...", "résumé.txt"] {
+            let p: Path = s.parse().unwrap();
+            assert_eq!(p.to_string(), *s);
+            assert_eq!(Path::try_from(*s).unwrap(), p);
+        }
+        assert_eq!(Path::try_from("a/b").unwrap(), path(&["a", "b"]));
+        assert_eq!(Path::try_from("").unwrap(), path(&[]));
+    }
+
+    #[test]
+    fn path_rejects_invalid_components() {
+        for bad in &["/a", "a/", "a//b", ".", "..", "a/./b", "a/../b", "../a"] {
+            match Path::try_from(*bad) {
+                Err(Error::InvalidPath(_)) => {}
+                other => panic!("{:?}: {:?}", bad, other),
+            }
+        }
+        for bad in &["", ".", "..", "a/b"] {
+            match Path::new(vec!["a".to_string(), bad.to_string()]) {
+                Err(Error::InvalidPath(_)) => {}
+                other => panic!("{:?}: {:?}", bad, other),
+            }
+        }
+        assert!(serde_json::from_str::<Path>(r#""a/../b""#).is_err());
+        let bin = bincode::serialize(&PathRepr {
+            elms: vec!["..".to_string()],
+        })
+        .unwrap();
+        assert!(bincode::deserialize::<Path>(&bin).is_err());
+        assert_eq!(
+            FileMeta::builder().path("a//b").build(),
+            Err(FileMetaError::InvalidPath("empty component".to_string()))
+        );
+    }
+
+    #[test]
+    fn path_display_matches_path_buf() {
+        for s in &["a", "a/b", "a/b/c.txt"] {
+            let p = Path::try_from(*s).unwrap();
+            assert_eq!(
+                std::path::PathBuf::from(&p),
+                std::path::Path::new(&p.to_string())
+            );
+            assert_eq!(
+                Path::try_from(std::path::PathBuf::from(&p).as_path()),
+                Ok(p)
+            );
+        }
+        assert_eq!(
+            std::path::PathBuf::from(&path(&[])),
+            std::path::PathBuf::new()
+        );
+    }
+
+    #[test]
+    fn path_simple_match_works() {
+        let p = |s: &str| Path::try_from(s).unwrap();
+        assert!(p("a.rs").simple_match("**/*.rs"));
+        assert!(p("src/aqfs.rs").simple_match("**/*.rs"));
+        assert!(p("a/b/c.rs").simple_match("**/*.rs"));
+        assert!(!p("a/b/c.rsx").simple_match("**/*.rs"));
+        assert!(p("src/main.rs").simple_match("src/*"));
+        assert!(!p("src/a/main.rs").simple_match("src/*"));
+        assert!(!p("src").simple_match("src/*"));
+        assert!(p("a/b/c").simple_match("a/*/c"));
+        assert!(!p("a/c").simple_match("a/*/c"));
+        assert!(!p("a/b/d/c").simple_match("a/*/c"));
+        assert!(p("a/b/d/c").simple_match("a/**/c"));
+        assert!(p("a/c").simple_match("a/**/c"));
+        assert!(p("résumé.txt").simple_match("r*sum*.txt"));
+        assert!(p("").simple_match(""));
+        assert!(p("").simple_match("**"));
+    }
+
+    #[test]
+    fn path_relative_to_works() {
+        assert_eq!(
+            path(&["a", "b", "c"]).relative_to(&path(&["a"])),
+            Some(path(&["b", "c"]))
+        );
+        assert_eq!(
+            path(&["a", "b"]).relative_to(&path(&["a", "b"])),
+            Some(path(&[]))
+        );
+        assert_eq!(path(&["a", "b"]).relative_to(&path(&["x"])), None);
+        // self is shorter than base.
+        assert_eq!(path(&["a"]).relative_to(&path(&["a", "b"])), None);
+        assert_eq!(
+            path(&["c"])
+                .resolve(&path(&["a", "b"]))
+                .relative_to(&path(&["a", "b"])),
+            Some(path(&["c"]))
+        );
+    }
+
+    #[tokio::test]
+    async fn ram_storage_works() -> Result<(), Error> {
+        let mut storage = RamStorage::new();
+        let files = storage.list_files().await?;
+        assert_eq!(files.len(), 0);
+        storage
+            .create_file(RamFile::new(
+                FileMeta {
+                    path: Path::new(vec!["dummy-path".to_string()]).unwrap(),
+                    mtime: Utc.timestamp(0, 0),
+                    locked: false,
+                    content_type: None,
+                    presigned_url: None,
+                    custom: Default::default(),
+                    sha256: None,
+                    size: None,
+                },
+                "dummy content".to_string().into_bytes(),
+            ))
+            .await?;
+        let mut files = storage.list_files().await?;
+        assert_eq!(files.len(), 1);
+        let bytes = files[0].read_all().await?;
+        assert_eq!(std::str::from_utf8(&bytes).unwrap(), "dummy content");
+        storage.remove_file(&files[0]).await?;
+        let files = storage.list_files().await?;
+        assert_eq!(files.len(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ram_storage_locked_file() -> Result<(), Error> {
+        let mut storage = RamStorage::new();
+        let meta = FileMeta {
+            path: Path::new(vec!["locked".to_string()]).unwrap(),
+            mtime: Utc.timestamp(0, 0),
+            locked: true,
+            content_type: None,
+            presigned_url: None,
+            custom: Default::default(),
+            sha256: None,
+            size: None,
+        };
+        storage
+            .create_file(RamFile::new(meta.clone(), b"content".to_vec()))
+            .await?;
+        let files = storage.list_files().await?;
+        assert!(files[0].meta().locked);
+        assert_eq!(
+            storage.remove_file(&files[0]).await,
+            Err(Error::PermissionDenied)
+        );
+        assert_eq!(
+            storage
+                .create_file(RamFile::new(meta, b"overwrite".to_vec()))
+                .await,
+            Err(Error::PermissionDenied)
+        );
+        storage.unlock_file(&files[0]).await?;
+        storage.remove_file(&files[0]).await?;
+        assert_eq!(storage.list_files().await?.len(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn copy_to_writer_works() -> Result<(), Error> {
+        let mut storage = RamStorage::new();
+        storage
+            .create_file(RamFile::new(
+                FileMeta {
+                    path: path(&["a"]),
+                    mtime: Utc.timestamp(0, 0),
+                    locked: false,
+                    content_type: None,
+                    presigned_url: None,
+                    custom: Default::default(),
+                    sha256: None,
+                    size: None,
+                },
+                b"dummy content".to_vec(),
+            ))
+            .await?;
+        let mut buf: Vec<u8> = Vec::new();
+        assert_eq!(storage.copy_to_writer(&path(&["a"]), &mut buf).await?, 13);
+        assert_eq!(buf, b"dummy content");
+        assert_eq!(
+            storage.copy_to_writer(&path(&["b"]), &mut buf).await,
+            Err(Error::NotFound { path: path(&["b"]) })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn file_meta_json_is_human_readable() {
+        let meta = FileMeta {
+            path: path(&["a", "b", "c.txt"]),
+            mtime: Utc.timestamp(0, 0),
+            locked: false,
+            content_type: None,
+            presigned_url: None,
+            custom: Default::default(),
+            sha256: None,
+            size: None,
+        };
+        let json = serde_json::to_string(&meta).unwrap();
+        assert_eq!(
+            json,
+            r#"{"path":"a/b/c.txt","mtime":"1970-01-01T00:00:00+00:00","locked":false,"content_type":null,"custom":{},"sha256":null,"size":null}"#
+        );
+        assert_eq!(serde_json::from_str::<FileMeta>(&json).unwrap(), meta);
+        // bincode keeps the derived representation.
+        let bin = bincode::serialize(&meta).unwrap();
+        assert_eq!(bincode::deserialize::<FileMeta>(&bin).unwrap(), meta);
+    }
+
+    #[tokio::test]
+    async fn ram_storage_interns_contents() -> Result<(), Error> {
+        let mut storage = RamStorage::new();
+        for name in &["a", "b", "c"] {
+            storage
+                .create_file(RamFile::new(
+                    FileMeta {
+                        path: path(&[*name]),
+                        mtime: Utc.timestamp(0, 0),
+                        locked: false,
+                        content_type: None,
+                        presigned_url: None,
+                        custom: Default::default(),
+                        sha256: None,
+                        size: None,
+                    },
+                    b"common content".to_vec(),
+                ))
+                .await?;
+        }
+        let files = storage.list_files().await?;
+        assert!(Arc::ptr_eq(&files[0].data, &files[1].data));
+        assert!(Arc::ptr_eq(&files[1].data, &files[2].data));
+        assert_eq!(storage.contents.len(), 1);
+        for f in files.iter() {
+            storage.remove_file(f).await?;
+        }
+        assert_eq!(storage.contents.len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn path_json_is_plain_string() {
+        let p = path(&["a", "b"]);
+        assert_eq!(serde_json::to_string(&p).unwrap(), r#""a/b""#);
+        assert_eq!(serde_json::from_str::<Path>(r#""a/b""#).unwrap(), p);
+        assert_eq!(serde_json::from_str::<Path>(r#""""#).unwrap(), path(&[]));
+        let bin = bincode::serialize(&p).unwrap();
+        assert_eq!(bincode::deserialize::<Path>(&bin).unwrap(), p);
+    }
+
+    #[test]
+    fn ram_storage_is_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<RamStorage>();
+        assert_send_sync::<RamFile>();
+    }
+
+    #[test]
+    fn path_try_from_std_path() {
+        let p = Path::try_from(std::path::Path::new("a/b/c.txt")).unwrap();
+        assert_eq!(p, path(&["a", "b", "c.txt"]));
+        assert_eq!(
+            std::path::PathBuf::from(&p),
+            std::path::Path::new("a/b/c.txt")
+        );
+        for bad in &["/a/b", "../a", "./a", "a/../b"] {
+            assert_eq!(
+                Path::try_from(std::path::Path::new(bad)),
+                Err(PathError::AbsoluteOrRelativePath)
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn path_try_from_non_utf8_std_path() {
+        use std::os::unix::ffi::OsStrExt;
+        let bad = std::ffi::OsStr::from_bytes(b"a/\xff");
+        assert_eq!(
+            Path::try_from(std::path::Path::new(bad)),
+            Err(PathError::NonUtf8Component)
+        );
+    }
+
+    #[test]
+    fn file_meta_eq_ignoring_mtime() {
+        let meta = FileMeta {
+            path: path(&["a"]),
+            mtime: Utc.timestamp(0, 0),
+            locked: false,
+            content_type: None,
+            presigned_url: None,
+            custom: Default::default(),
+            sha256: None,
+            size: None,
+        };
+        let touched = FileMeta {
+            mtime: Utc.timestamp(10, 0),
+            ..meta.clone()
+        };
+        let other = FileMeta {
+            path: path(&["b"]),
+            ..meta.clone()
+        };
+        assert_ne!(meta, touched);
+        assert!(meta.content_eq(&touched));
+        assert!(meta.path_eq(&touched));
+        assert!(!meta.content_eq(&other));
+        assert!(!meta.path_eq(&other));
+        let digested = FileMeta {
+            sha256: Some(sha256(b"a")),
+            ..meta.clone()
+        };
+        let modified = FileMeta {
+            sha256: Some(sha256(b"b")),
+            ..touched
+        };
+        assert!(meta.content_eq(&digested));
+        assert!(!digested.content_eq(&modified));
+    }
+
+    #[test]
+    fn file_meta_ord() {
+        let meta = |name: &str, secs: i64| {
+            FileMeta::builder()
+                .path(name)
+                .mtime(Utc.timestamp(secs, 0))
+                .build()
+                .unwrap()
+        };
+        let mut metas = vec![
+            meta("b", 0),
+            meta("a-b", 0),
+            meta("a/b", 1),
+            meta("a/b", 0),
+            meta("a", 0),
+        ];
+        metas.sort();
+        assert_eq!(
+            metas,
+            vec![
+                meta("a", 0),
+                meta("a/b", 0),
+                meta("a/b", 1),
+                meta("a-b", 0),
+                meta("b", 0),
+            ]
+        );
+        let locked = FileMeta {
+            locked: true,
+            ..meta("a", 0)
+        };
+        assert_ne!(locked.cmp(&meta("a", 0)), std::cmp::Ordering::Equal);
+    }
+
+    #[tokio::test]
+    async fn verify_integrity_works() -> Result<(), Error> {
+        let mut st = RamStorage::new();
+        let good = FileMeta::builder()
+            .path("good")
+            .sha256(sha256(b"good"))
+            .build()?;
+        let corrupted = FileMeta::builder()
+            .path("corrupted")
+            .sha256(sha256(b"good"))
+            .build()?;
+        let unknown = FileMeta::builder().path("unknown").build()?;
+        st.create_file(RamFile::new(good, b"good".to_vec())).await?;
+        st.create_file(RamFile::new(corrupted.clone(), b"bad".to_vec()))
+            .await?;
+        st.create_file(RamFile::new(unknown.clone(), b"unknown".to_vec()))
+            .await?;
+        let report = st.verify_integrity().await?;
+        assert_eq!(
+            report.corrupted,
+            vec![(
+                FileMeta {
+                    size: Some(3),
+                    ..corrupted
+                },
+                sha256(b"bad")
+            )]
+        );
+        // RamStorage records the digest of a file without one.
+        assert!(report.unverifiable.is_empty());
+        let unknown = st.get_file(&unknown.path).await?;
+        assert_eq!(unknown.meta().sha256, Some(sha256(b"unknown")));
+
+        // A file without a digest can't be verified by the default method. RamFile::new would
+        // record the digest.
+        let meta = FileMeta::builder().path("unknown").build()?;
+        let mut st = FixedStorage(vec![RamFile {
+            meta: meta.clone(),
+            data: Arc::new(b"unknown".to_vec()),
+        }]);
+        let report = st.verify_integrity().await?;
+        assert!(report.corrupted.is_empty());
+        assert_eq!(report.unverifiable, vec![meta]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_files_paged_works() -> Result<(), Error> {
+        let mut st = RamStorage::new();
+        for i in 0..100 {
+            st.create_file(RamFile::new(
+                FileMeta::builder()
+                    .path(format!("file{}", i).as_str())
+                    .build()?,
+                vec![],
+            ))
+            .await?;
+        }
+        let mut seen = std::collections::HashSet::new();
+        let mut cursor = None;
+        let mut pages = 0;
+        loop {
+            let page = st.list_files_paged(cursor, 10).await?;
+            assert!(page.items.len() <= 10);
+            for f in page.items {
+                assert!(seen.insert(f.meta().path.clone()));
+            }
+            pages += 1;
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(seen.len(), 100);
+        assert_eq!(pages, 10);
+        match st.list_files_paged(None, 0).await {
+            Err(Error::Unexpected(_)) => {}
+            res => panic!("{:?}", res.map(|page| page.next_cursor)),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chain_works() -> Result<(), Error> {
+        let meta = |name: &str| FileMeta::builder().path(name).build().unwrap();
+        let mut st = chain(RamStorage::new(), SharedRamStorage::new());
+        st.create_file((meta("a"), b"a".to_vec())).await?;
+        let mut files = st.list_files().await?;
+        assert_eq!(files.len(), 1);
+        assert!(matches!(files[0], EitherFile::A(_)));
+        assert_eq!(files[0].read_all().await?, b"a");
+        assert!(matches!(
+            st.get_file(&"a".parse()?).await?,
+            EitherFile::A(_)
+        ));
+        let (a, mut b) = st.into_parts();
+        assert_eq!(b.list_files().await?.len(), 1);
+        assert_eq!(b.get_file(&"a".parse()?).await?.read_all().await?, b"a");
+
+        let mut st = chain(a, b);
+        let file = st.list_files().await?.remove(0);
+        st.remove_file(&file).await?;
+        let (mut a, mut b) = st.into_parts();
+        assert!(a.list_files().await?.is_empty());
+        assert!(b.list_files().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fallback_works() -> Result<(), Error> {
+        let meta = |name: &str| FileMeta::builder().path(name).build().unwrap();
+        let mut backup = SharedRamStorage::new();
+        backup
+            .create_file((meta("both"), b"backup".to_vec()))
+            .await?;
+        backup
+            .create_file((meta("backup"), b"backup".to_vec()))
+            .await?;
+        let mut primary = RamStorage::new();
+        primary
+            .create_file((meta("both"), b"primary".to_vec()))
+            .await?;
+        let mut st = fallback(primary, backup.clone());
+        let mut files = st.list_files().await?;
+        files.sort_by_key(|f| f.meta().path.to_string());
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].read_all().await?, b"backup");
+        assert_eq!(files[1].read_all().await?, b"primary");
+
+        let mut file = st.get_file(&"both".parse()?).await?;
+        assert!(matches!(file, EitherFile::A(_)));
+        assert_eq!(file.read_all().await?, b"primary");
+        let mut file = st.get_file(&"backup".parse()?).await?;
+        assert!(matches!(file, EitherFile::B(_)));
+        assert_eq!(file.read_all().await?, b"backup");
+        assert!(st.exists(&"backup".parse()?).await?);
+        assert!(!st.exists(&"missing".parse()?).await?);
+        assert!(matches!(
+            st.get_file(&"missing".parse()?).await,
+            Err(Error::NotFound { .. })
+        ));
+
+        st.create_file((meta("new"), b"new".to_vec())).await?;
+        assert_eq!(backup.list_files().await?.len(), 2);
+        st.remove_file(&files[1]).await?;
+        assert_eq!(st.list_files().await?.len(), 2);
+        assert_eq!(backup.list_files().await?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn storage_pair_works() -> Result<(), Error> {
+        let shared = SharedRamStorage::new();
+        assert!(StoragePair::new(shared.clone(), shared.clone()).is_same_backend());
+        assert!(!StoragePair::new(shared, SharedRamStorage::new()).is_same_backend());
+        assert!(!StoragePair::new(RamStorage::new(), RamStorage::new()).is_same_backend());
+
+        let mut pair = StoragePair::new(RamStorage::new(), SharedRamStorage::new());
+        let (first, second) = pair.both_mut();
+        first
+            .create_file(RamFile::new(
+                FileMeta::builder().path("a").build()?,
+                b"a".to_vec(),
+            ))
+            .await?;
+        for f in first.list_files().await? {
+            second.create_file(f).await?;
+        }
+        let (_, mut second) = pair.into_parts();
+        assert_eq!(second.list_files().await?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shared_ram_storage_works() -> Result<(), Error> {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SharedRamStorage>();
+
+        let mut st0 = SharedRamStorage::new();
+        let mut st1 = st0.clone();
+        st0.create_file(RamFile::new(
+            FileMeta {
+                path: path(&["a"]),
+                mtime: Utc.timestamp(0, 0),
+                locked: false,
+                content_type: None,
+                presigned_url: None,
+                custom: Default::default(),
+                sha256: None,
+                size: None,
+            },
+            b"content".to_vec(),
+        ))
+        .await?;
+        let mut files = st1.list_files().await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].read_all().await?, b"content");
+        st1.remove_file(&files[0]).await?;
+        assert_eq!(st0.list_files().await?.len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn ram_file_conversions() {
+        let meta = FileMeta {
+            path: path(&["a"]),
+            mtime: Utc.timestamp(0, 0),
+            locked: false,
+            content_type: None,
+            presigned_url: None,
+            custom: Default::default(),
+            sha256: Some(sha256(b"content")),
+            size: Some(7),
+        };
+        let file = RamFile::from((meta.clone(), b"content".to_vec()));
+        assert_eq!(file.data(), b"content");
+        let shared = file.clone();
+        assert_eq!(shared.into_parts(), (meta.clone(), b"content".to_vec()));
+        let (m, d): (FileMeta, Vec<u8>) = file.into();
+        assert_eq!((m, d), (meta, b"content".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn boxed_files_are_files() -> Result<(), Error> {
+        let meta = |name: &str| FileMeta::builder().path(name).build().unwrap();
+        let mut st = RamStorage::new();
+        st.create_file(Box::new(RamFile::new(meta("a"), b"a".to_vec())))
+            .await?;
+        let files: Vec<Box<dyn File + Send>> = vec![
+            RamFile::new(meta("b"), b"b".to_vec()).boxed(),
+            (meta("c"), b"c".to_vec()).boxed(),
+        ];
+        for f in files {
+            st.create_file(f).await?;
+        }
+        assert_eq!(st.list_files().await?.len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_file_works() -> Result<(), Error> {
+        let mut st = RamStorage::new();
+        let meta = FileMeta::builder().path("a/b").build()?;
+        let meta = st.create_file(RamFile::new(meta, b"ab".to_vec())).await?;
+        let mut f = st.get_file(&path(&["a", "b"])).await?;
+        assert_eq!(f.meta(), &meta);
+        assert_eq!(f.read_all().await?, b"ab");
+        assert_eq!(
+            st.get_file(&path(&["a"])).await.unwrap_err(),
+            Error::NotFound { path: path(&["a"]) }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn exists_works() -> Result<(), Error> {
+        let mut st = RamStorage::new();
+        let meta = FileMeta::builder().path("a/b").build()?;
+        st.create_file(RamFile::new(meta, vec![])).await?;
+        assert!(st.exists(&path(&["a", "b"])).await?);
+        assert!(!st.exists(&path(&["a"])).await?);
+        Ok(())
+    }
+
+    // The full paths of the files and the names of the directories followed by '/'.
+    pub(crate) fn dir_entry_names(entries: &[DirEntry]) -> Vec<String> {
+        entries
+            .iter()
+            .map(|e| match e {
+                DirEntry::File(meta) => meta.path.to_string(),
+                DirEntry::Dir(name) => format!("{}/", name),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn create_files_works() -> Result<(), Error> {
+        let files: Vec<RamFile> = ["a", "d/b"]
+            .iter()
+            .map(|name| {
+                let meta = FileMeta::builder().path(*name).build().unwrap();
+                RamFile::new(meta, name.as_bytes().to_vec())
+            })
+            .collect();
+        let mut st = RamStorage::new().boxed();
+        st.create_files(files).await?;
+        let mut names: Vec<String> = st
+            .list_files()
+            .await?
+            .iter()
+            .map(|f| f.meta().path.to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["a", "d/b"]);
+        assert_eq!(
+            st.get_file(&path(&["d", "b"])).await?.read_all().await?,
+            b"d/b"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ram_list_files_stream_is_lazy() -> Result<(), Error> {
+        let mut st = RamStorage::new();
+        for i in 0..1000 {
+            let meta = FileMeta::builder()
+                .path(format!("f{}", i).as_str())
+                .build()?;
+            st.create_file(RamFile::new(meta, format!("{}", i).into_bytes()))
+                .await?;
+        }
+        // A clone of a RamFile shares its content, so the clones alive are counted by the Arcs.
+        let clones = |st: &RamStorage| -> usize {
+            st.files.values().map(|f| Arc::strong_count(&f.data)).sum()
+        };
+        let before = clones(&st);
+        let taken: Vec<RamFile> = st.list_files_stream().take(3).try_collect().await?;
+        assert_eq!(taken.len(), 3);
+        assert_eq!(clones(&st), before + 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_only_storage_works() -> Result<(), Error> {
+        let mut inner = RamStorage::new();
+        let meta = FileMeta::builder().path("a").locked(true).build()?;
+        inner
+            .create_file(RamFile::new(meta.clone(), b"a".to_vec()))
+            .await?;
+        let mut st = ReadOnlyStorage::new(inner);
+        let a = st.get_file(&path(&["a"])).await?;
+        assert_eq!(a.clone().read_all().await?, b"a");
+        assert_eq!(st.list_files().await?, vec![a.clone()]);
+        assert!(st.exists(&path(&["a"])).await?);
+
+        let b = RamFile::new(FileMeta::builder().path("b").build()?, vec![]);
+        assert_eq!(st.create_file(b.clone()).await, Err(Error::ReadOnly));
+        assert_eq!(st.create_files(vec![b]).await, Err(Error::ReadOnly));
+        assert_eq!(st.remove_file(&a).await, Err(Error::ReadOnly));
+        assert_eq!(st.unlock_file(&a).await, Err(Error::ReadOnly));
+        assert_eq!(st.rename_file(&a, path(&["c"])).await, Err(Error::ReadOnly));
+        assert_eq!(
+            st.copy_file(&a, path(&["c"]), true).await,
+            Err(Error::ReadOnly)
+        );
+        let res = st
+            .get_or_create_file(&path(&["c"]), || async { Err(Error::NotImplemented) })
+            .await;
+        assert_eq!(res, Err(Error::ReadOnly));
+
+        let mut inner = st.into_inner();
+        assert_eq!(inner.list_files().await?, vec![a]);
+        assert!(inner.list_files().await?[0].meta().locked);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scoped_storage_works() -> Result<(), Error> {
+        let mut inner = RamStorage::new();
+        for name in &[
+            "photos/2023/a",
+            "photos/2023/d/b",
+            "photos/2022/c",
+            "photos/2023",
+        ] {
+            let meta = FileMeta::builder().path(*name).build()?;
+            inner.create_file((meta, name.as_bytes().to_vec())).await?;
+        }
+        let mut st = ScopedStorage::new(inner, path(&["photos", "2023"]));
+        let mut names: Vec<String> = st
+            .list_files()
+            .await?
+            .iter()
+            .map(|f| f.meta().path.to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["a", "d/b"]);
+        assert_eq!(
+            dir_entry_names(&st.list_dir(&Path::default()).await?),
+            vec!["a", "d/"]
+        );
+        let mut a = st.get_file(&path(&["a"])).await?;
+        assert_eq!(a.meta().path, path(&["a"]));
+        assert_eq!(a.read_all().await?, b"photos/2023/a");
+        assert_eq!(
+            st.get_file(&path(&["c"])).await,
+            Err(Error::NotFound { path: path(&["c"]) })
+        );
+        // "photos/2023" is a file of the inner storage, but it's the root of the scope.
+        assert_eq!(
+            st.get_file(&Path::default()).await,
+            Err(Error::NotFound {
+                path: Path::default()
+            })
+        );
+        assert!(!st.exists(&Path::default()).await?);
+
+        let meta = FileMeta::builder().path("e").build()?;
+        let stored = st.create_file((meta, b"e".to_vec())).await?;
+        assert_eq!(stored.path, path(&["e"]));
+        st.rename_file(&a, path(&["f"])).await?;
+        let e = st.get_file(&path(&["e"])).await?;
+        st.remove_file(&e).await?;
+
+        let mut inner = st.into_inner();
+        let mut names: Vec<String> = inner
+            .list_files()
+            .await?
+            .iter()
+            .map(|f| f.meta().path.to_string())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                "photos/2022/c",
+                "photos/2023",
+                "photos/2023/d/b",
+                "photos/2023/f"
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn transformed_storage_works() -> Result<(), Error> {
+        // Reverse the content and append a byte, which decode checks.
+        #[derive(Clone)]
+        struct Reverse;
+
+        impl ContentTransform for Reverse {
+            fn encode(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+                Ok(data.iter().rev().chain(&[b'!']).cloned().collect())
+            }
+
+            fn decode(&self, mut data: Vec<u8>) -> Result<Vec<u8>, Error> {
+                if data.pop() != Some(b'!') {
+                    return Err(Error::Unexpected("Not encoded".to_string()));
+                }
+                data.reverse();
+                Ok(data)
+            }
+        }
+
+        let mut st = TransformedStorage::new(RamStorage::new(), Reverse);
+        let meta = FileMeta::builder().path("a").build()?;
+        let stored = st.create_file((meta, b"abc".to_vec())).await?;
+        assert_eq!((stored.size, stored.sha256), (None, None));
+        let meta = FileMeta::builder().path("b").build()?;
+        st.create_files(vec![(meta, b"de".to_vec())]).await?;
+        let mut a = st.get_file(&path(&["a"])).await?;
+        assert_eq!(a.read_all().await?, b"abc");
+        assert_eq!(a.read_range(1, 1).await?, b"b");
+        assert_eq!(a.content_hash().await?, sha256(b"abc"));
+        let mut files: Vec<_> = st.list_files_stream().try_collect().await?;
+        files.sort_by_key(|f| f.meta().path.to_string());
+        assert_eq!(files[1].read_all().await?, b"de");
+        assert!(st
+            .list_filemetas()
+            .await?
+            .iter()
+            .all(|meta| meta.size.is_none()));
+
+        let mut inner = st.into_inner();
+        let mut a = inner.get_file(&path(&["a"])).await?;
+        assert_eq!(a.read_all().await?, b"cba!");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_filemetas_works() -> Result<(), Error> {
+        let mut st = RamStorage::new();
+        assert!(st.list_filemetas().await?.is_empty());
+        let meta = FileMeta::builder().path("d/a").locked(true).build()?;
+        st.create_file(RamFile::new(meta.clone(), b"a".to_vec()))
+            .await?;
+        let metas = st.list_filemetas().await?;
+        assert_eq!(metas.len(), 1);
+        assert_eq!(metas[0].path, meta.path);
+        assert!(metas[0].locked);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_dir_works() -> Result<(), Error> {
+        let mut st = RamStorage::new();
+        assert_eq!(st.list_dir(&Path::default()).await?, vec![]);
+        for name in &["d/f", "a", "d/e/c", "d/b", "d/e/g/h"] {
+            let meta = FileMeta::builder().path(*name).build()?;
+            st.create_file(RamFile::new(meta, vec![])).await?;
+        }
+        assert_eq!(
+            dir_entry_names(&st.list_dir(&Path::default()).await?),
+            vec!["a", "d/"]
+        );
+        assert_eq!(
+            dir_entry_names(&st.list_dir(&path(&["d"])).await?),
+            vec!["d/b", "e/", "d/f"]
+        );
+        assert_eq!(
+            dir_entry_names(&st.list_dir(&path(&["d", "e"])).await?),
+            vec!["d/e/c", "g/"]
+        );
+        assert_eq!(st.list_dir(&path(&["x"])).await?, vec![]);
+        assert_eq!(st.list_dir(&path(&["a"])).await?, vec![]);
+
+        // The default implementation, via list_files, agrees.
+        let mut st = fallback(st, RamStorage::new());
+        assert_eq!(
+            dir_entry_names(&st.list_dir(&path(&["d"])).await?),
+            vec!["d/b", "e/", "d/f"]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rename_file_works() -> Result<(), Error> {
+        let mut st = RamStorage::new();
+        for (name, locked) in &[("a", false), ("b", false), ("locked", true)] {
+            let meta = FileMeta::builder().path(*name).locked(*locked).build()?;
+            st.create_file(RamFile::new(meta, name.as_bytes().to_vec()))
+                .await?;
+        }
+        let a = st.get_file(&path(&["a"])).await?;
+        st.rename_file(&a, path(&["c"])).await?;
+        assert!(!st.exists(&path(&["a"])).await?);
+        let mut c = st.get_file(&path(&["c"])).await?;
+        assert_eq!(c.meta().path, path(&["c"]));
+        assert_eq!(c.read_all().await?, b"a");
+
+        assert_eq!(
+            st.rename_file(&c, path(&["b"])).await,
+            Err(Error::AlreadyExists { path: path(&["b"]) })
+        );
+        let locked = st.get_file(&path(&["locked"])).await?;
+        assert_eq!(
+            st.rename_file(&locked, path(&["d"])).await,
+            Err(Error::PermissionDenied)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn copy_file_works() -> Result<(), Error> {
+        let mut st = RamStorage::new();
+        for name in &["a", "b"] {
+            let meta = FileMeta::builder()
+                .path(*name)
+                .mtime(Utc.timestamp(0, 0))
+                .content_type("text/plain")
+                .build()?;
+            st.create_file(RamFile::new(meta, name.as_bytes().to_vec()))
+                .await?;
+        }
+        let a = st.get_file(&path(&["a"])).await?;
+        st.copy_file(&a, path(&["c"]), false).await?;
+        let mut c = st.get_file(&path(&["c"])).await?;
+        assert_eq!(c.read_all().await?, b"a");
+        assert!(c.meta().mtime > a.meta().mtime);
+        assert_eq!(c.meta().content_type, a.meta().content_type);
+        assert_eq!(st.get_file(&path(&["a"])).await?, a);
+
+        assert_eq!(
+            st.copy_file(&a, path(&["b"]), false).await,
+            Err(Error::AlreadyExists { path: path(&["b"]) })
+        );
+        st.copy_file(&a, path(&["b"]), true).await?;
+        assert_eq!(st.get_file(&path(&["b"])).await?.read_all().await?, b"a");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_range_works() -> Result<(), Error> {
+        let meta = FileMeta::builder().path("a").build()?;
+        check_read_range(&mut RamFile::new(meta.clone(), b"0123456789".to_vec())).await?;
+        check_read_range(&mut (meta.clone(), b"0123456789".to_vec())).await?;
+
+        // The default implementation over read_stream.
+        struct StreamOnly(RamFile);
+
+        #[async_trait]
+        impl File for StreamOnly {
+            fn meta(&self) -> &FileMeta {
+                self.0.meta()
+            }
+
+            async fn read_stream<'a>(&'a mut self) -> Result<FileReader<'a>, Error> {
+                self.0.read_stream().await
+            }
+        }
+
+        check_read_range(&mut StreamOnly(RamFile::new(meta, b"0123456789".to_vec()))).await
+    }
+
+    #[tokio::test]
+    async fn get_or_create_file_works() -> Result<(), Error> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let calls = AtomicUsize::new(0);
+        let calls = &calls;
+        let generator = || async move {
+            calls.fetch_add(1, Ordering::SeqCst);
+            // Let the other caller run meanwhile.
+            tokio::task::yield_now().await;
+            let meta = FileMeta::builder().path("ignored").build()?;
+            Ok::<_, Error>((meta, b"gen".to_vec()))
+        };
+        let path = path(&["a"]);
+        let mut st0 = SharedRamStorage::new();
+        let mut st1 = st0.clone();
+        let (f0, f1) = futures::join!(
+            st0.get_or_create_file(&path, generator),
+            st1.get_or_create_file(&path, generator)
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(f0?.meta().path, path);
+        assert_eq!(f1?.data(), b"gen");
+
+        let mut st = RamStorage::new();
+        let mut f = st.get_or_create_file(&path, generator).await?;
+        assert_eq!(f.read_all().await?, b"gen");
+        st.get_or_create_file(&path, generator).await?;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    // The futures of StorageEntity are Send, so the tasks may run on any worker thread.
+    #[tokio::test(threaded_scheduler)]
+    async fn mutex_storage_works() -> Result<(), Error> {
+        let st = Arc::new(tokio::sync::Mutex::new(RamStorage::new()));
+        let tasks: Vec<_> = (0..10)
+            .map(|i| {
+                let mut st = Arc::clone(&st);
+                tokio::spawn(async move {
+                    let meta = FileMeta::builder()
+                        .path(format!("{}", i).as_str())
+                        .build()?;
+                    st.create_file(RamFile::new(meta, vec![i])).await?;
+                    Ok::<_, Error>(st.list_files().await?.len())
+                })
+            })
+            .collect();
+        for task in tasks {
+            assert!(task.await.unwrap()? >= 1);
+        }
+        let mut st = st;
+        let mut files = st.list_files().await?;
+        assert_eq!(files.len(), 10);
+        files.sort_by(|f0, f1| f0.meta().cmp(f1.meta()));
+        assert_eq!(files[3].read_all().await?, vec![3]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_files_visible_works() -> Result<(), Error> {
+        let mut st = RamStorage::new();
+        for name in &["a", ".DS_Store", ".git/config", "b/.hidden", "c/d"] {
+            let meta = FileMeta::builder().path(*name).build()?;
+            st.create_file(RamFile::new(meta, vec![])).await?;
+        }
+        let mut paths: Vec<String> = st
+            .list_files_visible()
+            .await?
+            .iter()
+            .map(|f| f.meta().path.to_string())
+            .collect();
+        paths.sort();
+        assert_eq!(paths, vec!["a", "c/d"]);
+        assert_eq!(st.list_files().await?.len(), 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tuples_are_files() -> Result<(), Error> {
+        let meta = |name: &str| FileMeta::builder().path(name).build().unwrap();
+        let mut st = RamStorage::new();
+        st.create_file((meta("vec"), b"vec".to_vec())).await?;
+        st.create_file((meta("slice"), &b"slice"[..])).await?;
+        st.create_file((meta("string"), "string".to_string()).into_file())
+            .await?;
+        st.create_file(RamFile::new(meta("ram"), b"ram".to_vec()).into_file())
+            .await?;
+        let mut files = st.list_files().await?;
+        assert_eq!(files.len(), 4);
+        for f in files.iter_mut() {
+            assert_eq!(f.read_all().await?, f.meta().path.to_string().into_bytes());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn error_display() {
+        assert_eq!(
+            Error::NotFound {
+                path: path(&["a", "b"])
+            }
+            .to_string(),
+            "file not found: a/b"
+        );
+        assert_eq!(
+            Error::AlreadyExists { path: path(&["a"]) }.to_string(),
+            "file already exists: a"
+        );
+        assert_eq!(
+            Error::RusotoFail(Cause::new("SlowDown")).to_string(),
+            "S3 error: SlowDown"
+        );
+        let boxed: Box<dyn std::error::Error> = Box::new(Error::PermissionDenied);
+        assert_eq!(boxed.to_string(), "permission denied");
+    }
+
+    #[test]
+    fn error_keeps_source() {
+        use std::error::Error as _;
+
+        let err = Error::from(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "no way",
+        ));
+        assert_eq!(err.to_string(), "I/O error: no way");
+        let source = err.source().unwrap();
+        assert_eq!(
+            source.downcast_ref::<std::io::Error>().unwrap().kind(),
+            std::io::ErrorKind::PermissionDenied
+        );
+        // Clones share the source, and errors with the same message are equal.
+        assert_eq!(err.clone(), err);
+        assert_eq!(
+            Error::SerdeFail(Cause::new("bad")),
+            Error::SerdeFail(Cause::new("bad"))
+        );
+        assert!(Error::PermissionDenied.source().is_none());
+
+        // It fits Box<dyn Error + Send + Sync>, as applications use with `?`.
+        fn not_found() -> Result<(), Error> {
+            Err(Error::NotFound { path: path(&["a"]) })
+        }
+        fn fails() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+            not_found()?;
+            Ok(())
+        }
+        assert_eq!(fails().unwrap_err().to_string(), "file not found: a");
+    }
+
+    #[test]
+    fn error_classification() {
+        let transient = Error::RusotoFail(Cause::new("Connection reset by peer"));
+        assert!(transient.is_transient());
+        assert!(!transient.is_auth_error());
+        let slowdown =
+            Error::RusotoFail(Cause::new("503 SlowDown: Please reduce your request rate"));
+        assert!(slowdown.is_transient());
+        let denied = Error::RusotoFail(Cause::new("AccessDenied: Access Denied"));
+        assert!(!denied.is_transient());
+        assert!(denied.is_auth_error());
+        assert!(Error::PermissionDenied.is_auth_error());
+        for err in vec![
+            Error::NotFound { path: path(&["a"]) },
+            Error::PermissionDenied,
+            Error::InvalidPath("a".to_string()),
+        ] {
+            assert!(!err.is_transient());
+        }
+    }
+
+    #[tokio::test]
+    async fn ram_storage_capacity() -> Result<(), Error> {
+        let meta = |name: &str| FileMeta {
+            path: path(&[name]),
+            mtime: Utc.timestamp(0, 0),
+            locked: false,
+            content_type: None,
+            presigned_url: None,
+            custom: Default::default(),
+            sha256: None,
+            size: None,
+        };
+        let mut storage = RamStorage::new().with_capacity(10);
+        storage
+            .create_file(RamFile::new(meta("a"), b"12345678".to_vec()))
+            .await?;
+        assert_eq!(storage.used_space().await?, 8);
+        assert_eq!(storage.quota_check(2).await, Ok(()));
+        assert_eq!(
+            storage.quota_check(3).await,
+            Err(Error::QuotaExceeded {
+                requested: 3,
+                available: 2
+            })
+        );
+        assert!(storage
+            .create_file(RamFile::new(meta("b"), b"123".to_vec()))
+            .await
+            .is_err());
+        // Overwriting frees the old content.
+        storage
+            .create_file(RamFile::new(meta("a"), b"1234567890".to_vec()))
+            .await?;
+        // Unlimited by default.
+        assert_eq!(RamStorage::new().quota_check(u64::MAX).await, Ok(()));
         Ok(())
     }
 }
@@ -0,0 +1,587 @@
+use crate::aqfs;
+use async_trait::async_trait;
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+const DEFAULT_MAX_SIZE: u64 = 1024 * 1024 * 1024;
+
+// The contents cached on disk, named by their SHA-256 digests in hex. The index is in memory and
+// rebuilt from the directory on start. Any failure of the directory is logged and treated as a
+// miss, so that a broken cache only costs the backend accesses.
+struct Cache {
+    dir: PathBuf,
+    max_size: u64,
+    size: u64,
+    // Incremented on each access to tell the least recently used entry.
+    clock: u64,
+    entries: HashMap<[u8; 32], CacheEntry>,
+    // The digest of the content each path was read with, to invalidate it on writes.
+    by_path: HashMap<aqfs::Path, [u8; 32]>,
+}
+
+struct CacheEntry {
+    size: u64,
+    last_used: u64,
+}
+
+fn hex(digest: &[u8; 32]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_hex(name: &str) -> Option<[u8; 32]> {
+    if name.len() != 64 {
+        return None;
+    }
+    let mut digest = [0u8; 32];
+    for (i, b) in digest.iter_mut().enumerate() {
+        *b = u8::from_str_radix(name.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+impl Cache {
+    fn new(dir: PathBuf) -> Self {
+        let mut cache = Cache {
+            dir,
+            max_size: DEFAULT_MAX_SIZE,
+            size: 0,
+            clock: 0,
+            entries: HashMap::new(),
+            by_path: HashMap::new(),
+        };
+        if let Err(err) = cache.load() {
+            tracing::warn!("Can't use cache directory {}: {}", cache.dir.display(), err);
+        }
+        cache
+    }
+
+    // Pick up the entries left by the previous runs, which are all older than the new ones.
+    fn load(&mut self) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let digest = match entry.file_name().to_str().and_then(parse_hex) {
+                Some(digest) => digest,
+                None => continue,
+            };
+            let size = entry.metadata()?.len();
+            self.size += size;
+            self.entries
+                .insert(digest, CacheEntry { size, last_used: 0 });
+        }
+        remove_files(&self.evict());
+        Ok(())
+    }
+
+    fn entry_path(&self, digest: &[u8; 32]) -> PathBuf {
+        self.dir.join(hex(digest))
+    }
+
+    // Mark the entry of digest as used, and return the path of its file if there is one.
+    fn touch(&mut self, digest: &[u8; 32]) -> Option<PathBuf> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.entries.get_mut(digest)?.last_used = clock;
+        Some(self.entry_path(digest))
+    }
+
+    // Add the entry of digest whose file of size bytes has been written, and return the files of
+    // the evicted entries to remove.
+    fn insert(&mut self, digest: [u8; 32], size: u64) -> Vec<PathBuf> {
+        self.clock += 1;
+        let clock = self.clock;
+        match self.entries.get_mut(&digest) {
+            // Another reader has written the same content meanwhile.
+            Some(entry) => entry.last_used = clock,
+            None => {
+                self.size += size;
+                self.entries.insert(
+                    digest,
+                    CacheEntry {
+                        size,
+                        last_used: clock,
+                    },
+                );
+            }
+        }
+        self.evict()
+    }
+
+    // Remove the entry of digest, and return the path of its file to remove.
+    fn remove(&mut self, digest: &[u8; 32]) -> Option<PathBuf> {
+        let entry = self.entries.remove(digest)?;
+        self.size -= entry.size;
+        Some(self.entry_path(digest))
+    }
+
+    fn invalidate(&mut self, path: &aqfs::Path) -> Option<PathBuf> {
+        let digest = self.by_path.remove(path)?;
+        if self.by_path.values().any(|d| d == &digest) {
+            return None;
+        }
+        self.remove(&digest)
+    }
+
+    // Remove the least recently used entries until the cache fits in max_size, and return the
+    // paths of their files to remove.
+    // FIXME: This scans all the entries for each eviction.
+    fn evict(&mut self) -> Vec<PathBuf> {
+        let mut evicted = vec![];
+        while self.size > self.max_size {
+            let digest = match self.entries.iter().min_by_key(|(_, e)| e.last_used) {
+                Some((digest, _)) => *digest,
+                None => break,
+            };
+            evicted.extend(self.remove(&digest));
+        }
+        evicted
+    }
+}
+
+// Remove the files of the removed entries.
+fn remove_files(paths: &[PathBuf]) {
+    for path in paths {
+        if let Err(err) = std::fs::remove_file(path) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                tracing::warn!("Can't remove cache {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+// Same as remove_files, but on the blocking thread pool.
+async fn remove_files_blocking(paths: Vec<PathBuf>) {
+    if !paths.is_empty() {
+        let _ = tokio::task::spawn_blocking(move || remove_files(&paths)).await;
+    }
+}
+
+// The cached content of digest, if any. The cache is locked only to look up the entry, and the file
+// is read on the blocking thread pool, so that a slow disk blocks neither the other readers nor
+// the runtime. The same goes for write_entry and invalidate.
+async fn read_entry(cache: &Mutex<Cache>, digest: &[u8; 32]) -> Option<Vec<u8>> {
+    let path = cache.lock().unwrap().touch(digest)?;
+    match tokio::fs::read(&path).await {
+        Ok(data) if &aqfs::sha256(&data) == digest => return Some(data),
+        // The entry may be broken, e.g. by a crash while writing it.
+        Ok(_) => {}
+        Err(err) => tracing::warn!("Can't read cache of {}: {}", hex(digest), err),
+    }
+    let removed = cache.lock().unwrap().remove(digest);
+    remove_files_blocking(removed.into_iter().collect()).await;
+    None
+}
+
+// Cache data as the content of digest, and give it back. The file is written to a temporary file
+// first, so that a concurrent read_entry never reads it partially.
+async fn write_entry(cache: &Mutex<Cache>, digest: [u8; 32], data: Vec<u8>) -> Vec<u8> {
+    let size = data.len() as u64;
+    let dir = {
+        let cache = cache.lock().unwrap();
+        if size > cache.max_size || cache.entries.contains_key(&digest) {
+            return data;
+        }
+        cache.dir.clone()
+    };
+    let path = dir.join(hex(&digest));
+    let (written, data) = tokio::task::spawn_blocking(move || {
+        let written = (|| {
+            // An unpersisted temporary file is removed on drop, and its name is never taken for
+            // an entry by Cache::load.
+            let mut tmp = tempfile::NamedTempFile::new_in(&dir)?;
+            tmp.write_all(&data)?;
+            tmp.persist(&path).map_err(|e| e.error)?;
+            Ok::<_, std::io::Error>(())
+        })();
+        (written, data)
+    })
+    .await
+    // The closure never panics.
+    .unwrap();
+    match written {
+        Ok(()) => {
+            let evicted = cache.lock().unwrap().insert(digest, size);
+            remove_files_blocking(evicted).await;
+        }
+        Err(err) => tracing::warn!("Can't write cache of {}: {}", hex(&digest), err),
+    }
+    data
+}
+
+async fn invalidate(cache: &Mutex<Cache>, path: &aqfs::Path) {
+    let removed = cache.lock().unwrap().invalidate(path);
+    remove_files_blocking(removed.into_iter().collect()).await;
+}
+
+// Cache the contents of the files of the inner storage in a local directory, e.g. not to download
+// the same content from S3 twice. Contents are keyed by FileMeta::sha256, so files without it are
+// never cached, and a stale entry is never read for a replaced file. Only read_all populates the
+// cache, while read_stream and read_range use an entry if there is one.
+pub struct CachingStorage<S: aqfs::StorageEntity> {
+    inner: S,
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl<S: aqfs::StorageEntity> CachingStorage<S> {
+    pub fn new(inner: S, dir: impl Into<PathBuf>) -> Self {
+        CachingStorage {
+            inner,
+            cache: Arc::new(Mutex::new(Cache::new(dir.into()))),
+        }
+    }
+
+    // Set the total size of the cached contents in bytes, beyond which the least recently used
+    // ones are evicted. Defaults to 1 GiB.
+    pub fn with_max_size(self, max_size: u64) -> Self {
+        let evicted = {
+            let mut cache = self.cache.lock().unwrap();
+            cache.max_size = max_size;
+            cache.evict()
+        };
+        remove_files(&evicted);
+        self
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn wrap(&self, inner: S::File) -> File<S::File> {
+        File {
+            inner,
+            cache: Arc::clone(&self.cache),
+        }
+    }
+}
+
+pub struct File<F> {
+    inner: F,
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl<F: aqfs::File> File<F> {
+    async fn cached(&self) -> Option<Vec<u8>> {
+        let digest = self.inner.meta().sha256?;
+        read_entry(&self.cache, &digest).await
+    }
+}
+
+#[async_trait]
+impl<F: aqfs::File> aqfs::File for File<F> {
+    fn meta(&self) -> &aqfs::FileMeta {
+        self.inner.meta()
+    }
+
+    async fn read_all(&mut self) -> Result<Vec<u8>, aqfs::Error> {
+        if let Some(data) = self.cached().await {
+            return Ok(data);
+        }
+        let data = self.inner.read_all().await?;
+        match self.inner.meta().sha256 {
+            // Don't cache a content under a digest it doesn't match.
+            Some(digest) if aqfs::sha256(&data) == digest => {
+                let data = write_entry(&self.cache, digest, data).await;
+                let path = self.inner.meta().path.clone();
+                self.cache.lock().unwrap().by_path.insert(path, digest);
+                Ok(data)
+            }
+            _ => Ok(data),
+        }
+    }
+
+    async fn read_stream<'a>(&'a mut self) -> Result<aqfs::FileReader<'a>, aqfs::Error> {
+        if let Some(data) = self.cached().await {
+            return Ok(Box::pin(std::io::Cursor::new(data)));
+        }
+        self.inner.read_stream().await
+    }
+
+    fn size_hint(&self) -> Option<u64> {
+        self.inner.size_hint()
+    }
+
+    async fn content_hash(&mut self) -> Result<[u8; 32], aqfs::Error> {
+        self.inner.content_hash().await
+    }
+}
+
+#[async_trait]
+impl<S: aqfs::StorageEntity> aqfs::StorageEntity for CachingStorage<S> {
+    type File = File<S::File>;
+
+    async fn list_files(&mut self) -> Result<Vec<Self::File>, aqfs::Error> {
+        let files = self.inner.list_files().await?;
+        Ok(files.into_iter().map(|f| self.wrap(f)).collect())
+    }
+
+    async fn list_filemetas(&mut self) -> Result<Vec<aqfs::FileMeta>, aqfs::Error> {
+        self.inner.list_filemetas().await
+    }
+
+    async fn get_file(&mut self, path: &aqfs::Path) -> Result<Self::File, aqfs::Error> {
+        let file = self.inner.get_file(path).await?;
+        Ok(self.wrap(file))
+    }
+
+    async fn list_dir(&mut self, path: &aqfs::Path) -> Result<Vec<aqfs::DirEntry>, aqfs::Error> {
+        self.inner.list_dir(path).await
+    }
+
+    async fn exists(&mut self, path: &aqfs::Path) -> Result<bool, aqfs::Error> {
+        self.inner.exists(path).await
+    }
+
+    async fn create_file(
+        &mut self,
+        file: impl aqfs::File + 'async_trait,
+    ) -> Result<aqfs::FileMeta, aqfs::Error> {
+        invalidate(&self.cache, &file.meta().path).await;
+        self.inner.create_file(file).await
+    }
+
+    async fn create_files(
+        &mut self,
+        files: Vec<impl aqfs::File + 'async_trait>,
+    ) -> Result<(), aqfs::Error> {
+        for file in files.iter() {
+            invalidate(&self.cache, &file.meta().path).await;
+        }
+        self.inner.create_files(files).await
+    }
+
+    async fn remove_file(&mut self, file: &Self::File) -> Result<(), aqfs::Error> {
+        invalidate(&self.cache, &file.meta().path).await;
+        self.inner.remove_file(&file.inner).await
+    }
+
+    async fn unlock_file(&mut self, file: &Self::File) -> Result<(), aqfs::Error> {
+        self.inner.unlock_file(&file.inner).await
+    }
+
+    async fn rename_file(
+        &mut self,
+        file: &Self::File,
+        new_path: aqfs::Path,
+    ) -> Result<(), aqfs::Error> {
+        invalidate(&self.cache, &file.meta().path).await;
+        invalidate(&self.cache, &new_path).await;
+        self.inner.rename_file(&file.inner, new_path).await
+    }
+
+    async fn copy_file(
+        &mut self,
+        file: &Self::File,
+        dest: aqfs::Path,
+        overwrite: bool,
+    ) -> Result<(), aqfs::Error> {
+        invalidate(&self.cache, &dest).await;
+        self.inner.copy_file(&file.inner, dest, overwrite).await
+    }
+
+    fn identity(&self) -> Option<aqfs::StorageIdentity> {
+        self.inner.identity()
+    }
+
+    fn capabilities(&self) -> aqfs::Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn list_files_stream<'a>(&'a mut self) -> BoxStream<'a, Result<Self::File, aqfs::Error>>
+    where
+        Self::File: 'a,
+    {
+        let cache = Arc::clone(&self.cache);
+        self.inner
+            .list_files_stream()
+            .map_ok(move |inner| File {
+                inner,
+                cache: Arc::clone(&cache),
+            })
+            .boxed()
+    }
+
+    async fn capacity(&mut self) -> Result<Option<u64>, aqfs::Error> {
+        self.inner.capacity().await
+    }
+
+    async fn used_space(&mut self) -> Result<u64, aqfs::Error> {
+        self.inner.used_space().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::aqfs::{File as FileTrait, StorageEntity};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tempfile::TempDir;
+
+    // A RamStorage counting the reads of the contents of its files.
+    struct CountingStorage {
+        inner: aqfs::RamStorage,
+        reads: Arc<AtomicUsize>,
+    }
+
+    struct CountingFile {
+        inner: aqfs::RamFile,
+        reads: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl aqfs::File for CountingFile {
+        fn meta(&self) -> &aqfs::FileMeta {
+            self.inner.meta()
+        }
+
+        async fn read_stream<'a>(&'a mut self) -> Result<aqfs::FileReader<'a>, aqfs::Error> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.inner.read_stream().await
+        }
+
+        async fn read_all(&mut self) -> Result<Vec<u8>, aqfs::Error> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.inner.read_all().await
+        }
+    }
+
+    #[async_trait]
+    impl aqfs::StorageEntity for CountingStorage {
+        type File = CountingFile;
+
+        async fn list_files(&mut self) -> Result<Vec<CountingFile>, aqfs::Error> {
+            let reads = &self.reads;
+            Ok(self
+                .inner
+                .list_files()
+                .await?
+                .into_iter()
+                .map(|inner| CountingFile {
+                    inner,
+                    reads: Arc::clone(reads),
+                })
+                .collect())
+        }
+
+        async fn create_file(
+            &mut self,
+            file: impl aqfs::File + 'async_trait,
+        ) -> Result<aqfs::FileMeta, aqfs::Error> {
+            self.inner.create_file(file).await
+        }
+
+        async fn remove_file(&mut self, file: &CountingFile) -> Result<(), aqfs::Error> {
+            self.inner.remove_file(&file.inner).await
+        }
+
+        async fn unlock_file(&mut self, file: &CountingFile) -> Result<(), aqfs::Error> {
+            self.inner.unlock_file(&file.inner).await
+        }
+    }
+
+    fn counting_storage() -> (CountingStorage, Arc<AtomicUsize>) {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let st = CountingStorage {
+            inner: aqfs::RamStorage::new(),
+            reads: Arc::clone(&reads),
+        };
+        (st, reads)
+    }
+
+    fn path(s: &str) -> aqfs::Path {
+        s.parse().unwrap()
+    }
+
+    async fn create(
+        st: &mut impl StorageEntity,
+        name: &str,
+        data: &[u8],
+    ) -> Result<(), aqfs::Error> {
+        let meta = aqfs::FileMeta::builder().path(name).build()?;
+        st.create_file((meta, data.to_vec())).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn hits_skip_backend() -> Result<(), aqfs::Error> {
+        let tmp_dir = TempDir::new()?;
+        let (inner, reads) = counting_storage();
+        let mut st = CachingStorage::new(inner, tmp_dir.path());
+        create(&mut st, "a", b"a").await?;
+        assert_eq!(st.get_file(&path("a")).await?.read_all().await?, b"a");
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+        assert_eq!(st.get_file(&path("a")).await?.read_all().await?, b"a");
+        let mut data = vec![];
+        tokio::io::AsyncReadExt::read_to_end(
+            &mut st.get_file(&path("a")).await?.read_stream().await?,
+            &mut data,
+        )
+        .await?;
+        assert_eq!(data, b"a");
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+
+        // A new storage picks up the cache left on disk.
+        let (inner, reads) = counting_storage();
+        let mut st2 = CachingStorage::new(inner, tmp_dir.path());
+        create(&mut st2, "b", b"a").await?;
+        assert_eq!(st2.get_file(&path("b")).await?.read_all().await?, b"a");
+        assert_eq!(reads.load(Ordering::SeqCst), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn writes_invalidate() -> Result<(), aqfs::Error> {
+        let tmp_dir = TempDir::new()?;
+        let (inner, reads) = counting_storage();
+        let mut st = CachingStorage::new(inner, tmp_dir.path());
+        create(&mut st, "a", b"a").await?;
+        st.get_file(&path("a")).await?.read_all().await?;
+        create(&mut st, "a", b"b").await?;
+        assert_eq!(st.get_file(&path("a")).await?.read_all().await?, b"b");
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+
+        let a = st.get_file(&path("a")).await?;
+        st.remove_file(&a).await?;
+        assert!(st.cache.lock().unwrap().entries.is_empty());
+        assert_eq!(std::fs::read_dir(tmp_dir.path())?.count(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used() -> Result<(), aqfs::Error> {
+        let tmp_dir = TempDir::new()?;
+        let (inner, reads) = counting_storage();
+        let mut st = CachingStorage::new(inner, tmp_dir.path()).with_max_size(2);
+        for name in &["a", "b", "c"] {
+            create(&mut st, name, name.as_bytes()).await?;
+        }
+        st.get_file(&path("a")).await?.read_all().await?;
+        st.get_file(&path("b")).await?.read_all().await?;
+        st.get_file(&path("a")).await?.read_all().await?;
+        // c evicts b, which is used less recently than a.
+        st.get_file(&path("c")).await?.read_all().await?;
+        assert_eq!(reads.load(Ordering::SeqCst), 3);
+        st.get_file(&path("a")).await?.read_all().await?;
+        assert_eq!(reads.load(Ordering::SeqCst), 3);
+        st.get_file(&path("b")).await?.read_all().await?;
+        assert_eq!(reads.load(Ordering::SeqCst), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unwritable_cache_falls_back() -> Result<(), aqfs::Error> {
+        let tmp_dir = TempDir::new()?;
+        // The cache directory can't be made under a file.
+        let file = tmp_dir.path().join("file");
+        std::fs::write(&file, "")?;
+        let (inner, reads) = counting_storage();
+        let mut st = CachingStorage::new(inner, file.join("cache"));
+        create(&mut st, "a", b"a").await?;
+        assert_eq!(st.get_file(&path("a")).await?.read_all().await?, b"a");
+        assert_eq!(st.get_file(&path("a")).await?.read_all().await?, b"a");
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+use crate::aqfs;
+
+// The layout of a content written by CompressedStorage: MAGIC, a mode byte, and the payload, which
+// is the content itself for MODE_STORED and its zstd frame for MODE_ZSTD. Anything else, e.g. an
+// object uploaded before the wrapper was used or one with an unknown mode, is read as it is.
+// MAGIC starts with a non-ASCII byte like that of PNG so that text files never start with it.
+const MAGIC: &[u8; 4] = b"\x89AQZ";
+const MODE_STORED: u8 = 0;
+const MODE_ZSTD: u8 = 1;
+const HEADER_LENGTH: usize = MAGIC.len() + 1;
+
+fn compress(level: i32, data: &[u8]) -> Result<Vec<u8>, aqfs::Error> {
+    let compressed = zstd::encode_all(data, level)?;
+    // Already compressed contents, e.g. JPEG, only grow, so they are stored as they are.
+    let (mode, payload) = if compressed.len() < data.len() {
+        (MODE_ZSTD, &compressed[..])
+    } else {
+        (MODE_STORED, data)
+    };
+    Ok([&MAGIC[..], &[mode][..], payload].concat())
+}
+
+fn decompress(data: Vec<u8>) -> Result<Vec<u8>, aqfs::Error> {
+    if data.len() < HEADER_LENGTH || &data[..MAGIC.len()] != MAGIC {
+        return Ok(data);
+    }
+    match data[MAGIC.len()] {
+        MODE_STORED => Ok(data[HEADER_LENGTH..].to_vec()),
+        MODE_ZSTD => Ok(zstd::decode_all(&data[HEADER_LENGTH..])?),
+        // Not written by CompressedStorage, though it happens to start with MAGIC.
+        _ => Ok(data),
+    }
+}
+
+// Compress the contents of the files with zstd before writing them to the inner storage, and
+// decompress them on read. Objects written without it are read as they are, so it can be put over
+// a storage already in use.
+pub type CompressedStorage<S> = aqfs::TransformedStorage<S, Zstd>;
+
+// The ContentTransform of CompressedStorage.
+#[derive(Debug, Clone)]
+pub struct Zstd {
+    level: i32,
+}
+
+impl Zstd {
+    pub fn new() -> Self {
+        Zstd {
+            level: zstd::DEFAULT_COMPRESSION_LEVEL,
+        }
+    }
+
+    // Set the zstd compression level, from 1 (fastest) to 22 (smallest). Defaults to zstd's.
+    pub fn with_level(mut self, level: i32) -> Self {
+        self.level = level;
+        self
+    }
+}
+
+impl Default for Zstd {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl aqfs::ContentTransform for Zstd {
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>, aqfs::Error> {
+        compress(self.level, data)
+    }
+
+    fn decode(&self, data: Vec<u8>) -> Result<Vec<u8>, aqfs::Error> {
+        decompress(data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::aqfs::{File as FileTrait, StorageEntity};
+
+    async fn create(
+        st: &mut impl StorageEntity,
+        name: &str,
+        data: Vec<u8>,
+    ) -> Result<(), aqfs::Error> {
+        let meta = aqfs::FileMeta::builder().path(name).build()?;
+        st.create_file((meta, data)).await?;
+        Ok(())
+    }
+
+    async fn read(st: &mut impl StorageEntity, name: &str) -> Result<Vec<u8>, aqfs::Error> {
+        st.get_file(&name.parse()?).await?.read_all().await
+    }
+
+    #[tokio::test]
+    async fn works() -> Result<(), aqfs::Error> {
+        let text = "hello, world\n".repeat(1000).into_bytes();
+        let mut noise = vec![0u8; 4096];
+        rand::Rng::fill(&mut rand::thread_rng(), &mut noise[..]);
+
+        let mut st = CompressedStorage::new(aqfs::RamStorage::new(), Zstd::new().with_level(19));
+        create(&mut st, "text", text.clone()).await?;
+        create(&mut st, "noise", noise.clone()).await?;
+        create(&mut st, "empty", vec![]).await?;
+        assert_eq!(read(&mut st, "text").await?, text);
+        assert_eq!(read(&mut st, "noise").await?, noise);
+        assert_eq!(read(&mut st, "empty").await?, b"");
+        let mut file = st.get_file(&"text".parse()?).await?;
+        assert_eq!(file.content_hash().await?, aqfs::sha256(&text));
+
+        let mut inner = st.into_inner();
+        let stored = read(&mut inner, "text").await?;
+        assert_eq!(&stored[..HEADER_LENGTH], b"\x89AQZ\x01");
+        assert!(stored.len() < text.len() / 10);
+        let stored = read(&mut inner, "noise").await?;
+        assert_eq!(&stored[..HEADER_LENGTH], b"\x89AQZ\x00");
+        assert_eq!(stored.len(), noise.len() + HEADER_LENGTH);
+        assert_eq!(read(&mut inner, "empty").await?, b"\x89AQZ\x00");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reads_uncompressed_objects() -> Result<(), aqfs::Error> {
+        let mut inner = aqfs::RamStorage::new();
+        create(&mut inner, "old", b"old".to_vec()).await?;
+        create(&mut inner, "old_empty", vec![]).await?;
+        create(&mut inner, "old_magic", MAGIC.to_vec()).await?;
+        create(&mut inner, "old_mode", b"\x89AQZ\x7fold".to_vec()).await?;
+        let mut st = CompressedStorage::new(inner, Zstd::new());
+        create(&mut st, "new", b"new".to_vec()).await?;
+        assert_eq!(read(&mut st, "old").await?, b"old");
+        assert_eq!(read(&mut st, "old_empty").await?, b"");
+        assert_eq!(read(&mut st, "old_magic").await?, MAGIC);
+        assert_eq!(read(&mut st, "old_mode").await?, b"\x89AQZ\x7fold");
+        assert_eq!(read(&mut st, "new").await?, b"new");
+        assert_eq!(st.list_files().await?.len(), 5);
+        Ok(())
+    }
+}
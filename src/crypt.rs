@@ -0,0 +1,144 @@
+use crate::aqfs;
+use aes_gcm::aead::{Aead, NewAead};
+use rand::Rng;
+
+// The layout of an encrypted content: FORMAT_VERSION, a random nonce, and the AES-256-GCM
+// ciphertext with its tag. The version is there so that the layout can change, e.g. to bind the
+// content to an encrypted path.
+const FORMAT_VERSION: u8 = 1;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+const OVERHEAD: u64 = (1 + NONCE_LENGTH + TAG_LENGTH) as u64;
+
+fn encrypt(key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>, aqfs::Error> {
+    let cipher = aes_gcm::Aes256Gcm::new(aes_gcm::Key::from_slice(key));
+    let mut nonce = [0u8; NONCE_LENGTH];
+    rand::thread_rng().fill(&mut nonce);
+    let ciphertext = cipher
+        .encrypt(aes_gcm::Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| aqfs::Error::CryptoFail("Can't encrypt content".to_string()))?;
+    Ok([&[FORMAT_VERSION][..], &nonce[..], &ciphertext[..]].concat())
+}
+
+fn decrypt(key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>, aqfs::Error> {
+    if (data.len() as u64) < OVERHEAD {
+        return Err(aqfs::Error::CryptoFail("Content is too short".to_string()));
+    }
+    if data[0] != FORMAT_VERSION {
+        return Err(aqfs::Error::CryptoFail(format!(
+            "Unknown content format {}",
+            data[0]
+        )));
+    }
+    let (nonce, ciphertext) = data[1..].split_at(NONCE_LENGTH);
+    let cipher = aes_gcm::Aes256Gcm::new(aes_gcm::Key::from_slice(key));
+    cipher
+        .decrypt(aes_gcm::Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| aqfs::Error::CryptoFail("Can't decrypt content; wrong key?".to_string()))
+}
+
+// Encrypt the contents of the files with AES-256-GCM before writing them to the inner storage,
+// and decrypt them on read, so that the backend can't read them. The key is never stored.
+// FIXME: Paths and the rest of the metadata are stored in the clear.
+pub type EncryptedStorage<S> = aqfs::TransformedStorage<S, AesGcm>;
+
+// The ContentTransform of EncryptedStorage with a 256-bit key.
+#[derive(Clone)]
+pub struct AesGcm {
+    key: [u8; 32],
+}
+
+impl AesGcm {
+    pub fn new(key: [u8; 32]) -> Self {
+        AesGcm { key }
+    }
+}
+
+impl aqfs::ContentTransform for AesGcm {
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>, aqfs::Error> {
+        encrypt(&self.key, data)
+    }
+
+    fn decode(&self, data: Vec<u8>) -> Result<Vec<u8>, aqfs::Error> {
+        decrypt(&self.key, &data)
+    }
+
+    // The digest of the plaintext isn't stored not to let the backend confirm a guess of the
+    // content, so File::content_hash decrypts the content to compute it.
+    fn content_meta(&self, meta: aqfs::FileMeta) -> aqfs::FileMeta {
+        aqfs::FileMeta {
+            size: meta.size.map(|size| size.saturating_sub(OVERHEAD)),
+            sha256: None,
+            ..meta
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::aqfs::{File as FileTrait, StorageEntity};
+
+    const KEY: [u8; 32] = [42u8; 32];
+
+    async fn roundtrip<S: StorageEntity>(inner: S) -> Result<S, aqfs::Error> {
+        let mut st = EncryptedStorage::new(inner, AesGcm::new(KEY));
+        let meta = aqfs::FileMeta::builder().path("a").build()?;
+        let stored = st.create_file((meta, b"secret".to_vec())).await?;
+        assert_eq!(stored.size, Some(6));
+        let files = st.list_files().await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].meta().size, Some(6));
+        let path: aqfs::Path = "a".parse()?;
+        let mut file = st.get_file(&path).await?;
+        assert_eq!(file.read_all().await?, b"secret");
+        assert_eq!(file.read_range(1, 3).await?, b"ecr");
+        assert_eq!(file.content_hash().await?, aqfs::sha256(b"secret"));
+
+        let mut inner = st.into_inner();
+        let data = inner.get_file(&path).await?.read_all().await?;
+        assert_eq!(data.len() as u64, 6 + OVERHEAD);
+        assert!(!data.windows(6).any(|w| w == b"secret"));
+        Ok(inner)
+    }
+
+    #[tokio::test]
+    async fn ram_roundtrip() -> Result<(), aqfs::Error> {
+        roundtrip(aqfs::RamStorage::new()).await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "backend-local")]
+    #[tokio::test]
+    async fn local_roundtrip() -> Result<(), aqfs::Error> {
+        let (st, _tmp_dir) = crate::local::Storage::init_in_temp()?;
+        roundtrip(st).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wrong_key_and_truncation_fail() -> Result<(), aqfs::Error> {
+        let mut inner = roundtrip(aqfs::RamStorage::new()).await?;
+        let path: aqfs::Path = "a".parse()?;
+        let data = inner.get_file(&path).await?.read_all().await?;
+
+        let mut st = EncryptedStorage::new(inner, AesGcm::new([0u8; 32]));
+        match st.get_file(&path).await?.read_all().await {
+            Err(aqfs::Error::CryptoFail(_)) => {}
+            res => panic!("{:?}", res),
+        }
+
+        let mut inner = st.into_inner();
+        for len in &[0, 5, data.len() - 1] {
+            let meta = aqfs::FileMeta::builder().path("a").build()?;
+            inner.create_file((meta, data[..*len].to_vec())).await?;
+            let mut st = EncryptedStorage::new(inner, AesGcm::new(KEY));
+            match st.get_file(&path).await?.read_all().await {
+                Err(aqfs::Error::CryptoFail(_)) => {}
+                res => panic!("{:?}", res),
+            }
+            inner = st.into_inner();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,12 @@
+pub mod aqfs;
+pub mod cache;
+#[cfg(feature = "compression")]
+pub mod compress;
+#[cfg(feature = "encryption")]
+pub mod crypt;
+#[cfg(feature = "backend-local")]
+pub mod local;
+pub mod multi_region;
+#[cfg(feature = "backend-s3")]
+pub mod s3;
+pub mod sync;
@@ -2,29 +2,64 @@ use crate::aqfs;
 use crate::aqfs::File as FileTrait;
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
-use std::io::{Read, Write};
+use futures::stream::{BoxStream, StreamExt};
+use sha2::Digest;
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, Write};
+use tokio::io::AsyncReadExt;
+
+// Prefix of temporary files in the root. They are not listed as files.
+const TEMP_PREFIX: &str = ".asynq-tmp-";
+// Extended attributes are stored in FileMeta::custom under this prefix, e.g. "xattr.user.foo".
+#[cfg(feature = "xattr")]
+const XATTR_KEY_PREFIX: &str = "xattr.";
 
 pub struct File {
     meta: aqfs::FileMeta,
     realpath: std::path::PathBuf,
 }
 
-#[async_trait(?Send)]
+#[async_trait]
 impl aqfs::File for File {
     fn meta(&self) -> &aqfs::FileMeta {
         &self.meta
     }
 
     async fn read_all(&mut self) -> Result<Vec<u8>, aqfs::Error> {
-        let mut f = std::fs::File::open(&self.realpath)?;
+        let mut f = open_options().read(true).open(&self.realpath)?;
         let mut buf = Vec::new();
         f.read_to_end(&mut buf)?;
         Ok(buf)
     }
+
+    async fn read_stream<'a>(&'a mut self) -> Result<aqfs::FileReader<'a>, aqfs::Error> {
+        let f = open_options().read(true).open(&self.realpath)?;
+        Ok(Box::pin(tokio::fs::File::from_std(f)))
+    }
+
+    fn size_hint(&self) -> Option<u64> {
+        std::fs::metadata(&self.realpath).ok().map(|m| m.len())
+    }
+
+    async fn read_range(&mut self, offset: u64, len: u64) -> Result<Vec<u8>, aqfs::Error> {
+        let mut f = open_options().read(true).open(&self.realpath)?;
+        // Seeking past the end is fine; the read just returns nothing.
+        f.seek(std::io::SeekFrom::Start(offset))?;
+        let mut buf = Vec::new();
+        f.take(len).read_to_end(&mut buf)?;
+        Ok(buf)
+    }
 }
 
+// Size of the buffer create_file copies the content through.
+const COPY_BUFFER_SIZE: usize = 1024 * 1024;
+
 pub struct Storage {
     root: std::path::PathBuf,
+    verify_writes: bool,
+    preserve_xattrs: bool,
+    fsync: bool,
+    sync_dir: bool,
 }
 
 impl Storage {
@@ -32,67 +67,607 @@ impl Storage {
         if !root.is_dir() {
             panic!("Root should be a directory.");
         }
-        Self { root }
+        Self {
+            root,
+            verify_writes: false,
+            preserve_xattrs: false,
+            fsync: false,
+            sync_dir: false,
+        }
+    }
+
+    // Create root if it doesn't exist and make a storage there.
+    pub fn init(root: std::path::PathBuf) -> Result<Self, aqfs::Error> {
+        std::fs::create_dir_all(&root).map_err(|e| match e.kind() {
+            std::io::ErrorKind::PermissionDenied => aqfs::Error::PermissionDenied,
+            _ => aqfs::Error::from(e),
+        })?;
+        Ok(Self::new(root))
+    }
+
+    // Make a storage in a new temporary directory, which is removed when the TempDir is dropped.
+    pub fn init_in_temp() -> Result<(Self, tempfile::TempDir), aqfs::Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        Ok((Self::init(tmp_dir.path().to_path_buf())?, tmp_dir))
+    }
+
+    // Read back each written file and compare its SHA-256 digest with the written content's to
+    // detect corruption that fsync doesn't guarantee against, e.g., bad sectors.
+    pub fn with_verify_writes(mut self, verify_writes: bool) -> Self {
+        self.verify_writes = verify_writes;
+        self
+    }
+
+    // Flush the content of each written file to the disk before returning, so that it survives a
+    // system crash. This costs much, e.g., small files are written 100x slower on spinning disks.
+    pub fn with_fsync(mut self, fsync: bool) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    // Flush the root directory after a file is created or renamed into it. Without this, the new
+    // entry may be lost on a system crash, e.g., on ext4, even if the content is flushed.
+    pub fn with_sync_dir(mut self, sync_dir: bool) -> Self {
+        self.sync_dir = sync_dir;
+        self
+    }
+
+    fn sync_root(&self) -> Result<(), aqfs::Error> {
+        if self.sync_dir {
+            sync_dir(&self.root)?;
+        }
+        Ok(())
+    }
+
+    // Read user.* extended attributes into FileMeta::custom in list_files, and write them back
+    // from there in create_file. Other namespaces such as security.* and trusted.* are ignored
+    // not to let a remote storage change them.
+    #[cfg(feature = "xattr")]
+    pub fn with_preserve_xattrs(mut self, preserve_xattrs: bool) -> Self {
+        self.preserve_xattrs = preserve_xattrs;
+        self
+    }
+
+    #[cfg(feature = "xattr")]
+    fn read_custom(&self, realpath: &std::path::Path) -> BTreeMap<String, String> {
+        if !self.preserve_xattrs {
+            return BTreeMap::new();
+        }
+        read_xattrs(realpath)
+    }
+
+    #[cfg(not(feature = "xattr"))]
+    fn read_custom(&self, _realpath: &std::path::Path) -> BTreeMap<String, String> {
+        BTreeMap::new()
     }
 
+    #[cfg(feature = "xattr")]
+    fn write_custom(
+        &self,
+        realpath: &std::path::Path,
+        custom: &BTreeMap<String, String>,
+    ) -> Result<(), aqfs::Error> {
+        if !self.preserve_xattrs {
+            return Ok(());
+        }
+        write_xattrs(realpath, custom)
+    }
+
+    #[cfg(not(feature = "xattr"))]
+    fn write_custom(
+        &self,
+        _realpath: &std::path::Path,
+        _custom: &BTreeMap<String, String>,
+    ) -> Result<(), aqfs::Error> {
+        Ok(())
+    }
+
+    // aqfs::Path has no empty, `.` or `..` component, so the result is always under root.
     fn get_real_path(&self, src: &aqfs::Path) -> std::path::PathBuf {
         self.root.join(std::path::PathBuf::from(src))
     }
+
+    fn read_root(&self) -> Result<std::fs::ReadDir, aqfs::Error> {
+        std::fs::read_dir(&self.root).map_err(|e| {
+            aqfs::Error::Unexpected(format!(
+                "Can't read directory {}: {}",
+                self.root.to_string_lossy(),
+                e
+            ))
+        })
+    }
+
+    // Make a File from an entry of the directory dir, or None if it's not a regular file or
+    // unreadable.
+    fn file_from_entry(
+        &self,
+        dir: &aqfs::Path,
+        entry: std::io::Result<std::fs::DirEntry>,
+    ) -> Option<File> {
+        let entry = entry.ok()?;
+        let file_type = entry.file_type().ok()?;
+        if !file_type.is_file() {
+            return None;
+        }
+        let metadata = entry.metadata().ok()?;
+        let file_name = entry.file_name().into_string().ok()?;
+        if file_name.starts_with(TEMP_PREFIX) {
+            return None;
+        }
+        let path = aqfs::Path::new(vec![file_name]).ok()?.resolve(dir);
+        self.file_from_metadata(path, &metadata)
+    }
+
+    fn file_from_metadata(&self, path: aqfs::Path, metadata: &std::fs::Metadata) -> Option<File> {
+        let mtime = DateTime::<Utc>::from(metadata.modified().ok()?);
+        let locked = metadata.permissions().readonly();
+        let realpath = self.get_real_path(&path);
+        Some(File {
+            meta: aqfs::FileMeta {
+                path,
+                mtime,
+                locked,
+                content_type: None,
+                presigned_url: None,
+                custom: self.read_custom(&realpath),
+                sha256: None,
+                size: Some(metadata.len()),
+            },
+            realpath,
+        })
+    }
+
+    // Open a file to write incrementally. The data goes to a temporary file in the root, which is
+    // renamed to the real path on close, so no one sees a partially written file.
+    pub fn open_sink(&mut self, meta: aqfs::FileMeta) -> Result<Sink, aqfs::Error> {
+        let tmp = tempfile::Builder::new()
+            .prefix(TEMP_PREFIX)
+            .tempfile_in(&self.root)?;
+        Ok(Sink {
+            realpath: self.get_real_path(&meta.path),
+            meta,
+            tmp,
+            preserve_xattrs: self.preserve_xattrs,
+            fsync: self.fsync,
+            sync_dir: if self.sync_dir {
+                Some(self.root.clone())
+            } else {
+                None
+            },
+        })
+    }
+
+    // Append data to the file at path, creating it if it doesn't exist.
+    // This is a local-only operation, so it's not a part of aqfs::StorageEntity.
+    pub async fn append_file(&mut self, path: &aqfs::Path, data: &[u8]) -> Result<(), aqfs::Error> {
+        let realpath = self.get_real_path(path);
+        if is_locked(&realpath) {
+            return Err(aqfs::Error::PermissionDenied);
+        }
+        {
+            let mut realfile = open_options().append(true).create(true).open(&realpath)?;
+            realfile.write_all(data)?;
+            realfile.flush()?;
+        }
+        filetime::set_file_mtime(&realpath, filetime::FileTime::now())?;
+        Ok(())
+    }
 }
 
-#[async_trait(?Send)]
-impl aqfs::StorageEntity<File> for Storage {
+#[async_trait]
+impl aqfs::StorageEntity for Storage {
+    type File = File;
+
     async fn list_files(&mut self) -> Result<Vec<File>, aqfs::Error> {
         // FIXME: recursion
-        let files = std::fs::read_dir(&self.root)
-            .map_err(|e| {
-                aqfs::Error::Unexpected(format!(
+        Ok(self
+            .read_root()?
+            .filter_map(|entry| self.file_from_entry(&aqfs::Path::default(), entry))
+            .collect())
+    }
+
+    // Stat the real path instead of reading the whole directory.
+    async fn get_file(&mut self, path: &aqfs::Path) -> Result<File, aqfs::Error> {
+        let not_found = || aqfs::Error::NotFound { path: path.clone() };
+        let path_str = path.to_string();
+        let file_name = path_str.rsplit('/').next().unwrap_or_default();
+        if file_name.starts_with(TEMP_PREFIX) {
+            return Err(not_found());
+        }
+        // Don't follow symlinks, which list_files doesn't list either.
+        let metadata = match std::fs::symlink_metadata(self.get_real_path(path)) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Err(not_found()),
+            Err(err) => return Err(err.into()),
+        };
+        if !metadata.file_type().is_file() {
+            return Err(not_found());
+        }
+        self.file_from_metadata(path.clone(), &metadata)
+            .ok_or_else(not_found)
+    }
+
+    // Symlinks are skipped as in list_files, whether they point to files or directories.
+    async fn list_dir(&mut self, path: &aqfs::Path) -> Result<Vec<aqfs::DirEntry>, aqfs::Error> {
+        let realpath = self.get_real_path(path);
+        let entries = match std::fs::read_dir(&realpath) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => {
+                return Err(aqfs::Error::Unexpected(format!(
                     "Can't read directory {}: {}",
-                    self.root.to_string_lossy(),
-                    e
-                ))
-            })?
-            .filter_map(|entry| {
-                let entry = entry.ok()?;
-                let file_type = entry.file_type().ok()?;
-                if !file_type.is_file() {
-                    return None;
+                    realpath.to_string_lossy(),
+                    err
+                )))
+            }
+        };
+        let mut res = Vec::new();
+        for entry in entries {
+            let is_dir = match &entry {
+                Ok(entry) => entry.file_type().map_or(false, |t| t.is_dir()),
+                Err(_) => false,
+            };
+            if is_dir {
+                if let Ok(name) = entry?.file_name().into_string() {
+                    res.push(aqfs::DirEntry::Dir(name));
                 }
-                let metadata = entry.metadata().ok()?;
-                let file_name = entry.file_name().into_string().ok()?;
-                let path = aqfs::Path::new(vec![file_name]);
-                let mtime = DateTime::<Utc>::from(metadata.modified().ok()?);
-                Some(File {
-                    realpath: self.get_real_path(&path),
-                    meta: aqfs::FileMeta { path, mtime },
-                })
-            })
-            .collect();
-        Ok(files)
+            } else if let Some(file) = self.file_from_entry(path, entry) {
+                res.push(aqfs::DirEntry::File(file.meta));
+            }
+        }
+        aqfs::sort_dir_entries(&mut res);
+        Ok(res)
+    }
+
+    // Only a regular file counts, as in list_files. Directories and symlinks, even dangling ones,
+    // are taken as absent.
+    async fn exists(&mut self, path: &aqfs::Path) -> Result<bool, aqfs::Error> {
+        let path_str = path.to_string();
+        if path_str
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+            .starts_with(TEMP_PREFIX)
+        {
+            return Ok(false);
+        }
+        match std::fs::symlink_metadata(self.get_real_path(path)) {
+            Ok(metadata) => Ok(metadata.file_type().is_file()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn capabilities(&self) -> aqfs::Capabilities {
+        aqfs::Capabilities {
+            supports_streaming_list: true,
+        }
+    }
+
+    // Files hidden by the OS are excluded too.
+    async fn list_files_visible(&mut self) -> Result<Vec<File>, aqfs::Error> {
+        Ok(self
+            .list_files()
+            .await?
+            .into_iter()
+            .filter(|f| !f.meta.path.is_hidden() && !has_hidden_attribute(&f.realpath))
+            .collect())
+    }
+
+    // Stream read_dir entries one by one instead of collecting them first.
+    fn list_files_stream<'a>(&'a mut self) -> BoxStream<'a, Result<File, aqfs::Error>> {
+        let this = &*self;
+        let root = aqfs::Path::default();
+        match this.read_root() {
+            Ok(entries) => futures::stream::iter(
+                entries.filter_map(move |entry| this.file_from_entry(&root, entry)),
+            )
+            .map(Ok)
+            .boxed(),
+            Err(err) => futures::stream::once(async { Err(err) }).boxed(),
+        }
     }
 
     async fn create_file(
         &mut self,
         mut file: impl aqfs::File + 'async_trait,
-    ) -> Result<(), aqfs::Error> {
-        // FIXME: Use a temporary file and move it to the correct path.
+    ) -> Result<aqfs::FileMeta, aqfs::Error> {
         let realpath = self.get_real_path(&file.meta().path);
-        {
-            let mut realfile = std::fs::File::create(&realpath)?;
-            realfile.write_all(&file.read_all().await?)?;
+        if is_locked(&realpath) {
+            return Err(aqfs::Error::PermissionDenied);
+        }
+        // The content goes to a temporary file in the root, which is renamed to the real path only
+        // after it's completely written, as in open_sink. On any failure the temporary file is
+        // removed when dropped, and the file at the real path, if any, is kept intact.
+        let mut tmp = tempfile::Builder::new()
+            .prefix(TEMP_PREFIX)
+            .tempfile_in(&self.root)?;
+        // Copy the content chunk by chunk, hashing it on the way for the verification.
+        let mut reader = file.read_stream().await?;
+        let mut hasher = sha2::Sha256::new();
+        let mut buf = vec![0u8; COPY_BUFFER_SIZE];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            tmp.write_all(&buf[..n])?;
+            if self.verify_writes {
+                hasher.input(&buf[..n]);
+            }
+        }
+        if self.verify_writes {
+            // Make the data durable before the verification read.
+            tmp.as_file().sync_all()?;
+            let mut expected_hash = [0u8; 32];
+            expected_hash.copy_from_slice(&hasher.result());
+            let actual_hash = aqfs::sha256(&std::fs::read(tmp.path())?);
+            if expected_hash != actual_hash {
+                return Err(aqfs::Error::WriteVerificationFailed {
+                    path: file.meta().path.clone(),
+                    expected_hash,
+                    actual_hash,
+                });
+            }
+        } else if self.fsync {
+            tmp.as_file().sync_data()?;
         }
         filetime::set_file_mtime(
-            &realpath,
+            tmp.path(),
             filetime::FileTime::from_system_time(std::time::SystemTime::from(file.meta().mtime)),
         )?;
+        self.write_custom(tmp.path(), &file.meta().custom)?;
+        // A rename replaces a symlink instead of following it, so refuse it as open_options does.
+        if std::fs::symlink_metadata(&realpath).map_or(false, |m| m.file_type().is_symlink()) {
+            return Err(symlink_error().into());
+        }
+        tmp.persist(&realpath)
+            .map_err(|e| aqfs::Error::from(e.error))?;
+        self.sync_root()?;
+        if file.meta().locked {
+            set_readonly(&realpath, true)?;
+        }
 
-        Ok(())
+        // Read mtime back because the filesystem may have truncated it to its resolution.
+        let metadata = std::fs::metadata(&realpath)?;
+        Ok(aqfs::FileMeta {
+            mtime: DateTime::<Utc>::from(metadata.modified()?),
+            locked: metadata.permissions().readonly(),
+            size: Some(metadata.len()),
+            // Not recorded, as in list_files; see File::content_hash.
+            sha256: None,
+            ..file.meta().clone()
+        })
     }
 
     async fn remove_file(&mut self, file: &File) -> Result<(), aqfs::Error> {
+        // Removing a read-only file is allowed on UNIX, so check it by ourselves.
+        if is_locked(&file.realpath) {
+            return Err(aqfs::Error::PermissionDenied);
+        }
         std::fs::remove_file(&file.realpath)?;
         Ok(())
     }
+
+    // FIXME: Another process may create new_path between the check and the rename, which is
+    // then overwritten.
+    async fn rename_file(&mut self, file: &File, new_path: aqfs::Path) -> Result<(), aqfs::Error> {
+        if is_locked(&file.realpath) {
+            return Err(aqfs::Error::PermissionDenied);
+        }
+        let new_realpath = self.get_real_path(&new_path);
+        // Anything at new_path, even a directory or a dangling symlink, is in the way.
+        if std::fs::symlink_metadata(&new_realpath).is_ok() {
+            return Err(aqfs::Error::AlreadyExists { path: new_path });
+        }
+        std::fs::rename(&file.realpath, &new_realpath)?;
+        self.sync_root()
+    }
+
+    async fn copy_file(
+        &mut self,
+        file: &File,
+        dest: aqfs::Path,
+        overwrite: bool,
+    ) -> Result<(), aqfs::Error> {
+        let dest_realpath = self.get_real_path(&dest);
+        if std::fs::symlink_metadata(&dest_realpath).is_ok() {
+            if !overwrite {
+                return Err(aqfs::Error::AlreadyExists { path: dest });
+            }
+            if is_locked(&dest_realpath) {
+                return Err(aqfs::Error::PermissionDenied);
+            }
+        }
+        std::fs::copy(&file.realpath, &dest_realpath)?;
+        // The copy inherits the permissions, so unlock it to set its attributes first.
+        set_readonly(&dest_realpath, false)?;
+        filetime::set_file_mtime(&dest_realpath, filetime::FileTime::now())?;
+        // Extended attributes aren't copied by std::fs::copy.
+        self.write_custom(&dest_realpath, &file.meta().custom)?;
+        if file.meta().locked {
+            set_readonly(&dest_realpath, true)?;
+        }
+        self.sync_root()
+    }
+
+    async fn unlock_file(&mut self, file: &File) -> Result<(), aqfs::Error> {
+        set_readonly(&file.realpath, false)?;
+        Ok(())
+    }
+
+    fn identity(&self) -> Option<aqfs::StorageIdentity> {
+        // Canonicalize not to be fooled by symlinks and relative paths.
+        let root = self
+            .root
+            .canonicalize()
+            .unwrap_or_else(|_| self.root.clone());
+        Some(aqfs::StorageIdentity::new(format!(
+            "local:{}",
+            root.to_string_lossy()
+        )))
+    }
+}
+
+pub struct Sink {
+    meta: aqfs::FileMeta,
+    realpath: std::path::PathBuf,
+    tmp: tempfile::NamedTempFile,
+    preserve_xattrs: bool,
+    fsync: bool,
+    // The directory to flush after the rename, if any.
+    sync_dir: Option<std::path::PathBuf>,
+}
+
+#[async_trait]
+impl aqfs::SinkFile for Sink {
+    async fn write_chunk(&mut self, data: &[u8]) -> Result<(), aqfs::Error> {
+        self.tmp.write_all(data)?;
+        Ok(())
+    }
+
+    async fn close(self) -> Result<aqfs::FileMeta, aqfs::Error> {
+        if is_locked(&self.realpath) {
+            return Err(aqfs::Error::PermissionDenied);
+        }
+        if self.fsync {
+            self.tmp.as_file().sync_data()?;
+        }
+        self.tmp
+            .persist(&self.realpath)
+            .map_err(|e| aqfs::Error::from(e.error))?;
+        if let Some(dir) = &self.sync_dir {
+            sync_dir(dir)?;
+        }
+        filetime::set_file_mtime(
+            &self.realpath,
+            filetime::FileTime::from_system_time(std::time::SystemTime::from(self.meta.mtime)),
+        )?;
+        #[cfg(feature = "xattr")]
+        {
+            if self.preserve_xattrs {
+                write_xattrs(&self.realpath, &self.meta.custom)?;
+            }
+        }
+        if self.meta.locked {
+            set_readonly(&self.realpath, true)?;
+        }
+        let metadata = std::fs::metadata(&self.realpath)?;
+        Ok(aqfs::FileMeta {
+            mtime: DateTime::<Utc>::from(metadata.modified()?),
+            locked: metadata.permissions().readonly(),
+            size: Some(metadata.len()),
+            // Not recorded, as in list_files; see File::content_hash.
+            sha256: None,
+            ..self.meta
+        })
+    }
+}
+
+// Open files without following a symlink at the last component, so that a symlink swapped in
+// by someone else can't redirect our reads and writes, and without leaking the descriptor to
+// child processes. std already sets O_CLOEXEC on UNIX, but we don't rely on it.
+#[cfg(unix)]
+fn open_options() -> std::fs::OpenOptions {
+    use nix::fcntl::OFlag;
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut options = std::fs::OpenOptions::new();
+    options.custom_flags((OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC).bits());
+    options
+}
+
+#[cfg(not(unix))]
+fn open_options() -> std::fs::OpenOptions {
+    std::fs::OpenOptions::new()
+}
+
+// The error of opening a symlink with open_options.
+#[cfg(unix)]
+fn symlink_error() -> std::io::Error {
+    std::io::Error::from_raw_os_error(nix::errno::Errno::ELOOP as i32)
+}
+
+#[cfg(not(unix))]
+fn symlink_error() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, "Refusing to replace a symlink")
+}
+
+// Whether the OS marks the file hidden apart from its name, which only Windows does.
+#[cfg(windows)]
+fn has_hidden_attribute(realpath: &std::path::Path) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    std::fs::metadata(realpath).map_or(false, |m| m.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+}
+
+#[cfg(not(windows))]
+fn has_hidden_attribute(_realpath: &std::path::Path) -> bool {
+    false
+}
+
+#[cfg(feature = "xattr")]
+fn read_xattrs(realpath: &std::path::Path) -> BTreeMap<String, String> {
+    let mut custom = BTreeMap::new();
+    let names = match xattr::list(realpath) {
+        Ok(names) => names,
+        Err(_) => return custom,
+    };
+    for name in names {
+        // FIXME: Non-UTF-8 names and values are dropped since custom holds Strings.
+        let name = match name.into_string() {
+            Ok(name) if name.starts_with("user.") => name,
+            _ => continue,
+        };
+        if let Ok(Some(value)) = xattr::get(realpath, &name) {
+            if let Ok(value) = String::from_utf8(value) {
+                custom.insert(format!("{}{}", XATTR_KEY_PREFIX, name), value);
+            }
+        }
+    }
+    custom
+}
+
+#[cfg(feature = "xattr")]
+fn write_xattrs(
+    realpath: &std::path::Path,
+    custom: &BTreeMap<String, String>,
+) -> Result<(), aqfs::Error> {
+    for (key, value) in custom {
+        let name = match key.strip_prefix(XATTR_KEY_PREFIX) {
+            Some(name) if name.starts_with("user.") => name,
+            _ => continue,
+        };
+        xattr::set(realpath, name, value.as_bytes())?;
+    }
+    Ok(())
+}
+
+// Directories can't be opened as files on Windows, where NTFS journals renames anyway.
+#[cfg(unix)]
+fn sync_dir(dir: &std::path::Path) -> Result<(), aqfs::Error> {
+    std::fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &std::path::Path) -> Result<(), aqfs::Error> {
+    Ok(())
+}
+
+// Locked files are represented by the read-only permission, which is FILE_ATTRIBUTE_READONLY on
+// Windows. We don't use `chattr +i` on Linux because it requires CAP_LINUX_IMMUTABLE.
+fn is_locked(realpath: &std::path::Path) -> bool {
+    match std::fs::metadata(realpath) {
+        Ok(metadata) => metadata.permissions().readonly(),
+        Err(_) => false,
+    }
+}
+
+fn set_readonly(realpath: &std::path::Path, readonly: bool) -> Result<(), aqfs::Error> {
+    let mut permissions = std::fs::metadata(realpath)?.permissions();
+    permissions.set_readonly(readonly);
+    std::fs::set_permissions(realpath, permissions)?;
+    Ok(())
 }
 
 #[cfg(test)]
@@ -100,19 +675,25 @@ mod test {
     use super::*;
     use crate::aqfs::StorageEntity;
     use chrono::offset::TimeZone;
+    use std::convert::TryFrom;
     use tempfile::TempDir;
 
     #[tokio::test]
     async fn works() -> Result<(), aqfs::Error> {
-        let tmp_dir = TempDir::new()?;
-        let mut storage = Storage::new(tmp_dir.path().to_path_buf());
+        let (mut storage, tmp_dir) = Storage::init_in_temp()?;
         let files = storage.list_files().await?;
         assert_eq!(files.len(), 0);
-        storage
+        let created = storage
             .create_file(aqfs::RamFile::new(
                 aqfs::FileMeta {
-                    path: aqfs::Path::new(vec!["dummy-path".to_string()]),
+                    path: aqfs::Path::new(vec!["dummy-path".to_string()]).unwrap(),
                     mtime: Utc.timestamp(0, 0),
+                    locked: false,
+                    content_type: None,
+                    presigned_url: None,
+                    custom: Default::default(),
+                    sha256: None,
+                    size: None,
                 },
                 "dummy content".to_string().into_bytes(),
             ))
@@ -123,6 +704,7 @@ mod test {
         );
         let mut files = storage.list_files().await?;
         assert_eq!(files.len(), 1);
+        assert_eq!(files[0].meta(), &created);
         let bytes = files[0].read_all().await?;
         assert_eq!(std::str::from_utf8(&bytes).unwrap(), "dummy content");
         storage.remove_file(&files[0]).await?;
@@ -131,4 +713,459 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn locked_file() -> Result<(), aqfs::Error> {
+        let tmp_dir = TempDir::new()?;
+        let mut storage = Storage::new(tmp_dir.path().to_path_buf());
+        storage
+            .create_file(aqfs::RamFile::new(
+                aqfs::FileMeta {
+                    path: aqfs::Path::new(vec!["locked".to_string()]).unwrap(),
+                    mtime: Utc.timestamp(0, 0),
+                    locked: true,
+                    content_type: None,
+                    presigned_url: None,
+                    custom: Default::default(),
+                    sha256: None,
+                    size: None,
+                },
+                "dummy content".to_string().into_bytes(),
+            ))
+            .await?;
+        let files = storage.list_files().await?;
+        assert_eq!(files.len(), 1);
+        assert!(files[0].meta().locked);
+        assert_eq!(
+            storage.remove_file(&files[0]).await,
+            Err(aqfs::Error::PermissionDenied)
+        );
+        storage.unlock_file(&files[0]).await?;
+        storage.remove_file(&files[0]).await?;
+        assert_eq!(storage.list_files().await?.len(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn append_file_works() -> Result<(), aqfs::Error> {
+        let tmp_dir = TempDir::new()?;
+        let mut storage = Storage::new(tmp_dir.path().to_path_buf());
+        let path = aqfs::Path::new(vec!["log".to_string()]).unwrap();
+        storage.append_file(&path, b"first\n").await?;
+        storage.append_file(&path, b"second\n").await?;
+        let mut files = storage.list_files().await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].read_all().await?, b"first\nsecond\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn storage_is_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Storage>();
+        assert_send_sync::<File>();
+    }
+
+    #[tokio::test]
+    async fn verify_writes() -> Result<(), aqfs::Error> {
+        let tmp_dir = TempDir::new()?;
+        let mut storage = Storage::new(tmp_dir.path().to_path_buf()).with_verify_writes(true);
+        storage
+            .create_file(aqfs::RamFile::new(
+                aqfs::FileMeta {
+                    path: aqfs::Path::new(vec!["dummy-path".to_string()]).unwrap(),
+                    mtime: Utc.timestamp(0, 0),
+                    locked: false,
+                    content_type: None,
+                    presigned_url: None,
+                    custom: Default::default(),
+                    sha256: None,
+                    size: None,
+                },
+                "dummy content".to_string().into_bytes(),
+            ))
+            .await?;
+        let mut files = storage.list_files().await?;
+        assert_eq!(files[0].read_all().await?, b"dummy content");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_files_stream_works() -> Result<(), aqfs::Error> {
+        use futures::stream::TryStreamExt;
+
+        let tmp_dir = TempDir::new()?;
+        let mut storage = Storage::new(tmp_dir.path().to_path_buf());
+        for name in &["a", "b", "c"] {
+            std::fs::write(tmp_dir.path().join(name), name)?;
+        }
+        std::fs::create_dir(tmp_dir.path().join("dir"))?;
+        let mut names: Vec<String> = storage
+            .list_files_stream()
+            .map_ok(|f| f.meta().path.to_string())
+            .try_collect()
+            .await?;
+        names.sort();
+        assert_eq!(names, vec!["a", "b", "c"]);
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlink_is_not_followed() -> Result<(), aqfs::Error> {
+        let tmp_dir = TempDir::new()?;
+        let outside = TempDir::new()?;
+        let target = outside.path().join("target");
+        std::fs::write(&target, "original")?;
+        std::os::unix::fs::symlink(&target, tmp_dir.path().join("link"))?;
+
+        let mut storage = Storage::new(tmp_dir.path().to_path_buf());
+        let res = storage
+            .create_file(aqfs::RamFile::new(
+                aqfs::FileMeta {
+                    path: aqfs::Path::new(vec!["link".to_string()]).unwrap(),
+                    mtime: Utc.timestamp(0, 0),
+                    locked: false,
+                    content_type: None,
+                    presigned_url: None,
+                    custom: Default::default(),
+                    sha256: None,
+                    size: None,
+                },
+                "overwritten".to_string().into_bytes(),
+            ))
+            .await;
+        assert!(res.is_err());
+        assert_eq!(std::fs::read_to_string(&target)?, "original");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sink_works() -> Result<(), aqfs::Error> {
+        use crate::aqfs::SinkFile;
+
+        let tmp_dir = TempDir::new()?;
+        let mut storage = Storage::new(tmp_dir.path().to_path_buf());
+        let mut sink = storage.open_sink(aqfs::FileMeta {
+            path: aqfs::Path::new(vec!["dump".to_string()]).unwrap(),
+            mtime: Utc.timestamp(0, 0),
+            locked: false,
+            content_type: None,
+            presigned_url: None,
+            custom: Default::default(),
+            sha256: None,
+            size: None,
+        })?;
+        sink.write_chunk(b"first\n").await?;
+        sink.write_chunk(b"second\n").await?;
+        // Nothing is visible until the sink is closed.
+        assert_eq!(storage.list_files().await?.len(), 0);
+        let meta = sink.close().await?;
+        let mut files = storage.list_files().await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].meta(), &meta);
+        assert_eq!(files[0].read_all().await?, b"first\nsecond\n");
+
+        Ok(())
+    }
+
+    #[cfg(feature = "xattr")]
+    #[tokio::test]
+    async fn xattrs_are_preserved() -> Result<(), aqfs::Error> {
+        let (storage, _tmp_dir) = Storage::init_in_temp()?;
+        let mut storage = storage.with_preserve_xattrs(true);
+        let mut custom = BTreeMap::new();
+        custom.insert("xattr.user.asynq".to_string(), "value".to_string());
+        // Not in the user namespace, so ignored.
+        custom.insert("xattr.trusted.asynq".to_string(), "value".to_string());
+        storage
+            .create_file(aqfs::RamFile::new(
+                aqfs::FileMeta::builder().path("a").custom(custom).build()?,
+                b"a".to_vec(),
+            ))
+            .await?;
+        let realpath = storage.get_real_path(&aqfs::Path::try_from("a").unwrap());
+        assert_eq!(
+            xattr::get(&realpath, "user.asynq")?,
+            Some(b"value".to_vec())
+        );
+
+        let files = storage.list_files().await?;
+        let mut expected = BTreeMap::new();
+        expected.insert("xattr.user.asynq".to_string(), "value".to_string());
+        assert_eq!(files[0].meta().custom, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fsync_works() -> Result<(), aqfs::Error> {
+        use aqfs::SinkFile;
+
+        let (storage, _tmp_dir) = Storage::init_in_temp()?;
+        let mut storage = storage.with_fsync(true).with_sync_dir(true);
+        storage
+            .create_file((aqfs::FileMeta::builder().path("a").build()?, b"a".to_vec()))
+            .await?;
+        let mut sink = storage.open_sink(aqfs::FileMeta::builder().path("b").build()?)?;
+        sink.write_chunk(b"b").await?;
+        sink.close().await?;
+        let mut files = storage.list_files().await?;
+        files.sort_by_key(|f| f.meta().path.to_string());
+        assert_eq!(files[0].read_all().await?, b"a");
+        assert_eq!(files[1].read_all().await?, b"b");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_file_works() -> Result<(), aqfs::Error> {
+        let (mut storage, _tmp_dir) = Storage::init_in_temp()?;
+        let meta = aqfs::FileMeta::builder().path("a").build()?;
+        let created = storage.create_file((meta, b"a".to_vec())).await?;
+        let mut f = storage
+            .get_file(&aqfs::Path::try_from("a").unwrap())
+            .await?;
+        assert_eq!(f.meta(), &created);
+        assert_eq!(f.read_all().await?, b"a");
+        assert_eq!(
+            storage
+                .get_file(&aqfs::Path::try_from("missing").unwrap())
+                .await
+                .unwrap_err(),
+            aqfs::Error::NotFound {
+                path: aqfs::Path::try_from("missing").unwrap()
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn exists_works() -> Result<(), aqfs::Error> {
+        let (mut storage, tmp_dir) = Storage::init_in_temp()?;
+        let meta = aqfs::FileMeta::builder().path("a").build()?;
+        storage.create_file((meta, vec![])).await?;
+        std::fs::create_dir(tmp_dir.path().join("dir"))?;
+        assert!(storage.exists(&aqfs::Path::try_from("a").unwrap()).await?);
+        assert!(
+            !storage
+                .exists(&aqfs::Path::try_from("dir").unwrap())
+                .await?
+        );
+        assert!(
+            !storage
+                .exists(&aqfs::Path::try_from("missing").unwrap())
+                .await?
+        );
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(
+                tmp_dir.path().join("missing"),
+                tmp_dir.path().join("dangling"),
+            )?;
+            assert!(
+                !storage
+                    .exists(&aqfs::Path::try_from("dangling").unwrap())
+                    .await?
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_dir_works() -> Result<(), aqfs::Error> {
+        use crate::aqfs::test::dir_entry_names;
+
+        let (mut storage, tmp_dir) = Storage::init_in_temp()?;
+        assert_eq!(storage.list_dir(&aqfs::Path::default()).await?, vec![]);
+        std::fs::create_dir_all(tmp_dir.path().join("d").join("e"))?;
+        std::fs::create_dir(tmp_dir.path().join("empty"))?;
+        std::fs::write(tmp_dir.path().join("a"), "a")?;
+        std::fs::write(tmp_dir.path().join("d").join("b"), "b")?;
+        std::fs::write(tmp_dir.path().join("d").join("e").join("c"), "c")?;
+        std::fs::write(
+            tmp_dir.path().join("d").join(format!("{}x", TEMP_PREFIX)),
+            "",
+        )?;
+        assert_eq!(
+            dir_entry_names(&storage.list_dir(&aqfs::Path::default()).await?),
+            vec!["a", "d/", "empty/"]
+        );
+        let d = aqfs::Path::try_from("d").unwrap();
+        let entries = storage.list_dir(&d).await?;
+        assert_eq!(dir_entry_names(&entries), vec!["d/b", "e/"]);
+        match &entries[0] {
+            aqfs::DirEntry::File(meta) => assert_eq!(meta.size, Some(1)),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(
+            storage
+                .list_dir(&aqfs::Path::try_from("missing").unwrap())
+                .await?,
+            vec![]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn boxed_storages_work() -> Result<(), aqfs::Error> {
+        let (local, _tmp_dir) = Storage::init_in_temp()?;
+        let mut storages: Vec<aqfs::BoxedStorage> =
+            vec![aqfs::RamStorage::new().boxed(), local.boxed()];
+        for st in storages.iter_mut() {
+            let meta = aqfs::FileMeta::builder().path("a").build()?;
+            st.create_file((meta, b"a".to_vec())).await?;
+            let files = st.list_files().await?;
+            assert_eq!(files.len(), 1);
+            assert_eq!(files[0].meta().path.to_string(), "a");
+            let a = aqfs::Path::try_from("a").unwrap();
+            assert_eq!(st.get_file(&a).await?.read_all().await?, b"a");
+            let b = aqfs::Path::try_from("b").unwrap();
+            st.rename_file(&files[0], b.clone()).await?;
+            assert!(!st.exists(&a).await?);
+            let file = st.get_file(&b).await?;
+            st.remove_file(&file).await?;
+            assert!(st.list_files().await?.is_empty());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rename_file_works() -> Result<(), aqfs::Error> {
+        let (mut storage, tmp_dir) = Storage::init_in_temp()?;
+        for name in &["a", "b"] {
+            let meta = aqfs::FileMeta::builder().path(*name).build()?;
+            storage
+                .create_file((meta, name.as_bytes().to_vec()))
+                .await?;
+        }
+        let a = storage
+            .get_file(&aqfs::Path::try_from("a").unwrap())
+            .await?;
+        storage
+            .rename_file(&a, aqfs::Path::try_from("c").unwrap())
+            .await?;
+        assert!(!tmp_dir.path().join("a").exists());
+        let mut c = storage
+            .get_file(&aqfs::Path::try_from("c").unwrap())
+            .await?;
+        assert_eq!(c.meta().mtime, a.meta().mtime);
+        assert_eq!(c.read_all().await?, b"a");
+        assert_eq!(
+            storage
+                .rename_file(&c, aqfs::Path::try_from("b").unwrap())
+                .await,
+            Err(aqfs::Error::AlreadyExists {
+                path: aqfs::Path::try_from("b").unwrap()
+            })
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn copy_file_works() -> Result<(), aqfs::Error> {
+        let (mut storage, _tmp_dir) = Storage::init_in_temp()?;
+        for name in &["a", "b"] {
+            let meta = aqfs::FileMeta::builder()
+                .path(*name)
+                .mtime(Utc.timestamp(0, 0))
+                .build()?;
+            storage
+                .create_file((meta, name.as_bytes().to_vec()))
+                .await?;
+        }
+        let a = storage
+            .get_file(&aqfs::Path::try_from("a").unwrap())
+            .await?;
+        storage
+            .copy_file(&a, aqfs::Path::try_from("c").unwrap(), false)
+            .await?;
+        let mut c = storage
+            .get_file(&aqfs::Path::try_from("c").unwrap())
+            .await?;
+        assert_eq!(c.read_all().await?, b"a");
+        assert!(c.meta().mtime > a.meta().mtime);
+
+        assert_eq!(
+            storage
+                .copy_file(&a, aqfs::Path::try_from("b").unwrap(), false)
+                .await,
+            Err(aqfs::Error::AlreadyExists {
+                path: aqfs::Path::try_from("b").unwrap()
+            })
+        );
+        storage
+            .copy_file(&a, aqfs::Path::try_from("b").unwrap(), true)
+            .await?;
+        let mut b = storage
+            .get_file(&aqfs::Path::try_from("b").unwrap())
+            .await?;
+        assert_eq!(b.read_all().await?, b"a");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_range_works() -> Result<(), aqfs::Error> {
+        let (mut storage, _tmp_dir) = Storage::init_in_temp()?;
+        let meta = aqfs::FileMeta::builder().path("a").build()?;
+        storage.create_file((meta, b"0123456789".to_vec())).await?;
+        let mut f = storage
+            .get_file(&aqfs::Path::try_from("a").unwrap())
+            .await?;
+        aqfs::test::check_read_range(&mut f).await
+    }
+
+    #[tokio::test]
+    async fn create_file_cleans_up_broken_stream() -> Result<(), aqfs::Error> {
+        let (mut storage, tmp_dir) = Storage::init_in_temp()?;
+        let file = aqfs::test::FailingFile {
+            meta: aqfs::FileMeta::builder().path("broken").build()?,
+            size: 3 * COPY_BUFFER_SIZE / 2,
+        };
+        assert!(storage.create_file(file).await.is_err());
+        assert!(!tmp_dir.path().join("broken").exists());
+        assert_eq!(storage.list_files().await?.len(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_overwrite_keeps_old_file() -> Result<(), aqfs::Error> {
+        let (mut storage, tmp_dir) = Storage::init_in_temp()?;
+        let meta = aqfs::FileMeta::builder().path("a").build()?;
+        storage.create_file((meta.clone(), b"old".to_vec())).await?;
+        let file = aqfs::test::FailingFile {
+            meta,
+            size: 3 * COPY_BUFFER_SIZE / 2,
+        };
+        assert!(storage.create_file(file).await.is_err());
+        assert_eq!(std::fs::read(tmp_dir.path().join("a"))?, b"old");
+        // The temporary file is gone too.
+        assert_eq!(std::fs::read_dir(tmp_dir.path())?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn init_works() -> Result<(), aqfs::Error> {
+        let tmp_dir = TempDir::new()?;
+        let root = tmp_dir.path().join("a").join("b");
+        Storage::init(root.clone())?;
+        assert!(root.is_dir());
+        // Initializing an existing directory is fine.
+        Storage::init(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn identity_works() -> Result<(), aqfs::Error> {
+        let tmp_dir = TempDir::new()?;
+        let st0 = Storage::new(tmp_dir.path().to_path_buf());
+        let st1 = Storage::new(tmp_dir.path().join("."));
+        let (st2, _tmp_dir) = Storage::init_in_temp()?;
+        assert_eq!(st0.identity(), st1.identity());
+        assert_ne!(st0.identity(), st2.identity());
+        Ok(())
+    }
 }
@@ -1,8 +1,3 @@
-mod aqfs;
-mod local;
-mod s3;
-mod sync;
-
 #[tokio::main]
 async fn main() {
     //
@@ -0,0 +1,300 @@
+use crate::aqfs::{self, File as FileTrait, StorageEntity};
+use async_trait::async_trait;
+use std::collections::{BTreeMap, HashMap};
+
+// Active-active storage over replicas in several regions, e.g. s3::Storages of buckets in
+// different regions. Writes go to every region in parallel, and reads go to the primary region,
+// falling back to the next ones in order while it fails. Regions diverge when a write succeeds in
+// some of them only, which check_consistency detects and reconcile repairs.
+pub struct MultiRegionStorage<S: StorageEntity> {
+    regions: Vec<(String, S)>,
+    primary: usize,
+}
+
+#[cfg(feature = "backend-s3")]
+pub type MultiRegionS3Storage = MultiRegionStorage<crate::s3::Storage>;
+
+// The result of MultiRegionStorage::reconcile.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReconcileReport {
+    // The files copied, each with the name of the region it's copied to.
+    pub copied: Vec<(aqfs::Path, String)>,
+}
+
+impl<S: StorageEntity> MultiRegionStorage<S> {
+    // regions are pairs of a name, e.g. "us-east-1", and the storage there. At least one region
+    // is needed.
+    pub fn new(regions: Vec<(String, S)>) -> Result<Self, aqfs::Error> {
+        if regions.is_empty() {
+            return Err(aqfs::Error::Config(
+                "At least one region is needed".to_string(),
+            ));
+        }
+        Ok(Self {
+            regions,
+            primary: 0,
+        })
+    }
+
+    // Read from regions[index] first, e.g. the closest one. Defaults to 0.
+    pub fn with_primary_region_index(mut self, index: usize) -> Result<Self, aqfs::Error> {
+        if index >= self.regions.len() {
+            return Err(aqfs::Error::Config(format!(
+                "No region at index {} of {} regions",
+                index,
+                self.regions.len()
+            )));
+        }
+        self.primary = index;
+        Ok(self)
+    }
+
+    pub fn region_mut(&mut self, index: usize) -> &mut S {
+        &mut self.regions[index].1
+    }
+
+    pub fn into_regions(self) -> Vec<(String, S)> {
+        self.regions
+    }
+
+    // The indices of the regions from the primary, wrapping around.
+    fn read_order(&self) -> Vec<usize> {
+        let n = self.regions.len();
+        (0..n).map(|i| (self.primary + i) % n).collect()
+    }
+
+    // Check that every region has the same files by comparing the Merkle roots of their metadata.
+    // Returns Err(Error::JournalDivergence) with the regions that differ from the primary.
+    pub async fn check_consistency(&mut self) -> Result<(), aqfs::Error> {
+        let mut roots = vec![];
+        for (_, st) in self.regions.iter_mut() {
+            let metas = st
+                .list_files()
+                .await?
+                .iter()
+                .map(|f| f.meta().clone())
+                .collect();
+            roots.push(merkle_root(metas)?);
+        }
+        let regions: Vec<String> = self
+            .regions
+            .iter()
+            .zip(roots.iter())
+            .filter(|(_, root)| **root != roots[self.primary])
+            .map(|((name, _), _)| name.clone())
+            .collect();
+        if regions.is_empty() {
+            Ok(())
+        } else {
+            Err(aqfs::Error::JournalDivergence { regions })
+        }
+    }
+
+    // Bring every file to every region in its latest version by mtime.
+    // FIXME: A file removed in some regions but not in the others comes back, since a removal
+    // leaves nothing to compare by.
+    pub async fn reconcile(&mut self) -> Result<ReconcileReport, aqfs::Error> {
+        // The latest version of each path and the index of a region that has it.
+        let mut latest: BTreeMap<aqfs::Path, (aqfs::FileMeta, usize)> = BTreeMap::new();
+        let mut metas: Vec<HashMap<aqfs::Path, aqfs::FileMeta>> = vec![];
+        for (i, (_, st)) in self.regions.iter_mut().enumerate() {
+            let mut region_metas = HashMap::new();
+            for f in st.list_files().await? {
+                let meta = f.meta().clone();
+                match latest.get(&meta.path) {
+                    Some((newest, _)) if newest.mtime >= meta.mtime => {}
+                    _ => {
+                        latest.insert(meta.path.clone(), (meta.clone(), i));
+                    }
+                }
+                region_metas.insert(meta.path.clone(), meta);
+            }
+            metas.push(region_metas);
+        }
+        let mut report = ReconcileReport::default();
+        for (path, (meta, from)) in latest {
+            let mut content = None;
+            for i in 0..self.regions.len() {
+                if metas[i].get(&path) == Some(&meta) {
+                    continue;
+                }
+                if content.is_none() {
+                    let mut f = aqfs::find_file(&mut self.regions[from].1, &path)
+                        .await?
+                        .ok_or_else(|| aqfs::Error::NotFound { path: path.clone() })?;
+                    content = Some(f.read_all().await?);
+                }
+                let data = content.clone().unwrap();
+                let (name, st) = &mut self.regions[i];
+                st.create_file(aqfs::RamFile::new(meta.clone(), data))
+                    .await?;
+                report.copied.push((path.clone(), name.clone()));
+            }
+        }
+        Ok(report)
+    }
+}
+
+// The root of the Merkle tree whose leaves are the digests of metas in order.
+fn merkle_root(mut metas: Vec<aqfs::FileMeta>) -> Result<[u8; 32], aqfs::Error> {
+    metas.sort();
+    let mut level = vec![];
+    for meta in metas.iter() {
+        let bytes =
+            bincode::serialize(meta).map_err(|e| aqfs::Error::SerdeFail(aqfs::Cause::new(*e)))?;
+        level.push(aqfs::sha256(&bytes));
+    }
+    if level.is_empty() {
+        return Ok(aqfs::sha256(b""));
+    }
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| aqfs::sha256(&pair.concat()))
+            .collect();
+    }
+    Ok(level[0])
+}
+
+#[async_trait]
+impl<S: StorageEntity> StorageEntity for MultiRegionStorage<S> {
+    type File = S::File;
+
+    async fn list_files(&mut self) -> Result<Vec<S::File>, aqfs::Error> {
+        let mut last_err = None;
+        for i in self.read_order() {
+            let (name, st) = &mut self.regions[i];
+            match st.list_files().await {
+                Ok(files) => return Ok(files),
+                Err(err) => {
+                    tracing::warn!("Region {} is unavailable: {}", name, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap())
+    }
+
+    // Returns the metadata stored in the primary region. If any region fails, the first failure
+    // is returned, and the file stays in the other regions until reconcile.
+    async fn create_file(
+        &mut self,
+        mut file: impl aqfs::File + 'async_trait,
+    ) -> Result<aqfs::FileMeta, aqfs::Error> {
+        let file = aqfs::RamFile::new(file.meta().clone(), file.read_all().await?);
+        let results = futures::future::join_all(
+            self.regions
+                .iter_mut()
+                .map(|(_, st)| st.create_file(file.clone())),
+        )
+        .await;
+        let mut metas = results.into_iter().collect::<Result<Vec<_>, _>>()?;
+        Ok(metas.swap_remove(self.primary))
+    }
+
+    async fn remove_file(&mut self, file: &S::File) -> Result<(), aqfs::Error> {
+        let path = &file.meta().path;
+        futures::future::join_all(
+            self.regions
+                .iter_mut()
+                .map(|(_, st)| aqfs::remove_at(st, path)),
+        )
+        .await
+        .into_iter()
+        .collect()
+    }
+
+    async fn unlock_file(&mut self, file: &S::File) -> Result<(), aqfs::Error> {
+        let path = &file.meta().path;
+        futures::future::join_all(
+            self.regions
+                .iter_mut()
+                .map(|(_, st)| aqfs::unlock_at(st, path)),
+        )
+        .await
+        .into_iter()
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::offset::TimeZone;
+    use chrono::Utc;
+    use std::convert::TryFrom;
+
+    #[tokio::test]
+    async fn works() -> Result<(), aqfs::Error> {
+        let meta = |name: &str, secs: i64| {
+            aqfs::FileMeta::builder()
+                .path(name)
+                .mtime(Utc.timestamp(secs, 0))
+                .build()
+                .unwrap()
+        };
+        let mut st = MultiRegionStorage::new(vec![
+            ("a".to_string(), aqfs::RamStorage::new()),
+            ("b".to_string(), aqfs::RamStorage::new()),
+        ])?
+        .with_primary_region_index(1)?;
+        st.create_file(aqfs::RamFile::new(meta("x", 0), b"x".to_vec()))
+            .await?;
+        st.check_consistency().await?;
+        assert_eq!(st.region_mut(0).list_files().await?.len(), 1);
+
+        // Diverge: y is written to the primary only, and x is updated in the other only.
+        st.region_mut(1)
+            .create_file(aqfs::RamFile::new(meta("y", 0), b"y".to_vec()))
+            .await?;
+        st.region_mut(0)
+            .create_file(aqfs::RamFile::new(meta("x", 10), b"x2".to_vec()))
+            .await?;
+        assert_eq!(
+            st.check_consistency().await,
+            Err(aqfs::Error::JournalDivergence {
+                regions: vec!["a".to_string()]
+            })
+        );
+        assert_eq!(st.list_files().await?.len(), 2);
+
+        let report = st.reconcile().await?;
+        assert_eq!(
+            report.copied,
+            vec![
+                (aqfs::Path::try_from("x").unwrap(), "b".to_string()),
+                (aqfs::Path::try_from("y").unwrap(), "a".to_string()),
+            ]
+        );
+        st.check_consistency().await?;
+        let mut x = aqfs::find_file(st.region_mut(1), &aqfs::Path::try_from("x").unwrap())
+            .await?
+            .unwrap();
+        assert_eq!(x.read_all().await?, b"x2");
+
+        let x = st
+            .list_files()
+            .await?
+            .into_iter()
+            .find(|f| f.meta().path == aqfs::Path::try_from("x").unwrap())
+            .unwrap();
+        st.remove_file(&x).await?;
+        assert_eq!(st.region_mut(0).list_files().await?.len(), 1);
+        assert_eq!(st.region_mut(1).list_files().await?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_regions() {
+        let regions: Vec<(String, aqfs::RamStorage)> = vec![];
+        assert!(matches!(
+            MultiRegionStorage::new(regions),
+            Err(aqfs::Error::Config(_))
+        ));
+        let st = MultiRegionStorage::new(vec![("a".to_string(), aqfs::RamStorage::new())]).unwrap();
+        assert!(matches!(
+            st.with_primary_region_index(1),
+            Err(aqfs::Error::Config(_))
+        ));
+    }
+}
@@ -1,77 +1,780 @@
 use crate::aqfs;
 use crate::aqfs::File as FileTrait;
+use aes_gcm::aead::{Aead, NewAead};
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
-use futures;
-use rusoto_core::Region;
+use futures::stream::BoxStream;
+use rand::Rng;
+use rusoto_cloudwatch::CloudWatch;
+use rusoto_core::credential::{
+    AwsCredentials, CredentialsError, DefaultCredentialsProvider, ProfileProvider,
+    ProvideAwsCredentials, StaticProvider,
+};
+use rusoto_core::{Region, RusotoError};
+use rusoto_s3::util::{PreSignedRequest, PreSignedRequestOption};
 use rusoto_s3::S3;
+use rusoto_sqs::Sqs;
 use serde::{Deserialize, Serialize};
-use std::{cell::RefCell, collections::HashMap, env, rc::Rc, str::FromStr};
-use tokio::io::AsyncReadExt;
+use sha2::Digest;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env,
+    future::Future,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite};
 use uuid::Uuid;
 
-impl<E: std::error::Error + 'static> From<rusoto_core::RusotoError<E>> for aqfs::Error {
+// S3 recommends to back off from 1 s on SlowDown.
+const SLOWDOWN_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const SLOWDOWN_MAX_DELAY: Duration = Duration::from_secs(64);
+const DEFAULT_SLOWDOWN_RETRIES: u32 = 5;
+const REPLICATION_POLL_INTERVAL: Duration = Duration::from_millis(200);
+// S3 object keys are limited to 1,024 bytes in UTF-8.
+const MAX_KEY_LENGTH: usize = 1024;
+// How much of the content is looked at to detect its content type.
+const CONTENT_TYPE_SNIFF_LENGTH: usize = 8 * 1024;
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+// Size of each part of multipart uploads by Sink. S3 requires 5 MiB at least except the last one.
+const PART_SIZE: usize = 8 * 1024 * 1024;
+// Size of the buffer create_file streams the content through.
+const COPY_BUFFER_SIZE: usize = 1024 * 1024;
+const DEFAULT_MAX_JOURNAL_RECORDS: usize = 1000;
+// How many objects S3Client::put_objects uploads at a time.
+const DEFAULT_PUT_CONCURRENCY: usize = 20;
+const ENCRYPTED_JOURNAL_CONTENT_TYPE: &str = "application/vnd.asynq.journal.encrypted";
+const NDJSON_JOURNAL_CONTENT_TYPE: &str = "application/x-ndjson";
+const ENCRYPTED_NDJSON_JOURNAL_CONTENT_TYPE: &str =
+    "application/vnd.asynq.journal.ndjson.encrypted";
+// AES-GCM uses 96-bit nonces.
+const NONCE_LENGTH: usize = 12;
+// Updated with a new content after each journal write, so that readers can tell if the journal has
+// changed by its ETag.
+const JOURNAL_SENTINEL_KEY: &str = "journal/.last-modified";
+// The maximum number of keys S3 returns in a ListObjectsV2 response.
+const DEFAULT_LIST_PAGE_SIZE: i64 = 1000;
+// Every object is tagged with its kind in the URL query format of x-amz-tagging, so that bucket
+// lifecycle rules can tell them apart by tag as well as by prefix. For example, the following rule
+// deletes journal objects 30 days after their creation:
+//   { "ID": "asynq-journal-expiration", "Status": "Enabled",
+//     "Filter": { "Tag": { "Key": "type", "Value": "journal" } },
+//     "Expiration": { "Days": 30 } }
+const JOURNAL_TAGGING: &str = "type=journal";
+const DATA_TAGGING: &str = "type=data";
+// Long polling of SQS waits at most 20 seconds.
+const SQS_WAIT_TIME_SECONDS: i64 = 20;
+// A received message is hidden from other receivers for this long, and reappears unless deleted.
+const SQS_VISIBILITY_TIMEOUT_SECONDS: i64 = 60;
+const SQS_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+impl<E: std::error::Error + Send + Sync + 'static> From<rusoto_core::RusotoError<E>>
+    for aqfs::Error
+{
     fn from(from: rusoto_core::RusotoError<E>) -> Self {
-        aqfs::Error::RusotoFail(format!("{}", from))
+        aqfs::Error::RusotoFail(aqfs::Cause::new(from))
     }
 }
 
 impl From<bincode::Error> for aqfs::Error {
     fn from(from: bincode::Error) -> Self {
-        aqfs::Error::SerdeFail(from.to_string())
+        aqfs::Error::SerdeFail(aqfs::Cause::new(*from))
+    }
+}
+
+// Check a string that will be (a part of) S3 object key, so that we don't get an opaque 400
+// error from S3. Rust strings are always valid UTF-8, so it's not checked here.
+fn validate_key(key: &str) -> Result<(), aqfs::Error> {
+    if key.len() > MAX_KEY_LENGTH {
+        return Err(aqfs::Error::InvalidPath(format!(
+            "longer than {} bytes: {}",
+            MAX_KEY_LENGTH,
+            key.len()
+        )));
+    }
+    if key.contains('\0') {
+        return Err(aqfs::Error::InvalidPath(format!(
+            "contains a null byte: {:?}",
+            key
+        )));
+    }
+    Ok(())
+}
+
+// Detect the content type from the magic bytes at the head of data.
+fn detect_content_type(data: &[u8]) -> String {
+    let head = &data[..std::cmp::min(data.len(), CONTENT_TYPE_SNIFF_LENGTH)];
+    infer::get(head)
+        .map(|t| t.mime_type().to_string())
+        .unwrap_or_else(|| DEFAULT_CONTENT_TYPE.to_string())
+}
+
+// Sort journal objects in the order they were uploaded. LastModified is set by S3, so unlike the
+// timestamp in the key, it doesn't depend on the writer's clock. The key breaks ties and is the
+// only order for objects without LastModified.
+fn sort_journal_objects(objects: &mut [rusoto_s3::Object]) {
+    objects.sort_by_key(|o| {
+        (
+            o.last_modified
+                .as_ref()
+                .and_then(|t| DateTime::parse_from_rfc3339(t).ok()),
+            o.key.clone(),
+        )
+    });
+}
+
+// Check if body, an S3 event notification, says an object under journal_prefix is created or
+// removed. Other messages, e.g. s3:TestEvent, are ignored.
+fn is_journal_event(body: &str, journal_prefix: &str) -> bool {
+    let event: serde_json::Value = match serde_json::from_str(body) {
+        Ok(event) => event,
+        Err(_) => return false,
+    };
+    let records = match event["Records"].as_array() {
+        Some(records) => records,
+        None => return false,
+    };
+    records.iter().any(|record| {
+        let name = record["eventName"].as_str().unwrap_or("");
+        let key = record["s3"]["object"]["key"].as_str().unwrap_or("");
+        (name.starts_with("ObjectCreated:") || name.starts_with("ObjectRemoved:"))
+            && key.starts_with(journal_prefix)
+    })
+}
+
+// Receive messages from the queue once, set invalidated if any of them is about the journal, and
+// delete them. Messages not deleted due to an error are received again after the visibility
+// timeout.
+async fn poll_journal_events(
+    client: &rusoto_sqs::SqsClient,
+    queue_url: &str,
+    journal_prefix: &str,
+    invalidated: &AtomicBool,
+) -> Result<(), aqfs::Error> {
+    let output = client
+        .receive_message(rusoto_sqs::ReceiveMessageRequest {
+            queue_url: queue_url.to_string(),
+            max_number_of_messages: Some(10),
+            wait_time_seconds: Some(SQS_WAIT_TIME_SECONDS),
+            visibility_timeout: Some(SQS_VISIBILITY_TIMEOUT_SECONDS),
+            ..Default::default()
+        })
+        .await?;
+    for message in output.messages.unwrap_or_default() {
+        if let Some(body) = &message.body {
+            if is_journal_event(body, journal_prefix) {
+                invalidated.store(true, Ordering::SeqCst);
+            }
+        }
+        if let Some(receipt_handle) = message.receipt_handle {
+            client
+                .delete_message(rusoto_sqs::DeleteMessageRequest {
+                    queue_url: queue_url.to_string(),
+                    receipt_handle,
+                })
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+fn http_client(
+    tls: &TlsConfig,
+) -> Result<
+    rusoto_core::HttpClient<hyper_tls::HttpsConnector<hyper::client::HttpConnector>>,
+    aqfs::Error,
+> {
+    if *tls == TlsConfig::default() {
+        return rusoto_core::HttpClient::new().map_err(|e| aqfs::Error::Config(e.to_string()));
+    }
+    let mut builder = native_tls::TlsConnector::builder();
+    for pem in &tls.ca_certs {
+        let cert = native_tls::Certificate::from_pem(pem)
+            .map_err(|e| aqfs::Error::Config(format!("Invalid CA certificate: {}", e)))?;
+        builder.add_root_certificate(cert);
+    }
+    if tls.insecure_skip_verify {
+        tracing::warn!("TLS certificate verification is disabled");
+        builder.danger_accept_invalid_certs(true);
+    }
+    let tls_connector = builder
+        .build()
+        .map_err(|e| aqfs::Error::Config(e.to_string()))?;
+    let mut http = hyper::client::HttpConnector::new();
+    // Let the TLS connector handle https URLs.
+    http.enforce_http(false);
+    Ok(rusoto_core::HttpClient::from_connector(
+        hyper_tls::HttpsConnector::from((http, tls_connector.into())),
+    ))
+}
+
+// The region named name, or the one of an S3-compatible service at endpoint.
+fn region_from_name(name: &str, endpoint: Option<String>) -> Result<Region, aqfs::Error> {
+    match endpoint {
+        Some(endpoint) => Ok(Region::Custom {
+            name: name.to_string(),
+            endpoint,
+        }),
+        None => Region::from_str(name).map_err(|e| aqfs::Error::Config(format!("{}: {}", name, e))),
+    }
+}
+
+// A credentials provider shared by the rusoto client and presigned_get_url, so that presigned URLs
+// are signed with the same credentials as the requests.
+#[derive(Clone)]
+struct SharedCredentials(Arc<dyn ProvideAwsCredentials + Send + Sync>);
+
+impl SharedCredentials {
+    fn new<P: ProvideAwsCredentials + Send + Sync + 'static>(provider: P) -> Self {
+        SharedCredentials(Arc::new(provider))
+    }
+}
+
+#[async_trait]
+impl ProvideAwsCredentials for SharedCredentials {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        self.0.credentials().await
     }
 }
 
+// Encrypt plaintext with AES-256-GCM under a random nonce, which is prepended to the result.
+fn encrypt_journal(key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>, aqfs::Error> {
+    let cipher = aes_gcm::Aes256Gcm::new(aes_gcm::Key::from_slice(key));
+    let mut nonce = [0u8; NONCE_LENGTH];
+    rand::thread_rng().fill(&mut nonce);
+    let ciphertext = cipher
+        .encrypt(aes_gcm::Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| aqfs::Error::CryptoFail("Can't encrypt journal".to_string()))?;
+    Ok([&nonce[..], &ciphertext[..]].concat())
+}
+
+fn decrypt_journal(key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>, aqfs::Error> {
+    if data.len() < NONCE_LENGTH {
+        return Err(aqfs::Error::CryptoFail("Journal is too short".to_string()));
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LENGTH);
+    let cipher = aes_gcm::Aes256Gcm::new(aes_gcm::Key::from_slice(key));
+    cipher
+        .decrypt(aes_gcm::Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| aqfs::Error::CryptoFail("Can't decrypt journal; wrong key?".to_string()))
+}
+
+// S3 returns HTTP 503 SlowDown when the request rate exceeds the bucket's limit.
+// rusoto has no dedicated variant for it, so it comes as an unknown error.
+fn is_slowdown<E>(err: &RusotoError<E>) -> bool {
+    match err {
+        RusotoError::Unknown(res) => {
+            res.status.as_u16() == 503 && String::from_utf8_lossy(&res.body).contains("SlowDown")
+        }
+        _ => false,
+    }
+}
+
+// Optional parameters of PutObject.
+#[derive(Debug, Clone, Default)]
+struct PutOptions {
+    expires: Option<DateTime<Utc>>,
+    content_type: Option<String>,
+    tagging: Option<String>,
+    // Base64-encoded MD5 digest of the body, which S3 checks the body against.
+    content_md5: Option<String>,
+}
+
+// Metrics of S3 requests, reported after each GetObject, PutObject, and ListObjectsV2.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct RequestMetrics {
+    bytes_uploaded: u64,
+    bytes_downloaded: u64,
+    operation_count: u64,
+}
+
+// Receiver of RequestMetrics. report is called on the request path, so it must not block.
+trait MetricsSink: Send + Sync {
+    fn report(&self, metrics: RequestMetrics);
+}
+
+// Send RequestMetrics to CloudWatch as custom metrics.
+struct CloudWatchSink {
+    client: rusoto_cloudwatch::CloudWatchClient,
+    namespace: String,
+}
+
+impl MetricsSink for CloudWatchSink {
+    fn report(&self, metrics: RequestMetrics) {
+        let datum = |name: &str, value: u64, unit: &str| rusoto_cloudwatch::MetricDatum {
+            metric_name: name.to_string(),
+            value: Some(value as f64),
+            unit: Some(unit.to_string()),
+            ..Default::default()
+        };
+        let mut request = rusoto_cloudwatch::PutMetricDataInput::default();
+        request.namespace = self.namespace.clone();
+        request.metric_data = vec![
+            datum("AsynqBytesUploaded", metrics.bytes_uploaded, "Bytes"),
+            datum("AsynqBytesDownloaded", metrics.bytes_downloaded, "Bytes"),
+            datum("AsynqOperationCount", metrics.operation_count, "Count"),
+        ];
+        // Fire and forget. Metrics are best effort and must not slow down S3 operations.
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            if let Err(err) = client.put_metric_data(request).await {
+                tracing::warn!("Failed to put metrics to CloudWatch: {}", err);
+            }
+        });
+    }
+}
+
+#[derive(Clone)]
 struct S3Client {
     client: rusoto_s3::S3Client,
+    credentials: SharedCredentials,
+    // Kept to make clients of other regions in the same way.
+    tls: TlsConfig,
+    endpoint: Option<String>,
+    region: Region,
     bucket: String,
+    // Prepended to every key, so that a bucket can be shared among storages.
+    // The keys that the methods take and return don't include it.
+    prefix: String,
+    slowdown_retries: u32,
+    metrics: Option<Arc<dyn MetricsSink>>,
+    list_page_size: i64,
+    put_concurrency: usize,
 }
 
 impl S3Client {
-    pub fn new(region: Region, bucket: String) -> Self {
+    fn with_client(
+        client: rusoto_s3::S3Client,
+        credentials: SharedCredentials,
+        region: Region,
+        bucket: String,
+    ) -> Self {
         Self {
-            client: rusoto_s3::S3Client::new(region),
+            client,
+            credentials,
+            tls: TlsConfig::default(),
+            endpoint: None,
+            region,
             bucket: bucket,
+            prefix: String::new(),
+            slowdown_retries: DEFAULT_SLOWDOWN_RETRIES,
+            metrics: None,
+            list_page_size: DEFAULT_LIST_PAGE_SIZE,
+            put_concurrency: DEFAULT_PUT_CONCURRENCY,
+        }
+    }
+
+    // A client of bucket in the region named region, with the same credentials, TLS settings, and
+    // endpoint as self.
+    fn for_region(&self, region: &str, bucket: String) -> Result<Self, aqfs::Error> {
+        let region = region_from_name(region, self.endpoint.clone())?;
+        let client = rusoto_s3::S3Client::new_with(
+            http_client(&self.tls)?,
+            self.credentials.clone(),
+            region.clone(),
+        );
+        Ok(Self {
+            client,
+            region,
+            bucket,
+            ..self.clone()
+        })
+    }
+
+    fn full_key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    fn report_metrics(&self, bytes_uploaded: u64, bytes_downloaded: u64) {
+        if let Some(sink) = &self.metrics {
+            sink.report(RequestMetrics {
+                bytes_uploaded,
+                bytes_downloaded,
+                operation_count: 1,
+            });
+        }
+    }
+
+    // Call op and retry it with exponential backoff while S3 says SlowDown.
+    async fn with_retry<T, E, Fut>(&self, mut op: impl FnMut() -> Fut) -> Result<T, aqfs::Error>
+    where
+        E: std::error::Error + Send + Sync + 'static,
+        Fut: Future<Output = Result<T, RusotoError<E>>>,
+    {
+        let mut delay = SLOWDOWN_INITIAL_DELAY;
+        let mut retries = 0;
+        loop {
+            match op().await {
+                Err(err) if retries < self.slowdown_retries && is_slowdown(&err) => {
+                    retries += 1;
+                    // Add +-25% jitter not to retry in lockstep with other clients.
+                    let wait = delay.mul_f64(rand::thread_rng().gen_range(0.75, 1.25));
+                    tracing::warn!(
+                        "S3 returned SlowDown; retrying in {:?} ({}/{})",
+                        wait,
+                        retries,
+                        self.slowdown_retries
+                    );
+                    tokio::time::delay_for(wait).await;
+                    delay = std::cmp::min(delay * 2, SLOWDOWN_MAX_DELAY);
+                }
+                res => return Ok(res?),
+            }
         }
     }
 
     async fn get_object(&self, key: String) -> Result<rusoto_s3::GetObjectOutput, aqfs::Error> {
-        let mut request = rusoto_s3::GetObjectRequest::default();
-        request.bucket = self.bucket.clone();
-        request.key = key;
-        Ok(self.client.get_object(request).await?)
+        self.get_object_with(key, None).await
+    }
+
+    // Get only the bytes in range, which is inclusive at both ends, by the Range header.
+    async fn get_object_with(
+        &self,
+        key: String,
+        range: Option<(u64, u64)>,
+    ) -> Result<rusoto_s3::GetObjectOutput, aqfs::Error> {
+        let output = self
+            .with_retry(|| {
+                let mut request = rusoto_s3::GetObjectRequest::default();
+                request.bucket = self.bucket.clone();
+                request.key = self.full_key(&key);
+                request.range = range.map(|(first, last)| format!("bytes={}-{}", first, last));
+                self.client.get_object(request)
+            })
+            .await?;
+        self.report_metrics(0, output.content_length.unwrap_or(0) as u64);
+        Ok(output)
     }
 
     async fn put_object(
         &self,
         key: String,
-        body: Option<rusoto_s3::StreamingBody>,
+        body: Vec<u8>,
     ) -> Result<rusoto_s3::PutObjectOutput, aqfs::Error> {
-        let mut request = rusoto_s3::PutObjectRequest::default();
-        request.bucket = self.bucket.clone();
-        request.key = key;
-        request.body = body;
-        Ok(self.client.put_object(request).await?)
+        self.put_object_with(key, body, &PutOptions::default())
+            .await
+    }
+
+    async fn put_object_with(
+        &self,
+        key: String,
+        body: Vec<u8>,
+        options: &PutOptions,
+    ) -> Result<rusoto_s3::PutObjectOutput, aqfs::Error> {
+        validate_key(&self.full_key(&key))?;
+        // The body is consumed by each request, so keep it as Vec<u8> to retry.
+        let output = self
+            .with_retry(|| {
+                let mut request = rusoto_s3::PutObjectRequest::default();
+                request.bucket = self.bucket.clone();
+                request.key = self.full_key(&key);
+                request.body = Some(body.clone().into());
+                request.expires = options
+                    .expires
+                    .map(|t| t.format("%a, %d %b %Y %H:%M:%S GMT").to_string());
+                request.content_type = options.content_type.clone();
+                request.tagging = options.tagging.clone();
+                request.content_md5 = options.content_md5.clone();
+                self.client.put_object(request)
+            })
+            .await
+            .map_err(|err| match err {
+                // rusoto has no dedicated error for BadDigest, so it's told by the message.
+                aqfs::Error::RusotoFail(cause) if cause.to_string().contains("BadDigest") => {
+                    aqfs::Error::ChecksumMismatch
+                }
+                err => err,
+            })?;
+        self.report_metrics(body.len() as u64, 0);
+        Ok(output)
+    }
+
+    // Put the objects, at most put_concurrency of them at a time. On the first failure, the
+    // uploads in progress are dropped, i.e. aborted, and the failure is returned; the objects
+    // already put are left.
+    async fn put_objects(
+        &self,
+        items: Vec<(String, Vec<u8>, PutOptions)>,
+    ) -> Result<(), aqfs::Error> {
+        use futures::stream::{StreamExt, TryStreamExt};
+
+        futures::stream::iter(items)
+            .map(|(key, body, options)| async move {
+                self.put_object_with(key, body, &options).await
+            })
+            .buffer_unordered(self.put_concurrency)
+            .try_for_each(|_| async { Ok(()) })
+            .await
     }
 
+    // List the first page of objects under prefix.
     async fn list_objects_v2(
         &self,
         prefix: String,
     ) -> Result<rusoto_s3::ListObjectsV2Output, aqfs::Error> {
-        let mut request = rusoto_s3::ListObjectsV2Request::default();
+        self.list_objects_v2_page(prefix, None).await
+    }
+
+    async fn list_objects_v2_page(
+        &self,
+        prefix: String,
+        continuation_token: Option<String>,
+    ) -> Result<rusoto_s3::ListObjectsV2Output, aqfs::Error> {
+        let mut output = self
+            .with_retry(|| {
+                let mut request = rusoto_s3::ListObjectsV2Request::default();
+                request.bucket = self.bucket.clone();
+                request.prefix = Some(self.full_key(&prefix));
+                request.continuation_token = continuation_token.clone();
+                request.max_keys = Some(self.list_page_size);
+                self.client.list_objects_v2(request)
+            })
+            .await?;
+        self.report_metrics(0, 0);
+        for object in output.contents.iter_mut().flatten() {
+            if let Some(key) = object.key.as_mut() {
+                if key.starts_with(&self.prefix) {
+                    *key = key[self.prefix.len()..].to_string();
+                }
+            }
+        }
+        Ok(output)
+    }
+
+    // List all objects under prefix following the pages.
+    async fn list_all_objects(
+        &self,
+        prefix: String,
+    ) -> Result<Vec<rusoto_s3::Object>, aqfs::Error> {
+        let mut objects = vec![];
+        let mut continuation_token = None;
+        loop {
+            let output = self
+                .list_objects_v2_page(prefix.clone(), continuation_token)
+                .await?;
+            objects.extend(output.contents.unwrap_or(vec![]));
+            match output.next_continuation_token {
+                Some(token) if output.is_truncated == Some(true) => {
+                    continuation_token = Some(token)
+                }
+                _ => return Ok(objects),
+            }
+        }
+    }
+
+    async fn put_bucket_lifecycle_configuration(
+        &self,
+        configuration: rusoto_s3::BucketLifecycleConfiguration,
+    ) -> Result<(), aqfs::Error> {
+        self.with_retry(|| {
+            let mut request = rusoto_s3::PutBucketLifecycleConfigurationRequest::default();
+            request.bucket = self.bucket.clone();
+            request.lifecycle_configuration = Some(configuration.clone());
+            self.client.put_bucket_lifecycle_configuration(request)
+        })
+        .await
+    }
+
+    async fn head_object(&self, key: String) -> Result<rusoto_s3::HeadObjectOutput, aqfs::Error> {
+        self.with_retry(|| {
+            let mut request = rusoto_s3::HeadObjectRequest::default();
+            request.bucket = self.bucket.clone();
+            request.key = self.full_key(&key);
+            self.client.head_object(request)
+        })
+        .await
+    }
+
+    async fn get_object_tagging(&self, key: String) -> Result<Vec<rusoto_s3::Tag>, aqfs::Error> {
+        let output = self
+            .with_retry(|| {
+                let mut request = rusoto_s3::GetObjectTaggingRequest::default();
+                request.bucket = self.bucket.clone();
+                request.key = self.full_key(&key);
+                self.client.get_object_tagging(request)
+            })
+            .await?;
+        Ok(output.tag_set)
+    }
+
+    // Returns the upload ID.
+    async fn create_multipart_upload(
+        &self,
+        key: String,
+        options: &PutOptions,
+    ) -> Result<String, aqfs::Error> {
+        validate_key(&self.full_key(&key))?;
+        let output = self
+            .with_retry(|| {
+                let mut request = rusoto_s3::CreateMultipartUploadRequest::default();
+                request.bucket = self.bucket.clone();
+                request.key = self.full_key(&key);
+                request.expires = options
+                    .expires
+                    .map(|t| t.format("%a, %d %b %Y %H:%M:%S GMT").to_string());
+                request.content_type = options.content_type.clone();
+                request.tagging = options.tagging.clone();
+                self.client.create_multipart_upload(request)
+            })
+            .await?;
+        output
+            .upload_id
+            .ok_or_else(|| aqfs::Error::Unexpected("No upload ID is returned".to_string()))
+    }
+
+    async fn upload_part(
+        &self,
+        key: String,
+        upload_id: String,
+        part_number: i64,
+        body: Vec<u8>,
+    ) -> Result<rusoto_s3::CompletedPart, aqfs::Error> {
+        let output = self
+            .with_retry(|| {
+                let mut request = rusoto_s3::UploadPartRequest::default();
+                request.bucket = self.bucket.clone();
+                request.key = self.full_key(&key);
+                request.upload_id = upload_id.clone();
+                request.part_number = part_number;
+                request.body = Some(body.clone().into());
+                self.client.upload_part(request)
+            })
+            .await?;
+        self.report_metrics(body.len() as u64, 0);
+        Ok(rusoto_s3::CompletedPart {
+            e_tag: output.e_tag,
+            part_number: Some(part_number),
+        })
+    }
+
+    async fn complete_multipart_upload(
+        &self,
+        key: String,
+        upload_id: String,
+        parts: Vec<rusoto_s3::CompletedPart>,
+    ) -> Result<rusoto_s3::CompleteMultipartUploadOutput, aqfs::Error> {
+        self.with_retry(|| {
+            let mut request = rusoto_s3::CompleteMultipartUploadRequest::default();
+            request.bucket = self.bucket.clone();
+            request.key = self.full_key(&key);
+            request.upload_id = upload_id.clone();
+            request.multipart_upload = Some(rusoto_s3::CompletedMultipartUpload {
+                parts: Some(parts.clone()),
+            });
+            self.client.complete_multipart_upload(request)
+        })
+        .await
+    }
+
+    async fn abort_multipart_upload(
+        &self,
+        key: String,
+        upload_id: String,
+    ) -> Result<rusoto_s3::AbortMultipartUploadOutput, aqfs::Error> {
+        self.with_retry(|| {
+            let mut request = rusoto_s3::AbortMultipartUploadRequest::default();
+            request.bucket = self.bucket.clone();
+            request.key = self.full_key(&key);
+            request.upload_id = upload_id.clone();
+            self.client.abort_multipart_upload(request)
+        })
+        .await
+    }
+
+    // Make a URL to GET key without credentials until expires_in passes.
+    async fn presigned_get_url(
+        &self,
+        key: String,
+        expires_in: Duration,
+    ) -> Result<String, aqfs::Error> {
+        let credentials = self
+            .credentials
+            .credentials()
+            .await
+            .map_err(|e| aqfs::Error::RusotoFail(aqfs::Cause::new(e)))?;
+        let request = rusoto_s3::GetObjectRequest {
+            bucket: self.bucket.clone(),
+            key: self.full_key(&key),
+            ..Default::default()
+        };
+        Ok(request.get_presigned_url(
+            &self.region,
+            &credentials,
+            &PreSignedRequestOption { expires_in },
+        ))
+    }
+
+    // Ok(false) if the bucket doesn't exist. Err(PermissionDenied) if it exists but is not ours.
+    async fn bucket_exists(&self) -> Result<bool, aqfs::Error> {
+        let mut request = rusoto_s3::HeadBucketRequest::default();
         request.bucket = self.bucket.clone();
-        request.prefix = Some(prefix);
-        Ok(self.client.list_objects_v2(request).await?)
+        match self.client.head_bucket(request).await {
+            Ok(()) => Ok(true),
+            Err(RusotoError::Service(rusoto_s3::HeadBucketError::NoSuchBucket(_))) => Ok(false),
+            // The response to HEAD has no body, so the status is all we can tell the error by.
+            Err(RusotoError::Unknown(response)) if response.status.as_u16() == 404 => Ok(false),
+            Err(RusotoError::Unknown(response)) if response.status.as_u16() == 403 => {
+                Err(aqfs::Error::PermissionDenied)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // Whether versioning is enabled on the bucket. A suspended one counts as disabled.
+    async fn is_versioning_enabled(&self) -> Result<bool, aqfs::Error> {
+        let output = self
+            .with_retry(|| {
+                let mut request = rusoto_s3::GetBucketVersioningRequest::default();
+                request.bucket = self.bucket.clone();
+                self.client.get_bucket_versioning(request)
+            })
+            .await?;
+        Ok(output.status.as_deref() == Some("Enabled"))
+    }
+
+    async fn create_bucket(&self) -> Result<(), aqfs::Error> {
+        self.with_retry(|| {
+            let mut request = rusoto_s3::CreateBucketRequest::default();
+            request.bucket = self.bucket.clone();
+            request.create_bucket_configuration = match &self.region {
+                // us-east-1 is the default and must not be specified. S3-compatible services
+                // create buckets in their own region.
+                Region::UsEast1 | Region::Custom { .. } => None,
+                region => Some(rusoto_s3::CreateBucketConfiguration {
+                    location_constraint: Some(region.name().to_string()),
+                }),
+            };
+            self.client.create_bucket(request)
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_object(
+        &self,
+        key: String,
+    ) -> Result<rusoto_s3::DeleteObjectOutput, aqfs::Error> {
+        self.with_retry(|| {
+            let mut request = rusoto_s3::DeleteObjectRequest::default();
+            request.bucket = self.bucket.clone();
+            request.key = self.full_key(&key);
+            self.client.delete_object(request)
+        })
+        .await
     }
 }
 
 pub struct File {
-    client: Rc<RefCell<S3Client>>,
+    client: Arc<S3Client>,
     meta: aqfs::FileMeta,
     key: String,
 }
 
-#[async_trait(?Send)]
+#[async_trait]
 impl aqfs::File for File {
     fn meta(&self) -> &aqfs::FileMeta {
         &self.meta
@@ -80,7 +783,6 @@ impl aqfs::File for File {
     async fn read_all(&mut self) -> Result<Vec<u8>, aqfs::Error> {
         let mut src = Vec::new();
         self.client
-            .borrow()
             .get_object(self.key.clone())
             .await?
             .body
@@ -90,12 +792,64 @@ impl aqfs::File for File {
             .await?;
         Ok(src)
     }
+
+    // Pass S3's streaming body through.
+    async fn read_stream<'a>(&'a mut self) -> Result<aqfs::FileReader<'a>, aqfs::Error> {
+        let body = self
+            .client
+            .get_object(self.key.clone())
+            .await?
+            .body
+            .ok_or_else(|| aqfs::Error::Unexpected("No body is returned".to_string()))?;
+        Ok(Box::pin(body.into_async_read()))
+    }
+
+    async fn read_range(&mut self, offset: u64, len: u64) -> Result<Vec<u8>, aqfs::Error> {
+        // An empty range can't be written in the Range header.
+        if len == 0 {
+            return Ok(vec![]);
+        }
+        let last = offset.saturating_add(len - 1);
+        let output = match self
+            .client
+            .get_object_with(self.key.clone(), Some((offset, last)))
+            .await
+        {
+            Ok(output) => output,
+            // S3 answers 416 only if offset is at or after the end; a range straddling the end
+            // is cut there.
+            Err(aqfs::Error::RusotoFail(cause)) if cause.to_string().contains("InvalidRange") => {
+                return Ok(vec![])
+            }
+            Err(err) => return Err(err),
+        };
+        let mut buf = Vec::new();
+        output
+            .body
+            .ok_or_else(|| aqfs::Error::Unexpected("No body is returned".to_string()))?
+            .into_async_read()
+            .read_to_end(&mut buf)
+            .await?;
+        Ok(buf)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 enum Journal {
     CreateFile { meta: aqfs::FileMeta, key: String },
     RemoveFile { meta: aqfs::FileMeta },
+    // The file at from moves to to, keeping its data object.
+    RenameFile { from: aqfs::Path, to: aqfs::Path },
+}
+
+impl Journal {
+    // The paths that the record changes.
+    fn paths(&self) -> Vec<&aqfs::Path> {
+        match self {
+            Journal::CreateFile { meta, .. } | Journal::RemoveFile { meta } => vec![&meta.path],
+            Journal::RenameFile { from, to } => vec![from, to],
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -111,157 +865,1500 @@ struct JournalFile {
     // FIXME: Add blockchain to detect any branch on the journal.
 }
 
-pub struct Storage {
-    client: Rc<RefCell<S3Client>>,
+// Bincode journal files start with this since FileMeta got size, because bincode can't tell the
+// layouts apart by itself. Those without it are decoded with the older layouts of FileMeta. Read
+// as the number of records of an older file, it would be more than 2^56, so no older file starts
+// with it.
+const BINCODE_JOURNAL_MAGIC: &[u8; 8] = b"aqjnl\x00\x00\x01";
+
+// The layouts of FileMeta in the bincode journal files before BINCODE_JOURNAL_MAGIC, oldest first.
+// Each added a field at the end; presigned_url was never serialized. Path and mtime have always
+// been encoded in the same way in bincode.
+#[derive(Serialize, Deserialize)]
+struct FileMetaV0 {
+    path: aqfs::Path,
+    #[serde(with = "aqfs::mtime_serde")]
+    mtime: DateTime<Utc>,
 }
 
-impl Storage {
-    pub fn new(region: Region, bucket: String) -> Self {
-        Storage {
-            client: Rc::new(RefCell::new(S3Client {
-                client: rusoto_s3::S3Client::new(region),
-                bucket: bucket,
-            })),
+#[derive(Serialize, Deserialize)]
+struct FileMetaV1 {
+    path: aqfs::Path,
+    #[serde(with = "aqfs::mtime_serde")]
+    mtime: DateTime<Utc>,
+    locked: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FileMetaV2 {
+    path: aqfs::Path,
+    #[serde(with = "aqfs::mtime_serde")]
+    mtime: DateTime<Utc>,
+    locked: bool,
+    content_type: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FileMetaV3 {
+    path: aqfs::Path,
+    #[serde(with = "aqfs::mtime_serde")]
+    mtime: DateTime<Utc>,
+    locked: bool,
+    content_type: Option<String>,
+    custom: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FileMetaV4 {
+    path: aqfs::Path,
+    #[serde(with = "aqfs::mtime_serde")]
+    mtime: DateTime<Utc>,
+    locked: bool,
+    content_type: Option<String>,
+    custom: BTreeMap<String, String>,
+    sha256: Option<[u8; 32]>,
+}
+
+impl From<FileMetaV0> for aqfs::FileMeta {
+    fn from(meta: FileMetaV0) -> Self {
+        aqfs::FileMeta {
+            path: meta.path,
+            mtime: meta.mtime,
+            locked: false,
+            content_type: None,
+            presigned_url: None,
+            custom: Default::default(),
+            sha256: None,
+            size: None,
         }
     }
+}
 
-    pub fn default() -> Storage {
-        let region = match env::var("S3_REGION") {
-            Ok(s) => Region::from_str(&s).unwrap(),
-            Err(_) => Region::Custom {
-                name: "s3-asynq".to_string(),
-                endpoint: env::var("S3_ENDPOINT").unwrap_or("http://localhost:9000".to_string()),
-            },
-        };
-        let bucket = env::var("S3_BUCKET").unwrap_or("asynq".to_string());
-        Self::new(region, bucket)
+impl From<FileMetaV1> for aqfs::FileMeta {
+    fn from(meta: FileMetaV1) -> Self {
+        aqfs::FileMeta {
+            locked: meta.locked,
+            ..FileMetaV0 {
+                path: meta.path,
+                mtime: meta.mtime,
+            }
+            .into()
+        }
     }
+}
 
-    // Fetch and parse journal, and construct whole file system.
-    async fn fetch_remote_filesystem(&mut self) -> Result<HashMap<aqfs::Path, File>, aqfs::Error> {
-        // Get list of journal files (objects) from S3.
-        let mut journal_objects = self
-            .client
-            .borrow()
-            .list_objects_v2("journal/".to_string())
-            .await?
-            .contents
-            .unwrap_or(vec![]);
-        // Sort by its name.
-        journal_objects.sort_by_key(|o| o.key.clone().unwrap());
-        // Fetch all journal files from S3 in parallel.
-        let futures = journal_objects
-            .into_iter()
-            .map(|o| async {
-                // Get the object, read it, and parse it into struct JournalFile.
-                let mut src = Vec::new();
-                self.client
-                    .borrow()
-                    .get_object(o.key.unwrap())
-                    .await?
-                    .body
-                    .unwrap()
-                    .into_async_read()
-                    .read_to_end(&mut src)
-                    .await?;
-                Ok::<JournalFile, aqfs::Error>(bincode::deserialize::<JournalFile>(&src[..])?)
-            })
-            .collect::<Vec<_>>();
-        let journal_files: Vec<JournalFile> = futures::future::try_join_all(futures).await?;
-        // Follow the journal and construct whole file system.
-        let mut fs = HashMap::new();
-        for rec in journal_files
-            .into_iter()
-            .flat_map(|j| j.records.into_iter())
-        {
-            match rec.journal {
-                Journal::CreateFile { meta, key } => {
-                    fs.insert(
-                        meta.path.clone(),
-                        File {
-                            meta,
-                            key,
-                            client: Rc::clone(&self.client),
-                        },
-                    );
-                }
-                Journal::RemoveFile { meta } => {
-                    fs.remove(&meta.path);
-                }
+impl From<FileMetaV2> for aqfs::FileMeta {
+    fn from(meta: FileMetaV2) -> Self {
+        aqfs::FileMeta {
+            content_type: meta.content_type,
+            ..FileMetaV1 {
+                path: meta.path,
+                mtime: meta.mtime,
+                locked: meta.locked,
             }
+            .into()
         }
-        Ok(fs)
     }
 }
 
-#[async_trait(?Send)]
-impl aqfs::StorageEntity<File> for Storage {
-    async fn list_files(&mut self) -> Result<Vec<File>, aqfs::Error> {
-        Ok(self
-            .fetch_remote_filesystem()
-            .await?
-            .into_iter()
-            .map(|(_, f)| f)
-            .collect())
+impl From<FileMetaV3> for aqfs::FileMeta {
+    fn from(meta: FileMetaV3) -> Self {
+        aqfs::FileMeta {
+            custom: meta.custom,
+            ..FileMetaV2 {
+                path: meta.path,
+                mtime: meta.mtime,
+                locked: meta.locked,
+                content_type: meta.content_type,
+            }
+            .into()
+        }
     }
+}
 
-    async fn create_file(
-        &mut self,
-        mut file: impl aqfs::File + 'async_trait,
-    ) -> Result<(), aqfs::Error> {
-        // Upload the file's content.
-        let key = format!("data/{}", Uuid::new_v4().to_simple().to_string());
-        self.client
-            .borrow()
-            .put_object(key.clone(), Some((&mut file).read_all().await?.into()))
-            .await?;
-
-        // Create journal and put it to journal/.
-        let timestamp = Utc::now();
-        let journal_key = format!(
-            "journal/{}-{}",
-            timestamp.format("%Y%m%d%H%M%S%f"),
-            Uuid::new_v4().to_simple().to_string()
-        );
-        let meta = file.meta().clone();
-        let journal = bincode::serialize(&JournalFile {
-            records: vec![JournalRecord {
-                timestamp,
-                key: journal_key.clone(),
-                journal: Journal::CreateFile { meta, key },
-            }],
-        })?;
-        self.client
-            .borrow()
-            .put_object(journal_key, Some(journal.into()))
-            .await?;
-        // FIXME: Check if the upload has been done successfully, especially any branch of the journal did not occur.
-
-        Ok(())
+impl From<FileMetaV4> for aqfs::FileMeta {
+    fn from(meta: FileMetaV4) -> Self {
+        aqfs::FileMeta {
+            sha256: meta.sha256,
+            ..FileMetaV3 {
+                path: meta.path,
+                mtime: meta.mtime,
+                locked: meta.locked,
+                content_type: meta.content_type,
+                custom: meta.custom,
+            }
+            .into()
+        }
     }
+}
 
-    async fn remove_file(&mut self, file: &File) -> Result<(), aqfs::Error> {
-        // FIXME: Check if the file exists.
-        let timestamp = Utc::now();
-        let journal_key = format!(
-            "journal/{}-{}",
-            timestamp.format("%Y%m%d%H%M%S%f"),
-            Uuid::new_v4().to_simple().to_string()
-        );
-        let meta = file.meta().clone();
-        let journal = bincode::serialize(&JournalFile {
-            records: vec![JournalRecord {
-                timestamp,
-                key: journal_key.clone(),
-                journal: Journal::RemoveFile { meta },
-            }],
-        })?;
-        self.client
-            .borrow()
-            .put_object(journal_key, Some(journal.into()))
-            .await?;
-        Ok(())
-    }
+// The layouts of JournalFile and its content with one of the older layouts of FileMeta.
+#[derive(Serialize, Deserialize)]
+enum LegacyJournal<M> {
+    CreateFile { meta: M, key: String },
+    RemoveFile { meta: M },
+    RenameFile { from: aqfs::Path, to: aqfs::Path },
+}
+
+#[derive(Serialize, Deserialize)]
+struct LegacyJournalRecord<M> {
+    journal: LegacyJournal<M>,
+    timestamp: DateTime<Utc>,
+    key: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LegacyJournalFile<M> {
+    records: Vec<LegacyJournalRecord<M>>,
+}
+
+impl<M: Into<aqfs::FileMeta>> From<LegacyJournalRecord<M>> for JournalRecord {
+    fn from(rec: LegacyJournalRecord<M>) -> Self {
+        let journal = match rec.journal {
+            LegacyJournal::CreateFile { meta, key } => Journal::CreateFile {
+                meta: meta.into(),
+                key,
+            },
+            LegacyJournal::RemoveFile { meta } => Journal::RemoveFile { meta: meta.into() },
+            LegacyJournal::RenameFile { from, to } => Journal::RenameFile { from, to },
+        };
+        JournalRecord {
+            journal,
+            timestamp: rec.timestamp,
+            key: rec.key,
+        }
+    }
+}
+
+// Decode src as a journal file of the layout M. It must be consumed to the end, which rejects
+// most of the files of the other layouts that happen to be decoded without an error.
+fn deserialize_legacy_journal<M>(src: &[u8]) -> Result<Vec<JournalRecord>, aqfs::Error>
+where
+    M: serde::de::DeserializeOwned + Into<aqfs::FileMeta>,
+{
+    let mut reader = std::io::Cursor::new(src);
+    let file: LegacyJournalFile<M> = bincode::deserialize_from(&mut reader)?;
+    if reader.position() != src.len() as u64 {
+        return Err(aqfs::Error::Unexpected(
+            "Trailing bytes after journal".to_string(),
+        ));
+    }
+    Ok(file.records.into_iter().map(JournalRecord::from).collect())
+}
+
+fn serialize_bincode_journal(records: Vec<JournalRecord>) -> Result<Vec<u8>, aqfs::Error> {
+    let mut body = BINCODE_JOURNAL_MAGIC.to_vec();
+    bincode::serialize_into(&mut body, &JournalFile { records })?;
+    Ok(body)
+}
+
+fn deserialize_bincode_journal(src: &[u8]) -> Result<Vec<JournalRecord>, aqfs::Error> {
+    if src.starts_with(BINCODE_JOURNAL_MAGIC) {
+        let src = &src[BINCODE_JOURNAL_MAGIC.len()..];
+        return Ok(bincode::deserialize::<JournalFile>(src)?.records);
+    }
+    // Try the older layouts, newest first, since most of the files are of the newer ones.
+    let err = match deserialize_legacy_journal::<FileMetaV4>(src) {
+        Ok(records) => return Ok(records),
+        Err(err) => err,
+    };
+    let older: [fn(&[u8]) -> Result<Vec<JournalRecord>, aqfs::Error>; 4] = [
+        deserialize_legacy_journal::<FileMetaV3>,
+        deserialize_legacy_journal::<FileMetaV2>,
+        deserialize_legacy_journal::<FileMetaV1>,
+        deserialize_legacy_journal::<FileMetaV0>,
+    ];
+    for deserialize in older.iter() {
+        if let Ok(records) = deserialize(src) {
+            return Ok(records);
+        }
+    }
+    Err(err)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BucketPermissions {
+    pub can_list: bool,
+    pub can_read: bool,
+    pub can_write: bool,
+    pub can_delete: bool,
+}
+
+// How journal files are serialized. Readers tell the format of each journal file by its content
+// type, so a bucket can have journal files of both formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalFormat {
+    // A bincode-serialized JournalFile after BINCODE_JOURNAL_MAGIC.
+    Bincode,
+    // A JSON JournalRecord per line, which is parsed while being downloaded, so that only one
+    // record is in memory at a time. Encrypted ones have to be downloaded as a whole, though.
+    Ndjson,
+}
+
+impl Default for JournalFormat {
+    fn default() -> Self {
+        JournalFormat::Bincode
+    }
+}
+
+fn parse_ndjson_record(line: &str) -> Result<JournalRecord, aqfs::Error> {
+    serde_json::from_str(line).map_err(|e| aqfs::Error::SerdeFail(aqfs::Cause::new(e)))
+}
+
+fn apply_journal(state: &mut JournalState, journal: Journal) {
+    match journal {
+        Journal::CreateFile { meta, key } => {
+            state.insert(meta.path.clone(), (meta, key));
+        }
+        Journal::RemoveFile { meta } => {
+            state.remove(&meta.path);
+        }
+        Journal::RenameFile { from, to } => {
+            if let Some((mut meta, key)) = state.remove(&from) {
+                meta.path = to.clone();
+                state.insert(to, (meta, key));
+            }
+        }
+    }
+}
+
+// How journal files are stored. Encrypted journals hide the paths and other metadata of files,
+// though not their number, sizes, or the time they are written.
+#[derive(Clone)]
+pub enum JournalEncryption {
+    None,
+    AesGcm { key: [u8; 32] },
+}
+
+// The result of Storage::compact_and_rekey.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RekeyReport {
+    // The live files, now journaled under the new key.
+    pub rekeyed: Vec<aqfs::Path>,
+    // The old journal files that couldn't be deleted, which compact_and_rekey should be run
+    // again for.
+    pub failures: Vec<(String, aqfs::Error)>,
+}
+
+// Where to get the credentials from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CredentialSource {
+    // Environment variables, the profile file, and the instance metadata, in this order.
+    Default,
+    Static {
+        access_key: String,
+        secret_key: String,
+    },
+    // A named profile in the profile file.
+    Profile(String),
+}
+
+impl Default for CredentialSource {
+    fn default() -> Self {
+        CredentialSource::Default
+    }
+}
+
+// TLS settings for S3-compatible services with certificates not signed by a public CA.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TlsConfig {
+    // PEM-encoded certificates trusted in addition to the system's roots.
+    pub ca_certs: Vec<Vec<u8>>,
+    pub insecure_skip_verify: bool,
+}
+
+// Configuration of s3::Storage independent of the S3 client library.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct S3Config {
+    // The endpoint of an S3-compatible service, e.g. "http://localhost:9000" for MinIO.
+    // If None, the AWS endpoint of region is used.
+    pub endpoint: Option<String>,
+    pub region: String,
+    pub bucket: String,
+    // Store everything under this prefix, e.g. "asynq/", instead of the root of the bucket.
+    pub prefix: Option<String>,
+    pub credentials: CredentialSource,
+    pub tls: TlsConfig,
+}
+
+impl S3Config {
+    // Trust the PEM-encoded certificate pem, e.g. of a corporate CA, in addition to the system's
+    // roots.
+    pub fn with_tls_ca_cert(mut self, pem: Vec<u8>) -> Self {
+        self.tls.ca_certs.push(pem);
+        self
+    }
+
+    // WARNING: Accept ANY certificate, which makes the connection open to man-in-the-middle
+    // attacks. Never use this except against a local development server with a self-signed
+    // certificate; prefer with_tls_ca_cert.
+    pub fn with_tls_insecure_skip_verify(mut self, skip: bool) -> Self {
+        self.tls.insecure_skip_verify = skip;
+        self
+    }
+}
+
+// The client is shared by Storage and its Files, which issue requests concurrently. It has no
+// lock, so that Storage and File are Send; the configuration methods of Storage clone it if a File
+// still holds it, and that File keeps the old configuration.
+pub struct Storage {
+    client: Arc<S3Client>,
+    data_expires_header: Option<chrono::Duration>,
+    // Clients of the replica buckets and how long to wait for the replication.
+    replicas: Vec<S3Client>,
+    replication_timeout: Duration,
+    // The ETag of the journal sentinel and the state of the file system at that time.
+    journal_cache: Option<(String, JournalState)>,
+    // How long the presigned URLs returned by create_file are valid.
+    presign_expires_in: Option<Duration>,
+    max_journal_records: usize,
+    journal_encryption: JournalEncryption,
+    // Keys tried in turn when the key of journal_encryption can't decrypt a journal file.
+    retired_journal_keys: Vec<[u8; 32]>,
+    journal_format: JournalFormat,
+    // Set by the SQS poller of with_sqs_invalidation when the journal may have changed.
+    journal_invalidated: Option<Arc<AtomicBool>>,
+    // Whether the bucket is expected to be versioned, and whether verify_configuration fails
+    // rather than warns if it's not as expected.
+    versioning: bool,
+    strict_verification: bool,
+    upload_checksum: bool,
+    data_key_prefix_fn: Option<Arc<dyn Fn(&aqfs::FileMeta) -> String + Send + Sync>>,
+}
+
+// Path to the meta and the data object key of each live file.
+type JournalState = HashMap<aqfs::Path, (aqfs::FileMeta, String)>;
+
+// Err(PermissionDenied) if a locked file is at path in state, which mustn't be overwritten.
+fn check_unlocked(state: &JournalState, path: &aqfs::Path) -> Result<(), aqfs::Error> {
+    match state.get(path) {
+        Some((meta, _)) if meta.locked => Err(aqfs::Error::PermissionDenied),
+        _ => Ok(()),
+    }
+}
+
+impl Storage {
+    // A storage in bucket of the AWS region named region, with the default credentials.
+    pub fn new(region: String, bucket: String) -> Result<Self, aqfs::Error> {
+        Self::from_config(S3Config {
+            endpoint: None,
+            region,
+            bucket,
+            prefix: None,
+            credentials: CredentialSource::Default,
+            tls: TlsConfig::default(),
+        })
+    }
+
+    fn with_client(client: S3Client) -> Self {
+        Storage {
+            client: Arc::new(client),
+            data_expires_header: None,
+            replicas: vec![],
+            replication_timeout: Duration::from_secs(0),
+            journal_cache: None,
+            presign_expires_in: None,
+            max_journal_records: DEFAULT_MAX_JOURNAL_RECORDS,
+            journal_encryption: JournalEncryption::None,
+            retired_journal_keys: vec![],
+            journal_format: JournalFormat::default(),
+            journal_invalidated: None,
+            versioning: false,
+            strict_verification: false,
+            upload_checksum: false,
+            data_key_prefix_fn: None,
+        }
+    }
+
+    // Wait for each journal file to be replicated to the buckets in the other regions before
+    // returning, so that readers in those regions see the write. S3 cross-region replication is
+    // asynchronous, and bucket names are global, so each replica is a pair of region name and
+    // bucket. The replicas are accessed with the same credentials, TLS settings, and endpoint.
+    pub fn with_replication_wait(
+        mut self,
+        replicas: Vec<(String, String)>,
+        timeout: Duration,
+    ) -> Result<Self, aqfs::Error> {
+        self.replicas = replicas
+            .into_iter()
+            .map(|(region, bucket)| self.client.for_region(&region, bucket))
+            .collect::<Result<_, _>>()?;
+        self.replication_timeout = timeout;
+        Ok(self)
+    }
+
+    // Poll HeadObject on each replica until key appears.
+    async fn wait_for_replication(&self, key: &str) -> Result<(), aqfs::Error> {
+        let deadline = tokio::time::Instant::now() + self.replication_timeout;
+        for replica in self.replicas.iter() {
+            while replica.head_object(key.to_string()).await.is_err() {
+                if tokio::time::Instant::now() >= deadline {
+                    return Err(aqfs::Error::ReplicationTimeout {
+                        region: replica.region.name().to_string(),
+                    });
+                }
+                tokio::time::delay_for(REPLICATION_POLL_INTERVAL).await;
+            }
+        }
+        Ok(())
+    }
+
+    // Make create_file return a presigned URL of the uploaded content in FileMeta::presigned_url,
+    // valid for expires_in, so that it can be shared with those who have no credentials.
+    pub fn with_presign_created_files(mut self, expires_in: Duration) -> Self {
+        self.presign_expires_in = Some(expires_in);
+        self
+    }
+
+    // Encrypt journal files written from now on. Plaintext journal files already in the bucket
+    // are still read, as they are told by their content type.
+    pub fn with_journal_encryption(mut self, encryption: JournalEncryption) -> Self {
+        self.journal_encryption = encryption;
+        self
+    }
+
+    // Send the MD5 digest of each data object with it, so that S3 rejects a content corrupted in
+    // transit, which create_file returns as Error::ChecksumMismatch.
+    // FIXME: Check the parts of multipart uploads by Sink too.
+    pub fn with_upload_checksum(mut self, enabled: bool) -> Self {
+        self.upload_checksum = enabled;
+        self
+    }
+
+    // Put each data object under the prefix f returns for its metadata instead of "data/", e.g.
+    // "data/video/" for videos, so that lifecycle and tiering rules can be configured by prefix.
+    // The content type is detected before f is called, except for open_sink. The prefixes should
+    // be under "data/" to be covered by set_data_lifecycle.
+    pub fn with_data_key_prefix_fn(
+        mut self,
+        f: impl Fn(&aqfs::FileMeta) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.data_key_prefix_fn = Some(Arc::new(f));
+        self
+    }
+
+    // Also read the journal files encrypted under key, e.g. while another process is running
+    // compact_and_rekey from key.
+    pub fn with_retired_journal_key(mut self, key: [u8; 32]) -> Self {
+        self.retired_journal_keys.push(key);
+        self
+    }
+
+    // Write journal files in format from now on. Journal files already in the bucket are still
+    // read in their own formats.
+    pub fn with_journal_format(mut self, format: JournalFormat) -> Self {
+        self.journal_format = format;
+        self
+    }
+
+    // Split a journal write into files of at most n records, e.g. in batch_create. 0 is taken as
+    // 1, since a journal file must have a record.
+    pub fn with_max_journal_records(mut self, n: usize) -> Self {
+        self.max_journal_records = n.max(1);
+        self
+    }
+
+    // Trust the journal cache until S3 event notifications delivered to the SQS queue at queue_url
+    // say the journal has changed, instead of checking the sentinel on every listing.
+    // The bucket must be configured to send s3:ObjectCreated:* and s3:ObjectRemoved:* events of
+    // journal/ to the queue, which should be dedicated to this storage since every message in it
+    // is consumed. The queue is polled by a task spawned here, so this must be called within a
+    // tokio runtime. The task ends once the storage is dropped.
+    pub fn with_sqs_invalidation(
+        mut self,
+        queue_url: String,
+        region: String,
+    ) -> Result<Self, aqfs::Error> {
+        let client = rusoto_sqs::SqsClient::new_with(
+            http_client(&self.client.tls)?,
+            self.client.credentials.clone(),
+            region_from_name(&region, None)?,
+        );
+        let invalidated = Arc::new(AtomicBool::new(true));
+        let weak = Arc::downgrade(&invalidated);
+        let journal_prefix = self.client.full_key("journal/");
+        tokio::spawn(async move {
+            while let Some(invalidated) = weak.upgrade() {
+                if let Err(err) =
+                    poll_journal_events(&client, &queue_url, &journal_prefix, &invalidated).await
+                {
+                    tracing::warn!("Can't poll {}: {}", queue_url, err);
+                    // Events may be lost meanwhile.
+                    invalidated.store(true, Ordering::SeqCst);
+                    drop(invalidated);
+                    tokio::time::delay_for(SQS_RETRY_DELAY).await;
+                }
+            }
+        });
+        self.journal_invalidated = Some(invalidated);
+        Ok(self)
+    }
+
+    // Set the Expires header of data objects to ttl after their upload, which tells HTTP caches,
+    // e.g. those in front of presigned URLs, how long they may serve the content. S3 doesn't delete
+    // objects by it; use set_data_lifecycle for that.
+    pub fn with_data_expires_header(mut self, ttl: chrono::Duration) -> Self {
+        self.data_expires_header = Some(ttl);
+        self
+    }
+
+    // Configure the bucket's lifecycle rule to delete data objects days after their creation.
+    // NOTE: This replaces the whole lifecycle configuration of the bucket.
+    // NOTE: Data objects deleted by the rule while their journal entries remain cause errors in
+    // read_all.
+    pub async fn set_data_lifecycle(&self, days: u32) -> Result<(), aqfs::Error> {
+        let client = &self.client;
+        let rule = rusoto_s3::LifecycleRule {
+            id: Some("asynq-data-expiration".to_string()),
+            status: "Enabled".to_string(),
+            filter: Some(rusoto_s3::LifecycleRuleFilter {
+                prefix: Some(client.full_key("data/")),
+                ..Default::default()
+            }),
+            expiration: Some(rusoto_s3::LifecycleExpiration {
+                days: Some(days as i64),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        client
+            .put_bucket_lifecycle_configuration(rusoto_s3::BucketLifecycleConfiguration {
+                rules: vec![rule],
+            })
+            .await
+    }
+
+    // Store everything under prefix, e.g. "asynq/", instead of the root of the bucket.
+    // The replicas set by with_replication_wait use the same prefix.
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        Arc::make_mut(&mut self.client).prefix = prefix.to_string();
+        for replica in self.replicas.iter_mut() {
+            replica.prefix = prefix.to_string();
+        }
+        self
+    }
+
+    // Set how many data objects batch_create uploads at a time. Defaults to 20. 0 is taken as 1.
+    pub fn with_put_concurrency(mut self, n: usize) -> Self {
+        Arc::make_mut(&mut self.client).put_concurrency = n.max(1);
+        self
+    }
+
+    // Set how many times a request is retried when S3 returns SlowDown.
+    pub fn with_slowdown_retries(mut self, n: u32) -> Self {
+        Arc::make_mut(&mut self.client).slowdown_retries = n;
+        self
+    }
+
+    // Emit AsynqBytesUploaded, AsynqBytesDownloaded, and AsynqOperationCount to CloudWatch in
+    // namespace after each GetObject, PutObject, and ListObjectsV2.
+    pub fn with_cloudwatch_metrics(
+        self,
+        namespace: String,
+        region: String,
+    ) -> Result<Self, aqfs::Error> {
+        let client = rusoto_cloudwatch::CloudWatchClient::new_with(
+            http_client(&self.client.tls)?,
+            self.client.credentials.clone(),
+            region_from_name(&region, None)?,
+        );
+        Ok(self.with_metrics_sink(Arc::new(CloudWatchSink { client, namespace })))
+    }
+
+    fn with_metrics_sink(mut self, sink: Arc<dyn MetricsSink>) -> Self {
+        Arc::make_mut(&mut self.client).metrics = Some(sink);
+        self
+    }
+
+    pub async fn bucket_exists(&self) -> Result<bool, aqfs::Error> {
+        self.client.bucket_exists().await
+    }
+
+    // Declare that the bucket is versioned. In a versioned bucket, remove_file leaves delete
+    // markers and the removed contents stay, and are charged for, as noncurrent versions.
+    // Defaults to false. Checked by verify_configuration.
+    pub fn with_versioning(mut self, versioning: bool) -> Self {
+        self.versioning = versioning;
+        self
+    }
+
+    // Make verify_configuration fail instead of warning on a mismatch.
+    pub fn with_strict_verification(mut self, strict: bool) -> Self {
+        self.strict_verification = strict;
+        self
+    }
+
+    // Check that the bucket is as configured. A mismatch is warned about, or an
+    // Err(Error::Config) in strict mode.
+    pub async fn verify_configuration(&self) -> Result<(), aqfs::Error> {
+        let versioned = self.client.is_versioning_enabled().await?;
+        if versioned == self.versioning {
+            return Ok(());
+        }
+        let msg = if versioned {
+            "the bucket is versioned, but with_versioning(false) is set; removed files are kept \
+             as noncurrent versions"
+        } else {
+            "with_versioning(true) is set, but the bucket is not versioned; removed files are \
+             gone for good"
+        };
+        if self.strict_verification {
+            return Err(aqfs::Error::Config(msg.to_string()));
+        }
+        tracing::warn!("{}", msg);
+        Ok(())
+    }
+
+    // Create the bucket unless it exists. Returns whether it's created.
+    pub async fn create_bucket_if_missing(&self) -> Result<bool, aqfs::Error> {
+        let client = &self.client;
+        if client.bucket_exists().await? {
+            return Ok(false);
+        }
+        client.create_bucket().await?;
+        Ok(true)
+    }
+
+    // Check what we can do on the bucket before starting a long operation, e.g., sync.
+    // This test-writes a small sentinel object, reads it, and deletes it.
+    pub async fn check_permissions(&self) -> Result<BucketPermissions, aqfs::Error> {
+        let client = &self.client;
+        let listed = client.list_objects_v2("journal/".to_string()).await;
+        let can_list = listed.is_ok();
+        let sentinel_key = format!("permission-check/{}", Uuid::new_v4().to_simple());
+        let can_write = client
+            .put_object(sentinel_key.clone(), b"asynq".to_vec())
+            .await
+            .is_ok();
+        let can_read = if can_write {
+            client.get_object(sentinel_key.clone()).await.is_ok()
+        } else {
+            // We have no sentinel to read, so try an existing journal object if any.
+            match listed
+                .ok()
+                .and_then(|o| o.contents)
+                .and_then(|c| c.into_iter().next())
+                .and_then(|o| o.key)
+            {
+                Some(key) => client.get_object(key).await.is_ok(),
+                None => false,
+            }
+        };
+        let can_delete = can_write && client.delete_object(sentinel_key).await.is_ok();
+        Ok(BucketPermissions {
+            can_list,
+            can_read,
+            can_write,
+            can_delete,
+        })
+    }
+
+    pub fn from_config(config: S3Config) -> Result<Self, aqfs::Error> {
+        let region = region_from_name(&config.region, config.endpoint.clone())?;
+        let http = http_client(&config.tls)?;
+        let credentials = match config.credentials {
+            CredentialSource::Default => SharedCredentials::new(
+                DefaultCredentialsProvider::new()
+                    .map_err(|e| aqfs::Error::Config(e.to_string()))?,
+            ),
+            CredentialSource::Static {
+                access_key,
+                secret_key,
+            } => SharedCredentials::new(StaticProvider::new_minimal(access_key, secret_key)),
+            CredentialSource::Profile(profile) => {
+                let mut provider =
+                    ProfileProvider::new().map_err(|e| aqfs::Error::Config(e.to_string()))?;
+                provider.set_profile(profile);
+                SharedCredentials::new(provider)
+            }
+        };
+        let client = rusoto_s3::S3Client::new_with(http, credentials.clone(), region.clone());
+        let mut client = S3Client::with_client(client, credentials, region, config.bucket);
+        client.prefix = config.prefix.unwrap_or_default();
+        client.tls = config.tls;
+        client.endpoint = config.endpoint;
+        Ok(Self::with_client(client))
+    }
+
+    // Configure by the environment variables S3_REGION, S3_ENDPOINT, S3_BUCKET, and S3_PREFIX.
+    // Without S3_REGION, a MinIO at S3_ENDPOINT or localhost:9000 is assumed.
+    pub fn from_env() -> Result<Self, aqfs::Error> {
+        let config = S3Config {
+            endpoint: match env::var("S3_REGION") {
+                Ok(_) => env::var("S3_ENDPOINT").ok(),
+                Err(_) => {
+                    Some(env::var("S3_ENDPOINT").unwrap_or("http://localhost:9000".to_string()))
+                }
+            },
+            region: env::var("S3_REGION").unwrap_or("s3-asynq".to_string()),
+            bucket: env::var("S3_BUCKET").unwrap_or("asynq".to_string()),
+            prefix: env::var("S3_PREFIX").ok(),
+            credentials: CredentialSource::Default,
+            tls: TlsConfig::default(),
+        };
+        Self::from_config(config)
+    }
+
+    // List files as they were at the time at by replaying only the journal records until then.
+    pub async fn list_files_at(&mut self, at: DateTime<Utc>) -> Result<Vec<File>, aqfs::Error> {
+        Ok(self
+            .fetch_remote_filesystem_at(Some(at))
+            .await?
+            .into_iter()
+            .map(|(_, f)| f)
+            .collect())
+    }
+
+    async fn fetch_remote_filesystem(&mut self) -> Result<HashMap<aqfs::Path, File>, aqfs::Error> {
+        self.fetch_remote_filesystem_at(None).await
+    }
+
+    // Fetch and parse journal, and construct whole file system.
+    // If at is specified, records newer than it are ignored.
+    async fn fetch_remote_filesystem_at(
+        &mut self,
+        at: Option<DateTime<Utc>>,
+    ) -> Result<HashMap<aqfs::Path, File>, aqfs::Error> {
+        let state = match at {
+            Some(_) => self.replay_journal(at).await?,
+            None => self.replay_journal_cached().await?,
+        };
+        Ok(state
+            .into_iter()
+            .map(|(path, (meta, key))| {
+                (
+                    path,
+                    File {
+                        meta,
+                        key,
+                        client: Arc::clone(&self.client),
+                    },
+                )
+            })
+            .collect())
+    }
+
+    // Replay the whole journal unless it's unchanged since the last replay, which is told by the
+    // ETag of the sentinel. Without the sentinel, e.g., in a bucket written by an older version,
+    // the journal is always replayed.
+    async fn replay_journal_cached(&mut self) -> Result<JournalState, aqfs::Error> {
+        if let Some(invalidated) = &self.journal_invalidated {
+            // Reset before the replay, so that an event during it is caught next time.
+            let stale = invalidated.swap(false, Ordering::SeqCst);
+            if let (false, Some((_, state))) = (stale, &self.journal_cache) {
+                return Ok(state.clone());
+            }
+        }
+        let etag = self
+            .client
+            .head_object(JOURNAL_SENTINEL_KEY.to_string())
+            .await
+            .ok()
+            .and_then(|o| o.e_tag);
+        if let (Some(etag), Some((cached_etag, state))) = (&etag, &self.journal_cache) {
+            if etag == cached_etag {
+                return Ok(state.clone());
+            }
+        }
+        // The ETag is taken before the replay, so a write during it is caught next time.
+        let state = self.replay_journal(None).await?;
+        self.journal_cache = etag.map(|etag| (etag, state.clone()));
+        Ok(state)
+    }
+
+    async fn replay_journal(&self, at: Option<DateTime<Utc>>) -> Result<JournalState, aqfs::Error> {
+        // Get list of journal files (objects) from S3. Only their keys are kept in memory.
+        let mut journal_objects: Vec<rusoto_s3::Object> = self
+            .client
+            .list_all_objects("journal/".to_string())
+            .await?
+            .into_iter()
+            .filter(|o| o.key.as_deref() != Some(JOURNAL_SENTINEL_KEY))
+            .collect();
+        sort_journal_objects(&mut journal_objects);
+        // Follow the journal and construct whole file system.
+        // Journal files are fetched and applied one by one, so that only one of them is in memory
+        // at a time no matter how large the whole journal is.
+        let mut state = HashMap::new();
+        for o in journal_objects {
+            self.apply_journal_file(o.key.unwrap(), at, &mut state)
+                .await?;
+        }
+        Ok(state)
+    }
+
+    // Get the journal file at key and apply its records written until at to state.
+    async fn apply_journal_file(
+        &self,
+        key: String,
+        at: Option<DateTime<Utc>>,
+        state: &mut JournalState,
+    ) -> Result<(), aqfs::Error> {
+        use futures::stream::StreamExt;
+
+        let output = self.client.get_object(key).await?;
+        let content_type = output.content_type.unwrap_or_default();
+        let mut body = output
+            .body
+            .ok_or_else(|| aqfs::Error::Unexpected("No body is returned".to_string()))?
+            .into_async_read();
+        let mut apply = |rec: JournalRecord| {
+            if at.map_or(true, |at| rec.timestamp <= at) {
+                apply_journal(state, rec.journal);
+            }
+        };
+        if content_type == NDJSON_JOURNAL_CONTENT_TYPE {
+            let mut lines = tokio::io::BufReader::new(body).lines();
+            while let Some(line) = lines.next().await {
+                let line = line?;
+                if !line.is_empty() {
+                    apply(parse_ndjson_record(&line)?);
+                }
+            }
+            return Ok(());
+        }
+
+        let mut src = Vec::new();
+        body.read_to_end(&mut src).await?;
+        let encrypted = content_type == ENCRYPTED_JOURNAL_CONTENT_TYPE
+            || content_type == ENCRYPTED_NDJSON_JOURNAL_CONTENT_TYPE;
+        if encrypted {
+            src = self.decrypt_journal_file(&src)?;
+        }
+        if content_type == ENCRYPTED_NDJSON_JOURNAL_CONTENT_TYPE {
+            let src = std::str::from_utf8(&src)
+                .map_err(|e| aqfs::Error::SerdeFail(aqfs::Cause::new(e)))?;
+            for line in src.lines().filter(|line| !line.is_empty()) {
+                apply(parse_ndjson_record(line)?);
+            }
+        } else {
+            for rec in deserialize_bincode_journal(&src)? {
+                apply(rec);
+            }
+        }
+        Ok(())
+    }
+
+    // Decrypt a journal file with the current key or, failing that, with the retired ones.
+    fn decrypt_journal_file(&self, data: &[u8]) -> Result<Vec<u8>, aqfs::Error> {
+        let mut res = match &self.journal_encryption {
+            JournalEncryption::AesGcm { key } => decrypt_journal(key, data),
+            JournalEncryption::None => Err(aqfs::Error::CryptoFail(
+                "Journal is encrypted but no key is given".to_string(),
+            )),
+        };
+        for key in self.retired_journal_keys.iter() {
+            if res.is_ok() {
+                break;
+            }
+            res = decrypt_journal(key, data);
+        }
+        res
+    }
+
+    fn journal_content_type(&self) -> Option<String> {
+        let encrypted = match self.journal_encryption {
+            JournalEncryption::None => false,
+            JournalEncryption::AesGcm { .. } => true,
+        };
+        let content_type = match (self.journal_format, encrypted) {
+            (JournalFormat::Bincode, false) => return None,
+            (JournalFormat::Bincode, true) => ENCRYPTED_JOURNAL_CONTENT_TYPE,
+            (JournalFormat::Ndjson, false) => NDJSON_JOURNAL_CONTENT_TYPE,
+            (JournalFormat::Ndjson, true) => ENCRYPTED_NDJSON_JOURNAL_CONTENT_TYPE,
+        };
+        Some(content_type.to_string())
+    }
+
+    fn serialize_journal(&self, records: Vec<JournalRecord>) -> Result<Vec<u8>, aqfs::Error> {
+        match self.journal_format {
+            JournalFormat::Bincode => serialize_bincode_journal(records),
+            JournalFormat::Ndjson => {
+                let mut body = vec![];
+                for rec in records.iter() {
+                    serde_json::to_writer(&mut body, rec)
+                        .map_err(|e| aqfs::Error::SerdeFail(aqfs::Cause::new(e)))?;
+                    body.push(b'\n');
+                }
+                Ok(body)
+            }
+        }
+    }
+
+    // Options to upload the content of meta, whose head is data.
+    // Store the detected content type in meta too so that it goes to the journal.
+    // So does the SHA-256 digest of data, which Sink replaces for a multipart upload.
+    fn put_options(&self, meta: &mut aqfs::FileMeta, data: &[u8]) -> PutOptions {
+        if meta.content_type.is_none() {
+            meta.content_type = Some(detect_content_type(data));
+        }
+        // Record the digest as written, not the one of the source.
+        meta.sha256 = Some(aqfs::sha256(data));
+        let content_md5 = if self.upload_checksum {
+            Some(base64::encode(md5::compute(data).0))
+        } else {
+            None
+        };
+        PutOptions {
+            expires: self.data_expires_header.map(|ttl| Utc::now() + ttl),
+            content_type: meta.content_type.clone(),
+            tagging: Some(DATA_TAGGING.to_string()),
+            content_md5,
+        }
+    }
+
+    // A new key of the data object of meta.
+    fn data_key(&self, meta: &aqfs::FileMeta) -> String {
+        let prefix = match &self.data_key_prefix_fn {
+            Some(f) => f(meta),
+            None => "data/".to_string(),
+        };
+        format!("{}{}", prefix, Uuid::new_v4().to_simple().to_string())
+    }
+
+    // Open a file to write incrementally. The content is uploaded by multipart upload in
+    // PART_SIZE parts, and the file is journaled on close. Overwriting a locked file fails on
+    // close, before the upload is completed, since the journal can't be read here.
+    pub fn open_sink(&self, meta: aqfs::FileMeta) -> Result<Sink<'_>, aqfs::Error> {
+        validate_key(&meta.path.to_string())?;
+        Ok(Sink {
+            storage: self,
+            key: self.data_key(&meta),
+            meta,
+            upload_id: None,
+            parts: vec![],
+            buffer: vec![],
+            hasher: sha2::Sha256::new(),
+            size: 0,
+        })
+    }
+
+    // Create files with a single journal write, which is much faster than create_file for many
+    // files. The paths must be distinct, or Error::Unexpected is returned before anything is
+    // uploaded; see put_journals.
+    // The data objects are uploaded in parallel (see with_put_concurrency), and the journal is
+    // written only after all of them are uploaded. If any upload fails, no journal is written and
+    // the data objects are deleted on a best-effort basis. The contents are all read into memory
+    // first.
+    pub async fn batch_create<F: aqfs::File>(
+        &mut self,
+        files: Vec<F>,
+    ) -> Result<Vec<aqfs::FileMeta>, aqfs::Error> {
+        {
+            let mut paths = HashSet::new();
+            for file in files.iter() {
+                if !paths.insert(&file.meta().path) {
+                    return Err(aqfs::Error::Unexpected(format!(
+                        "{} is given twice",
+                        file.meta().path
+                    )));
+                }
+            }
+        }
+        let mut journals = vec![];
+        let mut metas = vec![];
+        let mut objects = vec![];
+        for mut file in files {
+            validate_key(&file.meta().path.to_string())?;
+            let data = file.read_all().await?;
+            let mut meta = file.meta().clone();
+            meta.size = Some(data.len() as u64);
+            let options = self.put_options(&mut meta, &data);
+            let key = self.data_key(&meta);
+            objects.push((key.clone(), data, options));
+            journals.push(Journal::CreateFile {
+                meta: meta.clone(),
+                key,
+            });
+            metas.push(meta);
+        }
+        let keys: Vec<String> = objects.iter().map(|(key, _, _)| key.clone()).collect();
+        if let Err(err) = self.client.put_objects(objects).await {
+            // Some uploads may have finished, and the aborted ones may still land, so delete them
+            // all. Deleting a missing key succeeds.
+            let client = &self.client;
+            let _ =
+                futures::future::join_all(keys.into_iter().map(|key| client.delete_object(key)))
+                    .await;
+            return Err(err);
+        }
+        self.put_journals(journals).await?;
+        Ok(metas)
+    }
+
+    // Rotate the journal key from old_key to new_key. The live files are written to new journal
+    // files encrypted under new_key, and then the old journal files are deleted, so the history
+    // for list_files_at is lost. The storage uses new_key afterwards.
+    // Interrupting this is safe: the new journal files are newer than the old ones and replay to
+    // the same state, and running it again with the same keys reads both and finishes the job.
+    // Data objects aren't encrypted, so they are left as they are.
+    // FIXME: Journals written by others during this may be overridden by the new journal files.
+    pub async fn compact_and_rekey(
+        &mut self,
+        old_key: [u8; 32],
+        new_key: [u8; 32],
+    ) -> Result<RekeyReport, aqfs::Error> {
+        self.journal_encryption = JournalEncryption::AesGcm { key: new_key };
+        if !self.retired_journal_keys.contains(&old_key) {
+            self.retired_journal_keys.push(old_key);
+        }
+        let old_journal_keys: Vec<String> = self
+            .client
+            .list_all_objects("journal/".to_string())
+            .await?
+            .into_iter()
+            .filter_map(|o| o.key)
+            .filter(|key| key != JOURNAL_SENTINEL_KEY)
+            .collect();
+        let state = self.replay_journal(None).await?;
+        let mut report = RekeyReport::default();
+        let mut journals = vec![];
+        for (path, (meta, key)) in state.into_iter() {
+            report.rekeyed.push(path);
+            journals.push(Journal::CreateFile { meta, key });
+        }
+        report.rekeyed.sort();
+        self.put_journals(journals).await?;
+        {
+            let client = &self.client;
+            for key in old_journal_keys {
+                if let Err(err) = client.delete_object(key.clone()).await {
+                    report.failures.push((key, err));
+                }
+            }
+        }
+        self.journal_cache = None;
+        Ok(report)
+    }
+
+    // Create a journal file consisting of a single record and put it to journal/.
+    async fn put_journal(&self, journal: Journal) -> Result<(), aqfs::Error> {
+        self.put_journals(vec![journal]).await
+    }
+
+    // Put journals to journal/, split into files of at most max_journal_records records so that
+    // each of them can be read without much memory. The files are put in parallel and have the
+    // same timestamp, so the order among them is lost; journals must not touch a path twice, or
+    // Error::Unexpected is returned before anything is put.
+    async fn put_journals(&self, journals: Vec<Journal>) -> Result<(), aqfs::Error> {
+        let mut paths = HashSet::new();
+        for path in journals.iter().flat_map(|journal| journal.paths()) {
+            if !paths.insert(path) {
+                return Err(aqfs::Error::Unexpected(format!(
+                    "{} is touched twice in a journal",
+                    path
+                )));
+            }
+        }
+        let timestamp = Utc::now();
+        let mut journal_files = vec![];
+        let mut journals = journals.into_iter().peekable();
+        while journals.peek().is_some() {
+            let journal_key = format!(
+                "journal/{}-{}",
+                timestamp.format("%Y%m%d%H%M%S%f"),
+                Uuid::new_v4().to_simple().to_string()
+            );
+            let records = journals
+                .by_ref()
+                .take(self.max_journal_records)
+                .map(|journal| JournalRecord {
+                    timestamp,
+                    key: journal_key.clone(),
+                    journal,
+                })
+                .collect();
+            let mut body = self.serialize_journal(records)?;
+            if let JournalEncryption::AesGcm { key } = &self.journal_encryption {
+                body = encrypt_journal(key, &body)?;
+            }
+            journal_files.push((journal_key, body));
+        }
+        let options = PutOptions {
+            content_type: self.journal_content_type(),
+            tagging: Some(JOURNAL_TAGGING.to_string()),
+            ..Default::default()
+        };
+        {
+            let client = &self.client;
+            futures::future::try_join_all(
+                journal_files
+                    .iter()
+                    .map(|(key, body)| client.put_object_with(key.clone(), body.clone(), &options)),
+            )
+            .await?;
+            client
+                .put_object_with(
+                    JOURNAL_SENTINEL_KEY.to_string(),
+                    Uuid::new_v4().to_simple().to_string().into_bytes(),
+                    &PutOptions {
+                        tagging: Some(JOURNAL_TAGGING.to_string()),
+                        ..Default::default()
+                    },
+                )
+                .await?;
+        }
+        for (journal_key, _) in journal_files.iter() {
+            self.wait_for_replication(journal_key).await?;
+        }
+        // Our own write may reach us through SQS later than our next listing.
+        if let Some(invalidated) = &self.journal_invalidated {
+            invalidated.store(true, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl aqfs::StorageEntity for Storage {
+    type File = File;
+
+    async fn list_files(&mut self) -> Result<Vec<File>, aqfs::Error> {
+        Ok(self
+            .fetch_remote_filesystem()
+            .await?
+            .into_iter()
+            .map(|(_, f)| f)
+            .collect())
+    }
+
+    // Built from the journal without Files, which would each hold the client.
+    async fn list_filemetas(&mut self) -> Result<Vec<aqfs::FileMeta>, aqfs::Error> {
+        Ok(self
+            .replay_journal_cached()
+            .await?
+            .into_iter()
+            .map(|(_, (meta, _))| meta)
+            .collect())
+    }
+
+    // A later journal record may remove any file, so nothing is yielded until the whole journal is
+    // replayed, but each File is made only when it's yielded.
+    fn list_files_stream<'a>(&'a mut self) -> BoxStream<'a, Result<File, aqfs::Error>> {
+        use futures::stream::{StreamExt, TryStreamExt};
+
+        let client = Arc::clone(&self.client);
+        futures::stream::once(self.replay_journal_cached())
+            .map_ok(move |state| {
+                futures::stream::iter(state.into_iter().map(move |(_, (meta, key))| {
+                    Ok(File {
+                        meta,
+                        key,
+                        client: Arc::clone(&client),
+                    })
+                }))
+            })
+            .try_flatten()
+            .boxed()
+    }
+
+    // FIXME: This still replays the whole journal. Look up path in a cached filesystem instead.
+    async fn get_file(&mut self, path: &aqfs::Path) -> Result<File, aqfs::Error> {
+        self.fetch_remote_filesystem()
+            .await?
+            .remove(path)
+            .ok_or_else(|| aqfs::Error::NotFound { path: path.clone() })
+    }
+
+    async fn list_dir(&mut self, path: &aqfs::Path) -> Result<Vec<aqfs::DirEntry>, aqfs::Error> {
+        let fs = self.fetch_remote_filesystem().await?;
+        Ok(aqfs::dir_entries(path, fs.values().map(|f| &f.meta)))
+    }
+
+    async fn exists(&mut self, path: &aqfs::Path) -> Result<bool, aqfs::Error> {
+        Ok(self.fetch_remote_filesystem().await?.contains_key(path))
+    }
+
+    async fn create_file(
+        &mut self,
+        mut file: impl aqfs::File + 'async_trait,
+    ) -> Result<aqfs::FileMeta, aqfs::Error> {
+        validate_key(&file.meta().path.to_string())?;
+        // Fail before uploading anything. A large content is checked again on close of the sink.
+        check_unlocked(&self.replay_journal_cached().await?, &file.meta().path)?;
+        let (mut meta, key) = match file.size_hint() {
+            // A content fitting in a part is uploaded at once, whose length S3 needs up front.
+            Some(size) if size <= PART_SIZE as u64 => {
+                let data = (&mut file).read_all().await?;
+                let mut meta = file.meta().clone();
+                meta.size = Some(data.len() as u64);
+                let options = self.put_options(&mut meta, &data);
+                let key = self.data_key(&meta);
+                self.client
+                    .put_object_with(key.clone(), data, &options)
+                    .await?;
+
+                // Create journal and put it to journal/.
+                self.put_journal(Journal::CreateFile {
+                    meta: meta.clone(),
+                    key: key.clone(),
+                })
+                .await?;
+                (meta, key)
+            }
+            // Otherwise stream it by multipart upload, which needs no length.
+            _ => {
+                let mut sink = self.open_sink(file.meta().clone())?;
+                let key = sink.key.clone();
+                let copied = async {
+                    let mut reader = file.read_stream().await?;
+                    let mut buf = vec![0u8; COPY_BUFFER_SIZE];
+                    loop {
+                        let n = reader.read(&mut buf).await?;
+                        if n == 0 {
+                            return Ok::<_, aqfs::Error>(());
+                        }
+                        aqfs::SinkFile::write_chunk(&mut sink, &buf[..n]).await?;
+                    }
+                }
+                .await;
+                if let Err(err) = copied {
+                    let _ = sink.abort().await;
+                    return Err(err);
+                }
+                // The journal is written on close only if the upload succeeds.
+                (aqfs::SinkFile::close(sink).await?, key)
+            }
+        };
+        // FIXME: Check if the upload has been done successfully, especially any branch of the journal did not occur.
+
+        if let Some(expires_in) = self.presign_expires_in {
+            meta.presigned_url = Some(self.client.presigned_get_url(key, expires_in).await?);
+        }
+        Ok(meta)
+    }
+
+    async fn create_files(
+        &mut self,
+        files: Vec<impl aqfs::File + 'async_trait>,
+    ) -> Result<(), aqfs::Error> {
+        self.batch_create(files).await?;
+        Ok(())
+    }
+
+    async fn remove_file(&mut self, file: &File) -> Result<(), aqfs::Error> {
+        // FIXME: Check if the file exists.
+        if file.meta().locked {
+            return Err(aqfs::Error::PermissionDenied);
+        }
+        let meta = file.meta().clone();
+        self.put_journal(Journal::RemoveFile { meta }).await
+    }
+
+    // Only a journal record is written; the data object stays where it is.
+    // FIXME: Another writer may create new_path between the check and the journal.
+    async fn rename_file(&mut self, file: &File, new_path: aqfs::Path) -> Result<(), aqfs::Error> {
+        if file.meta().locked {
+            return Err(aqfs::Error::PermissionDenied);
+        }
+        validate_key(&new_path.to_string())?;
+        let fs = self.fetch_remote_filesystem().await?;
+        if !fs.contains_key(&file.meta().path) {
+            return Err(aqfs::Error::NotFound {
+                path: file.meta().path.clone(),
+            });
+        }
+        if fs.contains_key(&new_path) {
+            return Err(aqfs::Error::AlreadyExists { path: new_path });
+        }
+        self.put_journal(Journal::RenameFile {
+            from: file.meta().path.clone(),
+            to: new_path,
+        })
+        .await
+    }
+
+    // The copy is journaled with the data object of file rather than a copy of it, which is safe
+    // because data objects are never deleted but by a lifecycle rule. Hence the copy expires with
+    // file under set_data_lifecycle.
+    async fn copy_file(
+        &mut self,
+        file: &File,
+        dest: aqfs::Path,
+        overwrite: bool,
+    ) -> Result<(), aqfs::Error> {
+        validate_key(&dest.to_string())?;
+        let mut fs = self.fetch_remote_filesystem().await?;
+        match fs.get(&dest) {
+            Some(_) if !overwrite => return Err(aqfs::Error::AlreadyExists { path: dest }),
+            Some(f) if f.meta().locked => return Err(aqfs::Error::PermissionDenied),
+            _ => {}
+        }
+        let src = fs
+            .remove(&file.meta().path)
+            .ok_or_else(|| aqfs::Error::NotFound {
+                path: file.meta().path.clone(),
+            })?;
+        self.put_journal(Journal::CreateFile {
+            meta: aqfs::FileMeta {
+                path: dest,
+                mtime: Utc::now(),
+                ..src.meta
+            },
+            key: src.key,
+        })
+        .await
+    }
+
+    async fn unlock_file(&mut self, file: &File) -> Result<(), aqfs::Error> {
+        // Re-create the file with the same data object and the lock released.
+        let mut meta = file.meta().clone();
+        meta.locked = false;
+        self.put_journal(Journal::CreateFile {
+            meta,
+            key: file.key.clone(),
+        })
+        .await
+    }
+
+    fn identity(&self) -> Option<aqfs::StorageIdentity> {
+        let client = &self.client;
+        let host = match &client.region {
+            Region::Custom { endpoint, .. } => endpoint.clone(),
+            region => region.name().to_string(),
+        };
+        Some(aqfs::StorageIdentity::new(format!(
+            "s3:{}:{}/{}",
+            host, client.bucket, client.prefix
+        )))
+    }
+
+    async fn copy_to_writer<W: AsyncWrite + Unpin + Send>(
+        &mut self,
+        path: &aqfs::Path,
+        writer: &mut W,
+    ) -> Result<u64, aqfs::Error> {
+        let file = self
+            .fetch_remote_filesystem()
+            .await?
+            .remove(path)
+            .ok_or_else(|| aqfs::Error::NotFound { path: path.clone() })?;
+        // Pass S3's streaming body directly to the writer.
+        let mut reader = self
+            .client
+            .get_object(file.key)
+            .await?
+            .body
+            .ok_or_else(|| aqfs::Error::Unexpected("No body is returned".to_string()))?
+            .into_async_read();
+        Ok(tokio::io::copy(&mut reader, writer).await?)
+    }
+}
+
+pub struct Sink<'a> {
+    storage: &'a Storage,
+    meta: aqfs::FileMeta,
+    key: String,
+    // The multipart upload starts when the first part is filled, so that its content type can be
+    // detected. Files smaller than a part are uploaded by a single PutObject on close.
+    upload_id: Option<String>,
+    parts: Vec<rusoto_s3::CompletedPart>,
+    buffer: Vec<u8>,
+    // The digest of the whole content, which put_options can't compute from a part.
+    hasher: sha2::Sha256,
+    // The number of bytes written so far, which goes to meta on close.
+    size: u64,
+}
+
+impl<'a> Sink<'a> {
+    async fn upload_part(&mut self, body: Vec<u8>) -> Result<(), aqfs::Error> {
+        let storage = self.storage;
+        let client = &storage.client;
+        let upload_id = match &self.upload_id {
+            Some(upload_id) => upload_id.clone(),
+            None => {
+                let options = storage.put_options(&mut self.meta, &body);
+                let upload_id = client
+                    .create_multipart_upload(self.key.clone(), &options)
+                    .await?;
+                self.upload_id = Some(upload_id.clone());
+                upload_id
+            }
+        };
+        let part_number = self.parts.len() as i64 + 1;
+        let part = client
+            .upload_part(self.key.clone(), upload_id, part_number, body)
+            .await?;
+        self.parts.push(part);
+        Ok(())
+    }
+
+    async fn finish_upload(&mut self) -> Result<(), aqfs::Error> {
+        self.meta.size = Some(self.size);
+        let body = std::mem::replace(&mut self.buffer, vec![]);
+        if self.upload_id.is_none() {
+            let options = self.storage.put_options(&mut self.meta, &body);
+            self.storage
+                .client
+                .put_object_with(self.key.clone(), body, &options)
+                .await?;
+            return Ok(());
+        }
+        if !body.is_empty() {
+            self.upload_part(body).await?;
+        }
+        let mut digest = [0u8; 32];
+        digest.copy_from_slice(&self.hasher.clone().result());
+        self.meta.sha256 = Some(digest);
+        self.storage
+            .client
+            .complete_multipart_upload(
+                self.key.clone(),
+                self.upload_id.clone().unwrap(),
+                self.parts.clone(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    // Discard what has been written. Nothing appears in the storage.
+    pub async fn abort(self) -> Result<(), aqfs::Error> {
+        // Don't leave the uploaded parts, which are charged until aborted.
+        if let Some(upload_id) = self.upload_id {
+            self.storage
+                .client
+                .abort_multipart_upload(self.key, upload_id)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<'a> aqfs::SinkFile for Sink<'a> {
+    async fn write_chunk(&mut self, data: &[u8]) -> Result<(), aqfs::Error> {
+        self.hasher.input(data);
+        self.size += data.len() as u64;
+        self.buffer.extend_from_slice(data);
+        while self.buffer.len() >= PART_SIZE {
+            let rest = self.buffer.split_off(PART_SIZE);
+            let body = std::mem::replace(&mut self.buffer, rest);
+            self.upload_part(body).await?;
+        }
+        Ok(())
+    }
+
+    async fn close(mut self) -> Result<aqfs::FileMeta, aqfs::Error> {
+        let checked = match self.storage.replay_journal(None).await {
+            Ok(state) => check_unlocked(&state, &self.meta.path),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = checked {
+            let _ = self.abort().await;
+            return Err(err);
+        }
+        if let Err(err) = self.finish_upload().await {
+            let _ = self.abort().await;
+            return Err(err);
+        }
+        self.storage
+            .put_journal(Journal::CreateFile {
+                meta: self.meta.clone(),
+                key: self.key,
+            })
+            .await?;
+        Ok(self.meta)
+    }
 }
 
 #[cfg(test)]
@@ -269,48 +2366,1542 @@ mod test {
     use super::*;
     use crate::aqfs::StorageEntity;
     use chrono::offset::TimeZone;
+    use std::convert::TryFrom;
+
+    // The bucket shared by the tests. It must exist beforehand.
+    const TEST_BUCKET: &str = "asynq-test";
+
+    fn test_config(bucket: String) -> S3Config {
+        S3Config {
+            endpoint: Some("http://localhost:9000".to_string()),
+            region: "s3-asynq-test".to_string(),
+            bucket,
+            prefix: None,
+            credentials: CredentialSource::Default,
+            tls: TlsConfig::default(),
+        }
+    }
+
+    // A storage isolated from the other tests by a unique prefix in TEST_BUCKET.
+    // All the objects under the prefix are deleted on drop.
+    struct TestGuard {
+        storage: Option<Storage>,
+        prefix: String,
+    }
+
+    impl TestGuard {
+        // Apply a builder method of Storage, e.g. `guard.map(|s| s.with_prefix(prefix))`.
+        fn map(mut self, f: impl FnOnce(Storage) -> Storage) -> Self {
+            self.storage = self.storage.take().map(f);
+            self
+        }
+    }
+
+    impl std::ops::Deref for TestGuard {
+        type Target = Storage;
+
+        fn deref(&self) -> &Storage {
+            self.storage.as_ref().unwrap()
+        }
+    }
+
+    impl std::ops::DerefMut for TestGuard {
+        fn deref_mut(&mut self) -> &mut Storage {
+            self.storage.as_mut().unwrap()
+        }
+    }
+
+    impl Drop for TestGuard {
+        fn drop(&mut self) {
+            let prefix = self.prefix.clone();
+            // Drop can't await, so delete the objects in a runtime of its own. The client of
+            // the storage is bound to the runtime of the test and isn't reused.
+            let res = std::thread::spawn(move || {
+                let mut rt = tokio::runtime::Runtime::new().unwrap();
+                rt.block_on(async {
+                    let mut client = S3Client::clone(
+                        &Storage::from_config(test_config(TEST_BUCKET.to_string()))?.client,
+                    );
+                    client.prefix = prefix;
+                    for object in client.list_all_objects(String::new()).await? {
+                        if let Some(key) = object.key {
+                            client.delete_object(key).await?;
+                        }
+                    }
+                    Ok::<_, aqfs::Error>(())
+                })
+            })
+            .join();
+            if let Ok(Err(err)) = res {
+                tracing::warn!("Can't clean up {}: {}", self.prefix, err);
+            }
+        }
+    }
+
+    async fn get_test_storage() -> TestGuard {
+        let prefix = format!("{}/", Uuid::new_v4().to_simple());
+        TestGuard {
+            storage: Some(
+                Storage::from_config(test_config(TEST_BUCKET.to_string()))
+                    .unwrap()
+                    .with_prefix(&prefix),
+            ),
+            prefix,
+        }
+    }
+
+    #[tokio::test]
+    async fn works() -> Result<(), aqfs::Error> {
+        let mut storage = get_test_storage().await;
+        let files = storage.list_files().await?;
+        assert_eq!(files.len(), 0);
+        storage
+            .create_file(aqfs::RamFile::new(
+                aqfs::FileMeta {
+                    path: aqfs::Path::new(vec!["dummy-path".to_string()]).unwrap(),
+                    mtime: Utc.timestamp(0, 0),
+                    locked: false,
+                    content_type: None,
+                    presigned_url: None,
+                    custom: Default::default(),
+                    sha256: None,
+                    size: None,
+                },
+                "dummy content".to_string().into_bytes(),
+            ))
+            .await?;
+        let mut files = storage.list_files().await?;
+        assert_eq!(files.len(), 1);
+        let bytes = files[0].read_all().await?;
+        assert_eq!(std::str::from_utf8(&bytes).unwrap(), "dummy content");
+        storage.remove_file(&files[0]).await?;
+        let files = storage.list_files().await?;
+        assert_eq!(files.len(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn locked_file() -> Result<(), aqfs::Error> {
+        use crate::aqfs::SinkFile;
+
+        let mut storage = get_test_storage().await;
+        storage
+            .create_file(aqfs::RamFile::new(
+                aqfs::FileMeta {
+                    path: aqfs::Path::new(vec!["locked".to_string()]).unwrap(),
+                    mtime: Utc.timestamp(0, 0),
+                    locked: true,
+                    content_type: None,
+                    presigned_url: None,
+                    custom: Default::default(),
+                    sha256: None,
+                    size: None,
+                },
+                "dummy content".to_string().into_bytes(),
+            ))
+            .await?;
+        let mut files = storage.list_files().await?;
+        assert!(files[0].meta().locked);
+        assert_eq!(
+            storage.remove_file(&files[0]).await,
+            Err(aqfs::Error::PermissionDenied)
+        );
+        let meta = aqfs::FileMeta {
+            locked: false,
+            ..files[0].meta().clone()
+        };
+        assert_eq!(
+            storage
+                .create_file(aqfs::RamFile::new(meta.clone(), b"overwritten".to_vec()))
+                .await,
+            Err(aqfs::Error::PermissionDenied)
+        );
+        let mut sink = storage.open_sink(meta)?;
+        sink.write_chunk(b"overwritten").await?;
+        assert_eq!(sink.close().await, Err(aqfs::Error::PermissionDenied));
+        assert_eq!(files[0].read_all().await?, b"dummy content");
+        storage.unlock_file(&files[0]).await?;
+        let files = storage.list_files().await?;
+        assert!(!files[0].meta().locked);
+        storage.remove_file(&files[0]).await?;
+        assert_eq!(storage.list_files().await?.len(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_files_at_works() -> Result<(), aqfs::Error> {
+        let mut storage = get_test_storage().await;
+        let create = |name: &str, secs: i64| {
+            aqfs::RamFile::new(
+                aqfs::FileMeta {
+                    path: aqfs::Path::new(vec![name.to_string()]).unwrap(),
+                    mtime: Utc.timestamp(secs, 0),
+                    locked: false,
+                    content_type: None,
+                    presigned_url: None,
+                    custom: Default::default(),
+                    sha256: None,
+                    size: None,
+                },
+                name.to_string().into_bytes(),
+            )
+        };
+        let wait = || tokio::time::delay_for(std::time::Duration::from_millis(10));
+        storage.create_file(create("file0", 0)).await?;
+        wait().await;
+        storage.create_file(create("file1", 1)).await?;
+        wait().await;
+        let at = Utc::now();
+        wait().await;
+        storage.create_file(create("file2", 2)).await?;
+        let file0 = storage
+            .list_files()
+            .await?
+            .into_iter()
+            .find(|f| f.meta().path == aqfs::Path::new(vec!["file0".to_string()]).unwrap())
+            .unwrap();
+        storage.remove_file(&file0).await?;
+
+        fn names(files: Vec<File>) -> Vec<String> {
+            let mut names: Vec<String> = files.iter().map(|f| f.meta().path.to_string()).collect();
+            names.sort();
+            names
+        }
+        assert_eq!(names(storage.list_files().await?), vec!["file1", "file2"]);
+        assert_eq!(
+            names(storage.list_files_at(at).await?),
+            vec!["file0", "file1"]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_permissions_works() -> Result<(), aqfs::Error> {
+        let storage = get_test_storage().await;
+        assert_eq!(
+            storage.check_permissions().await?,
+            BucketPermissions {
+                can_list: true,
+                can_read: true,
+                can_write: true,
+                can_delete: true,
+            }
+        );
+        // The sentinel object must not be left.
+        let left = storage
+            .client
+            .list_objects_v2("permission-check/".to_string())
+            .await?
+            .contents
+            .unwrap_or(vec![]);
+        assert_eq!(left.len(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalid_path() -> Result<(), aqfs::Error> {
+        let mut storage = get_test_storage().await;
+        for name in vec!["a".repeat(MAX_KEY_LENGTH + 1), "a\0b".to_string()] {
+            let res = storage
+                .create_file(aqfs::RamFile::new(
+                    aqfs::FileMeta {
+                        path: aqfs::Path::new(vec![name]).unwrap(),
+                        mtime: Utc.timestamp(0, 0),
+                        locked: false,
+                        content_type: None,
+                        presigned_url: None,
+                        custom: Default::default(),
+                        sha256: None,
+                        size: None,
+                    },
+                    "dummy content".to_string().into_bytes(),
+                ))
+                .await;
+            match res {
+                Err(aqfs::Error::InvalidPath(_)) => {}
+                _ => panic!("Invalid path should be rejected: {:?}", res),
+            }
+        }
+        assert_eq!(storage.list_files().await?.len(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn data_expiration() -> Result<(), aqfs::Error> {
+        let storage = get_test_storage().await;
+        storage.set_data_lifecycle(90).await?;
+        let mut storage = storage.map(|s| s.with_data_expires_header(chrono::Duration::days(90)));
+        storage
+            .create_file(aqfs::RamFile::new(
+                aqfs::FileMeta {
+                    path: aqfs::Path::new(vec!["dummy-path".to_string()]).unwrap(),
+                    mtime: Utc.timestamp(0, 0),
+                    locked: false,
+                    content_type: None,
+                    presigned_url: None,
+                    custom: Default::default(),
+                    sha256: None,
+                    size: None,
+                },
+                "dummy content".to_string().into_bytes(),
+            ))
+            .await?;
+        let mut files = storage.list_files().await?;
+        let bytes = files[0].read_all().await?;
+        assert_eq!(std::str::from_utf8(&bytes).unwrap(), "dummy content");
+        let expires = storage
+            .client
+            .get_object(files[0].key.clone())
+            .await?
+            .expires
+            .expect("Expires header should be set");
+        let expires = DateTime::parse_from_rfc2822(&expires).unwrap();
+        let expected = Utc::now() + chrono::Duration::days(90);
+        assert!((expected - expires.with_timezone(&Utc)).num_minutes().abs() < 5);
+
+        // The objects are actually deleted by the lifecycle rule.
+        let client = &storage.client;
+        let mut request = rusoto_s3::GetBucketLifecycleConfigurationRequest::default();
+        request.bucket = client.bucket.clone();
+        let rules = client
+            .client
+            .get_bucket_lifecycle_configuration(request)
+            .await
+            .map_err(aqfs::Error::from)?
+            .rules
+            .unwrap_or_default();
+        let rule = rules
+            .iter()
+            .find(|r| r.id.as_deref() == Some("asynq-data-expiration"))
+            .expect("lifecycle rule should be set");
+        assert_eq!(rule.status, "Enabled");
+        assert_eq!(rule.expiration.as_ref().unwrap().days, Some(90));
+        assert_eq!(
+            rule.filter.as_ref().unwrap().prefix,
+            Some(client.full_key("data/"))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn objects_are_tagged() -> Result<(), aqfs::Error> {
+        let mut storage = get_test_storage().await;
+        storage
+            .create_file(aqfs::RamFile::new(
+                aqfs::FileMeta::builder().path("dummy-path").build()?,
+                "dummy content".to_string().into_bytes(),
+            ))
+            .await?;
+        let files = storage.list_files().await?;
+        let client = &storage.client;
+        let tag_of = |tags: Vec<rusoto_s3::Tag>| {
+            tags.into_iter()
+                .find(|tag| tag.key == "type")
+                .map(|tag| tag.value)
+        };
+        assert_eq!(
+            tag_of(client.get_object_tagging(files[0].key.clone()).await?),
+            Some("data".to_string())
+        );
+        let journals = client.list_all_objects("journal/".to_string()).await?;
+        for journal in journals {
+            assert_eq!(
+                tag_of(client.get_object_tagging(journal.key.unwrap()).await?),
+                Some("journal".to_string())
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_bucket_if_missing() -> Result<(), aqfs::Error> {
+        let bucket = format!("asynq-test-{}", Uuid::new_v4().to_simple());
+        let storage = Storage::from_config(test_config(bucket))?;
+        assert!(!storage.bucket_exists().await?);
+        assert!(storage.create_bucket_if_missing().await?);
+        assert!(storage.bucket_exists().await?);
+        assert!(!storage.create_bucket_if_missing().await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn zero_limits_are_clamped() {
+        let storage = Storage::from_config(test_config(TEST_BUCKET.to_string()))
+            .unwrap()
+            .with_max_journal_records(0)
+            .with_put_concurrency(0);
+        assert_eq!(storage.max_journal_records, 1);
+        assert_eq!(storage.client.put_concurrency, 1);
+    }
+
+    #[test]
+    fn storage_is_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Storage>();
+        assert_send_sync::<File>();
+    }
+
+    #[tokio::test]
+    async fn replication_wait() -> Result<(), aqfs::Error> {
+        let storage = get_test_storage().await;
+        let (region, bucket) = {
+            let client = &storage.client;
+            (client.region.name().to_string(), client.bucket.clone())
+        };
+        let meta = aqfs::FileMeta {
+            path: aqfs::Path::new(vec!["dummy-path".to_string()]).unwrap(),
+            mtime: Utc.timestamp(0, 0),
+            locked: false,
+            content_type: None,
+            presigned_url: None,
+            custom: Default::default(),
+            sha256: None,
+            size: None,
+        };
+
+        // The bucket itself is a trivially up-to-date replica.
+        let mut storage = storage.map(|s| {
+            s.with_replication_wait(vec![(region.clone(), bucket)], Duration::from_secs(5))
+                .unwrap()
+        });
+        storage
+            .create_file(aqfs::RamFile::new(meta.clone(), b"dummy content".to_vec()))
+            .await?;
+
+        // A bucket that doesn't exist never gets the journal.
+        let missing = format!("asynq-test-{}", Uuid::new_v4().to_simple());
+        let mut storage = storage.map(|s| {
+            s.with_replication_wait(vec![(region, missing)], Duration::from_millis(500))
+                .unwrap()
+        });
+        match storage
+            .create_file(aqfs::RamFile::new(meta, b"dummy content".to_vec()))
+            .await
+        {
+            Err(aqfs::Error::ReplicationTimeout { region }) => assert_eq!(region, "s3-asynq-test"),
+            res => panic!("Replication should time out: {:?}", res),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn request_metrics() -> Result<(), aqfs::Error> {
+        struct CapturingSink(std::sync::Mutex<Vec<RequestMetrics>>);
+        impl MetricsSink for CapturingSink {
+            fn report(&self, metrics: RequestMetrics) {
+                self.0.lock().unwrap().push(metrics);
+            }
+        }
+
+        let sink = Arc::new(CapturingSink(std::sync::Mutex::new(vec![])));
+        let mut storage = get_test_storage()
+            .await
+            .map(|s| s.with_metrics_sink(sink.clone()));
+        storage
+            .create_file(aqfs::RamFile::new(
+                aqfs::FileMeta {
+                    path: aqfs::Path::new(vec!["dummy-path".to_string()]).unwrap(),
+                    mtime: Utc.timestamp(0, 0),
+                    locked: false,
+                    content_type: None,
+                    presigned_url: None,
+                    custom: Default::default(),
+                    sha256: None,
+                    size: None,
+                },
+                "dummy content".to_string().into_bytes(),
+            ))
+            .await?;
+        let mut files = storage.list_files().await?;
+        files[0].read_all().await?;
+
+        // PutObject of data and journal, ListObjectsV2 and GetObject of journal, and GetObject of
+        // data. So everything uploaded has been downloaded.
+        let total = sink
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .fold(RequestMetrics::default(), |acc, m| RequestMetrics {
+                bytes_uploaded: acc.bytes_uploaded + m.bytes_uploaded,
+                bytes_downloaded: acc.bytes_downloaded + m.bytes_downloaded,
+                operation_count: acc.operation_count + m.operation_count,
+            });
+        assert_eq!(total.operation_count, 5);
+        assert!(total.bytes_uploaded > "dummy content".len() as u64);
+        assert_eq!(total.bytes_uploaded, total.bytes_downloaded);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn content_type_detection() -> Result<(), aqfs::Error> {
+        let mut storage = get_test_storage().await;
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+        let created = storage
+            .create_file(aqfs::RamFile::new(
+                aqfs::FileMeta {
+                    path: aqfs::Path::new(vec!["image".to_string()]).unwrap(),
+                    mtime: Utc.timestamp(0, 0),
+                    locked: false,
+                    content_type: None,
+                    presigned_url: None,
+                    custom: Default::default(),
+                    sha256: None,
+                    size: None,
+                },
+                png,
+            ))
+            .await?;
+        assert_eq!(created.content_type, Some("image/png".to_string()));
+        let files = storage.list_files().await?;
+        assert_eq!(files[0].meta().content_type, Some("image/png".to_string()));
+        let content_type = storage
+            .client
+            .get_object(files[0].key.clone())
+            .await?
+            .content_type;
+        assert_eq!(content_type, Some("image/png".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn journal_objects_sorted_by_last_modified() {
+        let object = |key: &str, last_modified: Option<&str>| rusoto_s3::Object {
+            key: Some(key.to_string()),
+            last_modified: last_modified.map(|t| t.to_string()),
+            ..Default::default()
+        };
+        // The writer of journal/0 had its clock set in the future.
+        let mut objects = vec![
+            object("journal/2", Some("2020-07-01T00:00:02.000Z")),
+            object("journal/9", Some("2020-07-01T00:00:00.000Z")),
+            object("journal/1", Some("2020-07-01T00:00:02.000Z")),
+            object("journal/5", None),
+            object("journal/3", None),
+        ];
+        sort_journal_objects(&mut objects);
+        let keys: Vec<&str> = objects
+            .iter()
+            .map(|o| o.key.as_ref().unwrap().as_str())
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                "journal/3",
+                "journal/5",
+                "journal/9",
+                "journal/1",
+                "journal/2"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn sink_works() -> Result<(), aqfs::Error> {
+        use crate::aqfs::SinkFile;
+
+        let mut storage = get_test_storage().await;
+        let meta = |name: &str| aqfs::FileMeta {
+            path: aqfs::Path::new(vec![name.to_string()]).unwrap(),
+            mtime: Utc.timestamp(0, 0),
+            locked: false,
+            content_type: None,
+            presigned_url: None,
+            custom: Default::default(),
+            sha256: None,
+            size: None,
+        };
+        // Two full parts and a short last one.
+        let chunk = vec![b'a'; 3 * 1024 * 1024];
+        let mut sink = storage.open_sink(meta("large"))?;
+        for _ in 0..6 {
+            sink.write_chunk(&chunk).await?;
+        }
+        assert_eq!(sink.parts.len(), 2);
+        sink.close().await?;
+        // Smaller than a part.
+        let mut sink = storage.open_sink(meta("small"))?;
+        sink.write_chunk(b"dummy content").await?;
+        sink.close().await?;
+
+        let mut files = storage.list_files().await?;
+        files.sort_by_key(|f| f.meta().path.to_string());
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].read_all().await?.len(), 6 * chunk.len());
+        assert_eq!(files[1].read_all().await?, b"dummy content");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn paginated_journal() -> Result<(), aqfs::Error> {
+        let mut storage = get_test_storage().await;
+        Arc::make_mut(&mut storage.client).list_page_size = 2;
+        for i in 0..5 {
+            storage
+                .create_file(aqfs::RamFile::new(
+                    aqfs::FileMeta {
+                        path: aqfs::Path::new(vec![format!("file{}", i)]).unwrap(),
+                        mtime: Utc.timestamp(0, 0),
+                        locked: false,
+                        content_type: None,
+                        presigned_url: None,
+                        custom: Default::default(),
+                        sha256: None,
+                        size: None,
+                    },
+                    "dummy content".to_string().into_bytes(),
+                ))
+                .await?;
+        }
+        assert_eq!(storage.list_files().await?.len(), 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn journal_event_detection() {
+        let event = |name: &str, key: &str| {
+            format!(
+                r#"{{"Records":[{{"eventName":"{}","s3":{{"object":{{"key":"{}"}}}}}}]}}"#,
+                name, key
+            )
+        };
+        assert!(is_journal_event(
+            &event("ObjectCreated:Put", "prefix/journal/a"),
+            "prefix/journal/"
+        ));
+        assert!(is_journal_event(
+            &event("ObjectRemoved:Delete", "prefix/journal/a"),
+            "prefix/journal/"
+        ));
+        assert!(!is_journal_event(
+            &event("ObjectCreated:Put", "prefix/data/a"),
+            "prefix/journal/"
+        ));
+        assert!(!is_journal_event(
+            &event("ObjectRestore:Post", "prefix/journal/a"),
+            "prefix/journal/"
+        ));
+        assert!(!is_journal_event(r#"{"Event":"s3:TestEvent"}"#, "journal/"));
+        assert!(!is_journal_event("not JSON", "journal/"));
+    }
+
+    #[tokio::test]
+    async fn put_objects() -> Result<(), aqfs::Error> {
+        use rusoto_mock::{MockCredentialsProvider, MockRequestDispatcher};
+
+        let client = |status| {
+            let mut client = S3Client::with_client(
+                rusoto_s3::S3Client::new_with(
+                    MockRequestDispatcher::with_status(status),
+                    MockCredentialsProvider,
+                    Region::UsEast1,
+                ),
+                SharedCredentials::new(MockCredentialsProvider),
+                Region::UsEast1,
+                "bucket".to_string(),
+            );
+            client.put_concurrency = 3;
+            client
+        };
+        let items = || {
+            (0..10)
+                .map(|i| (format!("data/{}", i), vec![0u8; 10], PutOptions::default()))
+                .collect::<Vec<_>>()
+        };
+        client(200).put_objects(items()).await?;
+        assert!(client(403).put_objects(items()).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_configuration() -> Result<(), aqfs::Error> {
+        use rusoto_mock::{MockCredentialsProvider, MockRequestDispatcher};
+
+        let storage = |status: &str| {
+            let body = format!(
+                "<VersioningConfiguration><Status>{}</Status></VersioningConfiguration>",
+                status
+            );
+            Storage::with_client(S3Client::with_client(
+                rusoto_s3::S3Client::new_with(
+                    MockRequestDispatcher::with_status(200).with_body(&body),
+                    MockCredentialsProvider,
+                    Region::UsEast1,
+                ),
+                SharedCredentials::new(MockCredentialsProvider),
+                Region::UsEast1,
+                "bucket".to_string(),
+            ))
+        };
+        storage("Suspended").verify_configuration().await?;
+        storage("Enabled")
+            .with_versioning(true)
+            .with_strict_verification(true)
+            .verify_configuration()
+            .await?;
+        // Only warned without strict mode.
+        storage("Enabled").verify_configuration().await?;
+        match storage("Enabled")
+            .with_strict_verification(true)
+            .verify_configuration()
+            .await
+        {
+            Err(aqfs::Error::Config(_)) => {}
+            res => panic!("A versioned bucket should be rejected: {:?}", res),
+        }
+        assert!(storage("Suspended")
+            .with_versioning(true)
+            .with_strict_verification(true)
+            .verify_configuration()
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sqs_invalidation() -> Result<(), aqfs::Error> {
+        use rusoto_mock::{MockCredentialsProvider, MockRequestDispatcher};
+
+        let body = r#"{"Records":[{"eventName":"ObjectCreated:Put","s3":{"object":{"key":"journal/a"}}}]}"#;
+        let response = format!(
+            "<ReceiveMessageResponse><ReceiveMessageResult><Message>\
+             <MessageId>id</MessageId><ReceiptHandle>handle</ReceiptHandle>\
+             <MD5OfBody>md5</MD5OfBody><Body>{}</Body>\
+             </Message></ReceiveMessageResult></ReceiveMessageResponse>",
+            body
+        );
+        let client = rusoto_sqs::SqsClient::new_with(
+            MockRequestDispatcher::with_status(200).with_body(&response),
+            MockCredentialsProvider,
+            Region::UsEast1,
+        );
+        let invalidated = AtomicBool::new(false);
+        poll_journal_events(&client, "queue", "journal/", &invalidated).await?;
+        assert!(invalidated.load(Ordering::SeqCst));
+
+        let invalidated = AtomicBool::new(false);
+        poll_journal_events(&client, "queue", "other/journal/", &invalidated).await?;
+        assert!(!invalidated.load(Ordering::SeqCst));
+        Ok(())
+    }
 
-    async fn get_test_storage() -> Storage {
-        let region = Region::Custom {
-            name: "s3-asynq-test".to_string(),
-            endpoint: "http://localhost:9000".to_string(),
+    #[tokio::test]
+    async fn journal_cache() -> Result<(), aqfs::Error> {
+        let mut storage = get_test_storage().await;
+        let create = |name: &str| {
+            aqfs::RamFile::new(
+                aqfs::FileMeta {
+                    path: aqfs::Path::new(vec![name.to_string()]).unwrap(),
+                    mtime: Utc.timestamp(0, 0),
+                    locked: false,
+                    content_type: None,
+                    presigned_url: None,
+                    custom: Default::default(),
+                    sha256: None,
+                    size: None,
+                },
+                "dummy content".to_string().into_bytes(),
+            )
         };
-        let bucket = format!("asynq-test-{}", Uuid::new_v4().to_simple());
-        let client = rusoto_s3::S3Client::new(region.clone());
+        storage.create_file(create("file0")).await?;
+        assert_eq!(storage.list_files().await?.len(), 1);
+        assert!(storage.journal_cache.is_some());
 
-        // create new bucket.
-        let mut request = rusoto_s3::CreateBucketRequest::default();
-        request.bucket = bucket.clone();
-        client
-            .create_bucket(request)
+        // A broken journal file put behind the sentinel's back isn't read while the cache is valid.
+        storage
+            .client
+            .put_object("journal/broken".to_string(), b"broken".to_vec())
+            .await?;
+        assert_eq!(storage.list_files().await?.len(), 1);
+        // A journal write updates the sentinel, so the whole journal is read again.
+        storage.create_file(create("file1")).await?;
+        assert!(storage.list_files().await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn presigned_url() -> Result<(), aqfs::Error> {
+        let mut storage = get_test_storage()
             .await
-            .expect("No connection to S3");
+            .map(|s| s.with_presign_created_files(Duration::from_secs(60)));
+        let meta = storage
+            .create_file(aqfs::RamFile::new(
+                aqfs::FileMeta {
+                    path: aqfs::Path::new(vec!["dummy-path".to_string()]).unwrap(),
+                    mtime: Utc.timestamp(0, 0),
+                    locked: false,
+                    content_type: None,
+                    presigned_url: None,
+                    custom: Default::default(),
+                    sha256: None,
+                    size: None,
+                },
+                "dummy content".to_string().into_bytes(),
+            ))
+            .await?;
+        let url = meta
+            .presigned_url
+            .expect("presigned URL should be returned");
+        let body = reqwest::get(&url).await.unwrap().text().await.unwrap();
+        assert_eq!(body, "dummy content");
+        // It's not journaled.
+        assert_eq!(storage.list_files().await?[0].meta().presigned_url, None);
 
-        Storage::new(region, bucket)
+        Ok(())
     }
 
     #[tokio::test]
-    async fn works() -> Result<(), aqfs::Error> {
+    async fn presigned_url_uses_configured_credentials() -> Result<(), aqfs::Error> {
+        let storage = Storage::from_config(S3Config {
+            endpoint: None,
+            region: "us-east-1".to_string(),
+            bucket: "bucket".to_string(),
+            prefix: None,
+            credentials: CredentialSource::Static {
+                access_key: "AKIDASYNQTEST".to_string(),
+                secret_key: "secret".to_string(),
+            },
+            tls: TlsConfig::default(),
+        })?;
+        let url = storage
+            .client
+            .presigned_get_url("key".to_string(), Duration::from_secs(60))
+            .await?;
+        assert!(url.contains("AKIDASYNQTEST"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn from_config_with_prefix() -> Result<(), aqfs::Error> {
+        let storage = get_test_storage().await;
+        let (bucket, prefix) = {
+            let client = &storage.client;
+            (client.bucket.clone(), client.full_key("prefix/"))
+        };
+        let mut prefixed = Storage::from_config(S3Config {
+            endpoint: Some("http://localhost:9000".to_string()),
+            region: "s3-asynq-test".to_string(),
+            bucket,
+            prefix: Some(prefix),
+            credentials: CredentialSource::Default,
+            tls: TlsConfig::default(),
+        })?;
+        prefixed
+            .create_file(aqfs::RamFile::new(
+                aqfs::FileMeta {
+                    path: aqfs::Path::new(vec!["dummy-path".to_string()]).unwrap(),
+                    mtime: Utc.timestamp(0, 0),
+                    locked: false,
+                    content_type: None,
+                    presigned_url: None,
+                    custom: Default::default(),
+                    sha256: None,
+                    size: None,
+                },
+                "dummy content".to_string().into_bytes(),
+            ))
+            .await?;
+        assert_eq!(prefixed.list_files().await?.len(), 1);
+        // Everything is under the prefix.
+        let client = &storage.client;
+        let all = client.list_all_objects("".to_string()).await?;
+        assert!(!all.is_empty());
+        assert!(all
+            .iter()
+            .all(|o| o.key.as_ref().unwrap().starts_with("prefix/")));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replicas_reject_unknown_region() -> Result<(), aqfs::Error> {
+        let storage = Storage::new("us-east-1".to_string(), "asynq".to_string())?;
+        match storage.with_replication_wait(
+            vec![("no-such-region".to_string(), "asynq".to_string())],
+            Duration::from_secs(1),
+        ) {
+            Err(aqfs::Error::Config(_)) => Ok(()),
+            _ => panic!("Unknown region should be rejected"),
+        }
+    }
+
+    #[test]
+    fn from_config_rejects_unknown_region() {
+        let res = Storage::from_config(S3Config {
+            endpoint: None,
+            region: "no-such-region".to_string(),
+            bucket: "asynq".to_string(),
+            prefix: None,
+            credentials: CredentialSource::Default,
+            tls: TlsConfig::default(),
+        });
+        match res {
+            Err(aqfs::Error::Config(_)) => {}
+            _ => panic!("Unknown region should be rejected"),
+        }
+    }
+
+    #[test]
+    fn tls_config() {
+        let config = S3Config {
+            endpoint: Some("https://localhost:9443".to_string()),
+            region: "s3-asynq-test".to_string(),
+            bucket: "asynq".to_string(),
+            prefix: None,
+            credentials: CredentialSource::Default,
+            tls: TlsConfig::default(),
+        };
+        match Storage::from_config(config.clone().with_tls_ca_cert(b"not a PEM".to_vec())) {
+            Err(aqfs::Error::Config(_)) => {}
+            _ => panic!("Invalid CA certificate should be rejected"),
+        }
+        assert!(Storage::from_config(config.with_tls_insecure_skip_verify(true)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn journal_rotation() -> Result<(), aqfs::Error> {
+        let mut storage = get_test_storage()
+            .await
+            .map(|s| s.with_max_journal_records(1000));
+        let files: Vec<aqfs::RamFile> = (0..2500)
+            .map(|i| {
+                aqfs::RamFile::new(
+                    aqfs::FileMeta {
+                        path: aqfs::Path::new(vec![format!("file{}", i)]).unwrap(),
+                        mtime: Utc.timestamp(0, 0),
+                        locked: false,
+                        content_type: None,
+                        presigned_url: None,
+                        custom: Default::default(),
+                        sha256: None,
+                        size: None,
+                    },
+                    format!("content{}", i).into_bytes(),
+                )
+            })
+            .collect();
+        storage.batch_create(files).await?;
+        let journal_objects = storage
+            .client
+            .list_all_objects("journal/".to_string())
+            .await?
+            .into_iter()
+            .filter(|o| o.key.as_deref() != Some(JOURNAL_SENTINEL_KEY))
+            .count();
+        assert_eq!(journal_objects, 3);
+        assert_eq!(storage.list_files().await?.len(), 2500);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn batch_create_rejects_duplicate_paths() -> Result<(), aqfs::Error> {
         let mut storage = get_test_storage().await;
-        let files = storage.list_files().await?;
-        assert_eq!(files.len(), 0);
+        let meta = aqfs::FileMeta::builder().path("a").build()?;
+        let files = vec![
+            aqfs::RamFile::new(meta.clone(), b"first".to_vec()),
+            aqfs::RamFile::new(meta.clone(), b"second".to_vec()),
+        ];
+        match storage.batch_create(files).await {
+            Err(aqfs::Error::Unexpected(_)) => {}
+            res => panic!("Duplicate paths should be rejected: {:?}", res),
+        }
+        // Nothing is uploaded.
+        assert!(storage
+            .client
+            .list_all_objects(String::new())
+            .await?
+            .is_empty());
+
+        let journals = vec![
+            Journal::CreateFile {
+                meta: meta.clone(),
+                key: "data/a".to_string(),
+            },
+            Journal::RenameFile {
+                from: meta.path.clone(),
+                to: "b".parse()?,
+            },
+        ];
+        assert!(matches!(
+            storage.put_journals(journals).await,
+            Err(aqfs::Error::Unexpected(_))
+        ));
+        assert!(storage.list_files().await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_filemetas_works() -> Result<(), aqfs::Error> {
+        let mut storage = get_test_storage().await;
+        for name in &["a", "d/b"] {
+            let meta = aqfs::FileMeta::builder().path(*name).build()?;
+            storage
+                .create_file((meta, name.as_bytes().to_vec()))
+                .await?;
+        }
+        let mut metas = storage.list_filemetas().await?;
+        metas.sort_by(|a, b| a.path.cmp(&b.path));
+        let names: Vec<String> = metas.iter().map(|m| m.path.to_string()).collect();
+        assert_eq!(names, vec!["a", "d/b"]);
+        assert_eq!(metas[1].size, Some(3));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_files_writes_one_journal() -> Result<(), aqfs::Error> {
+        let mut storage = get_test_storage().await;
+        let files: Vec<aqfs::RamFile> = (0..10)
+            .map(|i| {
+                let meta = aqfs::FileMeta::builder()
+                    .path(format!("d/file{}", i).as_str())
+                    .build()
+                    .unwrap();
+                aqfs::RamFile::new(meta, format!("content{}", i).into_bytes())
+            })
+            .collect();
+        storage.create_files(files).await?;
+        let journal_objects = storage
+            .client
+            .list_all_objects("journal/".to_string())
+            .await?
+            .into_iter()
+            .filter(|o| o.key.as_deref() != Some(JOURNAL_SENTINEL_KEY))
+            .count();
+        assert_eq!(journal_objects, 1);
+        let mut file = storage
+            .get_file(&aqfs::Path::try_from("d/file3").unwrap())
+            .await?;
+        assert_eq!(file.read_all().await?, b"content3");
+        assert_eq!(storage.list_files().await?.len(), 10);
+
+        Ok(())
+    }
+
+    #[test]
+    fn journal_encryption_roundtrip() -> Result<(), aqfs::Error> {
+        let key = [42u8; 32];
+        let encrypted = encrypt_journal(&key, b"journal")?;
+        assert_ne!(&encrypted[NONCE_LENGTH..], b"journal");
+        assert_eq!(decrypt_journal(&key, &encrypted)?, b"journal");
+        assert!(decrypt_journal(&[0u8; 32], &encrypted).is_err());
+        assert!(decrypt_journal(&key, b"short").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn bincode_journal_versions() -> Result<(), aqfs::Error> {
+        let meta = aqfs::FileMeta::builder()
+            .path("a")
+            .mtime(Utc.timestamp(0, 0))
+            .size(3)
+            .build()?;
+        let record = |journal| JournalRecord {
+            journal,
+            timestamp: Utc.timestamp(0, 0),
+            key: "journal/0".to_string(),
+        };
+        let body = serialize_bincode_journal(vec![record(Journal::CreateFile {
+            meta: meta.clone(),
+            key: "data/0".to_string(),
+        })])?;
+        match &deserialize_bincode_journal(&body)?[..] {
+            [JournalRecord {
+                journal: Journal::CreateFile { meta: decoded, .. },
+                ..
+            }] => assert_eq!(decoded, &meta),
+            records => panic!("unexpected records: {:?}", records),
+        }
+
+        // Journal files written with the older layouts of FileMeta.
+        fn legacy<M: Serialize>(meta: M) -> Result<Vec<u8>, aqfs::Error> {
+            Ok(bincode::serialize(&LegacyJournalFile {
+                records: vec![LegacyJournalRecord {
+                    journal: LegacyJournal::CreateFile {
+                        meta,
+                        key: "data/0".to_string(),
+                    },
+                    timestamp: Utc.timestamp(0, 0),
+                    key: "journal/0".to_string(),
+                }],
+            })?)
+        }
+        let decode = |body: Vec<u8>| -> Result<aqfs::FileMeta, aqfs::Error> {
+            match deserialize_bincode_journal(&body)?.pop() {
+                Some(JournalRecord {
+                    journal: Journal::CreateFile { meta, .. },
+                    ..
+                }) => Ok(meta),
+                record => panic!("unexpected record: {:?}", record),
+            }
+        };
+        let mut custom = BTreeMap::new();
+        custom.insert("owner".to_string(), "me".to_string());
+        let expected = aqfs::FileMeta {
+            locked: true,
+            content_type: Some("text/plain".to_string()),
+            custom: custom.clone(),
+            sha256: Some([1u8; 32]),
+            size: None,
+            ..meta.clone()
+        };
+        let body = legacy(FileMetaV4 {
+            path: meta.path.clone(),
+            mtime: meta.mtime,
+            locked: true,
+            content_type: Some("text/plain".to_string()),
+            custom: custom.clone(),
+            sha256: Some([1u8; 32]),
+        })?;
+        assert_eq!(decode(body)?, expected);
+        let expected = aqfs::FileMeta {
+            sha256: None,
+            ..expected
+        };
+        let body = legacy(FileMetaV3 {
+            path: meta.path.clone(),
+            mtime: meta.mtime,
+            locked: true,
+            content_type: Some("text/plain".to_string()),
+            custom,
+        })?;
+        assert_eq!(decode(body)?, expected);
+        let expected = aqfs::FileMeta {
+            custom: Default::default(),
+            ..expected
+        };
+        let body = legacy(FileMetaV2 {
+            path: meta.path.clone(),
+            mtime: meta.mtime,
+            locked: true,
+            content_type: Some("text/plain".to_string()),
+        })?;
+        assert_eq!(decode(body)?, expected);
+        let expected = aqfs::FileMeta {
+            content_type: None,
+            ..expected
+        };
+        let body = legacy(FileMetaV1 {
+            path: meta.path.clone(),
+            mtime: meta.mtime,
+            locked: true,
+        })?;
+        assert_eq!(decode(body)?, expected);
+        let expected = aqfs::FileMeta {
+            locked: false,
+            ..expected
+        };
+        let body = legacy(FileMetaV0 {
+            path: meta.path.clone(),
+            mtime: meta.mtime,
+        })?;
+        assert_eq!(decode(body.clone())?, expected);
+
+        // The bytes of a journal file written by the first release, encoded by hand not to depend
+        // on the types above.
+        fn string(body: &mut Vec<u8>, s: &str) {
+            body.extend_from_slice(&(s.len() as u64).to_le_bytes());
+            body.extend_from_slice(s.as_bytes());
+        }
+        let mut baseline = vec![];
+        baseline.extend_from_slice(&1u64.to_le_bytes()); // The number of records
+        baseline.extend_from_slice(&0u32.to_le_bytes()); // Journal::CreateFile
+        baseline.extend_from_slice(&1u64.to_le_bytes()); // The number of the path elements
+        string(&mut baseline, "a");
+        string(&mut baseline, "1970-01-01T00:00:00Z"); // mtime
+        string(&mut baseline, "data/0");
+        string(&mut baseline, "1970-01-01T00:00:00Z"); // timestamp
+        string(&mut baseline, "journal/0");
+        assert_eq!(baseline, body);
+        assert_eq!(decode(baseline.clone())?, expected);
+
+        baseline.push(0);
+        assert!(deserialize_bincode_journal(&baseline).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn encrypted_journal() -> Result<(), aqfs::Error> {
+        let encryption = JournalEncryption::AesGcm { key: [42u8; 32] };
+        let mut storage = get_test_storage()
+            .await
+            .map(|s| s.with_journal_encryption(encryption.clone()));
         storage
             .create_file(aqfs::RamFile::new(
                 aqfs::FileMeta {
-                    path: aqfs::Path::new(vec!["dummy-path".to_string()]),
+                    path: aqfs::Path::new(vec!["secret-path".to_string()]).unwrap(),
                     mtime: Utc.timestamp(0, 0),
+                    locked: false,
+                    content_type: None,
+                    presigned_url: None,
+                    custom: Default::default(),
+                    sha256: None,
+                    size: None,
                 },
                 "dummy content".to_string().into_bytes(),
             ))
             .await?;
+        let files = storage.list_files().await?;
+        assert_eq!(files[0].meta().path.to_string(), "secret-path");
+
+        // The path can't be seen in the journal object.
+        let client = &storage.client;
+        let journal_key = client
+            .list_all_objects("journal/".to_string())
+            .await?
+            .into_iter()
+            .filter_map(|o| o.key)
+            .find(|key| key != JOURNAL_SENTINEL_KEY)
+            .unwrap();
+        let output = client.get_object(journal_key).await?;
+        assert_eq!(
+            output.content_type.as_deref(),
+            Some(ENCRYPTED_JOURNAL_CONTENT_TYPE)
+        );
+        let mut body = Vec::new();
+        output
+            .body
+            .unwrap()
+            .into_async_read()
+            .read_to_end(&mut body)
+            .await?;
+        assert!(!String::from_utf8_lossy(&body).contains("secret-path"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compact_and_rekey() -> Result<(), aqfs::Error> {
+        let (old_key, new_key) = ([1u8; 32], [2u8; 32]);
+        let meta = |name: &str| aqfs::FileMeta::builder().path(name).build().unwrap();
+        let mut storage = get_test_storage()
+            .await
+            .map(|s| s.with_journal_encryption(JournalEncryption::AesGcm { key: old_key }));
+        storage.create_file((meta("a"), b"a".to_vec())).await?;
+        storage.create_file((meta("b"), b"b".to_vec())).await?;
+        let b = storage
+            .list_files()
+            .await?
+            .into_iter()
+            .find(|f| f.meta().path == meta("b").path)
+            .unwrap();
+        storage.remove_file(&b).await?;
+
+        let report = storage.compact_and_rekey(old_key, new_key).await?;
+        assert_eq!(report.rekeyed, vec![aqfs::Path::try_from("a").unwrap()]);
+        assert!(report.failures.is_empty());
+
+        // Only the new key is needed now.
+        let mut storage = storage.map(|s| {
+            let mut s = s.with_journal_encryption(JournalEncryption::AesGcm { key: new_key });
+            s.retired_journal_keys.clear();
+            s
+        });
         let mut files = storage.list_files().await?;
         assert_eq!(files.len(), 1);
-        let bytes = files[0].read_all().await?;
-        assert_eq!(std::str::from_utf8(&bytes).unwrap(), "dummy content");
-        storage.remove_file(&files[0]).await?;
-        let files = storage.list_files().await?;
-        assert_eq!(files.len(), 0);
+        assert_eq!(files[0].read_all().await?, b"a");
+
+        // Running it again is harmless.
+        let report = storage.compact_and_rekey(old_key, new_key).await?;
+        assert_eq!(report.rekeyed, vec![aqfs::Path::try_from("a").unwrap()]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upload_checksum() -> Result<(), aqfs::Error> {
+        let mut storage = get_test_storage()
+            .await
+            .map(|s| s.with_upload_checksum(true));
+        let meta = aqfs::FileMeta::builder().path("a").build()?;
+        let stored = storage.create_file((meta, b"content".to_vec())).await?;
+        assert_eq!(stored.sha256, Some(aqfs::sha256(b"content")));
+        let report = storage.verify_integrity().await?;
+        assert!(report.corrupted.is_empty() && report.unverifiable.is_empty());
+
+        // A body corrupted in transit.
+        let options = PutOptions {
+            content_md5: Some(base64::encode(md5::compute(b"content").0)),
+            ..Default::default()
+        };
+        let res = storage
+            .client
+            .put_object_with("data/corrupted".to_string(), b"c0ntent".to_vec(), &options)
+            .await;
+        assert_eq!(res.unwrap_err(), aqfs::Error::ChecksumMismatch);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn data_key_prefix_fn() -> Result<(), aqfs::Error> {
+        let mut storage = get_test_storage().await.map(|s| {
+            s.with_data_key_prefix_fn(|meta| match meta.content_type.as_deref() {
+                Some("image/png") => "data/image/".to_string(),
+                _ => "data/other/".to_string(),
+            })
+        });
+        let meta = |name: &str| aqfs::FileMeta::builder().path(name).build().unwrap();
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+        storage.create_file((meta("a.png"), png.clone())).await?;
+        storage
+            .create_file((meta("a.txt"), b"text".to_vec()))
+            .await?;
+        {
+            let client = &storage.client;
+            assert_eq!(
+                client
+                    .list_all_objects("data/image/".to_string())
+                    .await?
+                    .len(),
+                1
+            );
+            assert_eq!(
+                client
+                    .list_all_objects("data/other/".to_string())
+                    .await?
+                    .len(),
+                1
+            );
+        }
+        // The journal has the full keys.
+        let mut files = storage.list_files().await?;
+        files.sort_by(|f0, f1| f0.meta().cmp(f1.meta()));
+        assert!(files[0].key.starts_with("data/image/"));
+        assert_eq!(files[0].read_all().await?, png);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_file_works() -> Result<(), aqfs::Error> {
+        let mut storage = get_test_storage().await;
+        let meta = aqfs::FileMeta::builder().path("a").build()?;
+        storage.create_file((meta, b"a".to_vec())).await?;
+        let mut f = storage
+            .get_file(&aqfs::Path::try_from("a").unwrap())
+            .await?;
+        assert_eq!(f.read_all().await?, b"a");
+        assert_eq!(
+            storage
+                .get_file(&aqfs::Path::try_from("missing").unwrap())
+                .await
+                .unwrap_err(),
+            aqfs::Error::NotFound {
+                path: aqfs::Path::try_from("missing").unwrap()
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn exists_works() -> Result<(), aqfs::Error> {
+        let mut storage = get_test_storage().await;
+        let meta = aqfs::FileMeta::builder().path("a").build()?;
+        storage.create_file((meta, vec![])).await?;
+        assert!(storage.exists(&aqfs::Path::try_from("a").unwrap()).await?);
+        assert!(
+            !storage
+                .exists(&aqfs::Path::try_from("missing").unwrap())
+                .await?
+        );
+        let file = storage
+            .get_file(&aqfs::Path::try_from("a").unwrap())
+            .await?;
+        storage.remove_file(&file).await?;
+        assert!(!storage.exists(&aqfs::Path::try_from("a").unwrap()).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_dir_works() -> Result<(), aqfs::Error> {
+        use crate::aqfs::test::dir_entry_names;
+
+        let mut storage = get_test_storage().await;
+        assert_eq!(storage.list_dir(&aqfs::Path::default()).await?, vec![]);
+        for name in &["a", "d/b", "d/e/c"] {
+            let meta = aqfs::FileMeta::builder().path(*name).build()?;
+            storage.create_file((meta, vec![])).await?;
+        }
+        assert_eq!(
+            dir_entry_names(&storage.list_dir(&aqfs::Path::default()).await?),
+            vec!["a", "d/"]
+        );
+        assert_eq!(
+            dir_entry_names(
+                &storage
+                    .list_dir(&aqfs::Path::try_from("d").unwrap())
+                    .await?
+            ),
+            vec!["d/b", "e/"]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rename_file_works() -> Result<(), aqfs::Error> {
+        let mut storage = get_test_storage().await;
+        for name in &["a", "b"] {
+            let meta = aqfs::FileMeta::builder().path(*name).build()?;
+            storage
+                .create_file((meta, name.as_bytes().to_vec()))
+                .await?;
+        }
+        let a = storage
+            .get_file(&aqfs::Path::try_from("a").unwrap())
+            .await?;
+        storage
+            .rename_file(&a, aqfs::Path::try_from("c").unwrap())
+            .await?;
+        assert!(!storage.exists(&aqfs::Path::try_from("a").unwrap()).await?);
+        let mut c = storage
+            .get_file(&aqfs::Path::try_from("c").unwrap())
+            .await?;
+        // The data object is shared, not copied.
+        assert_eq!(c.key, a.key);
+        assert_eq!(c.read_all().await?, b"a");
+        assert_eq!(
+            storage
+                .rename_file(&c, aqfs::Path::try_from("b").unwrap())
+                .await,
+            Err(aqfs::Error::AlreadyExists {
+                path: aqfs::Path::try_from("b").unwrap()
+            })
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn copy_file_works() -> Result<(), aqfs::Error> {
+        let mut storage = get_test_storage().await;
+        for name in &["a", "b"] {
+            let meta = aqfs::FileMeta::builder()
+                .path(*name)
+                .mtime(Utc.timestamp(0, 0))
+                .build()?;
+            storage
+                .create_file((meta, name.as_bytes().to_vec()))
+                .await?;
+        }
+        let a = storage
+            .get_file(&aqfs::Path::try_from("a").unwrap())
+            .await?;
+        storage
+            .copy_file(&a, aqfs::Path::try_from("c").unwrap(), false)
+            .await?;
+        let mut c = storage
+            .get_file(&aqfs::Path::try_from("c").unwrap())
+            .await?;
+        assert_eq!(c.key, a.key);
+        assert!(c.meta().mtime > a.meta().mtime);
+        assert_eq!(c.read_all().await?, b"a");
+
+        assert_eq!(
+            storage
+                .copy_file(&a, aqfs::Path::try_from("b").unwrap(), false)
+                .await,
+            Err(aqfs::Error::AlreadyExists {
+                path: aqfs::Path::try_from("b").unwrap()
+            })
+        );
+        storage
+            .copy_file(&a, aqfs::Path::try_from("b").unwrap(), true)
+            .await?;
+        let mut b = storage
+            .get_file(&aqfs::Path::try_from("b").unwrap())
+            .await?;
+        assert_eq!(b.read_all().await?, b"a");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_range_works() -> Result<(), aqfs::Error> {
+        let mut storage = get_test_storage().await;
+        let meta = aqfs::FileMeta::builder().path("a").build()?;
+        storage.create_file((meta, b"0123456789".to_vec())).await?;
+        let mut f = storage
+            .get_file(&aqfs::Path::try_from("a").unwrap())
+            .await?;
+        aqfs::test::check_read_range(&mut f).await
+    }
+
+    #[tokio::test]
+    async fn create_file_cleans_up_broken_stream() -> Result<(), aqfs::Error> {
+        let mut storage = get_test_storage().await;
+        // Break after a part is uploaded.
+        let file = aqfs::test::FailingFile {
+            meta: aqfs::FileMeta::builder().path("broken").build()?,
+            size: PART_SIZE + 1,
+        };
+        assert!(storage.create_file(file).await.is_err());
+        assert_eq!(storage.list_files().await?.len(), 0);
+        assert_eq!(
+            storage
+                .client
+                .list_all_objects("data/".to_string())
+                .await?
+                .len(),
+            0
+        );
+        Ok(())
+    }
 
+    #[tokio::test]
+    async fn ndjson_journal() -> Result<(), aqfs::Error> {
+        let meta = |name: &str| aqfs::FileMeta::builder().path(name).build().unwrap();
+        let mut storage = get_test_storage()
+            .await
+            .map(|s| s.with_journal_format(JournalFormat::Ndjson));
+        storage.create_file((meta("a"), b"a".to_vec())).await?;
+        storage.create_file((meta("b"), b"b".to_vec())).await?;
+        {
+            let client = &storage.client;
+            let journal_key = client
+                .list_all_objects("journal/".to_string())
+                .await?
+                .into_iter()
+                .filter_map(|o| o.key)
+                .find(|key| key != JOURNAL_SENTINEL_KEY)
+                .unwrap();
+            let output = client.get_object(journal_key).await?;
+            assert_eq!(
+                output.content_type.as_deref(),
+                Some(NDJSON_JOURNAL_CONTENT_TYPE)
+            );
+            let mut body = String::new();
+            output
+                .body
+                .unwrap()
+                .into_async_read()
+                .read_to_string(&mut body)
+                .await?;
+            assert_eq!(body.lines().count(), 1);
+            parse_ndjson_record(body.lines().next().unwrap())?;
+        }
+
+        // Journal files of both formats are read.
+        let mut storage = storage.map(|s| s.with_journal_format(JournalFormat::Bincode));
+        let files = storage.list_files().await?;
+        let a = files
+            .iter()
+            .find(|f| f.meta().path.to_string() == "a")
+            .unwrap();
+        storage.remove_file(a).await?;
+        let mut files = storage.list_files().await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].read_all().await?, b"b");
         Ok(())
     }
 }
@@ -1,47 +1,683 @@
 use crate::aqfs;
+use crate::aqfs::File as FileTrait;
+use chrono::Duration;
+use futures::future::BoxFuture;
+use futures::stream::{BoxStream, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
-struct StorageSyncer<
-    ST0: aqfs::StorageEntity<F0>,
-    ST1: aqfs::StorageEntity<F1>,
-    F0: aqfs::File,
-    F1: aqfs::File,
-> {
-    st0: ST0,
-    st1: ST1,
-
-    // Thanks to: https://qnighy.hatenablog.com/entry/2018/01/14/220000
-    _marker0: std::marker::PhantomData<fn() -> F0>,
-    _marker1: std::marker::PhantomData<fn() -> F1>,
-}
-
-impl<
-        ST0: aqfs::StorageEntity<F0>,
-        ST1: aqfs::StorageEntity<F1>,
-        F0: aqfs::File,
-        F1: aqfs::File,
-    > StorageSyncer<ST0, ST1, F0, F1>
+// Summary of a sync, which can be output in machine-readable formats.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[must_use]
+pub struct SyncReport {
+    pub files_transferred: u64,
+    pub bytes_transferred: u64,
+    pub files_skipped: u64,
+    // Files removed from the destination by StorageSyncer::mirror_safe.
+    #[serde(default)]
+    pub files_removed: u64,
+    pub conflicts: Vec<aqfs::Path>,
+    pub duration_ms: u64,
+    // Failures of each file, which are recorded only by StorageSyncer::with_best_effort.
+    pub errors: Vec<String>,
+}
+
+impl SyncReport {
+    pub fn to_json(&self) -> String {
+        // SyncReport has no map with non-string keys, so this never fails.
+        serde_json::to_string(self).unwrap()
+    }
+
+    // Output the report as a CSV with a header line. conflicts and errors are joined by ';'.
+    pub fn to_csv(&self) -> String {
+        fn field(s: &str) -> String {
+            if s.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+                format!("\"{}\"", s.replace('"', "\"\""))
+            } else {
+                s.to_string()
+            }
+        }
+        let conflicts: Vec<String> = self.conflicts.iter().map(|p| p.to_string()).collect();
+        format!(
+            "files_transferred,bytes_transferred,files_skipped,files_removed,conflicts,duration_ms,errors\n{},{},{},{},{},{},{}\n",
+            self.files_transferred,
+            self.bytes_transferred,
+            self.files_skipped,
+            self.files_removed,
+            field(&conflicts.join(";")),
+            self.duration_ms,
+            field(&self.errors.join(";")),
+        )
+    }
+}
+
+// Decide whether a file should be transferred from a storage (src) to another (dest).
+// dest is None if the destination doesn't have the file.
+pub trait SyncStrategy: Send + Sync {
+    fn should_transfer(&self, src: &aqfs::FileMeta, dest: Option<&aqfs::FileMeta>) -> bool;
+}
+
+// Transfer every file regardless of the destination.
+pub struct AlwaysTransfer;
+
+impl SyncStrategy for AlwaysTransfer {
+    fn should_transfer(&self, _src: &aqfs::FileMeta, _dest: Option<&aqfs::FileMeta>) -> bool {
+        true
+    }
+}
+
+// Transfer a file if the destination doesn't have it or the source is newer by more than
+// tolerance. The tolerance absorbs the difference of timestamp resolution among storages.
+pub struct MtimeStrategy {
+    pub tolerance: Duration,
+}
+
+impl Default for MtimeStrategy {
+    fn default() -> Self {
+        Self {
+            tolerance: Duration::zero(),
+        }
+    }
+}
+
+impl SyncStrategy for MtimeStrategy {
+    fn should_transfer(&self, src: &aqfs::FileMeta, dest: Option<&aqfs::FileMeta>) -> bool {
+        match dest {
+            None => true,
+            Some(dest) => src.mtime > dest.mtime + self.tolerance,
+        }
+    }
+}
+
+// Transfer a file if the destination doesn't have it, the sizes differ, or the source is newer by
+// more than tolerance. An unknown size is taken as the same.
+pub struct SizeAndMtimeStrategy {
+    pub tolerance: Duration,
+}
+
+impl Default for SizeAndMtimeStrategy {
+    fn default() -> Self {
+        Self {
+            tolerance: Duration::zero(),
+        }
+    }
+}
+
+impl SyncStrategy for SizeAndMtimeStrategy {
+    fn should_transfer(&self, src: &aqfs::FileMeta, dest: Option<&aqfs::FileMeta>) -> bool {
+        match dest {
+            None => true,
+            Some(dest) => {
+                let size_differs = match (src.size, dest.size) {
+                    (Some(src_size), Some(dest_size)) => src_size != dest_size,
+                    _ => false,
+                };
+                size_differs || src.mtime > dest.mtime + self.tolerance
+            }
+        }
+    }
+}
+
+// Transfer a file if the destination doesn't have it or the SHA-256 digests differ. If either
+// digest is unknown, fallback decides instead.
+pub struct HashStrategy<F: SyncStrategy> {
+    fallback: F,
+}
+
+impl<F: SyncStrategy> HashStrategy<F> {
+    pub fn new(fallback: F) -> Self {
+        Self { fallback }
+    }
+}
+
+impl Default for HashStrategy<MtimeStrategy> {
+    fn default() -> Self {
+        Self::new(MtimeStrategy::default())
+    }
+}
+
+impl<F: SyncStrategy> SyncStrategy for HashStrategy<F> {
+    fn should_transfer(&self, src: &aqfs::FileMeta, dest: Option<&aqfs::FileMeta>) -> bool {
+        match dest.map(|dest| (src.sha256, dest.sha256)) {
+            None => true,
+            Some((Some(src_digest), Some(dest_digest))) => src_digest != dest_digest,
+            Some(_) => self.fallback.should_transfer(src, dest),
+        }
+    }
+}
+
+// Transfer a file if either of the strategies says so.
+pub struct EitherChanged<A: SyncStrategy, B: SyncStrategy> {
+    a: A,
+    b: B,
+}
+
+impl<A: SyncStrategy, B: SyncStrategy> EitherChanged<A, B> {
+    pub fn new(a: A, b: B) -> Self {
+        Self { a, b }
+    }
+}
+
+impl<A: SyncStrategy, B: SyncStrategy> SyncStrategy for EitherChanged<A, B> {
+    fn should_transfer(&self, src: &aqfs::FileMeta, dest: Option<&aqfs::FileMeta>) -> bool {
+        self.a.should_transfer(src, dest) || self.b.should_transfer(src, dest)
+    }
+}
+
+// A file that exists in both storages with different mtimes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConflictContext {
+    pub path: aqfs::Path,
+    pub src_meta: aqfs::FileMeta,
+    pub dest_meta: aqfs::FileMeta,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConflictResolution {
+    UseSource,
+    UseDest,
+    // Keep both: the source's version is stored at new_path and the destination's one wins at the
+    // original path.
+    Rename { new_path: aqfs::Path },
+    Skip,
+}
+
+// How to resolve conflicts. Resolved conflicts are recorded in SyncReport::conflicts.
+#[derive(Clone)]
+pub enum ConflictPolicy {
+    // Don't treat conflicts specially and let SyncStrategy decide.
+    Strategy,
+    UseSource,
+    UseDest,
+    Skip,
+    // Ask the callback. It's called for one conflict at a time, never concurrently, so that it can
+    // e.g. call an external API or prompt the user.
+    Custom(Arc<dyn Fn(ConflictContext) -> BoxFuture<'static, ConflictResolution> + Send + Sync>),
+}
+
+impl Default for ConflictPolicy {
+    fn default() -> Self {
+        ConflictPolicy::Strategy
+    }
+}
+
+impl ConflictPolicy {
+    // Returns None if the conflict should be left to SyncStrategy.
+    async fn resolve(
+        &self,
+        src: &aqfs::FileMeta,
+        dest: &aqfs::FileMeta,
+    ) -> Option<ConflictResolution> {
+        match self {
+            ConflictPolicy::Strategy => None,
+            ConflictPolicy::UseSource => Some(ConflictResolution::UseSource),
+            ConflictPolicy::UseDest => Some(ConflictResolution::UseDest),
+            ConflictPolicy::Skip => Some(ConflictResolution::Skip),
+            ConflictPolicy::Custom(callback) => Some(
+                callback(ConflictContext {
+                    path: src.path.clone(),
+                    src_meta: src.clone(),
+                    dest_meta: dest.clone(),
+                })
+                .await,
+            ),
+        }
+    }
+}
+
+// Transfer f to dest and record the result to report. If best_effort, a failure is recorded
+// rather than returned so that a broken file doesn't stop the whole sync.
+async fn transfer<ST: aqfs::StorageEntity>(
+    dest: &mut ST,
+    f: impl aqfs::File,
+    report: &mut SyncReport,
+    best_effort: bool,
+) -> Result<(), aqfs::Error> {
+    let path = f.meta().path.clone();
+    transfer_to(dest, f, path, report, best_effort).await
+}
+
+// inner with its metadata replaced, e.g. to store it at another path.
+struct WithMeta<F> {
+    meta: aqfs::FileMeta,
+    inner: F,
+}
+
+#[async_trait::async_trait]
+impl<F: aqfs::File> aqfs::File for WithMeta<F> {
+    fn meta(&self) -> &aqfs::FileMeta {
+        &self.meta
+    }
+
+    async fn read_stream<'a>(&'a mut self) -> Result<aqfs::FileReader<'a>, aqfs::Error> {
+        self.inner.read_stream().await
+    }
+
+    fn size_hint(&self) -> Option<u64> {
+        self.inner.size_hint()
+    }
+}
+
+// Same as transfer, but store f at path in dest.
+async fn transfer_to<ST: aqfs::StorageEntity>(
+    dest: &mut ST,
+    mut f: impl aqfs::File,
+    path: aqfs::Path,
+    report: &mut SyncReport,
+    best_effort: bool,
+) -> Result<(), aqfs::Error> {
+    let res = async {
+        let meta = aqfs::FileMeta {
+            path: path.clone(),
+            ..f.meta().clone()
+        };
+        let size = match f.size_hint() {
+            // Stream the content to dest without holding it in memory.
+            Some(size) => {
+                // Fail early rather than after a partial upload.
+                dest.quota_check(size).await?;
+                dest.create_file(WithMeta { meta, inner: f }).await?;
+                size
+            }
+            None => {
+                let data = f.read_all().await?;
+                let size = data.len() as u64;
+                dest.quota_check(size).await?;
+                dest.create_file(aqfs::RamFile::new(meta, data)).await?;
+                size
+            }
+        };
+        Ok::<u64, aqfs::Error>(size)
+    }
+    .await;
+    match res {
+        Ok(size) => {
+            report.files_transferred += 1;
+            report.bytes_transferred += size;
+        }
+        Err(err) if best_effort => report
+            .errors
+            .push(format!("{}: {:?}", path.to_string(), err)),
+        Err(err) => return Err(err),
+    }
+    Ok(())
+}
+
+// Hash function used to tell whether two files have the same content.
+pub trait Hasher: Send + Sync {
+    fn name(&self) -> &str;
+    fn hash(&self, data: &[u8]) -> Vec<u8>;
+}
+
+pub struct Sha256Hasher;
+
+impl Hasher for Sha256Hasher {
+    fn name(&self) -> &str {
+        "sha256"
+    }
+
+    fn hash(&self, data: &[u8]) -> Vec<u8> {
+        sha2::Sha256::digest(data).to_vec()
+    }
+}
+
+pub struct Sha512Hasher;
+
+impl Hasher for Sha512Hasher {
+    fn name(&self) -> &str {
+        "sha512"
+    }
+
+    fn hash(&self, data: &[u8]) -> Vec<u8> {
+        sha2::Sha512::digest(data).to_vec()
+    }
+}
+
+// About twice as fast as SHA-256 on large files.
+pub struct Blake3Hasher;
+
+impl Hasher for Blake3Hasher {
+    fn name(&self) -> &str {
+        "blake3"
+    }
+
+    fn hash(&self, data: &[u8]) -> Vec<u8> {
+        blake3::hash(data).as_bytes().to_vec()
+    }
+}
+
+// Hashing is CPU-bound, so the two files are hashed on separate cores if rayon is available.
+#[cfg(feature = "sync-rayon")]
+fn hash_pair(hasher: &dyn Hasher, data0: &[u8], data1: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    rayon::join(|| hasher.hash(data0), || hasher.hash(data1))
+}
+
+#[cfg(not(feature = "sync-rayon"))]
+fn hash_pair(hasher: &dyn Hasher, data0: &[u8], data1: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    (hasher.hash(data0), hasher.hash(data1))
+}
+
+// Returns false also when either file can't be read, so that the transfer reports the failure.
+async fn same_content(
+    hasher: &dyn Hasher,
+    f0: &mut impl aqfs::File,
+    f1: &mut impl aqfs::File,
+) -> bool {
+    match (f0.read_all().await, f1.read_all().await) {
+        (Ok(data0), Ok(data1)) => {
+            let (hash0, hash1) = hash_pair(hasher, &data0, &data1);
+            hash0 == hash1
+        }
+        _ => false,
+    }
+}
+
+// Check the strategy, and then the contents if hasher is given.
+async fn needs_transfer(
+    strategy: &dyn SyncStrategy,
+    hasher: Option<&dyn Hasher>,
+    src: &mut impl aqfs::File,
+    dest: Option<&mut impl aqfs::File>,
+) -> bool {
+    if !strategy.should_transfer(src.meta(), dest.as_ref().map(|dest| dest.meta())) {
+        return false;
+    }
+    match (hasher, dest) {
+        (Some(hasher), Some(dest)) => !same_content(hasher, src, dest).await,
+        _ => true,
+    }
+}
+
+// Use list_files_stream only if the storage streams for real; otherwise list_files is enough.
+async fn files_of<'a, ST: aqfs::StorageEntity>(
+    st: &'a mut ST,
+) -> Result<BoxStream<'a, Result<ST::File, aqfs::Error>>, aqfs::Error>
+where
+    ST::File: 'a,
 {
+    if st.capabilities().supports_streaming_list {
+        return Ok(st.list_files_stream());
+    }
+    let files = st.list_files().await?;
+    Ok(futures::stream::iter(files.into_iter().map(Ok)).boxed())
+}
+
+async fn list_by_path<ST: aqfs::StorageEntity>(
+    st: &mut ST,
+) -> Result<HashMap<aqfs::Path, ST::File>, aqfs::Error> {
+    Ok(st
+        .list_files()
+        .await?
+        .into_iter()
+        .map(|f| (f.meta().path.clone(), f))
+        .collect())
+}
+
+pub struct StorageSyncer<ST0: aqfs::StorageEntity, ST1: aqfs::StorageEntity> {
+    storages: aqfs::StoragePair<ST0, ST1>,
+    strategy: Box<dyn SyncStrategy>,
+    conflict_policy: ConflictPolicy,
+    hasher: Option<Box<dyn Hasher>>,
+    best_effort: bool,
+}
+
+impl<ST0: aqfs::StorageEntity, ST1: aqfs::StorageEntity> StorageSyncer<ST0, ST1> {
     pub fn new(st0: ST0, st1: ST1) -> Self {
+        Self::from_pair(aqfs::StoragePair::new(st0, st1))
+    }
+
+    pub fn from_pair(storages: aqfs::StoragePair<ST0, ST1>) -> Self {
         Self {
-            st0,
-            st1,
-            _marker0: std::marker::PhantomData,
-            _marker1: std::marker::PhantomData,
+            storages,
+            strategy: Box::new(AlwaysTransfer),
+            conflict_policy: ConflictPolicy::default(),
+            hasher: None,
+            best_effort: false,
         }
     }
 
-    pub async fn sync(&mut self) -> Result<(), aqfs::Error> {
+    // Same as new, but fail if st0 and st1 are backed by the same thing, e.g. the same directory,
+    // which would make every file conflict with itself.
+    pub fn try_new(st0: ST0, st1: ST1) -> Result<Self, aqfs::Error> {
+        let storages = aqfs::StoragePair::new(st0, st1);
+        if storages.is_same_backend() {
+            return Err(aqfs::Error::SameBackend);
+        }
+        Ok(Self::from_pair(storages))
+    }
+
+    pub fn source(&self) -> &ST0 {
+        self.storages.first()
+    }
+
+    pub fn source_mut(&mut self) -> &mut ST0 {
+        self.storages.first_mut()
+    }
+
+    pub fn destination(&self) -> &ST1 {
+        self.storages.second()
+    }
+
+    pub fn destination_mut(&mut self) -> &mut ST1 {
+        self.storages.second_mut()
+    }
+
+    // Take back the storages.
+    pub fn into_parts(self) -> (ST0, ST1) {
+        self.storages.into_parts()
+    }
+
+    // Set the strategy to decide which files are transferred. Defaults to AlwaysTransfer.
+    pub fn with_strategy(mut self, strategy: impl SyncStrategy + 'static) -> Self {
+        self.strategy = Box::new(strategy);
+        self
+    }
+
+    // Set how to resolve conflicts. Defaults to ConflictPolicy::Strategy.
+    pub fn with_conflict_policy(mut self, policy: ConflictPolicy) -> Self {
+        self.conflict_policy = policy;
+        self
+    }
+
+    // Compare the contents of files the strategy would transfer, and skip those already present
+    // with the same content in the destination. Disabled by default since it reads both files.
+    pub fn with_hasher(mut self, hasher: Box<dyn Hasher>) -> Self {
+        self.hasher = Some(hasher);
+        self
+    }
+
+    // Keep syncing the other files when a file fails, and record the failure in
+    // SyncReport::errors instead of returning it. Disabled by default, so that the first failure
+    // stops the sync.
+    pub fn with_best_effort(mut self, best_effort: bool) -> Self {
+        self.best_effort = best_effort;
+        self
+    }
+
+    /// Sync the storages. The first failure is returned as Err, unless with_best_effort is set, in
+    /// which case failures of each file are recorded in the report.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use asynq::aqfs::{File, FileMeta, RamFile, RamStorage, StorageEntity};
+    /// use asynq::sync::StorageSyncer;
+    ///
+    /// tokio_test::block_on(async {
+    ///     let mut st0 = RamStorage::new();
+    ///     let meta = FileMeta::builder().path("a.txt").build()?;
+    ///     st0.create_file(RamFile::new(meta, b"hello".to_vec())).await?;
+    ///     let mut syncer = StorageSyncer::new(st0, RamStorage::new());
+    ///     let report = syncer.sync().await?;
+    ///     assert!(report.errors.is_empty());
+    ///     let mut files = syncer.destination_mut().list_files().await?;
+    ///     assert_eq!(files.len(), 1);
+    ///     assert_eq!(files[0].read_all().await?, b"hello");
+    ///     Ok::<(), asynq::aqfs::Error>(())
+    /// })
+    /// .unwrap();
+    /// ```
+    pub async fn sync(&mut self) -> Result<SyncReport, aqfs::Error> {
+        let started = std::time::Instant::now();
+        let mut report = SyncReport::default();
+        let (st0, st1) = self.storages.both_mut();
+        let hasher = self.hasher.as_deref();
+        let best_effort = self.best_effort;
         // FIXME: We MUST need MUCH MUCH smarter algorithms here.
         // Send files from st0 to st1.
-        for f in self.st0.list_files().await?.into_iter() {
-            self.st1.create_file(f).await?;
+        // Conflicts are resolved here, and the resolutions are remembered for the other direction.
+        let mut st1_files = list_by_path(st1).await?;
+        let mut resolutions = HashMap::new();
+        let mut st0_stream = files_of(st0).await?;
+        while let Some(f) = st0_stream.next().await {
+            let mut f = f?;
+            let path = f.meta().path.clone();
+            let dest = st1_files.get(&path).map(|f1| f1.meta());
+            if let Some(dest) = dest.filter(|m| m.mtime != f.meta().mtime) {
+                if let Some(resolution) = self.conflict_policy.resolve(f.meta(), dest).await {
+                    report.conflicts.push(path.clone());
+                    match &resolution {
+                        ConflictResolution::UseSource => {
+                            transfer(st1, f, &mut report, best_effort).await?
+                        }
+                        ConflictResolution::Rename { new_path } => {
+                            transfer_to(st1, f, new_path.clone(), &mut report, best_effort).await?
+                        }
+                        ConflictResolution::UseDest => {}
+                        ConflictResolution::Skip => report.files_skipped += 1,
+                    }
+                    resolutions.insert(path, resolution);
+                    continue;
+                }
+            }
+            if needs_transfer(&*self.strategy, hasher, &mut f, st1_files.get_mut(&path)).await {
+                transfer(st1, f, &mut report, best_effort).await?;
+            } else {
+                report.files_skipped += 1;
+            }
         }
         // Send files from st1 to st0.
-        for f in self.st1.list_files().await?.into_iter() {
-            self.st0.create_file(f).await?;
+        drop(st0_stream);
+        let mut st0_files = list_by_path(st0).await?;
+        let mut st1_stream = files_of(st1).await?;
+        while let Some(f) = st1_stream.next().await {
+            let mut f = f?;
+            let path = f.meta().path.clone();
+            match resolutions.get(&path) {
+                Some(ConflictResolution::UseDest) | Some(ConflictResolution::Rename { .. }) => {
+                    transfer(st0, f, &mut report, best_effort).await?;
+                    continue;
+                }
+                Some(_) => continue,
+                None => {}
+            }
+            if needs_transfer(&*self.strategy, hasher, &mut f, st0_files.get_mut(&path)).await {
+                transfer(st0, f, &mut report, best_effort).await?;
+            } else {
+                report.files_skipped += 1;
+            }
+        }
+        report.duration_ms = started.elapsed().as_millis() as u64;
+        Ok(report)
+    }
+
+    // Copy the file at path from st1 back to st0, e.g. from a backup. If st0 has the file with a
+    // different mtime, the conflict policy decides with st1's version as the source. Restoring is
+    // explicit, so ConflictPolicy::Strategy lets st1's version win.
+    pub async fn restore(&mut self, path: &aqfs::Path) -> Result<(), aqfs::Error> {
+        let (st0, st1) = self.storages.both_mut();
+        let mut backup = list_by_path(st1)
+            .await?
+            .remove(path)
+            .ok_or_else(|| aqfs::Error::NotFound { path: path.clone() })?;
+        let mut restore_to = path.clone();
+        if let Some(current) = list_by_path(st0).await?.get(path) {
+            if current.meta().mtime != backup.meta().mtime {
+                match self
+                    .conflict_policy
+                    .resolve(backup.meta(), current.meta())
+                    .await
+                {
+                    None | Some(ConflictResolution::UseSource) => {}
+                    Some(ConflictResolution::UseDest) | Some(ConflictResolution::Skip) => {
+                        return Ok(())
+                    }
+                    Some(ConflictResolution::Rename { new_path }) => restore_to = new_path,
+                }
+            }
         }
+        let data = backup.read_all().await?;
+        st0.quota_check(data.len() as u64).await?;
+        let meta = aqfs::FileMeta {
+            path: restore_to,
+            ..backup.meta().clone()
+        };
+        st0.create_file(aqfs::RamFile::new(meta, data)).await?;
         Ok(())
     }
+
+    // Make st1 a mirror of st0: transfer new and updated files, and then remove the files that
+    // are only in st1. Removal starts only after every transfer has succeeded and the transferred
+    // files are listed in st1, so st1 never lacks a file that is in both its old and new states,
+    // even if this is interrupted halfway.
+    pub async fn mirror_safe(&mut self) -> Result<SyncReport, aqfs::Error> {
+        let started = std::time::Instant::now();
+        let mut report = SyncReport::default();
+        let (st0, st1) = self.storages.both_mut();
+        let hasher = self.hasher.as_deref();
+        let best_effort = self.best_effort;
+        let mut st1_files = list_by_path(st1).await?;
+        let mut src_paths = HashSet::new();
+        let mut transferred = vec![];
+        let mut st0_stream = files_of(st0).await?;
+        while let Some(f) = st0_stream.next().await {
+            let mut f = f?;
+            let path = f.meta().path.clone();
+            src_paths.insert(path.clone());
+            if needs_transfer(&*self.strategy, hasher, &mut f, st1_files.get_mut(&path)).await {
+                let errors = report.errors.len();
+                transfer(st1, f, &mut report, best_effort).await?;
+                if report.errors.len() == errors {
+                    transferred.push(path);
+                }
+            } else {
+                report.files_skipped += 1;
+            }
+        }
+        drop(st0_stream);
+
+        let st1_files = list_by_path(st1).await?;
+        for path in transferred.iter() {
+            if !st1_files.contains_key(path) {
+                if !best_effort {
+                    return Err(aqfs::Error::NotFound { path: path.clone() });
+                }
+                report
+                    .errors
+                    .push(format!("{}: Not found after transfer", path.to_string()));
+            }
+        }
+        if report.errors.is_empty() {
+            for (path, f) in st1_files.iter() {
+                if src_paths.contains(path) {
+                    continue;
+                }
+                match st1.remove_file(f).await {
+                    Ok(()) => report.files_removed += 1,
+                    Err(err) if best_effort => {
+                        report
+                            .errors
+                            .push(format!("{}: {:?}", path.to_string(), err))
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        report.duration_ms = started.elapsed().as_millis() as u64;
+        Ok(report)
+    }
 }
 
 #[cfg(test)]
@@ -51,6 +687,7 @@ mod test {
     use chrono::offset::TimeZone;
     use chrono::Utc;
     use std::collections::HashMap;
+    use std::convert::TryFrom;
 
     async fn is_storages_equivalent(
         st0: &mut aqfs::RamStorage,
@@ -82,8 +719,14 @@ mod test {
         let mut st0 = aqfs::RamStorage::new();
         st0.create_file(aqfs::RamFile::new(
             aqfs::FileMeta {
-                path: aqfs::Path::new(vec!["dummy-path0".to_string()]),
+                path: aqfs::Path::new(vec!["dummy-path0".to_string()]).unwrap(),
                 mtime: Utc.timestamp(0, 0),
+                locked: false,
+                content_type: None,
+                presigned_url: None,
+                custom: Default::default(),
+                sha256: None,
+                size: None,
             },
             "dummy content 0".to_string().into_bytes(),
         ))
@@ -91,17 +734,761 @@ mod test {
         let mut st1 = aqfs::RamStorage::new();
         st1.create_file(aqfs::RamFile::new(
             aqfs::FileMeta {
-                path: aqfs::Path::new(vec!["dummy-path1".to_string()]),
+                path: aqfs::Path::new(vec!["dummy-path1".to_string()]).unwrap(),
                 mtime: Utc.timestamp(0, 0),
+                locked: false,
+                content_type: None,
+                presigned_url: None,
+                custom: Default::default(),
+                sha256: None,
+                size: None,
             },
             "dummy content 1".to_string().into_bytes(),
         ))
         .await?;
         let mut syncer = StorageSyncer::new(st0, st1);
+        let report = syncer.sync().await?;
+        assert_eq!(report.files_transferred, 3);
+        assert_eq!(report.bytes_transferred, 15 * 3);
+        assert_eq!(report.files_skipped, 0);
+        assert!(report.errors.is_empty());
+        assert_eq!(syncer.source_mut().list_files().await.unwrap().len(), 2);
+        assert_eq!(
+            syncer.destination_mut().list_files().await.unwrap().len(),
+            2
+        );
+        let (mut st0, mut st1) = syncer.into_parts();
+        assert!(is_storages_equivalent(&mut st0, &mut st1).await);
+        Ok(())
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn sync_in_spawned_task() -> Result<(), aqfs::Error> {
+        let mut st0 = aqfs::RamStorage::new();
+        st0.create_file((aqfs::FileMeta::builder().path("a").build()?, b"a".to_vec()))
+            .await?;
+        let mut syncer = StorageSyncer::new(st0, aqfs::RamStorage::new());
+        let (report, mut syncer) = tokio::spawn(async move {
+            let report = syncer.sync().await?;
+            Ok::<_, aqfs::Error>((report, syncer))
+        })
+        .await
+        .unwrap()?;
+        assert_eq!(report.files_transferred, 1);
+        assert_eq!(syncer.destination_mut().list_files().await?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sync_from_read_only_storage() -> Result<(), aqfs::Error> {
+        let mut st0 = aqfs::RamStorage::new();
+        st0.create_file((aqfs::FileMeta::builder().path("a").build()?, b"a".to_vec()))
+            .await?;
+        let mut syncer =
+            StorageSyncer::new(aqfs::ReadOnlyStorage::new(st0), aqfs::RamStorage::new());
+        let report = syncer.sync().await?;
+        assert_eq!(report.files_transferred, 1);
+        assert_eq!(syncer.destination_mut().list_files().await?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sync_between_scoped_storages() -> Result<(), aqfs::Error> {
+        let mut st0 = aqfs::RamStorage::new();
+        for name in &["photos/2023/a", "photos/2023/d/b", "photos/2022/c"] {
+            st0.create_file((
+                aqfs::FileMeta::builder().path(*name).build()?,
+                b"x".to_vec(),
+            ))
+            .await?;
+        }
+        let mut st1 = aqfs::SharedRamStorage::new();
+        st1.create_file((
+            aqfs::FileMeta::builder().path("other/x").build()?,
+            b"x".to_vec(),
+        ))
+        .await?;
+        let mut syncer = StorageSyncer::new(
+            aqfs::ScopedStorage::new(st0, "photos/2023".parse()?),
+            aqfs::ScopedStorage::new(st1.clone(), "backup/photos".parse()?),
+        );
+        let report = syncer.sync().await?;
+        assert_eq!(report.files_transferred, 2);
+        let mut names: Vec<String> = st1
+            .list_files()
+            .await?
+            .iter()
+            .map(|f| f.meta().path.to_string())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["backup/photos/a", "backup/photos/d/b", "other/x"]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mtime_strategy_works() -> Result<(), aqfs::Error> {
+        let meta = |name: &str, secs: i64| aqfs::FileMeta {
+            path: aqfs::Path::new(vec![name.to_string()]).unwrap(),
+            mtime: Utc.timestamp(secs, 0),
+            locked: false,
+            content_type: None,
+            presigned_url: None,
+            custom: Default::default(),
+            sha256: None,
+            size: None,
+        };
+        let mut st0 = aqfs::RamStorage::new();
+        st0.create_file(aqfs::RamFile::new(meta("common", 0), b"old".to_vec()))
+            .await?;
+        st0.create_file(aqfs::RamFile::new(meta("only0", 0), b"only0".to_vec()))
+            .await?;
+        let mut st1 = aqfs::RamStorage::new();
+        st1.create_file(aqfs::RamFile::new(meta("common", 10), b"new".to_vec()))
+            .await?;
+        let mut syncer = StorageSyncer::new(st0, st1).with_strategy(MtimeStrategy::default());
+        let report = syncer.sync().await?;
+        assert!(report.errors.is_empty());
+        assert_eq!(syncer.source_mut().list_files().await.unwrap().len(), 2);
+        assert_eq!(
+            syncer.destination_mut().list_files().await.unwrap().len(),
+            2
+        );
+        let (mut st0, mut st1) = syncer.into_parts();
+        assert!(is_storages_equivalent(&mut st0, &mut st1).await);
+        for mut f in st0.list_files().await? {
+            if f.meta().path == aqfs::Path::new(vec!["common".to_string()]).unwrap() {
+                assert_eq!(f.read_all().await?, b"new");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn either_changed_works() {
+        let older = aqfs::FileMeta {
+            path: aqfs::Path::new(vec!["a".to_string()]).unwrap(),
+            mtime: Utc.timestamp(0, 0),
+            locked: false,
+            content_type: None,
+            presigned_url: None,
+            custom: Default::default(),
+            sha256: None,
+            size: None,
+        };
+        let newer = aqfs::FileMeta {
+            mtime: Utc.timestamp(10, 0),
+            ..older.clone()
+        };
+        let mtime = MtimeStrategy {
+            tolerance: Duration::seconds(20),
+        };
+        assert!(!mtime.should_transfer(&newer, Some(&older)));
+        assert!(mtime.should_transfer(&newer, None));
+        assert!(EitherChanged::new(mtime, AlwaysTransfer).should_transfer(&newer, Some(&older)));
+    }
+
+    #[test]
+    fn size_and_mtime_strategy() {
+        let dest = aqfs::FileMeta {
+            path: aqfs::Path::new(vec!["a".to_string()]).unwrap(),
+            mtime: Utc.timestamp(10, 0),
+            locked: false,
+            content_type: None,
+            presigned_url: None,
+            custom: Default::default(),
+            sha256: None,
+            size: Some(3),
+        };
+        let strategy = SizeAndMtimeStrategy::default();
+        assert!(strategy.should_transfer(&dest, None));
+        assert!(!strategy.should_transfer(&dest, Some(&dest)));
+        // Same mtime but a different size, e.g. written twice within the mtime resolution.
+        let resized = aqfs::FileMeta {
+            size: Some(4),
+            ..dest.clone()
+        };
+        assert!(strategy.should_transfer(&resized, Some(&dest)));
+        let newer = aqfs::FileMeta {
+            mtime: Utc.timestamp(20, 0),
+            ..dest.clone()
+        };
+        assert!(strategy.should_transfer(&newer, Some(&dest)));
+        // An unknown size doesn't count as a difference.
+        let unknown_size = aqfs::FileMeta {
+            size: None,
+            ..dest.clone()
+        };
+        assert!(!strategy.should_transfer(&unknown_size, Some(&dest)));
+    }
+
+    #[test]
+    fn hash_strategy() {
+        let dest = aqfs::FileMeta {
+            path: aqfs::Path::new(vec!["a".to_string()]).unwrap(),
+            mtime: Utc.timestamp(10, 0),
+            locked: false,
+            content_type: None,
+            presigned_url: None,
+            custom: Default::default(),
+            sha256: Some([0; 32]),
+            size: None,
+        };
+        let strategy = HashStrategy::default();
+        assert!(strategy.should_transfer(&dest, None));
+        // The digests decide regardless of mtime.
+        let touched = aqfs::FileMeta {
+            mtime: Utc.timestamp(20, 0),
+            ..dest.clone()
+        };
+        assert!(!strategy.should_transfer(&touched, Some(&dest)));
+        let changed = aqfs::FileMeta {
+            sha256: Some([1; 32]),
+            ..dest.clone()
+        };
+        assert!(strategy.should_transfer(&changed, Some(&dest)));
+        // Without a digest, the fallback, MtimeStrategy here, decides.
+        let undigested = aqfs::FileMeta {
+            sha256: None,
+            ..touched.clone()
+        };
+        assert!(strategy.should_transfer(&undigested, Some(&dest)));
+        let undigested = aqfs::FileMeta {
+            sha256: None,
+            ..dest.clone()
+        };
+        assert!(!strategy.should_transfer(&undigested, Some(&dest)));
+    }
+
+    #[test]
+    fn sync_report_formats() {
+        let report = SyncReport {
+            files_transferred: 42,
+            bytes_transferred: 1048576,
+            files_skipped: 100,
+            files_removed: 3,
+            conflicts: vec![aqfs::Path::new(vec!["a".to_string(), "b".to_string()]).unwrap()],
+            duration_ms: 3200,
+            errors: vec!["c: \"broken\"".to_string()],
+        };
+        let json = report.to_json();
+        assert_eq!(
+            json,
+            r#"{"files_transferred":42,"bytes_transferred":1048576,"files_skipped":100,"files_removed":3,"conflicts":["a/b"],"duration_ms":3200,"errors":["c: \"broken\""]}"#
+        );
+        assert_eq!(serde_json::from_str::<SyncReport>(&json).unwrap(), report);
+        assert_eq!(
+            report.to_csv(),
+            "files_transferred,bytes_transferred,files_skipped,files_removed,conflicts,duration_ms,errors\n42,1048576,100,3,a/b,3200,\"c: \"\"broken\"\"\"\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn custom_conflict_policy() -> Result<(), aqfs::Error> {
+        use futures::future::FutureExt;
+
+        let path = |name: &str| aqfs::Path::new(vec![name.to_string()]).unwrap();
+        let meta = |name: &str, secs: i64| aqfs::FileMeta {
+            path: path(name),
+            mtime: Utc.timestamp(secs, 0),
+            locked: false,
+            content_type: None,
+            presigned_url: None,
+            custom: Default::default(),
+            sha256: None,
+            size: None,
+        };
+        let mut st0 = aqfs::RamStorage::new();
+        st0.create_file(aqfs::RamFile::new(meta("a", 0), b"a0".to_vec()))
+            .await?;
+        st0.create_file(aqfs::RamFile::new(meta("b", 0), b"b0".to_vec()))
+            .await?;
+        let mut st1 = aqfs::RamStorage::new();
+        st1.create_file(aqfs::RamFile::new(meta("a", 10), b"a1".to_vec()))
+            .await?;
+        st1.create_file(aqfs::RamFile::new(meta("b", 10), b"b1".to_vec()))
+            .await?;
+
+        let calls = Arc::new(std::sync::Mutex::new(vec![]));
+        let policy = {
+            let calls = calls.clone();
+            ConflictPolicy::Custom(Arc::new(move |ctx: ConflictContext| {
+                let resolution = if ctx.path == path("a") {
+                    ConflictResolution::UseDest
+                } else {
+                    ConflictResolution::Rename {
+                        new_path: path("b.conflict"),
+                    }
+                };
+                calls.lock().unwrap().push(ctx);
+                async move { resolution }.boxed()
+            }))
+        };
+        let mut syncer = StorageSyncer::new(st0, st1).with_conflict_policy(policy);
+        let mut report = syncer.sync().await?;
+
+        let mut calls = calls.lock().unwrap().clone();
+        calls.sort_by_key(|ctx| ctx.path.to_string());
+        assert_eq!(
+            calls,
+            vec![
+                ConflictContext {
+                    path: path("a"),
+                    src_meta: meta("a", 0),
+                    dest_meta: meta("a", 10),
+                },
+                ConflictContext {
+                    path: path("b"),
+                    src_meta: meta("b", 0),
+                    dest_meta: meta("b", 10),
+                },
+            ]
+        );
+        report.conflicts.sort_by_key(|p| p.to_string());
+        assert_eq!(report.conflicts, vec![path("a"), path("b")]);
+        let (mut st0, mut st1) = syncer.into_parts();
+        assert!(is_storages_equivalent(&mut st0, &mut st1).await);
+        let mut contents = HashMap::new();
+        for mut f in st0.list_files().await? {
+            contents.insert(f.meta().path.to_string(), f.read_all().await?);
+        }
+        assert_eq!(contents.len(), 3);
+        assert_eq!(contents["a"], b"a1");
+        assert_eq!(contents["b"], b"b1");
+        assert_eq!(contents["b.conflict"], b"b0");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sync_with_itself() -> Result<(), aqfs::Error> {
+        let mut st = aqfs::SharedRamStorage::new();
+        st.create_file(aqfs::RamFile::new(
+            aqfs::FileMeta {
+                path: aqfs::Path::new(vec!["a".to_string()]).unwrap(),
+                mtime: Utc.timestamp(0, 0),
+                locked: false,
+                content_type: None,
+                presigned_url: None,
+                custom: Default::default(),
+                sha256: None,
+                size: None,
+            },
+            b"content".to_vec(),
+        ))
+        .await?;
+        let mut syncer = StorageSyncer::new(st.clone(), st.clone());
+        let report = syncer.sync().await?;
+        assert!(report.errors.is_empty());
+        assert!(report.conflicts.is_empty());
+        let mut files = st.list_files().await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].read_all().await?, b"content");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn quota_exceeded() -> Result<(), aqfs::Error> {
+        let mut st0 = aqfs::RamStorage::new();
+        st0.create_file(aqfs::RamFile::new(
+            aqfs::FileMeta {
+                path: aqfs::Path::new(vec!["large".to_string()]).unwrap(),
+                mtime: Utc.timestamp(0, 0),
+                locked: false,
+                content_type: None,
+                presigned_url: None,
+                custom: Default::default(),
+                sha256: None,
+                size: None,
+            },
+            vec![0; 100],
+        ))
+        .await?;
+        let st1 = aqfs::RamStorage::new().with_capacity(10);
+        let mut syncer = StorageSyncer::new(st0, st1);
+        match syncer.sync().await {
+            Err(aqfs::Error::QuotaExceeded { requested: 100, .. }) => {}
+            res => panic!("{:?}", res),
+        }
+        assert_eq!(syncer.destination_mut().list_files().await?.len(), 0);
+
+        let mut syncer = syncer.with_best_effort(true);
+        let report = syncer.sync().await?;
+        assert_eq!(report.files_transferred, 0);
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.errors[0].contains("QuotaExceeded"));
+        assert_eq!(syncer.destination_mut().list_files().await?.len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn try_new_rejects_same_backend() {
+        let st = aqfs::SharedRamStorage::new();
+        assert!(matches!(
+            StorageSyncer::try_new(st.clone(), st.clone()),
+            Err(aqfs::Error::SameBackend)
+        ));
+        assert!(StorageSyncer::try_new(st, aqfs::SharedRamStorage::new()).is_ok());
+        assert!(StorageSyncer::try_new(aqfs::RamStorage::new(), aqfs::RamStorage::new()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn try_new_rejects_same_locked_mutex() {
+        let st = Arc::new(tokio::sync::Mutex::new(aqfs::RamStorage::new()));
+        let _guard = st.lock().await;
+        assert!(matches!(
+            StorageSyncer::try_new(st.clone(), st.clone()),
+            Err(aqfs::Error::SameBackend)
+        ));
+    }
+
+    #[tokio::test]
+    async fn hasher_skips_same_content() -> Result<(), aqfs::Error> {
+        let meta = |name: &str, secs| aqfs::FileMeta {
+            path: aqfs::Path::new(vec![name.to_string()]).unwrap(),
+            mtime: Utc.timestamp(secs, 0),
+            locked: false,
+            content_type: None,
+            presigned_url: None,
+            custom: Default::default(),
+            sha256: None,
+            size: None,
+        };
+        let hashers: Vec<Box<dyn Hasher>> = vec![
+            Box::new(Sha256Hasher),
+            Box::new(Sha512Hasher),
+            Box::new(Blake3Hasher),
+        ];
+        for hasher in hashers {
+            let mut st0 = aqfs::RamStorage::new();
+            st0.create_file(aqfs::RamFile::new(meta("same", 1), b"same".to_vec()))
+                .await?;
+            st0.create_file(aqfs::RamFile::new(meta("diff", 1), b"new".to_vec()))
+                .await?;
+            let mut st1 = aqfs::RamStorage::new();
+            st1.create_file(aqfs::RamFile::new(meta("same", 0), b"same".to_vec()))
+                .await?;
+            st1.create_file(aqfs::RamFile::new(meta("diff", 0), b"old".to_vec()))
+                .await?;
+            let mut syncer = StorageSyncer::new(st0, st1)
+                .with_strategy(MtimeStrategy::default())
+                .with_hasher(hasher);
+            let report = syncer.sync().await?;
+            assert_eq!(report.files_transferred, 1);
+            let mut files = syncer.destination_mut().list_files().await?;
+            files.sort_by_key(|f| f.meta().path.to_string());
+            assert_eq!(files[0].read_all().await?, b"new");
+            assert_eq!(files[1].meta().mtime, Utc.timestamp(0, 0));
+        }
+        assert_eq!(Blake3Hasher.hash(b"").len(), 32);
+        assert_eq!(Sha512Hasher.hash(b"").len(), 64);
+        Ok(())
+    }
+
+    #[cfg(feature = "backend-local")]
+    #[tokio::test]
+    async fn streaming_list_works() -> Result<(), aqfs::Error> {
+        let (st0, _tmp0) = crate::local::Storage::init_in_temp()?;
+        let (st1, _tmp1) = crate::local::Storage::init_in_temp()?;
+        assert!(st0.capabilities().supports_streaming_list);
+        let mut syncer = StorageSyncer::new(st0, st1);
+        syncer
+            .source_mut()
+            .create_file(aqfs::RamFile::new(
+                aqfs::FileMeta {
+                    path: aqfs::Path::new(vec!["a".to_string()]).unwrap(),
+                    mtime: Utc.timestamp(0, 0),
+                    locked: false,
+                    content_type: None,
+                    presigned_url: None,
+                    custom: Default::default(),
+                    sha256: None,
+                    size: None,
+                },
+                b"a".to_vec(),
+            ))
+            .await?;
+        let report = syncer.sync().await?;
+        assert_eq!(report.files_transferred, 1);
+        let mut files = syncer.destination_mut().list_files().await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].read_all().await?, b"a");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sync_diff() -> Result<(), aqfs::Error> {
+        let meta = |name: &str| aqfs::FileMeta::builder().path(name).build().unwrap();
+        let mut st0 = aqfs::RamStorage::new();
+        st0.create_file(aqfs::RamFile::new(meta("a"), b"a".to_vec()))
+            .await?;
+        st0.create_file(aqfs::RamFile::new(meta("both"), b"both".to_vec()))
+            .await?;
+        let mut st1 = aqfs::RamStorage::new();
+        st1.create_file(aqfs::RamFile::new(meta("b"), b"b".to_vec()))
+            .await?;
+        st1.create_file(aqfs::RamFile::new(meta("both"), b"both".to_vec()))
+            .await?;
+        let (before0, before1) = (st0.clone_snapshot(), st1.clone_snapshot());
+        let mut syncer = StorageSyncer::new(st0, st1);
+        syncer.sync().await?;
+        let (st0, st1) = syncer.into_parts();
+        assert_eq!(
+            aqfs::diff(&before0, &st0),
+            aqfs::StorageDiff {
+                added: vec![aqfs::Path::try_from("b").unwrap()],
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            aqfs::diff(&before1, &st1),
+            aqfs::StorageDiff {
+                added: vec![aqfs::Path::try_from("a").unwrap()],
+                ..Default::default()
+            }
+        );
+        assert!(aqfs::diff(&st0, &st1).is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restore_works() -> Result<(), aqfs::Error> {
+        let meta = |name: &str, secs: i64| {
+            aqfs::FileMeta::builder()
+                .path(name)
+                .mtime(Utc.timestamp(secs, 0))
+                .build()
+                .unwrap()
+        };
+        let mut st0 = aqfs::RamStorage::new();
+        st0.create_file(aqfs::RamFile::new(meta("a", 0), b"good".to_vec()))
+            .await?;
+        let mut syncer = StorageSyncer::new(st0, aqfs::RamStorage::new());
         syncer.sync().await?;
-        assert_eq!(syncer.st0.list_files().await.unwrap().len(), 2);
-        assert_eq!(syncer.st1.list_files().await.unwrap().len(), 2);
-        assert!(is_storages_equivalent(&mut syncer.st0, &mut syncer.st1).await);
+        let corrupt = aqfs::RamFile::new(meta("a", 10), b"corrupted".to_vec());
+        syncer.source_mut().create_file(corrupt).await?;
+
+        // The backup is kept if the policy says so.
+        let mut syncer = syncer.with_conflict_policy(ConflictPolicy::UseDest);
+        syncer.restore(&aqfs::Path::try_from("a").unwrap()).await?;
+        let mut files = syncer.source_mut().list_files().await?;
+        assert_eq!(files[0].read_all().await?, b"corrupted");
+
+        let mut syncer = syncer.with_conflict_policy(ConflictPolicy::Strategy);
+        syncer.restore(&aqfs::Path::try_from("a").unwrap()).await?;
+        let mut files = syncer.source_mut().list_files().await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].read_all().await?, b"good");
+        assert_eq!(files[0].meta().mtime, Utc.timestamp(0, 0));
+
+        match syncer
+            .restore(&aqfs::Path::try_from("missing").unwrap())
+            .await
+        {
+            Err(aqfs::Error::NotFound { path }) => {
+                assert_eq!(path, aqfs::Path::try_from("missing").unwrap())
+            }
+            res => panic!("Restoring a missing file should fail: {:?}", res),
+        }
+        Ok(())
+    }
+
+    // A file of size bytes generated on the fly, which is never in memory as a whole.
+    struct GeneratedFile {
+        meta: aqfs::FileMeta,
+        size: u64,
+    }
+
+    #[async_trait::async_trait]
+    impl aqfs::File for GeneratedFile {
+        fn meta(&self) -> &aqfs::FileMeta {
+            &self.meta
+        }
+
+        async fn read_all(&mut self) -> Result<Vec<u8>, aqfs::Error> {
+            panic!("{} must be streamed", self.meta.path.to_string());
+        }
+
+        async fn read_stream<'a>(&'a mut self) -> Result<aqfs::FileReader<'a>, aqfs::Error> {
+            use tokio::io::AsyncReadExt;
+            Ok(Box::pin(tokio::io::repeat(b'x').take(self.size)))
+        }
+
+        fn size_hint(&self) -> Option<u64> {
+            Some(self.size)
+        }
+    }
+
+    // Read reader to the end in small pieces, and return its length and digest.
+    async fn stream_digest(
+        mut reader: aqfs::FileReader<'_>,
+    ) -> Result<(u64, [u8; 32]), aqfs::Error> {
+        let mut hasher = sha2::Sha256::new();
+        let mut buf = vec![0u8; 4 * 1024];
+        let mut total = 0;
+        loop {
+            let n = tokio::io::AsyncReadExt::read(&mut reader, &mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.input(&buf[..n]);
+            total += n as u64;
+        }
+        let mut digest = [0u8; 32];
+        digest.copy_from_slice(hasher.result().as_slice());
+        Ok((total, digest))
+    }
+
+    // A storage of GeneratedFiles, which keeps only the length and the digest of the written
+    // contents, so that they are never in memory as a whole.
+    #[derive(Default)]
+    struct GeneratedStorage {
+        files: HashMap<aqfs::Path, (aqfs::FileMeta, u64)>,
+        digests: HashMap<aqfs::Path, [u8; 32]>,
+    }
+
+    #[async_trait::async_trait]
+    impl aqfs::StorageEntity for GeneratedStorage {
+        type File = GeneratedFile;
+
+        async fn list_files(&mut self) -> Result<Vec<Self::File>, aqfs::Error> {
+            Ok(self
+                .files
+                .values()
+                .map(|(meta, size)| GeneratedFile {
+                    meta: meta.clone(),
+                    size: *size,
+                })
+                .collect())
+        }
+
+        async fn create_file(
+            &mut self,
+            mut file: impl aqfs::File + 'async_trait,
+        ) -> Result<aqfs::FileMeta, aqfs::Error> {
+            let (size, digest) = stream_digest(file.read_stream().await?).await?;
+            let meta = file.meta().clone();
+            self.digests.insert(meta.path.clone(), digest);
+            self.files.insert(meta.path.clone(), (meta.clone(), size));
+            Ok(meta)
+        }
+
+        async fn remove_file(&mut self, _file: &Self::File) -> Result<(), aqfs::Error> {
+            Err(aqfs::Error::NotImplemented)
+        }
+
+        async fn unlock_file(&mut self, _file: &Self::File) -> Result<(), aqfs::Error> {
+            Err(aqfs::Error::NotImplemented)
+        }
+    }
+
+    #[tokio::test]
+    async fn content_hash_across_backends() -> Result<(), aqfs::Error> {
+        let meta = |name: &str| aqfs::FileMeta::builder().path(name).build().unwrap();
+        let mut st = aqfs::RamStorage::new();
+        st.create_file((meta("a"), b"same".to_vec())).await?;
+        st.create_file((meta("b"), b"different".to_vec())).await?;
+        let mut hashes = vec![
+            st.get_file(&aqfs::Path::try_from("a").unwrap())
+                .await?
+                .content_hash()
+                .await?,
+            aqfs::hash_file(&mut (meta("a"), b"same".to_vec())).await?,
+        ];
+        #[cfg(feature = "backend-local")]
+        {
+            let (mut local, _tmp) = crate::local::Storage::init_in_temp()?;
+            local.create_file((meta("a"), b"same".to_vec())).await?;
+            let mut f = local.get_file(&aqfs::Path::try_from("a").unwrap()).await?;
+            // Computed on demand, since local files don't record it.
+            assert_eq!(f.meta().sha256, None);
+            hashes.push(f.content_hash().await?);
+        }
+        hashes.dedup();
+        assert_eq!(hashes.len(), 1);
+        let b = st.get_file(&aqfs::Path::try_from("b").unwrap()).await?;
+        assert_ne!(b.meta().sha256, Some(hashes[0]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stream_large_file() -> Result<(), aqfs::Error> {
+        // Far more than a read of stream_digest, which is enough to see it's streamed in pieces
+        // while keeping the test fast.
+        const SIZE: u64 = 4 * 1024 * 1024;
+        let mut f = GeneratedFile {
+            meta: aqfs::FileMeta::builder().path("large").build()?,
+            size: SIZE,
+        };
+        let (total, digest) = stream_digest(f.read_stream().await?).await?;
+        assert_eq!(total, SIZE);
+        assert_ne!(digest, aqfs::sha256(b""));
+
+        // Sync it between storages which never hold a content in memory. GeneratedFile panics if
+        // it's read as a whole.
+        let mut st0 = GeneratedStorage::default();
+        st0.files
+            .insert(f.meta.path.clone(), (f.meta.clone(), SIZE));
+        let mut syncer = StorageSyncer::new(st0, GeneratedStorage::default())
+            .with_strategy(MtimeStrategy::default());
+        let report = syncer.sync().await?;
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert_eq!(report.files_transferred, 1);
+        assert_eq!(report.bytes_transferred, SIZE);
+        let (_, st1) = syncer.into_parts();
+        assert_eq!(st1.files[&f.meta.path], (f.meta.clone(), SIZE));
+        assert_eq!(st1.digests[&f.meta.path], digest);
+
+        // A large RamFile streams too, in pieces of the reader's buffer.
+        let mut f = aqfs::RamFile::new(f.meta.clone(), vec![b'x'; 1024 * 1024]);
+        let mut sink = tokio::io::sink();
+        assert_eq!(
+            tokio::io::copy(&mut f.read_stream().await?, &mut sink).await?,
+            1024 * 1024
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mirror_safe_works() -> Result<(), aqfs::Error> {
+        let meta = |name: &str| aqfs::FileMeta::builder().path(name).build().unwrap();
+        let mut st0 = aqfs::RamStorage::new();
+        st0.create_file(aqfs::RamFile::new(meta("new"), b"new".to_vec()))
+            .await?;
+        let mut st1 = aqfs::RamStorage::new();
+        st1.create_file(aqfs::RamFile::new(meta("stale"), b"stale".to_vec()))
+            .await?;
+        let mut syncer = StorageSyncer::new(st0, st1);
+        let report = syncer.mirror_safe().await?;
+        assert!(report.errors.is_empty());
+        assert_eq!(report.files_transferred, 1);
+        assert_eq!(report.files_removed, 1);
+        let (mut st0, mut st1) = syncer.into_parts();
+        assert!(is_storages_equivalent(&mut st0, &mut st1).await);
+        assert_eq!(st1.list_files().await?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mirror_safe_keeps_dest_on_failure() -> Result<(), aqfs::Error> {
+        let meta = |name: &str| aqfs::FileMeta::builder().path(name).build().unwrap();
+        let mut st0 = aqfs::RamStorage::new();
+        st0.create_file(aqfs::RamFile::new(meta("new"), vec![0; 100]))
+            .await?;
+        // The upload fails as if interrupted, so the old file must survive.
+        let mut st1 = aqfs::RamStorage::new().with_capacity(10);
+        st1.create_file(aqfs::RamFile::new(meta("stale"), b"stale".to_vec()))
+            .await?;
+        let mut syncer = StorageSyncer::new(st0, st1);
+        assert!(syncer.mirror_safe().await.is_err());
+        let mut syncer = syncer.with_best_effort(true);
+        let report = syncer.mirror_safe().await?;
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.files_removed, 0);
+        let files = syncer.destination_mut().list_files().await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].meta().path, aqfs::Path::try_from("stale").unwrap());
         Ok(())
     }
 }